@@ -0,0 +1,75 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering::Relaxed},
+    time::Instant,
+};
+
+use super::{
+    comm_api::{ComServer, FilterType},
+    iface::{InterfaceConfig, InterfacePayload, InterfaceResult, IFACE_CFG},
+};
+
+// Technicians watch the bus before doing anything active on it, so the monitor must never
+// transmit. It talks to the adapter directly, and never calls a send function. The adapter is
+// opened in listen only mode, so the CAN controller does not even acknowledge frames, unless
+// it has no listen only mode and the config explicitly allows acknowledging
+
+/// Longest time a single read waits for frames, so a stop request is noticed quickly
+const MONITOR_READ_TIMEOUT_MS: u32 = 100;
+/// Most frames read from the adapter at once
+const MONITOR_READ_MAX: usize = 64;
+
+/// Passively monitors the CAN bus, calling `cb` with every frame received, until `stop` is set.
+///
+/// The interface is opened at [IFACE_CFG::BAUDRATE] in listen only mode. If the adapter
+/// has none, this fails unless [IFACE_CFG::MONITOR_ALLOW_ACK] is set
+pub fn monitor<F>(
+    comm_server: &Box<dyn ComServer>,
+    interface_cfg: &InterfaceConfig,
+    stop: &AtomicBool,
+    mut cb: F,
+) -> InterfaceResult<()>
+where
+    F: FnMut(InterfacePayload),
+{
+    let mut dev = comm_server.clone_box();
+    let baud = interface_cfg.get_param(IFACE_CFG::BAUDRATE)?;
+    let ext_can = interface_cfg.get_param_or_default(IFACE_CFG::EXT_CAN_ADDR, 0) > 0;
+    if let Err(e) = dev.open_can_interface_listen_only(baud, ext_can) {
+        if interface_cfg.get_param_or_default(IFACE_CFG::MONITOR_ALLOW_ACK, 0) == 0 {
+            return Err(e);
+        }
+        eprintln!(
+            "MONITOR - {}. Monitoring anyway, received frames will be acknowledged",
+            e.err_desc
+        );
+        dev.open_can_interface(baud, ext_can)?;
+    }
+    let res = monitor_frames(&mut dev, stop, &mut cb);
+    let closed = dev.close_can_interface();
+    res.and(closed)
+}
+
+fn monitor_frames<F>(
+    dev: &mut Box<dyn ComServer>,
+    stop: &AtomicBool,
+    cb: &mut F,
+) -> InterfaceResult<()>
+where
+    F: FnMut(InterfacePayload),
+{
+    // Adapters only pass frames which match a filter
+    dev.add_can_filter(FilterType::Pass { id: 0, mask: 0 })?;
+    while !stop.load(Relaxed) {
+        let frames = dev.read_can_packets(MONITOR_READ_TIMEOUT_MS, MONITOR_READ_MAX)?;
+        let timestamp = Some(Instant::now());
+        for f in frames {
+            cb(InterfacePayload {
+                id: f.id,
+                data: Vec::from(f.get_data()),
+                flags: vec![],
+                timestamp,
+            });
+        }
+    }
+    Ok(())
+}
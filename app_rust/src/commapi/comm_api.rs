@@ -4,6 +4,8 @@ use std::fmt::Debug;
 use std::time::Instant;
 use std::{fmt::Formatter, result::Result};
 
+use super::iface::format_hex;
+
 #[derive(Debug, Copy, Clone, Default)]
 pub struct CanFrame {
     pub id: u32,
@@ -57,9 +59,9 @@ impl std::fmt::Display for CanFrame {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "ID: 0x{:04X} Data: {:02X?}",
+            "ID: 0x{:04X} Data: {}",
             self.id,
-            &self.data[0..self.dlc as usize]
+            format_hex(&self.data[0..self.dlc as usize])
         )
     }
 }
@@ -76,8 +78,9 @@ impl std::fmt::Display for ISO15765Data {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "ISO15765: ID: 0x{:04X}, Payload: {:02X?}",
-            self.id, self.data
+            "ISO15765: ID: 0x{:04X}, Payload: {}",
+            self.id,
+            format_hex(&self.data)
         )
     }
 }
@@ -105,12 +108,156 @@ pub enum FilterType {
     IsoTP { id: u32, mask: u32, fc: u32 },
 }
 
+/// Value of an adapter specific option. See [ComServer::set_device_option]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigValue {
+    Bool(bool),
+    Int(u32),
+    Text(String),
+}
+
+impl ConfigValue {
+    /// Returns the value as a number, with booleans as 0 or 1. None for text
+    pub fn as_u32(&self) -> Option<u32> {
+        match self {
+            Self::Bool(b) => Some(*b as u32),
+            Self::Int(x) => Some(*x),
+            Self::Text(_) => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ComServerError {
     pub err_code: u32,
     pub err_desc: String,
 }
 
+/// Cause of a [ComServerError] which the user can do something about
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ComErrorKind {
+    /// Adapter is not plugged in, or does not exist
+    DeviceNotFound,
+    /// Adapter is already opened by another process
+    DeviceBusy,
+    /// The OS denied access to the adapter
+    PermissionDenied,
+    /// CAN controller has gone bus-off
+    BusOff,
+    /// ECU started sending a message longer than is accepted
+    MessageTooLong,
+    /// Adapter or gateway refused to pass the request on to the ECU (For example a DoIP
+    /// diagnostic message negative acknowledgement)
+    NoAck,
+    /// ECU or adapter did not respond in time
+    Timeout,
+    /// Any other error
+    Other,
+}
+
+impl ComServerError {
+    /// Error code used when the CAN controller has gone bus-off
+    pub const BUS_OFF: u32 = 0xB0FF;
+    /// Error code used when the adapter could not be found
+    pub const DEVICE_NOT_FOUND: u32 = 0xD001;
+    /// Error code used when the adapter is in use by another process
+    pub const DEVICE_BUSY: u32 = 0xD002;
+    /// Error code used when access to the adapter was denied
+    pub const PERMISSION_DENIED: u32 = 0xD003;
+    /// Error code used when the ECU started sending a message longer than is accepted
+    /// ([crate::commapi::iface::IFACE_CFG::MAX_RESPONSE_LEN])
+    pub const MESSAGE_TOO_LONG: u32 = 0xD004;
+    /// Error code used when the adapter or gateway did not acknowledge the request
+    pub const NO_ACK: u32 = 0xD005;
+    /// Error code used when the ECU or adapter did not respond in time
+    pub const TIMEOUT: u32 = 0xD006;
+
+    /// Creates an error of a known kind, with the adapter's / OS's description of it
+    pub fn from_kind(kind: ComErrorKind, err_desc: String) -> Self {
+        let err_code = match kind {
+            ComErrorKind::DeviceNotFound => Self::DEVICE_NOT_FOUND,
+            ComErrorKind::DeviceBusy => Self::DEVICE_BUSY,
+            ComErrorKind::PermissionDenied => Self::PERMISSION_DENIED,
+            ComErrorKind::BusOff => Self::BUS_OFF,
+            ComErrorKind::MessageTooLong => Self::MESSAGE_TOO_LONG,
+            ComErrorKind::NoAck => Self::NO_ACK,
+            ComErrorKind::Timeout => Self::TIMEOUT,
+            ComErrorKind::Other => 0,
+        };
+        Self { err_code, err_desc }
+    }
+
+    pub fn kind(&self) -> ComErrorKind {
+        match self.err_code {
+            Self::DEVICE_NOT_FOUND => ComErrorKind::DeviceNotFound,
+            Self::DEVICE_BUSY => ComErrorKind::DeviceBusy,
+            Self::PERMISSION_DENIED => ComErrorKind::PermissionDenied,
+            Self::BUS_OFF => ComErrorKind::BusOff,
+            Self::MESSAGE_TOO_LONG => ComErrorKind::MessageTooLong,
+            Self::NO_ACK => ComErrorKind::NoAck,
+            Self::TIMEOUT => ComErrorKind::Timeout,
+            _ => ComErrorKind::Other,
+        }
+    }
+
+    /// Returns what the user should do about the error, if it is of a known kind
+    pub fn get_help(&self) -> Option<&'static str> {
+        match self.kind() {
+            ComErrorKind::DeviceNotFound => Some("Adapter not found - Check it is plugged in"),
+            ComErrorKind::DeviceBusy => Some("Adapter in use - Close other diagnostic tools"),
+            ComErrorKind::PermissionDenied => {
+                Some("Access to the adapter was denied - Check your user's permissions")
+            }
+            ComErrorKind::BusOff => Some("CAN bus is off - Check the wiring"),
+            ComErrorKind::MessageTooLong => {
+                Some("ECU response is too long - Check the max response length")
+            }
+            ComErrorKind::NoAck => Some("Request was refused before reaching the ECU"),
+            ComErrorKind::Timeout => Some("No response - Check the ECU is powered on"),
+            ComErrorKind::Other => None,
+        }
+    }
+
+    /// Creates a [ComErrorKind::MessageTooLong] error for a message of `len` bytes, which is
+    /// longer than the `max` bytes accepted. `source` is what rejected it (For example "ISO-TP")
+    pub fn message_too_long(source: &str, len: usize, max: usize) -> Self {
+        Self {
+            err_code: Self::MESSAGE_TOO_LONG,
+            err_desc: format!(
+                "{} - Message of {} bytes is longer than the {} byte limit",
+                source, len, max
+            ),
+        }
+    }
+
+    /// Returns the length of the rejected message, if this is a [ComErrorKind::MessageTooLong]
+    /// error created with [ComServerError::message_too_long]
+    pub fn rejected_len(&self) -> Option<usize> {
+        if self.kind() != ComErrorKind::MessageTooLong {
+            return None;
+        }
+        let start = self.err_desc.find("Message of ")? + "Message of ".len();
+        self.err_desc[start..].split(' ').next()?.parse().ok()
+    }
+
+    /// Creates a [ComErrorKind::Timeout] error
+    pub fn timeout(err_desc: String) -> Self {
+        Self::from_kind(ComErrorKind::Timeout, err_desc)
+    }
+
+    pub fn bus_off() -> Self {
+        Self {
+            err_code: Self::BUS_OFF,
+            err_desc: "CAN controller is bus-off".into(),
+        }
+    }
+
+    /// Returns true if the error was caused by the CAN controller going bus-off
+    pub fn is_bus_off(&self) -> bool {
+        self.err_code == Self::BUS_OFF
+    }
+}
+
 impl std::fmt::Display for ComServerError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "Error code {} ({})", self.err_code, self.err_desc)
@@ -297,6 +444,27 @@ pub trait ComServer: Send + Sync + Debug {
         is_ext_can: bool,
     ) -> Result<(), ComServerError>;
 
+    /// Attempts to open a CAN interface in listen only mode, where the adapter's CAN controller
+    /// transmits nothing onto the bus, not even the acknowledgement of received frames.
+    /// The interface is closed with [ComServer::close_can_interface] as normal.
+    ///
+    /// Adapters which cannot guarantee this return an error (The default)
+    ///
+    /// ## Params
+    /// * `bus_speed` - Speed of the vehicle Canbus in bps, typically for an OBD-II port it is 500000
+    /// * `is_ext_can` - Tells the adapter to use extended CAN Addressing
+    fn open_can_interface_listen_only(
+        &mut self,
+        bus_speed: u32,
+        is_ext_can: bool,
+    ) -> Result<(), ComServerError> {
+        let _ = (bus_speed, is_ext_can);
+        Err(ComServerError {
+            err_code: 1,
+            err_desc: format!("{} adapter has no listen only mode", self.get_api()),
+        })
+    }
+
     /// Attempts to destroy the CAN Interface on the adapter
     fn close_can_interface(&mut self) -> Result<(), ComServerError>;
 
@@ -360,6 +528,16 @@ pub trait ComServer: Send + Sync + Debug {
         block_size: u32,
     ) -> Result<(), ComServerError>;
 
+    /// Sets the maximum number of Flow Control WAIT frames the adapter accepts from the ECU
+    /// whilst sending a multi-frame message, before aborting the transfer.
+    ///
+    /// If an ISO15765 channel is not currently opened on the device, this function
+    /// will return an error.
+    ///
+    /// # Params
+    /// * wft_max - Maximum number of consecutive WAIT frames
+    fn set_iso15765_wait_limit(&mut self, wft_max: u32) -> Result<(), ComServerError>;
+
     /// Tells the adapter to clear any data in its Rx buffer
     /// that is from CAN protocol
     fn clear_can_rx_buffer(&self) -> Result<(), ComServerError>;
@@ -383,6 +561,21 @@ pub trait ComServer: Send + Sync + Debug {
     /// * Returns the voltage in Volts
     fn read_battery_voltage(&self) -> Result<f32, ComServerError>;
 
+    /// Sets an adapter specific option (For example, a termination resistor or listen only
+    /// mode) which has no typed setting, on the interfaces that are open.
+    /// Options are set with [InterfaceConfig::set_raw](crate::commapi::iface::InterfaceConfig::set_raw)
+    ///
+    /// # Returns
+    /// * Returns false if the adapter does not know the option. This is the default
+    fn set_device_option(
+        &mut self,
+        key: &str,
+        value: &ConfigValue,
+    ) -> Result<bool, ComServerError> {
+        let _ = (key, value);
+        Ok(false)
+    }
+
     /// Clones this in memory into a new Box
     fn clone_box(&self) -> Box<dyn ComServer>;
 
@@ -0,0 +1,430 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream},
+    time::{Duration, Instant},
+};
+
+use super::{
+    comm_api::{ComErrorKind, ComServerError},
+    iface::{
+        BufferType, DiagTransport, InterfaceConfig, InterfacePayload, InterfaceResult, IFACE_CFG,
+    },
+    protocols::ResponseOptions,
+};
+
+/// TCP port DoIP entities listen for testers on (ISO13400-2)
+pub const DOIP_PORT: u16 = 13400;
+/// DoIP protocol version sent in our headers (ISO13400-2:2012)
+const PROTOCOL_VERSION: u8 = 0x02;
+const HEADER_LEN: usize = 8;
+
+const TYPE_HEADER_NACK: u16 = 0x0000;
+const TYPE_ROUTING_ACTIVATION_REQUEST: u16 = 0x0005;
+const TYPE_ROUTING_ACTIVATION_RESPONSE: u16 = 0x0006;
+const TYPE_ALIVE_CHECK_REQUEST: u16 = 0x0007;
+const TYPE_ALIVE_CHECK_RESPONSE: u16 = 0x0008;
+const TYPE_DIAG_MESSAGE: u16 = 0x8001;
+const TYPE_DIAG_MESSAGE_ACK: u16 = 0x8002;
+const TYPE_DIAG_MESSAGE_NACK: u16 = 0x8003;
+
+/// Routing activation response code for a successful activation
+const ROUTING_ACTIVATION_SUCCESS: u8 = 0x10;
+/// Generic header negative acknowledgement code for a message which is too large
+const NACK_MESSAGE_TOO_LARGE: u8 = 0x02;
+
+/// Length of the source and target addresses at the start of a diagnostic message
+const DIAG_MESSAGE_ADDR_LEN: usize = 4;
+
+/// Longest time to wait for the TCP connection to be made
+const CONNECT_TIMEOUT_MS: u64 = 2000;
+/// Longest time to wait for the routing activation response (ISO13400-2 A_DoIP_Ctrl)
+const ROUTING_ACTIVATION_TIMEOUT_MS: u64 = 2000;
+/// Longest time to wait for the entity to acknowledge a diagnostic message
+const DIAG_ACK_TIMEOUT_MS: u64 = 2000;
+/// Longest time to wait for the rest of a message once its first byte has arrived
+const MESSAGE_TIMEOUT_MS: u64 = 1000;
+/// Most reads [DoIpTransport::flush_rx] makes before giving up on emptying the receive buffer
+const MAX_FLUSH_READS: usize = 16;
+
+/// Diagnostics over IP (ISO13400-2) to a DoIP entity (ECU or gateway), over TCP.
+///
+/// Payload IDs are DoIP logical addresses. Sent payloads are addressed to their ID, and
+/// received payloads have the logical address of the ECU which sent them. Diagnostic messages
+/// are forwarded whole, so there is no segmenting or flow control as with ISO-TP.
+///
+/// Only the tester side of a single TCP connection is supported (No vehicle discovery over UDP
+/// and no TLS). Alive check requests from the entity are answered whilst waiting for messages
+pub struct DoIpTransport {
+    stream: TcpStream,
+    /// Our logical address
+    tester_addr: u16,
+    /// Logical address of the DoIP entity, from its routing activation response
+    entity_addr: u16,
+    /// Diagnostic messages received, waiting to be read
+    rx: VecDeque<InterfacePayload>,
+    /// Largest payload accepted from the entity. This is the largest response accepted
+    /// ([IFACE_CFG::MAX_RESPONSE_LEN]), after the addresses of a diagnostic message
+    max_payload_len: usize,
+}
+
+impl std::fmt::Debug for DoIpTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DoIpTransport")
+            .field("peer", &self.stream.peer_addr().ok())
+            .field("tester_addr", &self.tester_addr)
+            .field("entity_addr", &self.entity_addr)
+            .finish()
+    }
+}
+
+fn io_error(e: std::io::Error) -> ComServerError {
+    ComServerError::from_kind(ComErrorKind::Other, format!("DoIP - {}", e))
+}
+
+fn doip_error(desc: String) -> ComServerError {
+    ComServerError::from_kind(ComErrorKind::Other, format!("DoIP - {}", desc))
+}
+
+fn timeout_error(desc: &str) -> ComServerError {
+    ComServerError::timeout(format!("DoIP - {}", desc))
+}
+
+/// Builds a DoIP message (Generic header followed by `payload`)
+fn build_message(payload_type: u16, payload: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(HEADER_LEN + payload.len());
+    msg.push(PROTOCOL_VERSION);
+    msg.push(!PROTOCOL_VERSION);
+    msg.extend_from_slice(&payload_type.to_be_bytes());
+    msg.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    msg.extend_from_slice(payload);
+    msg
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Option<u16> {
+    data.get(pos..pos + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+}
+
+impl DoIpTransport {
+    /// Connects to the DoIP entity at `addr` (Usually on [DOIP_PORT]) and activates routing
+    /// for `tester_addr`. `activation_type` is 0x00 for a default activation. Messages longer
+    /// than `cfg`'s [IFACE_CFG::MAX_RESPONSE_LEN] are rejected
+    pub fn connect(
+        addr: SocketAddr,
+        tester_addr: u16,
+        activation_type: u8,
+        cfg: &InterfaceConfig,
+    ) -> InterfaceResult<Self> {
+        let stream = TcpStream::connect_timeout(&addr, Duration::from_millis(CONNECT_TIMEOUT_MS))
+            .map_err(io_error)?;
+        stream.set_nodelay(true).map_err(io_error)?;
+        let mut transport = Self {
+            stream,
+            tester_addr,
+            entity_addr: 0,
+            rx: VecDeque::new(),
+            max_payload_len: DIAG_MESSAGE_ADDR_LEN
+                + cfg.get_param_or_default(
+                    IFACE_CFG::MAX_RESPONSE_LEN,
+                    ResponseOptions::DEFAULT_MAX_RESPONSE_LEN,
+                ) as usize,
+        };
+        transport.activate_routing(activation_type)?;
+        Ok(transport)
+    }
+
+    /// Logical address of the DoIP entity which activated routing
+    pub fn entity_address(&self) -> u16 {
+        self.entity_addr
+    }
+
+    fn activate_routing(&mut self, activation_type: u8) -> InterfaceResult<()> {
+        let mut req = self.tester_addr.to_be_bytes().to_vec();
+        req.push(activation_type);
+        req.extend_from_slice(&[0x00; 4]); // Reserved by ISO13400
+        self.write_message(TYPE_ROUTING_ACTIVATION_REQUEST, &req)?;
+        let deadline = Instant::now() + Duration::from_millis(ROUTING_ACTIVATION_TIMEOUT_MS);
+        loop {
+            let (payload_type, payload) = match self.read_message(deadline)? {
+                Some(m) => m,
+                None => return Err(timeout_error("No routing activation response")),
+            };
+            if payload_type != TYPE_ROUTING_ACTIVATION_RESPONSE {
+                self.handle_message(payload_type, payload)?;
+                continue;
+            }
+            let code = payload.get(4).copied();
+            if code != Some(ROUTING_ACTIVATION_SUCCESS) {
+                return Err(doip_error(format!(
+                    "Routing activation was denied (Code {:02X?})",
+                    code
+                )));
+            }
+            self.entity_addr = read_u16(&payload, 2).unwrap_or_default();
+            println!(
+                "DoIP - Routing activated. Tester 0x{:04X}, entity 0x{:04X}",
+                self.tester_addr, self.entity_addr
+            );
+            return Ok(());
+        }
+    }
+
+    fn write_message(&mut self, payload_type: u16, payload: &[u8]) -> InterfaceResult<()> {
+        self.stream
+            .write_all(&build_message(payload_type, payload))
+            .map_err(io_error)
+    }
+
+    /// Reads one message, waiting until `deadline` for it to start. Returns None if no
+    /// message arrived in time
+    fn read_message(&mut self, deadline: Instant) -> InterfaceResult<Option<(u16, Vec<u8>)>> {
+        // A read timeout of 0 is rejected by the OS, so always wait at least 1 ms
+        let wait = deadline
+            .saturating_duration_since(Instant::now())
+            .max(Duration::from_millis(1));
+        self.stream.set_read_timeout(Some(wait)).map_err(io_error)?;
+        let mut header = [0u8; HEADER_LEN];
+        match self.stream.read(&mut header[..1]) {
+            Ok(0) => return Err(doip_error("Connection closed by the entity".into())),
+            Ok(_) => {}
+            Err(e)
+                if e.kind() == std::io::ErrorKind::WouldBlock
+                    || e.kind() == std::io::ErrorKind::TimedOut =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(io_error(e)),
+        }
+        // The message has started, so the rest of it follows shortly
+        self.stream
+            .set_read_timeout(Some(Duration::from_millis(MESSAGE_TIMEOUT_MS)))
+            .map_err(io_error)?;
+        self.stream.read_exact(&mut header[1..]).map_err(io_error)?;
+        if header[1] != !header[0] {
+            return Err(doip_error(format!(
+                "Invalid header (Version {:02X}, inverse {:02X})",
+                header[0], header[1]
+            )));
+        }
+        let payload_type = u16::from_be_bytes([header[2], header[3]]);
+        let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        if len > self.max_payload_len {
+            // Skip over the payload without buffering it, so the next message can still be read
+            self.write_message(TYPE_HEADER_NACK, &[NACK_MESSAGE_TOO_LARGE])?;
+            let _ = io::copy(&mut (&self.stream).take(len as u64), &mut io::sink());
+            return Err(ComServerError::message_too_long(
+                "DoIP",
+                len.saturating_sub(DIAG_MESSAGE_ADDR_LEN),
+                self.max_payload_len - DIAG_MESSAGE_ADDR_LEN,
+            ));
+        }
+        let mut payload = vec![0u8; len];
+        self.stream.read_exact(&mut payload).map_err(io_error)?;
+        Ok(Some((payload_type, payload)))
+    }
+
+    /// Handles a message which is not the one currently being waited for
+    fn handle_message(&mut self, payload_type: u16, payload: Vec<u8>) -> InterfaceResult<()> {
+        match payload_type {
+            TYPE_DIAG_MESSAGE => {
+                let source = read_u16(&payload, 0)
+                    .ok_or_else(|| doip_error("Diagnostic message has no address".into()))?;
+                let data = &payload[DIAG_MESSAGE_ADDR_LEN.min(payload.len())..];
+                let mut res = InterfacePayload::new(source as u32, data);
+                res.timestamp = Some(Instant::now());
+                self.rx.push_back(res);
+            }
+            TYPE_ALIVE_CHECK_REQUEST => {
+                let addr = self.tester_addr.to_be_bytes();
+                self.write_message(TYPE_ALIVE_CHECK_RESPONSE, &addr)?;
+            }
+            TYPE_HEADER_NACK => {
+                return Err(doip_error(format!(
+                    "Entity rejected our message header (Code {:02X?})",
+                    payload.first()
+                )))
+            }
+            TYPE_DIAG_MESSAGE_ACK => {} // Late acknowledgement of an earlier message
+            _ => eprintln!("DoIP - Ignoring message type 0x{:04X}", payload_type),
+        }
+        Ok(())
+    }
+
+    /// Waits for the entity to acknowledge the diagnostic message just sent. Diagnostic
+    /// messages received whilst waiting are queued
+    fn await_ack(&mut self) -> InterfaceResult<()> {
+        let deadline = Instant::now() + Duration::from_millis(DIAG_ACK_TIMEOUT_MS);
+        loop {
+            match self.read_message(deadline)? {
+                Some((TYPE_DIAG_MESSAGE_ACK, _)) => return Ok(()),
+                Some((TYPE_DIAG_MESSAGE_NACK, payload)) => {
+                    return Err(ComServerError::from_kind(
+                        ComErrorKind::NoAck,
+                        format!(
+                            "DoIP - Diagnostic message was rejected (Code {:02X?})",
+                            payload.get(4)
+                        ),
+                    ))
+                }
+                Some((payload_type, payload)) => self.handle_message(payload_type, payload)?,
+                None => return Err(timeout_error("Diagnostic message was not acknowledged")),
+            }
+        }
+    }
+}
+
+impl DiagTransport for DoIpTransport {
+    fn send_data(&mut self, data: &[InterfacePayload], _timeout: u32) -> InterfaceResult<usize> {
+        for payload in data {
+            let mut msg = self.tester_addr.to_be_bytes().to_vec();
+            msg.extend_from_slice(&(payload.id as u16).to_be_bytes());
+            msg.extend_from_slice(&payload.data);
+            self.write_message(TYPE_DIAG_MESSAGE, &msg)?;
+            self.await_ack()?;
+        }
+        Ok(data.len())
+    }
+
+    fn recv_data(&mut self, max: usize, timeout: u32) -> InterfaceResult<Vec<InterfacePayload>> {
+        let deadline = Instant::now() + Duration::from_millis(timeout as u64);
+        while self.rx.is_empty() {
+            match self.read_message(deadline)? {
+                Some((payload_type, payload)) => self.handle_message(payload_type, payload)?,
+                None => break,
+            }
+        }
+        let count = max.min(self.rx.len());
+        Ok(self.rx.drain(..count).collect())
+    }
+
+    fn clear_buffer(&mut self, buffer_type: BufferType) -> InterfaceResult<()> {
+        if buffer_type != BufferType::TX {
+            self.rx.clear();
+        }
+        Ok(())
+    }
+
+    fn send_recv_data(
+        &mut self,
+        request: InterfacePayload,
+        write_timeout: u32,
+        read_timeout: u32,
+    ) -> InterfaceResult<InterfacePayload> {
+        self.clear_buffer(BufferType::RX)?;
+        self.send_data(&[request], write_timeout)?;
+        self.recv_data(1, read_timeout)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| timeout_error("Timeout waiting"))
+    }
+
+    fn flush_rx(&mut self) -> InterfaceResult<usize> {
+        let mut flushed = 0;
+        for _ in 0..MAX_FLUSH_READS {
+            match self.recv_data(16, 0) {
+                Ok(msgs) if !msgs.is_empty() => flushed += msgs.len(),
+                _ => break,
+            }
+        }
+        self.rx.clear();
+        Ok(flushed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    use super::{
+        build_message, DoIpTransport, NACK_MESSAGE_TOO_LARGE, ROUTING_ACTIVATION_SUCCESS,
+        TYPE_DIAG_MESSAGE, TYPE_DIAG_MESSAGE_ACK, TYPE_HEADER_NACK,
+        TYPE_ROUTING_ACTIVATION_REQUEST, TYPE_ROUTING_ACTIVATION_RESPONSE,
+    };
+    use crate::commapi::{
+        comm_api::ComErrorKind,
+        iface::{DiagTransport, InterfaceConfig, InterfacePayload, IFACE_CFG},
+    };
+
+    const TESTER: u16 = 0x0E80;
+    const ENTITY: u16 = 0x1010;
+
+    fn read_message(stream: &mut TcpStream) -> (u16, Vec<u8>) {
+        let mut header = [0u8; 8];
+        stream.read_exact(&mut header).unwrap();
+        assert_eq!(header[..2], [0x02, 0xFD]);
+        let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let mut payload = vec![0u8; len];
+        stream.read_exact(&mut payload).unwrap();
+        (u16::from_be_bytes([header[2], header[3]]), payload)
+    }
+
+    fn diag_message(source: u16, target: u16, data: &[u8]) -> Vec<u8> {
+        let mut payload = source.to_be_bytes().to_vec();
+        payload.extend_from_slice(&target.to_be_bytes());
+        payload.extend_from_slice(data);
+        build_message(TYPE_DIAG_MESSAGE, &payload)
+    }
+
+    #[test]
+    fn test_loopback_entity() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let entity = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let (payload_type, payload) = read_message(&mut stream);
+            assert_eq!(payload_type, TYPE_ROUTING_ACTIVATION_REQUEST);
+            assert_eq!(payload, vec![0x0E, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00]);
+            let mut res = TESTER.to_be_bytes().to_vec();
+            res.extend_from_slice(&ENTITY.to_be_bytes());
+            res.extend_from_slice(&[ROUTING_ACTIVATION_SUCCESS, 0, 0, 0, 0]);
+            stream
+                .write_all(&build_message(TYPE_ROUTING_ACTIVATION_RESPONSE, &res))
+                .unwrap();
+
+            // TesterPresent, acknowledged and answered
+            let (payload_type, payload) = read_message(&mut stream);
+            assert_eq!(payload_type, TYPE_DIAG_MESSAGE);
+            assert_eq!(payload, vec![0x0E, 0x80, 0x10, 0x10, 0x3E, 0x00]);
+            let mut ack = ENTITY.to_be_bytes().to_vec();
+            ack.extend_from_slice(&TESTER.to_be_bytes());
+            ack.push(0x00);
+            stream
+                .write_all(&build_message(TYPE_DIAG_MESSAGE_ACK, &ack))
+                .unwrap();
+            stream
+                .write_all(&diag_message(ENTITY, TESTER, &[0x7E, 0x00]))
+                .unwrap();
+
+            // Response larger than the tester accepts, followed by one it does
+            let mut oversized = diag_message(ENTITY, TESTER, &[0x55; 0x101]);
+            oversized.extend(diag_message(ENTITY, TESTER, &[0x7E, 0x00]));
+            stream.write_all(&oversized).unwrap();
+            assert_eq!(
+                read_message(&mut stream),
+                (TYPE_HEADER_NACK, vec![NACK_MESSAGE_TOO_LARGE])
+            );
+        });
+
+        let mut cfg = InterfaceConfig::new();
+        cfg.add_param(IFACE_CFG::MAX_RESPONSE_LEN, 0x100);
+        let mut transport = DoIpTransport::connect(addr, TESTER, 0x00, &cfg).unwrap();
+        assert_eq!(transport.entity_address(), ENTITY);
+        let res = transport
+            .send_recv_data(InterfacePayload::new(ENTITY as u32, &[0x3E, 0x00]), 0, 2000)
+            .unwrap();
+        assert_eq!(res.id, ENTITY as u32);
+        assert_eq!(res.data, vec![0x7E, 0x00]);
+
+        let err = transport.recv_data(1, 2000).unwrap_err();
+        assert_eq!(err.kind(), ComErrorKind::MessageTooLong);
+        assert_eq!(err.rejected_len(), Some(0x101));
+        let res = transport.recv_data(1, 2000).unwrap();
+        assert_eq!(res[0].data, vec![0x7E, 0x00]);
+        entity.join().unwrap();
+    }
+}
@@ -0,0 +1,119 @@
+use std::{collections::VecDeque, time::Instant};
+
+use super::{
+    comm_api::{ComServer, FilterType},
+    iface::{BufferType, Interface, InterfaceConfig, InterfacePayload, InterfaceResult, IFACE_CFG},
+    protocols::{describe_service, CautionLevel, DiagProtocol},
+};
+
+/// Interface which never transmits anything. Every request is logged, and answered
+/// with a canned positive response, so the command flow of the app can be demonstrated
+/// (Or worked on) without a vehicle, and without any risk to one.
+///
+/// The response to a request is its SID + 0x40 followed by the request's parameter bytes,
+/// which is what ECUs echo for most services that have a sub-function. Services which
+/// return data only get the echo, so anything decoding their response will see it as too short.
+///
+/// Enabled by setting [IFACE_CFG::DRY_RUN] on the interface config. Unlike the mock interface
+/// used by the tests, it cannot be scripted
+#[derive(Debug)]
+pub struct DryRunInterface {
+    dev: Box<dyn ComServer>,
+    protocol: DiagProtocol,
+    /// ID the canned responses are sent from (The receive ID of the last ISO-TP filter)
+    recv_id: Option<u32>,
+    responses: VecDeque<InterfacePayload>,
+}
+
+impl DryRunInterface {
+    /// Creates a dry run interface. `dev` is never used to send or receive, and only
+    /// returned by [Interface::get_server]. `protocol` is used to look up the name and
+    /// caution level of the requests being logged
+    pub fn new(dev: Box<dyn ComServer>, protocol: DiagProtocol) -> Box<dyn Interface> {
+        Box::new(Self {
+            dev,
+            protocol,
+            recv_id: None,
+            responses: VecDeque::new(),
+        })
+    }
+
+    /// Returns true if the interface config asks for a dry run
+    pub fn is_enabled(cfg: &InterfaceConfig) -> bool {
+        cfg.get_param_or_default(IFACE_CFG::DRY_RUN, 0) > 0
+    }
+}
+
+impl Interface for DryRunInterface {
+    fn setup(&mut self, _cfg: &InterfaceConfig) -> InterfaceResult<()> {
+        println!("DRY RUN - Interface opened, nothing will be sent to the vehicle");
+        Ok(())
+    }
+
+    fn send_data(&mut self, data: &[InterfacePayload], _timeout: u32) -> InterfaceResult<usize> {
+        for p in data {
+            let sid = match p.data.first() {
+                Some(sid) => *sid,
+                None => continue,
+            };
+            match describe_service(self.protocol, sid) {
+                Some(info) if info.caution != CautionLevel::None => println!(
+                    "DRY RUN - Would send {} ({}, caution level {:?})",
+                    p, info.name, info.caution
+                ),
+                Some(info) => println!("DRY RUN - Would send {} ({})", p, info.name),
+                None => println!("DRY RUN - Would send {}", p),
+            }
+            let mut res = p.data.clone();
+            res[0] = sid.wrapping_add(0x40);
+            self.responses.push_back(InterfacePayload {
+                id: self.recv_id.unwrap_or(p.id),
+                data: res,
+                flags: vec![],
+                timestamp: Some(Instant::now()),
+            });
+        }
+        Ok(data.len())
+    }
+
+    fn recv_data(&mut self, max: usize, _timeout: u32) -> InterfaceResult<Vec<InterfacePayload>> {
+        let count = max.min(self.responses.len());
+        Ok(self.responses.drain(..count).collect())
+    }
+
+    fn add_filter(&mut self, f: FilterType) -> InterfaceResult<u32> {
+        if let FilterType::IsoTP { id, .. } = f {
+            self.recv_id = Some(id);
+        }
+        Ok(0)
+    }
+
+    fn rem_filter(&mut self, _f_id: u32) -> InterfaceResult<()> {
+        Ok(())
+    }
+
+    fn close(&mut self) -> InterfaceResult<()> {
+        println!("DRY RUN - Interface closed");
+        Ok(())
+    }
+
+    fn clear_buffer(&mut self, buffer_type: BufferType) -> InterfaceResult<()> {
+        if buffer_type != BufferType::TX {
+            self.responses.clear();
+        }
+        Ok(())
+    }
+
+    fn get_server(&self) -> Box<dyn ComServer> {
+        self.dev.clone_box()
+    }
+
+    fn clone_box(&self) -> Box<dyn Interface> {
+        Box::new(Self {
+            dev: self.dev.clone_box(),
+            protocol: self.protocol,
+            recv_id: self.recv_id,
+            responses: VecDeque::new(),
+        })
+    }
+}
@@ -1,11 +1,15 @@
 use std::fmt::Debug;
 use std::{
     borrow::BorrowMut,
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
-use super::comm_api::{CanFrame, Capability, ComServer, ComServerError, FilterType, ISO15765Data};
+use super::comm_api::{
+    CanFrame, Capability, ComServer, ComServerError, ConfigValue, FilterType, ISO15765Data,
+};
+use super::soft_isotp::SoftIsoTpInterface;
 
 pub type InterfaceResult<T> = std::result::Result<T, ComServerError>;
 
@@ -18,8 +22,61 @@ pub enum IFACE_CFG {
     EXT_CAN_ADDR,
     EXT_ISOTP_ADDR,
     PAD_FLOW_CONTROL,
+    /// Block size we advertise in the ISO-TP Flow Control frames sent when receiving. See
+    /// [InterfaceConfig::fc_block_size]
     ISOTP_BS,
+    /// Separation time (ST_min) we advertise in the ISO-TP Flow Control frames sent when
+    /// receiving. See [InterfaceConfig::fc_st_min]
     ISOTP_ST_MIN,
+    /// Maximum number of consecutive ISO-TP Flow Control WAIT frames accepted from the ECU
+    /// before a transfer is aborted (Default 10)
+    ISOTP_WFT_MAX,
+    /// Total time budget in ms for establishing a diagnostic session. 0 (Default) means no limit
+    CONNECT_TIMEOUT_MS,
+    /// Largest response in bytes that will be accepted from the ECU (Default 64KiB)
+    MAX_RESPONSE_LEN,
+    /// 1 (Default) if the tester's own bytes are echoed back on K-Line, and need discarding.
+    /// Set to 0 for adapters that already strip the echo
+    KLINE_ECHO,
+    /// KWP2000 P3min. Minimum time in ms between the ECU's response and the next request
+    /// (Default 0)
+    KWP_P3_MIN_MS,
+    /// Data bytes per CAN frame of ISO-TP transfers. 8 (Default) for classic CAN,
+    /// up to 64 for CAN-FD. See [crate::commapi::isotp::FrameLayout]
+    ISOTP_CAN_DLC,
+    /// Time in ms to wait after the ECU accepts a diagnostic session change, before
+    /// sending the next request (Default 0)
+    POST_SESSION_DELAY_MS,
+    /// 1 to retry the first request after a session change once, if the ECU rejects it as
+    /// busy or conditions not correct whilst re-initializing (Default 0)
+    POST_SESSION_RETRY,
+    /// ISO-TP addressing scheme. See [crate::commapi::isotp::AddressingMode::from_cfg]
+    ISOTP_ADDRESSING,
+    /// Address byte of extended and mixed addressing (The ECU's target address, or the
+    /// address extension)
+    ISOTP_TARGET_ADDR,
+    /// Tester address that extended addressing responses start with (Default 0xF1)
+    ISOTP_SOURCE_ADDR,
+    /// 1 (Default) to discard frames left in the receive buffer before each request is sent,
+    /// so a late response to an earlier request is not taken as the response. See [Interface::flush_rx]
+    FLUSH_RX,
+    /// 1 to log requests instead of sending them, answering each with a canned positive
+    /// response (Default 0). See [crate::commapi::dry_run::DryRunInterface]
+    DRY_RUN,
+    /// Time in ms without frames from the ECU to wait for before starting a diagnostic session,
+    /// on buses where the ECU is still sending normal traffic. 0 (Default) starts straight away
+    WAIT_FOR_IDLE_MS,
+    /// Longest time in ms to wait for [IFACE_CFG::WAIT_FOR_IDLE_MS] before starting the session
+    /// anyway (Default 2000)
+    IDLE_MAX_WAIT_MS,
+    /// 1 to let [crate::commapi::bus_monitor::monitor] run on adapters which have no listen
+    /// only mode. Nothing is sent, but the adapter's CAN controller still acknowledges
+    /// frames (Default 0)
+    MONITOR_ALLOW_ACK,
+    /// 1 to do ISO-TP in software over raw CAN, even if the adapter has ISO-TP of its own.
+    /// Adapters without ISO-TP always use it (Default 0).
+    /// See [crate::commapi::soft_isotp::SoftIsoTpInterface]
+    SOFTWARE_ISOTP,
 }
 
 impl ToString for IFACE_CFG {
@@ -33,6 +90,26 @@ impl ToString for IFACE_CFG {
 pub enum PayloadFlag {
     ISOTP_PAD_FRAME,
     ISOTP_EXT_ADDR,
+    /// Prepends an ISO-TP address extension byte to the message, for ECUs which share
+    /// a CAN ID and demultiplex requests by the extension byte.
+    ///
+    /// When set, the ISO-TP interface prepends the byte on TX (Telling the adapter the message
+    /// uses extended addressing, as if [PayloadFlag::ISOTP_EXT_ADDR] were also set), and on RX only
+    /// accepts messages whose first byte matches the extension byte, which is then stripped.
+    /// This works on a per message basis, so it can be used even if the interface was not setup with
+    /// [IFACE_CFG::EXT_ISOTP_ADDR], however for the adapter to reassemble multi-frame responses
+    /// correctly, [IFACE_CFG::EXT_ISOTP_ADDR] should also be set on the interface
+    ISOTP_ADDR_EXTENSION(u8),
+    /// Address byte responses must start with, when it differs from the byte set by
+    /// [PayloadFlag::ISOTP_ADDR_EXTENSION]. With extended addressing, requests start with the
+    /// ECU's address, but responses start with the tester's address
+    ISOTP_RX_ADDR_EXTENSION(u8),
+    /// Prepends the length of the message as a 1 or 2 byte (Big endian) field, for OEM
+    /// transports whose application layer expects an explicit length before the service bytes.
+    ///
+    /// When set, the ISO-TP interface adds the length on TX, and strips it from responses
+    /// whose prefix matches their length
+    LENGTH_PREFIXED(u8),
 }
 
 #[derive(Debug, Clone)]
@@ -40,6 +117,8 @@ pub struct InterfacePayload {
     pub id: u32,
     pub data: Vec<u8>,
     pub flags: Vec<PayloadFlag>,
+    /// When the payload was received by the interface. None for payloads being sent
+    pub timestamp: Option<Instant>,
 }
 
 impl InterfacePayload {
@@ -47,27 +126,150 @@ impl InterfacePayload {
         self.flags.iter().find(|x| *x == &f).is_some()
     }
 
+    /// Returns the ISO-TP address extension byte of the payload, if one is set
+    pub fn get_addr_extension(&self) -> Option<u8> {
+        self.flags.iter().find_map(|f| match f {
+            PayloadFlag::ISOTP_ADDR_EXTENSION(b) => Some(*b),
+            _ => None,
+        })
+    }
+
+    /// Returns the address byte responses to the payload must start with, if one is set
+    pub fn get_rx_addr_extension(&self) -> Option<u8> {
+        self.flags
+            .iter()
+            .find_map(|f| match f {
+                PayloadFlag::ISOTP_RX_ADDR_EXTENSION(b) => Some(*b),
+                _ => None,
+            })
+            .or_else(|| self.get_addr_extension())
+    }
+
+    /// Returns the width in bytes of the length prefix of the payload, if one is set
+    pub fn get_length_prefix(&self) -> Option<u8> {
+        self.flags.iter().find_map(|f| match f {
+            PayloadFlag::LENGTH_PREFIXED(w) => Some(*w),
+            _ => None,
+        })
+    }
+
     pub fn new(id: u32, data: &[u8]) -> Self {
         Self {
             id,
             data: Vec::from(data),
             flags: vec![],
+            timestamp: None,
+        }
+    }
+
+    /// Returns how long after `start` (For example, when the request was sent) the payload
+    /// was received. None if the payload has no receive timestamp
+    pub fn elapsed_since(&self, start: Instant) -> Option<Duration> {
+        self.timestamp.map(|t| t.saturating_duration_since(start))
+    }
+}
+
+/// Returns `data` with its length prepended as a `width` (1 or 2) byte big endian field
+pub fn add_length_prefix(data: &[u8], width: u8) -> InterfaceResult<Vec<u8>> {
+    let mut res = match (width, data.len()) {
+        (1, len) if len <= 0xFF => vec![len as u8],
+        (2, len) if len <= 0xFFFF => vec![(len >> 8) as u8, len as u8],
+        _ => {
+            return Err(ComServerError {
+                err_code: 97,
+                err_desc: format!(
+                    "Cannot prefix {} bytes with a {} byte length",
+                    data.len(),
+                    width
+                ),
+            })
+        }
+    };
+    res.extend_from_slice(data);
+    Ok(res)
+}
+
+/// Removes a `width` byte length prefix from `data`. Returns None if the prefix
+/// does not match the length of the rest of the data
+pub fn strip_length_prefix(data: &[u8], width: u8) -> Option<Vec<u8>> {
+    let width = width as usize;
+    if width == 0 || width > 2 || data.len() < width {
+        return None;
+    }
+    let len = data[..width]
+        .iter()
+        .fold(0usize, |acc, b| acc << 8 | *b as usize);
+    if len == data.len() - width {
+        Some(Vec::from(&data[width..]))
+    } else {
+        None
+    }
+}
+
+/// Formats a byte slice as space separated, two digit uppercase hex bytes, for example
+/// `02 10 03`. Every byte keeps its leading zero, so values of the same length always
+/// have the same width
+pub fn format_hex(data: &[u8]) -> String {
+    let bytes: Vec<String> = data.iter().map(|b| format!("{:02X}", b)).collect();
+    bytes.join(" ")
+}
+
+/// Formats a byte slice like [format_hex], without the spaces, for example `021003`
+pub fn format_hex_compact(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+/// Formats a byte slice as space separated hex bytes, for example `[02 10 03]`
+pub fn hex_dump(data: &[u8]) -> String {
+    format!("[{}]", format_hex(data))
+}
+
+impl std::fmt::Display for InterfacePayload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ID 0x{:03X}  {}", self.id, hex_dump(&self.data))?;
+        if !self.flags.is_empty() {
+            write!(f, " Flags: {:?}", self.flags)?;
         }
+        Ok(())
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct InterfaceConfig {
     params: HashMap<String, u32>,
+    /// Adapter specific options. See [InterfaceConfig::set_raw]
+    raw: HashMap<String, ConfigValue>,
 }
 
 impl InterfaceConfig {
     pub fn new() -> Self {
         Self {
             params: HashMap::new(),
+            raw: HashMap::new(),
         }
     }
 
+    /// Sets an adapter specific option, which is passed to the adapter as is once the
+    /// interface is open (See [ComServer::set_device_option]). Options the adapter does not
+    /// know are ignored with a warning, so a config can be shared between adapters
+    pub fn set_raw(&mut self, key: &str, value: ConfigValue) {
+        self.raw.insert(key.to_string(), value);
+    }
+
+    /// Passes the adapter specific options to `dev`. See [InterfaceConfig::set_raw]
+    pub fn apply_raw(&self, dev: &mut Box<dyn ComServer>) -> InterfaceResult<()> {
+        for (key, value) in &self.raw {
+            if !dev.set_device_option(key, value)? {
+                eprintln!(
+                    "Adapter ({}) does not support option {} - Ignoring it",
+                    dev.get_api(),
+                    key
+                );
+            }
+        }
+        Ok(())
+    }
+
     pub fn add_param(&mut self, param_name: IFACE_CFG, param_value: u32) {
         self.params.insert(param_name.to_string(), param_value);
     }
@@ -76,6 +278,31 @@ impl InterfaceConfig {
         *self.params.get(&param_name.to_string()).unwrap_or(&default)
     }
 
+    /// Number of consecutive frames the ECU may send us before waiting for our next Flow
+    /// Control frame, when we receive a multi-frame message. 0 (Default) lets the ECU send
+    /// the whole message without waiting
+    pub fn fc_block_size(&self) -> u8 {
+        self.get_param_or_default(IFACE_CFG::ISOTP_BS, 0).min(0xFF) as u8
+    }
+
+    /// Sets the block size we advertise. See [InterfaceConfig::fc_block_size]
+    pub fn set_fc_block_size(&mut self, block_size: u8) {
+        self.add_param(IFACE_CFG::ISOTP_BS, block_size as u32)
+    }
+
+    /// Minimum gap the ECU must leave between the consecutive frames it sends us, as the ISO-TP
+    /// ST_min byte (0x00-0x7F ms, or 0xF1-0xF9 for 100-900us). Raising it stops the ECU overrunning
+    /// slow adapters when reading large responses. Default 0 (As fast as the ECU can send)
+    pub fn fc_st_min(&self) -> u8 {
+        self.get_param_or_default(IFACE_CFG::ISOTP_ST_MIN, 0)
+            .min(0xFF) as u8
+    }
+
+    /// Sets the ST_min we advertise. See [InterfaceConfig::fc_st_min]
+    pub fn set_fc_st_min(&mut self, st_min: u8) {
+        self.add_param(IFACE_CFG::ISOTP_ST_MIN, st_min as u32)
+    }
+
     pub fn get_param(&self, param_name: IFACE_CFG) -> InterfaceResult<u32> {
         match self.params.get(&param_name.to_string()) {
             Some(x) => Ok(*x),
@@ -98,9 +325,15 @@ pub enum BufferType {
     BOTH,
 }
 
+/// Most reads [Interface::flush_rx] makes before giving up on emptying the receive buffer
+const MAX_FLUSH_READS: usize = 16;
+
 pub trait Interface: Send + Sync + Debug {
     fn setup(&mut self, cfg: &InterfaceConfig) -> InterfaceResult<()>;
     fn send_data(&mut self, data: &[InterfacePayload], timeout: u32) -> InterfaceResult<usize>;
+    /// Receives up to `max` payloads, waiting at most `timeout` ms for the first one.
+    /// Never returns more than `max` payloads. Payloads the adapter delivered beyond `max`
+    /// are buffered and returned by the next call, see [RxQueue]
     fn recv_data(&mut self, max: usize, timeout: u32) -> InterfaceResult<Vec<InterfacePayload>>;
     fn add_filter(&mut self, f: FilterType) -> InterfaceResult<u32>;
     fn rem_filter(&mut self, f_id: u32) -> InterfaceResult<()>;
@@ -114,23 +347,123 @@ pub trait Interface: Send + Sync + Debug {
     ) -> InterfaceResult<InterfacePayload> {
         self.clear_buffer(BufferType::RX)?;
         self.send_data(&[request], write_timeout)?;
-        let res = self.recv_data(1, read_timeout)?;
-        if res.is_empty() {
-            return Err(ComServerError {
-                err_code: 2,
-                err_desc: "Timeout waiting".into(),
-            });
-        } else {
-            return Ok(res[0].clone());
+        self.recv_data(1, read_timeout)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| ComServerError::timeout("Timeout waiting".into()))
+    }
+    /// Discards the frames waiting in the receive buffer (For example, a late response to a
+    /// request that timed out), then clears the adapter's receive buffer. Returns how many
+    /// frames were discarded
+    fn flush_rx(&mut self) -> InterfaceResult<usize> {
+        let mut flushed = 0;
+        // Bounded, so a busy bus cannot keep this reading forever
+        for _ in 0..MAX_FLUSH_READS {
+            match self.recv_data(16, 0) {
+                Ok(frames) if !frames.is_empty() => flushed += frames.len(),
+                _ => break,
+            }
         }
+        self.clear_buffer(BufferType::RX)?;
+        Ok(flushed)
+    }
+    /// Recovers the CAN controller after it went bus-off, by closing the interface and
+    /// setting it up again with `cfg`. Any filters need to be added again afterwards
+    fn recover_bus(&mut self, cfg: &InterfaceConfig) -> InterfaceResult<()> {
+        self.close()?;
+        self.setup(cfg)
     }
     fn get_server(&self) -> Box<dyn ComServer>;
     fn clone_box(&self) -> Box<dyn Interface>;
 }
 
+/// Byte level request / response path the protocols send diagnostic messages over.
+///
+/// [ProtocolServer](super::protocols::ProtocolServer) only needs to send a message to an ID,
+/// and receive whole messages back, so transports which are not a CAN adapter (For example
+/// [DoIpTransport](super::doip::DoIpTransport)) can be used in place of an [Interface].
+/// Payload IDs are the transport's addresses (CAN IDs, or DoIP logical addresses)
+pub trait DiagTransport: Send + Debug {
+    fn send_data(&mut self, data: &[InterfacePayload], timeout: u32) -> InterfaceResult<usize>;
+    /// Receives up to `max` messages, waiting at most `timeout` ms for the first one
+    fn recv_data(&mut self, max: usize, timeout: u32) -> InterfaceResult<Vec<InterfacePayload>>;
+    fn clear_buffer(&mut self, buffer_type: BufferType) -> InterfaceResult<()>;
+    /// Sends `request`, and returns the first message received after it
+    fn send_recv_data(
+        &mut self,
+        request: InterfacePayload,
+        write_timeout: u32,
+        read_timeout: u32,
+    ) -> InterfaceResult<InterfacePayload>;
+    /// Discards messages waiting to be read. Returns how many were discarded
+    fn flush_rx(&mut self) -> InterfaceResult<usize>;
+}
+
+impl DiagTransport for Box<dyn Interface> {
+    fn send_data(&mut self, data: &[InterfacePayload], timeout: u32) -> InterfaceResult<usize> {
+        (**self).send_data(data, timeout)
+    }
+
+    fn recv_data(&mut self, max: usize, timeout: u32) -> InterfaceResult<Vec<InterfacePayload>> {
+        (**self).recv_data(max, timeout)
+    }
+
+    fn clear_buffer(&mut self, buffer_type: BufferType) -> InterfaceResult<()> {
+        (**self).clear_buffer(buffer_type)
+    }
+
+    fn send_recv_data(
+        &mut self,
+        request: InterfacePayload,
+        write_timeout: u32,
+        read_timeout: u32,
+    ) -> InterfaceResult<InterfacePayload> {
+        (**self).send_recv_data(request, write_timeout, read_timeout)
+    }
+
+    fn flush_rx(&mut self) -> InterfaceResult<usize> {
+        (**self).flush_rx()
+    }
+}
+
+/// Receive buffer for payloads an adapter delivered beyond what was asked for.
+///
+/// When frames arrive bunched together (For example, a periodic or event stream running
+/// alongside a request), the adapter can return several of them from one read, and some
+/// adapters return more than the requested maximum. Rather than being dropped, the extra
+/// payloads are kept in the order they arrived, and the next read returns them before
+/// reading from the adapter again. Buffered payloads are returned straight away, without
+/// waiting for the read timeout. Clearing the RX buffer also discards them
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RxQueue {
+    pending: VecDeque<InterfacePayload>,
+}
+
+impl RxQueue {
+    /// Returns up to `max` payloads, taking buffered payloads first. If nothing is
+    /// buffered, `read` is called to read from the adapter, and anything it returns
+    /// beyond `max` is buffered for the next call
+    pub fn recv<F>(&mut self, max: usize, read: F) -> InterfaceResult<Vec<InterfacePayload>>
+    where
+        F: FnOnce(usize) -> InterfaceResult<Vec<InterfacePayload>>,
+    {
+        if self.pending.is_empty() {
+            self.pending.extend(read(max)?);
+        }
+        let count = max.min(self.pending.len());
+        Ok(self.pending.drain(..count).collect())
+    }
+
+    /// Discards all buffered payloads
+    pub fn clear(&mut self) {
+        self.pending.clear()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct CanbusInterface {
     dev: Box<dyn ComServer>,
+    rx: RxQueue,
 }
 
 impl CanbusInterface {
@@ -143,6 +476,7 @@ impl CanbusInterface {
         } else {
             Ok(Box::new(CanbusInterface {
                 dev: dev.clone_box(),
+                rx: RxQueue::default(),
             }))
         }
     }
@@ -150,12 +484,16 @@ impl CanbusInterface {
     pub fn new_raw(dev: Box<dyn ComServer>) -> Self {
         CanbusInterface {
             dev: dev.clone_box(),
+            rx: RxQueue::default(),
         }
     }
 }
 
 impl Interface for CanbusInterface {
     fn clear_buffer(&mut self, buffer_type: BufferType) -> InterfaceResult<()> {
+        if buffer_type != BufferType::TX {
+            self.rx.clear();
+        }
         match buffer_type {
             BufferType::TX => self.dev.clear_can_tx_buffer(),
             BufferType::RX => self.dev.clear_can_rx_buffer(),
@@ -170,7 +508,8 @@ impl Interface for CanbusInterface {
         self.dev.open_can_interface(
             cfg.get_param(IFACE_CFG::BAUDRATE)?,
             cfg.get_param_or_default(IFACE_CFG::EXT_CAN_ADDR, 0) > 0,
-        )
+        )?;
+        cfg.apply_raw(&mut self.dev)
     }
 
     fn send_data(&mut self, data: &[InterfacePayload], timeout: u32) -> InterfaceResult<usize> {
@@ -180,14 +519,19 @@ impl Interface for CanbusInterface {
     }
 
     fn recv_data(&mut self, max: usize, timeout: u32) -> InterfaceResult<Vec<InterfacePayload>> {
-        self.dev.read_can_packets(timeout, max).map(|v| {
-            v.iter()
-                .map(|f| InterfacePayload {
-                    id: f.id,
-                    data: Vec::from(f.get_data()),
-                    flags: vec![],
-                })
-                .collect()
+        let dev = &self.dev;
+        self.rx.recv(max, |max| {
+            dev.read_can_packets(timeout, max).map(|v| {
+                let timestamp = Some(Instant::now());
+                v.iter()
+                    .map(|f| InterfacePayload {
+                        id: f.id,
+                        data: Vec::from(f.get_data()),
+                        flags: vec![],
+                        timestamp,
+                    })
+                    .collect()
+            })
         })
     }
 
@@ -210,6 +554,7 @@ impl Interface for CanbusInterface {
     fn clone_box(&self) -> Box<dyn Interface> {
         Box::new(Self {
             dev: self.dev.clone(),
+            rx: RxQueue::default(),
         })
     }
 }
@@ -217,9 +562,27 @@ impl Interface for CanbusInterface {
 #[derive(Debug, Clone)]
 pub struct IsoTPInterface {
     dev: Box<dyn ComServer>,
+    /// Address byte responses to the last sent message must start with
+    addr_ext: Option<u8>,
+    /// Width of the length prefix used by the last sent message
+    length_prefix: Option<u8>,
+    rx: RxQueue,
 }
 
 impl IsoTPInterface {
+    /// Creates an ISO-TP interface for `dev`, which is done in software over raw CAN if the
+    /// adapter has no ISO-TP of its own, or `cfg` asks for it. See [SoftIsoTpInterface::is_enabled]
+    pub fn new_for_cfg(
+        dev: Box<dyn ComServer>,
+        cfg: &InterfaceConfig,
+    ) -> InterfaceResult<Box<dyn Interface>> {
+        if SoftIsoTpInterface::is_enabled(&*dev, cfg) {
+            SoftIsoTpInterface::new(dev)
+        } else {
+            Self::new(dev)
+        }
+    }
+
     pub fn new(dev: Box<dyn ComServer>) -> InterfaceResult<Box<dyn Interface>> {
         if dev.get_capabilities().supports_iso15765() != Capability::Yes {
             Err(ComServerError {
@@ -229,6 +592,9 @@ impl IsoTPInterface {
         } else {
             Ok(Box::new(IsoTPInterface {
                 dev: dev.clone_box(),
+                addr_ext: None,
+                length_prefix: None,
+                rx: RxQueue::default(),
             }))
         }
     }
@@ -236,6 +602,9 @@ impl IsoTPInterface {
 
 impl Interface for IsoTPInterface {
     fn clear_buffer(&mut self, buffer_type: BufferType) -> InterfaceResult<()> {
+        if buffer_type != BufferType::TX {
+            self.rx.clear();
+        }
         match buffer_type {
             BufferType::TX => self.dev.clear_iso15765_tx_buffer(),
             BufferType::RX => self.dev.clear_iso15765_rx_buffer(),
@@ -252,35 +621,90 @@ impl Interface for IsoTPInterface {
             cfg.get_param_or_default(IFACE_CFG::EXT_CAN_ADDR, 0) > 0,
             cfg.get_param_or_default(IFACE_CFG::EXT_ISOTP_ADDR, 0) > 0,
         )?;
-        // Use default if not specified
-        self.dev.set_iso15765_params(
-            cfg.get_param_or_default(IFACE_CFG::ISOTP_ST_MIN, 20),
-            cfg.get_param_or_default(IFACE_CFG::ISOTP_BS, 8),
-        )
+        // Flow control parameters the adapter advertises when receiving
+        self.dev
+            .set_iso15765_params(cfg.fc_st_min() as u32, cfg.fc_block_size() as u32)?;
+        self.dev
+            .set_iso15765_wait_limit(cfg.get_param_or_default(IFACE_CFG::ISOTP_WFT_MAX, 10))?;
+        cfg.apply_raw(&mut self.dev)
     }
 
     fn send_data(&mut self, data: &[InterfacePayload], timeout: u32) -> InterfaceResult<usize> {
-        let isotp_data: Vec<ISO15765Data> = data
+        if let Some(last) = data.last() {
+            self.addr_ext = last.get_rx_addr_extension();
+            self.length_prefix = last.get_length_prefix();
+        }
+        let isotp_data = data
             .iter()
-            .map(|t| ISO15765Data {
-                id: t.id,
-                data: t.data.clone(),
-                pad_frame: t.is_flag_set(PayloadFlag::ISOTP_PAD_FRAME),
-                ext_addressing: t.is_flag_set(PayloadFlag::ISOTP_EXT_ADDR),
+            .map(|t| {
+                let mut payload = match t.get_length_prefix() {
+                    Some(width) => add_length_prefix(&t.data, width)?,
+                    None => t.data.clone(),
+                };
+                if let Some(ext) = t.get_addr_extension() {
+                    payload.insert(0, ext);
+                }
+                Ok(ISO15765Data {
+                    id: t.id,
+                    data: payload,
+                    pad_frame: t.is_flag_set(PayloadFlag::ISOTP_PAD_FRAME),
+                    ext_addressing: t.is_flag_set(PayloadFlag::ISOTP_EXT_ADDR)
+                        || t.get_addr_extension().is_some(),
+                })
             })
-            .collect();
+            .collect::<InterfaceResult<Vec<ISO15765Data>>>()?;
         self.dev.send_iso15765_data(&isotp_data, timeout)
     }
 
     fn recv_data(&mut self, max: usize, timeout: u32) -> InterfaceResult<Vec<InterfacePayload>> {
-        self.dev.read_iso15765_packets(timeout, max).map(|v| {
-            v.iter()
-                .map(|f| InterfacePayload {
-                    id: f.id,
-                    data: f.data.clone(),
-                    flags: vec![],
-                })
-                .collect()
+        let addr_ext = self.addr_ext;
+        let length_prefix = self.length_prefix;
+        let dev = &self.dev;
+        self.rx.recv(max, |max| {
+            dev.read_iso15765_packets(timeout, max).map(|v| {
+                let timestamp = Some(Instant::now());
+                v.iter()
+                    .filter_map(|f| match addr_ext {
+                        None => Some(InterfacePayload {
+                            id: f.id,
+                            data: f.data.clone(),
+                            flags: vec![],
+                            timestamp,
+                        }),
+                        Some(ext) => {
+                            if f.data.first() != Some(&ext) {
+                                eprintln!(
+                                    "ISO-TP - Dropping message with wrong address extension {}",
+                                    hex_dump(&f.data)
+                                );
+                                None
+                            } else {
+                                Some(InterfacePayload {
+                                    id: f.id,
+                                    data: Vec::from(&f.data[1..]),
+                                    flags: vec![PayloadFlag::ISOTP_ADDR_EXTENSION(ext)],
+                                    timestamp,
+                                })
+                            }
+                        }
+                    })
+                    .map(|mut p| {
+                        if let Some(width) = length_prefix {
+                            match strip_length_prefix(&p.data, width) {
+                                Some(data) => {
+                                    p.data = data;
+                                    p.flags.push(PayloadFlag::LENGTH_PREFIXED(width));
+                                }
+                                None => eprintln!(
+                                    "ISO-TP - Response {} has no valid length prefix",
+                                    hex_dump(&p.data)
+                                ),
+                            }
+                        }
+                        p
+                    })
+                    .collect()
+            })
         })
     }
 
@@ -303,9 +727,151 @@ impl Interface for IsoTPInterface {
     fn clone_box(&self) -> Box<dyn Interface> {
         Box::new(Self {
             dev: self.dev.clone(),
+            addr_ext: self.addr_ext,
+            length_prefix: self.length_prefix,
+            rx: RxQueue::default(),
+        })
+    }
+}
+/// Discards the tester's own bytes echoed back on half-duplex K-Line, so that
+/// they are not mistaken for the ECU's response
+#[derive(Debug, Clone, Default)]
+pub(crate) struct KLineEcho {
+    enabled: bool,
+    pending: Vec<u8>,
+}
+
+impl KLineEcho {
+    pub fn new(cfg: &InterfaceConfig) -> Self {
+        Self {
+            enabled: cfg.get_param_or_default(IFACE_CFG::KLINE_ECHO, 1) > 0,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Records sent payloads, whose echo is expected before the response
+    pub fn on_send(&mut self, data: &[InterfacePayload]) {
+        if self.enabled {
+            data.iter()
+                .for_each(|x| self.pending.extend_from_slice(&x.data));
+        }
+    }
+
+    /// Strips echoed bytes from received payloads. Payloads which only
+    /// contained echo are dropped
+    pub fn on_recv(&mut self, frames: Vec<InterfacePayload>) -> Vec<InterfacePayload> {
+        frames
+            .into_iter()
+            .filter_map(|mut f| {
+                let matched = self
+                    .pending
+                    .iter()
+                    .zip(f.data.iter())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if matched < self.pending.len() && matched < f.data.len() {
+                    // Not our echo (Or it was corrupted). Nothing more to discard
+                    eprintln!("K-Line - Echo mismatch, keeping {}", hex_dump(&f.data));
+                    self.pending.clear();
+                    return Some(f);
+                }
+                self.pending.drain(..matched);
+                f.data.drain(..matched);
+                if f.data.is_empty() {
+                    None
+                } else {
+                    Some(f)
+                }
+            })
+            .collect()
+    }
+
+    /// Forgets the echo still expected, once the receive buffer it would arrive in is discarded
+    pub fn reset(&mut self) {
+        self.pending.clear();
+    }
+}
+
+/// K-Line interface with the echo of sent payloads removed from what is received. See [KLineEcho]
+#[derive(Debug)]
+pub(crate) struct KLineEchoInterface {
+    iface: Box<dyn Interface>,
+    echo: KLineEcho,
+}
+
+impl KLineEchoInterface {
+    pub fn over(iface: Box<dyn Interface>) -> Box<dyn Interface> {
+        Box::new(Self {
+            iface,
+            echo: KLineEcho::default(),
         })
     }
 }
+
+impl Interface for KLineEchoInterface {
+    fn setup(&mut self, cfg: &InterfaceConfig) -> InterfaceResult<()> {
+        self.iface.setup(cfg)?;
+        self.echo = KLineEcho::new(cfg);
+        Ok(())
+    }
+
+    fn send_data(&mut self, data: &[InterfacePayload], timeout: u32) -> InterfaceResult<usize> {
+        let sent = self.iface.send_data(data, timeout)?;
+        self.echo.on_send(&data[..sent.min(data.len())]);
+        Ok(sent)
+    }
+
+    fn recv_data(&mut self, max: usize, timeout: u32) -> InterfaceResult<Vec<InterfacePayload>> {
+        loop {
+            let frames = self.iface.recv_data(max, timeout)?;
+            if frames.is_empty() {
+                return Ok(frames);
+            }
+            // Read again if everything received was echo
+            let frames = self.echo.on_recv(frames);
+            if !frames.is_empty() {
+                return Ok(frames);
+            }
+        }
+    }
+
+    fn add_filter(&mut self, f: FilterType) -> InterfaceResult<u32> {
+        self.iface.add_filter(f)
+    }
+
+    fn rem_filter(&mut self, f_id: u32) -> InterfaceResult<()> {
+        self.iface.rem_filter(f_id)
+    }
+
+    fn close(&mut self) -> InterfaceResult<()> {
+        self.echo.reset();
+        self.iface.close()
+    }
+
+    fn clear_buffer(&mut self, buffer_type: BufferType) -> InterfaceResult<()> {
+        if buffer_type != BufferType::TX {
+            self.echo.reset();
+        }
+        self.iface.clear_buffer(buffer_type)
+    }
+
+    fn flush_rx(&mut self) -> InterfaceResult<usize> {
+        self.echo.reset();
+        self.iface.flush_rx()
+    }
+
+    fn get_server(&self) -> Box<dyn ComServer> {
+        self.iface.get_server()
+    }
+
+    fn clone_box(&self) -> Box<dyn Interface> {
+        Box::new(Self {
+            iface: self.iface.clone_box(),
+            echo: self.echo.clone(),
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Iso14230Interface {
     dev: Box<dyn ComServer>,
@@ -449,9 +1015,13 @@ impl DynamicInterface {
     ) -> InterfaceResult<Self> {
         let mut iface = match iface_type {
             InterfaceType::Can => CanbusInterface::new(server.clone_box())?,
-            InterfaceType::IsoTp => IsoTPInterface::new(server.clone_box())?,
-            InterfaceType::Iso14230 => Iso14230Interface::new(server.clone_box())?,
-            InterfaceType::Iso9141 => Iso9141Interface::new(server.clone_box())?,
+            InterfaceType::IsoTp => IsoTPInterface::new_for_cfg(server.clone_box(), cfg)?,
+            InterfaceType::Iso14230 => {
+                KLineEchoInterface::over(Iso14230Interface::new(server.clone_box())?)
+            }
+            InterfaceType::Iso9141 => {
+                KLineEchoInterface::over(Iso9141Interface::new(server.clone_box())?)
+            }
         };
         iface.setup(cfg)?;
         Ok(Self {
@@ -529,6 +1099,10 @@ impl Interface for DynamicInterface {
         self.exec(|iface| iface.send_recv_data(request.clone(), write_timeout, read_timeout))
     }
 
+    fn flush_rx(&mut self) -> InterfaceResult<usize> {
+        self.exec(|iface| iface.flush_rx())
+    }
+
     fn setup(&mut self, cfg: &InterfaceConfig) -> InterfaceResult<()> {
         self.exec(|iface| iface.setup(cfg))
     }
@@ -547,3 +1121,85 @@ impl Interface for DynamicInterface {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{InterfaceConfig, InterfacePayload, KLineEcho, RxQueue, IFACE_CFG};
+
+    fn recv(echo: &mut KLineEcho, frames: &[&[u8]]) -> Vec<Vec<u8>> {
+        echo.on_recv(
+            frames
+                .iter()
+                .map(|f| InterfacePayload::new(0x10, f))
+                .collect(),
+        )
+        .into_iter()
+        .map(|f| f.data)
+        .collect()
+    }
+
+    #[test]
+    fn test_rx_queue_batch() {
+        let mut rx = RxQueue::default();
+        // Adapter returns 3 frames bunched together, even though 1 was asked for
+        let res = rx
+            .recv(1, |_| {
+                Ok((1..=3)
+                    .map(|i| InterfacePayload::new(0x7E8, &[0x62, i]))
+                    .collect())
+            })
+            .unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].data, vec![0x62, 1]);
+        // Extras come back in order, without reading the adapter again
+        let res = rx
+            .recv(4, |_| panic!("Adapter read with frames buffered"))
+            .unwrap();
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[1].data, vec![0x62, 3]);
+        assert!(rx.recv(1, |_| Ok(vec![])).unwrap().is_empty());
+
+        rx.recv(1, |_| Ok(vec![InterfacePayload::new(0x7E8, &[1]); 2]))
+            .unwrap();
+        rx.clear();
+        assert!(rx.recv(1, |_| Ok(vec![])).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_kline_echo() {
+        let request = [InterfacePayload::new(0x10, &[0x81, 0x10, 0xF1, 0x3E])];
+        let mut echo = KLineEcho::new(&InterfaceConfig::new());
+
+        // Whole echo, then the response in the same payload
+        echo.on_send(&request);
+        assert_eq!(
+            recv(&mut echo, &[&[0x81, 0x10, 0xF1, 0x3E, 0x7E]]),
+            vec![vec![0x7E]]
+        );
+
+        // Echo only, then the response in its own payload
+        echo.on_send(&request);
+        assert!(recv(&mut echo, &[&[0x81, 0x10, 0xF1, 0x3E]]).is_empty());
+        assert_eq!(recv(&mut echo, &[&[0x7E]]), vec![vec![0x7E]]);
+
+        // Echo split across payloads
+        echo.on_send(&request);
+        assert!(recv(&mut echo, &[&[0x81, 0x10]]).is_empty());
+        assert_eq!(recv(&mut echo, &[&[0xF1], &[0x3E, 0x7E]]), vec![vec![0x7E]]);
+
+        // Corrupted echo is kept, and nothing more is discarded after it
+        echo.on_send(&request);
+        assert_eq!(
+            recv(&mut echo, &[&[0x81, 0x10, 0x00, 0x3E]]),
+            vec![vec![0x81, 0x10, 0x00, 0x3E]]
+        );
+        assert_eq!(recv(&mut echo, &[&[0x7E]]), vec![vec![0x7E]]);
+
+        // Adapter strips the echo itself
+        let mut cfg = InterfaceConfig::new();
+        cfg.add_param(IFACE_CFG::KLINE_ECHO, 0);
+        let mut echo = KLineEcho::new(&cfg);
+        echo.on_send(&request);
+        assert_eq!(recv(&mut echo, &[&[0x7E]]), vec![vec![0x7E]]);
+    }
+}
@@ -0,0 +1,740 @@
+//! ISO-TP (ISO15765-2) frame decoding, message segmentation and reassembly.
+//!
+//! Adapters with hardware ISO-TP do this themselves. This is used where the
+//! individual CAN frames of an ISO-TP transfer are handled by the application.
+//! Frames passed in here are the CAN data bytes, with any extended address byte removed.
+
+use std::{fmt::Display, time::Duration};
+
+use super::{
+    comm_api::ComServerError,
+    iface::{InterfaceConfig, IFACE_CFG},
+};
+
+/// Largest message length that a First Frame can describe in its 12 bit length
+pub const MAX_MESSAGE_LEN: usize = 0xFFF;
+
+/// Largest message length that a First Frame can describe with the escape sequence
+/// (12 bit length of 0, followed by a 32 bit length)
+pub const MAX_ESCAPED_MESSAGE_LEN: usize = u32::MAX as usize;
+
+/// Data bytes in a classic CAN frame
+pub const CLASSIC_CAN_DLC: usize = 8;
+
+/// Data bytes in the largest CAN-FD frame
+pub const MAX_CAN_FD_DLC: usize = 64;
+
+pub type IsoTpResult<T> = std::result::Result<T, IsoTpError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IsoTpError {
+    /// Frame has no data bytes
+    EmptyFrame,
+    /// Protocol control information type is not a valid ISO-TP frame type
+    InvalidPci(u8),
+    /// Length in the frame is invalid, or the frame is too short for it
+    InvalidLength {
+        frame_len: usize,
+        message_len: usize,
+    },
+    /// Consecutive frame was received without a preceding First Frame
+    UnexpectedConsecutiveFrame,
+    /// Consecutive frame sequence number is not the next one in the transfer
+    WrongSequenceNumber { expected: u8, actual: u8 },
+    /// Flow control frame has a reserved flow status
+    InvalidFlowStatus(u8),
+    /// Receiver cannot take a message of this length
+    ReceiverOverflow,
+    /// Receiver asked us to wait more often than allowed
+    WaitLimitExceeded(u32),
+    /// First Frame announced a message longer than the receiver accepts
+    MessageTooLong { len: usize, max: usize },
+}
+
+impl Display for IsoTpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::EmptyFrame => write!(f, "Empty ISO-TP frame"),
+            Self::InvalidPci(pci) => write!(f, "Invalid ISO-TP frame type 0x{:02X}", pci),
+            Self::InvalidLength {
+                frame_len,
+                message_len,
+            } => write!(
+                f,
+                "Invalid ISO-TP length {} in a {} byte frame",
+                message_len, frame_len
+            ),
+            Self::UnexpectedConsecutiveFrame => {
+                write!(f, "ISO-TP consecutive frame received without a first frame")
+            }
+            Self::WrongSequenceNumber { expected, actual } => write!(
+                f,
+                "ISO-TP consecutive frame out of sequence. Expected {}, got {}",
+                expected, actual
+            ),
+            Self::InvalidFlowStatus(fs) => write!(f, "Invalid ISO-TP flow status {}", fs),
+            Self::ReceiverOverflow => write!(f, "ISO-TP receiver overflowed"),
+            Self::WaitLimitExceeded(n) => {
+                write!(f, "ISO-TP receiver sent more than {} flow control waits", n)
+            }
+            Self::MessageTooLong { len, max } => write!(
+                f,
+                "ISO-TP message of {} bytes is longer than the {} byte limit",
+                len, max
+            ),
+        }
+    }
+}
+
+impl From<IsoTpError> for ComServerError {
+    fn from(e: IsoTpError) -> Self {
+        match e {
+            IsoTpError::MessageTooLong { len, max } => {
+                ComServerError::message_too_long("ISO-TP", len, max)
+            }
+            // The ECU never became ready to receive
+            IsoTpError::WaitLimitExceeded(_) => ComServerError::timeout(e.to_string()),
+            _ => ComServerError {
+                err_code: 98,
+                err_desc: e.to_string(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FlowStatus {
+    ContinueToSend,
+    Wait,
+    Overflow,
+}
+
+/// Encodes a Flow Control frame
+pub fn flow_control_frame(status: FlowStatus, block_size: u8, st_min: u8) -> Vec<u8> {
+    let fs = match status {
+        FlowStatus::ContinueToSend => 0,
+        FlowStatus::Wait => 1,
+        FlowStatus::Overflow => 2,
+    };
+    vec![0x30 | fs, block_size, st_min]
+}
+
+/// Decodes the ST_min byte of a Flow Control frame into the gap to leave between
+/// consecutive frames. 0x00-0x7F are milliseconds, and 0xF1-0xF9 are 100-900us.
+/// Reserved values are treated as the longest gap (127ms), as ISO15765-2 requires
+pub fn st_min_duration(st_min: u8) -> Duration {
+    match st_min {
+        0x00..=0x7F => Duration::from_millis(st_min as u64),
+        0xF1..=0xF9 => Duration::from_micros((st_min - 0xF0) as u64 * 100),
+        _ => Duration::from_millis(0x7F),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IsoTpFrame {
+    /// Complete message in one frame
+    Single(Vec<u8>),
+    /// Start of a segmented message, with the total message length
+    First { len: usize, data: Vec<u8> },
+    /// Continuation of a segmented message, with its 4 bit sequence number
+    Consecutive { sn: u8, data: Vec<u8> },
+    /// Flow control from the receiver of a segmented message
+    FlowControl {
+        status: FlowStatus,
+        block_size: u8,
+        st_min: u8,
+    },
+}
+
+impl IsoTpFrame {
+    /// Decodes a single CAN frame
+    pub fn parse(frame: &[u8]) -> IsoTpResult<Self> {
+        let pci = *frame.first().ok_or(IsoTpError::EmptyFrame)?;
+        match pci >> 4 {
+            0x0 => {
+                let (len, start) = match pci & 0x0F {
+                    // CAN-FD single frame. Length is in the next byte
+                    0 if frame.len() > 8 => (frame[1] as usize, 2),
+                    len => (len as usize, 1),
+                };
+                if len == 0 || frame.len() < start + len {
+                    return Err(IsoTpError::InvalidLength {
+                        frame_len: frame.len(),
+                        message_len: len,
+                    });
+                }
+                Ok(Self::Single(Vec::from(&frame[start..start + len])))
+            }
+            0x1 => {
+                let (len, start) = match (frame.get(1), frame.get(2..6)) {
+                    // Escape sequence, for messages longer than 4095 bytes
+                    (Some(0), Some(escaped)) if pci & 0x0F == 0 => (
+                        escaped.iter().fold(0usize, |acc, b| acc << 8 | *b as usize),
+                        6,
+                    ),
+                    (Some(low), _) => (((pci as usize & 0x0F) << 8) | *low as usize, 2),
+                    (None, _) => (0, 2),
+                };
+                // A First Frame must not hold the whole message, and the escape
+                // sequence is only used for lengths the 12 bit length cannot describe
+                if len <= frame.len().saturating_sub(start)
+                    || (start == 6 && len <= MAX_MESSAGE_LEN)
+                {
+                    return Err(IsoTpError::InvalidLength {
+                        frame_len: frame.len(),
+                        message_len: len,
+                    });
+                }
+                Ok(Self::First {
+                    len,
+                    data: Vec::from(&frame[start..]),
+                })
+            }
+            0x2 => Ok(Self::Consecutive {
+                sn: pci & 0x0F,
+                data: Vec::from(&frame[1..]),
+            }),
+            0x3 => {
+                let status = match pci & 0x0F {
+                    0 => FlowStatus::ContinueToSend,
+                    1 => FlowStatus::Wait,
+                    2 => FlowStatus::Overflow,
+                    x => return Err(IsoTpError::InvalidFlowStatus(x)),
+                };
+                if frame.len() < 3 {
+                    return Err(IsoTpError::InvalidLength {
+                        frame_len: frame.len(),
+                        message_len: 3,
+                    });
+                }
+                Ok(Self::FlowControl {
+                    status,
+                    block_size: frame[1],
+                    st_min: frame[2],
+                })
+            }
+            _ => Err(IsoTpError::InvalidPci(pci)),
+        }
+    }
+}
+
+/// How ECUs are addressed on the CAN bus (ISO15765-2). Which one is used is up to the OEM.
+///
+/// * Normal - 11 bit CAN IDs per ECU. Used by most passenger car OEMs (Mercedes, VAG UDS, Ford)
+/// * Normal fixed - 29 bit CAN IDs of the form `0x18DA<target><source>`. Used by heavy duty
+///   vehicles, and 29 bit OBD-II
+/// * Extended - Every frame begins with the target address, all ECUs share the tester's CAN ID.
+///   Used by BMW (Tester address 0xF1, on CAN ID 0x6F1)
+/// * Mixed - Every frame begins with an address extension byte, for ECUs behind a gateway
+///   on a remote network
+///
+/// VAG's TP2.0 is a different transport protocol, and is not covered by these
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AddressingMode {
+    Normal,
+    NormalFixed,
+    Extended { target: u8, source: u8 },
+    Mixed { address_extension: u8 },
+}
+
+impl AddressingMode {
+    /// Reads the addressing mode from [IFACE_CFG::ISOTP_ADDRESSING]. 0 (Default) is normal,
+    /// 1 normal fixed, 2 extended and 3 mixed. Extended and mixed addressing take their address
+    /// byte from [IFACE_CFG::ISOTP_TARGET_ADDR], and extended from [IFACE_CFG::ISOTP_SOURCE_ADDR]
+    pub fn from_cfg(cfg: &InterfaceConfig) -> Result<Self, String> {
+        let target = cfg.get_param(IFACE_CFG::ISOTP_TARGET_ADDR).map(|x| x as u8);
+        match cfg.get_param_or_default(IFACE_CFG::ISOTP_ADDRESSING, 0) {
+            0 => Ok(Self::Normal),
+            1 => Ok(Self::NormalFixed),
+            2 => Ok(Self::Extended {
+                target: target.map_err(|_| "Extended addressing needs ISOTP_TARGET_ADDR")?,
+                source: cfg.get_param_or_default(IFACE_CFG::ISOTP_SOURCE_ADDR, 0xF1) as u8,
+            }),
+            3 => Ok(Self::Mixed {
+                address_extension: target
+                    .map_err(|_| "Mixed addressing needs ISOTP_TARGET_ADDR")?,
+            }),
+            x => Err(format!("Unknown ISO-TP addressing mode {}", x)),
+        }
+    }
+
+    /// Returns true if frames start with an address byte
+    pub fn has_address_byte(&self) -> bool {
+        matches!(self, Self::Extended { .. } | Self::Mixed { .. })
+    }
+}
+
+/// How ISO-TP frames are laid out within CAN frames, which decides how many
+/// bytes of a message each frame can carry
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FrameLayout {
+    /// Data bytes per CAN frame. 8 for classic CAN, up to 64 for CAN-FD
+    pub can_dlc: usize,
+    /// Each frame starts with an address extension byte (Extended addressing)
+    pub ext_addressing: bool,
+}
+
+impl Default for FrameLayout {
+    fn default() -> Self {
+        Self::new(CLASSIC_CAN_DLC, false)
+    }
+}
+
+impl FrameLayout {
+    /// Creates a layout. `can_dlc` is clamped between 8 and 64 bytes
+    pub fn new(can_dlc: usize, ext_addressing: bool) -> Self {
+        Self {
+            can_dlc: can_dlc.clamp(CLASSIC_CAN_DLC, MAX_CAN_FD_DLC),
+            ext_addressing,
+        }
+    }
+
+    /// Creates the layout an interface was configured with
+    /// ([IFACE_CFG::ISOTP_CAN_DLC] and [IFACE_CFG::EXT_ISOTP_ADDR])
+    pub fn from_cfg(cfg: &InterfaceConfig) -> Self {
+        Self::new(
+            cfg.get_param_or_default(IFACE_CFG::ISOTP_CAN_DLC, CLASSIC_CAN_DLC as u32) as usize,
+            cfg.get_param_or_default(IFACE_CFG::EXT_ISOTP_ADDR, 0) > 0,
+        )
+    }
+
+    /// ISO-TP bytes per CAN frame, after the address extension byte
+    fn frame_len(&self) -> usize {
+        self.can_dlc - self.ext_addressing as usize
+    }
+
+    /// Largest message that fits in a single frame with the classic 1 byte PCI
+    fn classic_single_frame_capacity(&self) -> usize {
+        self.can_dlc.min(CLASSIC_CAN_DLC) - self.ext_addressing as usize - 1
+    }
+
+    /// Largest message that can be sent in a Single Frame. 7 bytes on classic CAN,
+    /// 6 with extended addressing. CAN-FD frames use a 2 byte PCI, leaving `can_dlc - 2`
+    pub fn single_frame_capacity(&self) -> usize {
+        if self.can_dlc > CLASSIC_CAN_DLC {
+            self.frame_len() - 2
+        } else {
+            self.classic_single_frame_capacity()
+        }
+    }
+
+    /// Splits a message into the ISO-TP frames it is sent as, without the address
+    /// extension byte. Messages larger than [FrameLayout::single_frame_capacity] are segmented,
+    /// and messages larger than [MAX_MESSAGE_LEN] use the escape sequence in the First Frame
+    pub fn segment(&self, msg: &[u8]) -> IsoTpResult<Vec<Vec<u8>>> {
+        if msg.is_empty() || msg.len() > MAX_ESCAPED_MESSAGE_LEN {
+            return Err(IsoTpError::InvalidLength {
+                frame_len: self.frame_len(),
+                message_len: msg.len(),
+            });
+        }
+        if msg.len() <= self.classic_single_frame_capacity() {
+            let mut frame = vec![msg.len() as u8];
+            frame.extend_from_slice(msg);
+            return Ok(vec![frame]);
+        }
+        if msg.len() <= self.single_frame_capacity() {
+            let mut frame = vec![0x00, msg.len() as u8];
+            frame.extend_from_slice(msg);
+            return Ok(vec![frame]);
+        }
+        let mut frame = if msg.len() > MAX_MESSAGE_LEN {
+            let mut frame = vec![0x10, 0x00];
+            frame.extend_from_slice(&(msg.len() as u32).to_be_bytes());
+            frame
+        } else {
+            vec![0x10 | (msg.len() >> 8) as u8, msg.len() as u8]
+        };
+        let first_len = self.frame_len() - frame.len();
+        frame.extend_from_slice(&msg[..first_len]);
+        let mut frames = vec![frame];
+        for (i, chunk) in msg[first_len..].chunks(self.frame_len() - 1).enumerate() {
+            let mut frame = vec![0x20 | ((i + 1) & 0x0F) as u8];
+            frame.extend_from_slice(chunk);
+            frames.push(frame);
+        }
+        Ok(frames)
+    }
+}
+
+/// Reassembles segmented ISO-TP messages from individual frames.
+///
+/// After an error, the transfer in progress is dropped, and the reassembler
+/// waits for the next Single or First Frame
+#[derive(Debug, Clone, Default)]
+pub struct IsoTpReassembler {
+    buffer: Vec<u8>,
+    expected_len: usize,
+    next_sn: u8,
+    in_progress: bool,
+    /// Longest message accepted, if limited
+    max_len: Option<usize>,
+}
+
+impl IsoTpReassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a reassembler which rejects First Frames announcing messages longer
+    /// than `max_len` bytes, before any of the message is buffered
+    pub fn with_max_len(max_len: usize) -> Self {
+        Self {
+            max_len: Some(max_len),
+            ..Self::default()
+        }
+    }
+
+    /// Returns true if a segmented message has been started but not completed
+    pub fn is_in_progress(&self) -> bool {
+        self.in_progress
+    }
+
+    /// Drops any transfer in progress
+    pub fn reset(&mut self) {
+        self.buffer.clear();
+        self.expected_len = 0;
+        self.next_sn = 0;
+        self.in_progress = false;
+    }
+
+    /// Processes the next received frame, returning the message if it is now complete.
+    ///
+    /// Flow control frames are not part of a received message, and are ignored
+    pub fn on_frame(&mut self, frame: &[u8]) -> IsoTpResult<Option<Vec<u8>>> {
+        let parsed = match IsoTpFrame::parse(frame) {
+            Ok(f) => f,
+            Err(e) => {
+                self.reset();
+                return Err(e);
+            }
+        };
+        match parsed {
+            IsoTpFrame::Single(data) => {
+                // A new message aborts any transfer in progress
+                self.reset();
+                Ok(Some(data))
+            }
+            IsoTpFrame::First { len, data } => {
+                self.reset();
+                if let Some(max) = self.max_len.filter(|max| len > *max) {
+                    return Err(IsoTpError::MessageTooLong { len, max });
+                }
+                self.buffer = data;
+                self.expected_len = len;
+                self.next_sn = 1;
+                self.in_progress = true;
+                Ok(None)
+            }
+            IsoTpFrame::Consecutive { sn, data } => {
+                if !self.in_progress {
+                    return Err(IsoTpError::UnexpectedConsecutiveFrame);
+                }
+                if sn != self.next_sn {
+                    let expected = self.next_sn;
+                    self.reset();
+                    return Err(IsoTpError::WrongSequenceNumber {
+                        expected,
+                        actual: sn,
+                    });
+                }
+                self.next_sn = (self.next_sn + 1) & 0x0F;
+                // The last frame may be padded
+                let remaining = self.expected_len - self.buffer.len();
+                self.buffer
+                    .extend_from_slice(&data[..data.len().min(remaining)]);
+                if self.buffer.len() == self.expected_len {
+                    self.in_progress = false;
+                    Ok(Some(std::mem::take(&mut self.buffer)))
+                } else {
+                    Ok(None)
+                }
+            }
+            IsoTpFrame::FlowControl { .. } => Ok(None),
+        }
+    }
+
+    /// Processes the next received frame like [IsoTpReassembler::on_frame], but passes the
+    /// message bytes each frame adds to `chunk` as they arrive, so a long message can be
+    /// processed before it is complete. Returns true once the message is complete.
+    ///
+    /// After an error, the chunks already passed on belong to a message that was dropped
+    pub fn on_frame_streaming(
+        &mut self,
+        frame: &[u8],
+        chunk: &mut dyn FnMut(&[u8]),
+    ) -> IsoTpResult<bool> {
+        let already_passed = match frame.first().map(|pci| pci >> 4) {
+            Some(0x0) | Some(0x1) => 0, // Single and First Frames start a new message
+            _ => self.buffer.len(),
+        };
+        match self.on_frame(frame)? {
+            Some(msg) => {
+                chunk(&msg[already_passed..]);
+                Ok(true)
+            }
+            None => {
+                if self.buffer.len() > already_passed {
+                    chunk(&self.buffer[already_passed..]);
+                }
+                Ok(false)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{
+        flow_control_frame, st_min_duration, FlowStatus, FrameLayout, IsoTpError, IsoTpFrame,
+        IsoTpReassembler,
+    };
+
+    /// Small deterministic PRNG (xorshift), so failures are reproducible
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn byte(&mut self) -> u8 {
+            self.next() as u8
+        }
+    }
+
+    #[test]
+    fn test_reassemble_multi_frame() {
+        let mut r = IsoTpReassembler::new();
+        assert_eq!(
+            r.on_frame(&[0x10, 0x0A, 0x62, 0xF1, 0x90, 0x01, 0x02, 0x03]),
+            Ok(None)
+        );
+        assert!(r.is_in_progress());
+        // Last frame is padded with 0xAA
+        let res = r.on_frame(&[0x21, 0x04, 0x05, 0x06, 0x07, 0xAA, 0xAA, 0xAA]);
+        assert_eq!(
+            res,
+            Ok(Some(vec![
+                0x62, 0xF1, 0x90, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07
+            ]))
+        );
+        assert!(!r.is_in_progress());
+        assert_eq!(r.on_frame(&[0x02, 0x50, 0x03]), Ok(Some(vec![0x50, 0x03])));
+    }
+
+    #[test]
+    fn test_reassemble_streaming() {
+        let mut r = IsoTpReassembler::new();
+        let mut chunks: Vec<Vec<u8>> = Vec::new();
+        let frames: [&[u8]; 4] = [
+            &[0x10, 0x0A, 0x62, 0xF1, 0x90, 0x01, 0x02, 0x03],
+            &[0x30, 0x00, 0x00], // Flow control adds nothing
+            &[0x21, 0x04, 0x05, 0x06, 0x07, 0xAA, 0xAA, 0xAA],
+            &[0x02, 0x50, 0x03],
+        ];
+        let complete: Vec<bool> = frames
+            .iter()
+            .map(|f| {
+                r.on_frame_streaming(f, &mut |c| chunks.push(c.to_vec()))
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(complete, vec![false, false, true, true]);
+        assert_eq!(
+            chunks,
+            vec![
+                vec![0x62, 0xF1, 0x90, 0x01, 0x02, 0x03],
+                vec![0x04, 0x05, 0x06, 0x07],
+                vec![0x50, 0x03],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reassemble_malformed() {
+        let mut r = IsoTpReassembler::new();
+        assert_eq!(r.on_frame(&[]), Err(IsoTpError::EmptyFrame));
+        assert_eq!(
+            r.on_frame(&[0x21, 0x00]),
+            Err(IsoTpError::UnexpectedConsecutiveFrame)
+        );
+        // First frame with a length of zero (12 bit length of 0 is the escape sequence)
+        assert!(matches!(
+            r.on_frame(&[0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0x06]),
+            Err(IsoTpError::InvalidLength { .. })
+        ));
+        // Escaped length which fits in the 12 bit length
+        assert!(matches!(
+            r.on_frame(&[0x10, 0x00, 0x00, 0x00, 0x0F, 0xFF, 0x05, 0x06]),
+            Err(IsoTpError::InvalidLength { .. })
+        ));
+        // Escape sequence cut short
+        assert!(matches!(
+            r.on_frame(&[0x10, 0x00, 0x01]),
+            Err(IsoTpError::InvalidLength { .. })
+        ));
+        // Single frame claiming more bytes than it has
+        assert!(matches!(
+            r.on_frame(&[0x07, 0x01]),
+            Err(IsoTpError::InvalidLength { .. })
+        ));
+        assert_eq!(r.on_frame(&[0x10, 0x14, 0, 0, 0, 0, 0, 0]), Ok(None));
+        assert_eq!(
+            r.on_frame(&[0x22, 0, 0, 0, 0, 0, 0, 0]),
+            Err(IsoTpError::WrongSequenceNumber {
+                expected: 1,
+                actual: 2
+            })
+        );
+        // Transfer was dropped after the bad sequence number
+        assert!(!r.is_in_progress());
+        assert_eq!(
+            r.on_frame(&[0x21, 0, 0, 0, 0, 0, 0, 0]),
+            Err(IsoTpError::UnexpectedConsecutiveFrame)
+        );
+    }
+
+    #[test]
+    fn test_reassemble_max_len() {
+        let mut r = IsoTpReassembler::with_max_len(0x14);
+        // First Frame announcing exactly the limit is accepted
+        assert_eq!(
+            r.on_frame(&[0x10, 0x14, 0x62, 0xF1, 0x90, 0x01, 0x02, 0x03]),
+            Ok(None)
+        );
+        assert_eq!(
+            r.on_frame(&[0x10, 0x15, 0x62, 0xF1, 0x90, 0x01, 0x02, 0x03]),
+            Err(IsoTpError::MessageTooLong {
+                len: 0x15,
+                max: 0x14
+            })
+        );
+        // The rejected transfer is not continued
+        assert!(!r.is_in_progress());
+        assert_eq!(
+            r.on_frame(&[0x21, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A]),
+            Err(IsoTpError::UnexpectedConsecutiveFrame)
+        );
+        // Escaped length of 4GiB
+        assert_eq!(
+            r.on_frame(&[0x10, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0x62, 0xF1]),
+            Err(IsoTpError::MessageTooLong {
+                len: 0xFFFF_FFFF,
+                max: 0x14
+            })
+        );
+        // Single frames are always within the limit
+        assert_eq!(r.on_frame(&[0x02, 0x50, 0x03]), Ok(Some(vec![0x50, 0x03])));
+    }
+
+    #[test]
+    fn test_flow_control() {
+        for status in &[
+            FlowStatus::ContinueToSend,
+            FlowStatus::Wait,
+            FlowStatus::Overflow,
+        ] {
+            assert_eq!(
+                IsoTpFrame::parse(&flow_control_frame(*status, 8, 0xF3)),
+                Ok(IsoTpFrame::FlowControl {
+                    status: *status,
+                    block_size: 8,
+                    st_min: 0xF3
+                })
+            );
+        }
+        assert_eq!(st_min_duration(0x00), Duration::from_millis(0));
+        assert_eq!(st_min_duration(0x14), Duration::from_millis(20));
+        assert_eq!(st_min_duration(0xF1), Duration::from_micros(100));
+        assert_eq!(st_min_duration(0xF9), Duration::from_micros(900));
+        // Reserved
+        assert_eq!(st_min_duration(0x80), Duration::from_millis(127));
+        assert_eq!(st_min_duration(0xFA), Duration::from_millis(127));
+    }
+
+    #[test]
+    fn test_single_frame_capacity() {
+        assert_eq!(FrameLayout::new(8, false).single_frame_capacity(), 7);
+        assert_eq!(FrameLayout::new(8, true).single_frame_capacity(), 6);
+        assert_eq!(FrameLayout::new(64, false).single_frame_capacity(), 62);
+        assert_eq!(FrameLayout::new(64, true).single_frame_capacity(), 61);
+
+        // 7 byte request must be segmented under extended addressing
+        let msg = [0x2E, 0xF1, 0x90, 0x01, 0x02, 0x03, 0x04];
+        assert_eq!(FrameLayout::new(8, false).segment(&msg).unwrap().len(), 1);
+        let frames = FrameLayout::new(8, true).segment(&msg).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert!(frames.iter().all(|f| f.len() <= 7));
+    }
+
+    #[test]
+    fn test_segment_round_trip() {
+        for len in &[7, 20, 63, 200] {
+            let msg: Vec<u8> = (0..*len).map(|x| x as u8).collect();
+            for layout in &[
+                FrameLayout::new(8, false),
+                FrameLayout::new(8, true),
+                FrameLayout::new(64, false),
+            ] {
+                let mut r = IsoTpReassembler::new();
+                let mut res = None;
+                for frame in layout.segment(&msg).unwrap() {
+                    assert!(frame.len() <= layout.can_dlc - layout.ext_addressing as usize);
+                    res = r.on_frame(&frame).unwrap();
+                }
+                assert_eq!(res, Some(msg.clone()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_escaped_length_round_trip() {
+        let mut rng = XorShift(0x1509_5765);
+        let msg: Vec<u8> = (0..5000).map(|_| rng.byte()).collect();
+        for layout in &[FrameLayout::new(8, false), FrameLayout::new(64, false)] {
+            let frames = layout.segment(&msg).unwrap();
+            assert_eq!(frames[0][..6], [0x10, 0x00, 0x00, 0x00, 0x13, 0x88]);
+            let mut r = IsoTpReassembler::new();
+            let mut res = None;
+            for frame in frames {
+                res = r.on_frame(&frame).unwrap();
+            }
+            assert_eq!(res, Some(msg.clone()));
+        }
+        // 4095 bytes still fits the 12 bit length
+        let frames = FrameLayout::default().segment(&msg[..0xFFF]).unwrap();
+        assert_eq!(frames[0][..2], [0x1F, 0xFF]);
+    }
+
+    #[test]
+    fn test_reassemble_fuzz() {
+        let mut rng = XorShift(0x4F56_4449_4147);
+        for _ in 0..2000 {
+            let mut r = IsoTpReassembler::new();
+            let mut fed = 0; // Bytes passed in, which bound the length of any message
+            for _ in 0..(rng.next() % 32) {
+                let len = (rng.next() % 65) as usize;
+                let mut frame: Vec<u8> = (0..len).map(|_| rng.byte()).collect();
+                // Bias towards valid frame types so multi frame paths are reached
+                if let Some(pci) = frame.first_mut() {
+                    *pci %= 0x40;
+                }
+                let was_in_progress = r.is_in_progress();
+                fed += frame.len();
+                match r.on_frame(&frame) {
+                    // Escaped First Frames can describe messages over MAX_MESSAGE_LEN
+                    Ok(Some(msg)) => assert!(msg.len() <= fed),
+                    Ok(None) => {}
+                    Err(IsoTpError::WrongSequenceNumber { .. }) => assert!(was_in_progress),
+                    Err(_) => {}
+                }
+            }
+        }
+    }
+}
@@ -1,9 +1,16 @@
 #[allow(dead_code)]
+pub mod bus_monitor;
+#[allow(dead_code)]
 pub mod comm_api;
+pub mod doip;
+pub mod dry_run;
 pub mod iface;
+pub mod isotp;
 pub mod passthru_api;
 pub mod pdu_api;
 pub mod protocols;
+pub mod session_recorder;
+pub mod soft_isotp;
 
 #[cfg(target_os = "linux")]
 pub mod socket_can_api;
@@ -1,5 +1,6 @@
 use crate::commapi::comm_api::{
-    CanFrame, Capability, ComServer, ComServerError, DeviceCapabilities, FilterType, ISO15765Data,
+    CanFrame, Capability, ComErrorKind, ComServer, ComServerError, ConfigValue, DeviceCapabilities,
+    FilterType, ISO15765Data,
 };
 use crate::passthru::{self, DrvVersion, PassthruDevice, PassthruDrv};
 use j2534_rust::FilterType::{BLOCK_FILTER, FLOW_CONTROL_FILTER, PASS_FILTER};
@@ -12,6 +13,18 @@ use j2534_rust::{
 use std::sync::{Arc, Mutex, RwLock};
 use std::{os::raw::c_void, time::Instant};
 
+/// J2534 configuration parameters which can be set with [ComServer::set_device_option],
+/// by name. Any other parameter (Such as vendor specific ones) can be set by its
+/// parameter ID instead, for example `0x10001`
+const SET_CONFIG_PARAMS: &[(&str, u32)] = &[
+    ("DATA_RATE", 0x01),
+    ("LOOPBACK", 0x03),
+    ("BIT_SAMPLE_POINT", 0x17),
+    ("SYNC_JUMP_WIDTH", 0x18),
+    ("CAN_MIXED_FORMAT", 0x8000),
+    ("J1962_PINS", 0x8001),
+];
+
 #[derive(Debug, Clone)]
 pub struct PassthruApi {
     device: Arc<PassthruDevice>,
@@ -395,6 +408,32 @@ impl ComServer for PassthruApi {
             .map_err(|e| self.convert_error(e))
     }
 
+    fn set_iso15765_wait_limit(&mut self, wft_max: u32) -> Result<(), ComServerError> {
+        let channel_id = match *self.iso15765_channel_idx.read().unwrap() {
+            Some(idx) => idx,
+            None => return Err(self.convert_error(ERR_INVALID_CHANNEL_ID)),
+        };
+        let mut params = [SConfig {
+            parameter: IoctlParam::ISO15765_WFT_MAX as u32,
+            value: wft_max,
+        }];
+
+        let mut sconfig_list = SConfigList {
+            num_of_params: 1,
+            config_ptr: params.as_mut_ptr(),
+        };
+        self.driver
+            .lock()
+            .unwrap()
+            .ioctl(
+                channel_id,
+                IoctlID::SET_CONFIG,
+                (&mut sconfig_list) as *mut _ as *mut c_void,
+                std::ptr::null_mut(),
+            )
+            .map_err(|e| self.convert_error(e))
+    }
+
     fn clear_can_rx_buffer(&self) -> Result<(), ComServerError> {
         match *self.can_channel_idx.read().unwrap() {
             Some(idx) => self.driver.lock().unwrap().ioctl(
@@ -511,6 +550,47 @@ impl ComServer for PassthruApi {
         "SAE J2534"
     }
 
+    fn set_device_option(
+        &mut self,
+        key: &str,
+        value: &ConfigValue,
+    ) -> Result<bool, ComServerError> {
+        let parameter = match SET_CONFIG_PARAMS.iter().find(|(name, _)| *name == key) {
+            Some((_, id)) => *id,
+            None => match key.strip_prefix("0x").map(|x| u32::from_str_radix(x, 16)) {
+                Some(Ok(id)) => id,
+                _ => return Ok(false),
+            },
+        };
+        let value = value.as_u32().ok_or_else(|| ComServerError {
+            err_code: 97,
+            err_desc: format!("J2534 parameter {} must be a number", key),
+        })?;
+        let channels = [
+            *self.can_channel_idx.read().unwrap(),
+            *self.iso15765_channel_idx.read().unwrap(),
+            *self.iso9141_channel_idx.read().unwrap(),
+        ];
+        for channel_id in channels.iter().flatten() {
+            let mut params = [SConfig { parameter, value }];
+            let mut sconfig_list = SConfigList {
+                num_of_params: 1,
+                config_ptr: params.as_mut_ptr(),
+            };
+            self.driver
+                .lock()
+                .unwrap()
+                .ioctl(
+                    *channel_id,
+                    IoctlID::SET_CONFIG,
+                    (&mut sconfig_list) as *mut _ as *mut c_void,
+                    std::ptr::null_mut(),
+                )
+                .map_err(|e| self.convert_error(e))?;
+        }
+        Ok(true)
+    }
+
     fn is_connected(&self) -> bool {
         return self.iso15765_channel_idx.read().unwrap().is_some()
             || self.can_channel_idx.read().unwrap().is_some()
@@ -608,9 +688,17 @@ impl PassthruApi {
         } else {
             e.to_string().into()
         };
-        ComServerError {
-            err_code: code,
-            err_desc: desc,
+        match e {
+            PassthruError::ERR_DEVICE_NOT_CONNECTED | PassthruError::ERR_INVALID_DEVICE_ID => {
+                ComServerError::from_kind(ComErrorKind::DeviceNotFound, desc)
+            }
+            PassthruError::ERR_DEVICE_IN_USE => {
+                ComServerError::from_kind(ComErrorKind::DeviceBusy, desc)
+            }
+            _ => ComServerError {
+                err_code: code,
+                err_desc: desc,
+            },
         }
     }
 }
@@ -100,6 +100,10 @@ impl ComServer for DpduAPI {
         unimplemented!()
     }
 
+    fn set_iso15765_wait_limit(&mut self, wft_max: u32) -> Result<(), ComServerError> {
+        unimplemented!()
+    }
+
     fn clear_can_rx_buffer(&self) -> Result<(), ComServerError> {
         unimplemented!()
     }
@@ -0,0 +1,83 @@
+//! Time source used by the diagnostic servers when computing timeouts and delays.
+//!
+//! [SystemClock] is used by default. [MockClock] only moves forward when told to,
+//! so that timeout paths can be tested without waiting for them in real time.
+
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+pub trait Clock: Debug + Send + Sync {
+    /// Milliseconds elapsed since the clock was created
+    fn now_ms(&self) -> u64;
+    /// Blocks the calling thread for `ms` milliseconds
+    fn sleep_ms(&self, ms: u64);
+
+    /// Milliseconds elapsed since `start` (A value previously returned by [Clock::now_ms])
+    fn elapsed_ms(&self, start: u64) -> u64 {
+        self.now_ms().saturating_sub(start)
+    }
+}
+
+/// Wall clock time
+#[derive(Debug, Copy, Clone)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    fn sleep_ms(&self, ms: u64) {
+        std::thread::sleep(Duration::from_millis(ms))
+    }
+}
+
+/// Virtual clock which is only advanced manually. Clones share the same time
+#[derive(Debug, Clone, Default)]
+pub struct MockClock {
+    now: Arc<AtomicU64>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Moves the clock forward by `ms` milliseconds
+    pub fn advance(&self, ms: u64) {
+        self.now.fetch_add(ms, Relaxed);
+    }
+}
+
+impl Clock for MockClock {
+    fn now_ms(&self) -> u64 {
+        self.now.load(Relaxed)
+    }
+
+    /// Returns immediately, advancing the clock by `ms` milliseconds
+    fn sleep_ms(&self, ms: u64) {
+        self.advance(ms)
+    }
+}
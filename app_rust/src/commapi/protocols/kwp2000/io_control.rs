@@ -0,0 +1,50 @@
+use crate::commapi::protocols::{ProtocolError, ProtocolResult, ProtocolServer};
+
+use super::KWP2000ECU;
+
+/*
+The service, Input Output Control By Local Identifier ($30), lets the tester take
+control of an input or output of the ECU (For example, to drive an actuator during an
+actuator test). The ECU responds with the local identifier, followed by the control
+status record, which usually starts with the control parameter and holds the state of
+the input / output.
+*/
+
+/// Common values of the input output control parameter. Values not listed here
+/// are manufacturer specific
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IoControlParameter {
+    /// Hands control of the input / output back to the ECU
+    ReturnControlToEcu = 0x00,
+    ReportCurrentState = 0x01,
+    ReportIoConditions = 0x02,
+    /// Forces the input / output to the given state (shortTermAdjustment)
+    ForceControl = 0x07,
+}
+
+/// Controls the input / output at local identifier `lid` with control parameter `param`,
+/// returning the control status record of the response (Everything after the local identifier).
+///
+/// `state` is the control state, which is only sent with parameters that take one
+/// (Such as [IoControlParameter::ForceControl])
+pub fn io_control_local(
+    ecu: &KWP2000ECU,
+    lid: u8,
+    param: u8,
+    state: &[u8],
+) -> ProtocolResult<Vec<u8>> {
+    let mut args = vec![lid, param];
+    args.extend_from_slice(state);
+    let res = ecu.run_command(super::Service::IOCTLByLocalID.into(), &args)?;
+    match res.get(1) {
+        Some(x) if *x == lid => Ok(res[2..].to_vec()),
+        Some(x) => Err(ProtocolError::CustomError(format!(
+            "ECU responded for local ID 0x{:02X}, not 0x{:02X}",
+            x, lid
+        ))),
+        None => Err(ProtocolError::InvalidResponseSize {
+            expect: 2,
+            actual: res.len(),
+        }),
+    }
+}
@@ -1,33 +1,33 @@
-use commapi::{
-    comm_api::{ComServer, ISO15765Config},
-    protocols::DTCState,
-};
+use commapi::comm_api::{ComServer, ISO15765Config};
 use std::sync::atomic::Ordering::Relaxed;
-use std::{
-    sync::{
-        atomic::AtomicBool,
-        mpsc::{self, Receiver, Sender},
-        Arc, Mutex, RwLock,
-    },
-    time::Instant,
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32},
+    mpsc::{self, Receiver, Sender},
+    Arc, Mutex, RwLock,
 };
 
 use self::start_diag_session::DiagSession;
 use crate::commapi::{
     self,
     comm_api::FilterType,
+    dry_run::DryRunInterface,
     iface::{
         DynamicInterface, Interface, InterfaceConfig, InterfaceType, IsoTPInterface, PayloadFlag,
+        IFACE_CFG,
     },
 };
 
 use super::{
-    CautionLevel, CommandError, DiagCfg, ECUCommand, ProtocolError, ProtocolResult, ProtocolServer,
-    Selectable, DTC,
+    clock::Clock, CautionLevel, CommandError, CommandRequest, CommandResponse, ConnectTimer,
+    ConnectionInfo, DiagCfg, DiagProtocol, DiagSessionState, DiagThread, ECUCommand,
+    PostSessionGuard, ProtocolError, ProtocolResult, ProtocolServer, RequestCorrelator,
+    ResponseOptions, Selectable, Stats, DTC,
 };
 
 pub mod clear_diag_information;
 pub mod ecu_reset;
+pub mod io_control;
+pub mod read_dtc_by_status;
 pub mod read_ecu_identification;
 pub mod read_status_dtc;
 pub mod start_diag_session;
@@ -67,6 +67,54 @@ pub enum Service {
     SupplierCustom(u8),
 }
 
+/// Names and caution levels of the KWP2000 services, for displaying services without
+/// going through [Service]. See [crate::commapi::protocols::describe_service]
+pub const KWP_SERVICES: &[(u8, &str, CautionLevel)] = &[
+    (0x10, "Start diagnostic session", CautionLevel::None),
+    (0x11, "Reset ECU", CautionLevel::Warn),
+    (0x14, "Clear diagnostic information", CautionLevel::None),
+    (0x17, "Read diagnostic trouble status", CautionLevel::None),
+    (
+        0x18,
+        "Read diagnostic trouble codes by status",
+        CautionLevel::None,
+    ),
+    (0x1A, "Read ECU Identification data", CautionLevel::None),
+    (0x21, "Read data by local ID", CautionLevel::Alert),
+    (0x22, "Read data by ID", CautionLevel::Alert),
+    (0x23, "Read memory by address", CautionLevel::Alert),
+    (0x27, "Security access", CautionLevel::Warn),
+    (
+        0x28,
+        "Disable normal message transmission",
+        CautionLevel::Alert,
+    ),
+    (
+        0x29,
+        "Enable normal message transmission",
+        CautionLevel::Alert,
+    ),
+    (0x2C, "Dynamically define local ID", CautionLevel::Alert),
+    (0x2E, "Write data by ID", CautionLevel::Alert),
+    (0x30, "IOCTL by local ID", CautionLevel::Alert),
+    (0x31, "Start routine by local ID", CautionLevel::Alert),
+    (0x32, "Stop routine by local ID", CautionLevel::Alert),
+    (
+        0x33,
+        "Request routine results by local ID",
+        CautionLevel::Alert,
+    ),
+    (0x34, "Request download", CautionLevel::Alert),
+    (0x35, "Request upload", CautionLevel::Alert),
+    (0x36, "Transfer data", CautionLevel::Alert),
+    (0x37, "Request transfer exit", CautionLevel::Alert),
+    (0x3B, "Write data by local ID", CautionLevel::Alert),
+    (0x3D, "Write memory by address", CautionLevel::Alert),
+    (0x3E, "Tester present", CautionLevel::None),
+    (0x85, "Control DTC Settings", CautionLevel::Warn),
+    (0x86, "Response on event", CautionLevel::Warn),
+];
+
 impl Selectable for Service {
     fn get_name(&self) -> String {
         format!("{:?}", &self)
@@ -316,6 +364,34 @@ impl CommandError for KwpNegativeCode {
         }
     }
 
+    fn get_byte(&self) -> u8 {
+        match self {
+            KwpNegativeCode::GeneralReject => 0x10,
+            KwpNegativeCode::ServiceNotSupported => 0x11,
+            KwpNegativeCode::SubFunctionNotSupported => 0x12,
+            KwpNegativeCode::Busy => 0x21,
+            KwpNegativeCode::RequestSequenceError => 0x22,
+            KwpNegativeCode::RoutineNotComplete => 0x23,
+            KwpNegativeCode::RequestOutOfRange => 0x31,
+            KwpNegativeCode::SecurityAccessDenied => 0x33,
+            KwpNegativeCode::InvalidKey => 0x35,
+            KwpNegativeCode::ExceededAttempts => 0x36,
+            KwpNegativeCode::TimeDelayNotExpired => 0x37,
+            KwpNegativeCode::DownloadNotAccepted => 0x40,
+            KwpNegativeCode::UploadNotAccepted => 0x50,
+            KwpNegativeCode::TransferSuspended => 0x71,
+            KwpNegativeCode::ResponsePending => 0x78,
+            KwpNegativeCode::ServiceNotSupportedActiveSession => 0x80,
+            KwpNegativeCode::DataDecompressionFailed => 0x9A,
+            KwpNegativeCode::DataDecryptionFailed => 0x9B,
+            KwpNegativeCode::ECUNotResponding => 0xA0,
+            KwpNegativeCode::ECUAddressUnknown => 0xA1,
+            KwpNegativeCode::CustomDaimler(x) => *x,
+            KwpNegativeCode::Reserved(x) => *x,
+            KwpNegativeCode::Unknown(x) => *x,
+        }
+    }
+
     fn from_byte(b: u8) -> Self {
         match b {
             0x10 => Self::GeneralReject,
@@ -351,11 +427,25 @@ impl CommandError for KwpNegativeCode {
 pub struct KWP2000ECU {
     should_run: Arc<AtomicBool>,
     last_error: Arc<RwLock<Option<ProtocolError>>>,
-    cmd_tx: Sender<(u8, Vec<u8>, bool)>,
-    cmd_rx: Arc<Receiver<ProtocolResult<Vec<u8>>>>,
+    cmd_tx: Sender<CommandRequest>,
+    cmd_rx: Arc<Receiver<CommandResponse>>,
+    correlator: RequestCorrelator,
     curr_session_type: Arc<RwLock<DiagSession>>,
     send_id: u32,
     cmd_mutex: Arc<Mutex<()>>,
+    strict_response_matching: Arc<AtomicBool>,
+    handle_response_pending: Arc<AtomicBool>,
+    security_level: Arc<RwLock<Option<u8>>>,
+    clear_engine_guard: Arc<AtomicBool>,
+    stats: Arc<RwLock<Stats>>,
+    comm_server: Box<dyn ComServer>,
+    global_id: Option<u32>,
+    p3_min_ms: Arc<AtomicU32>,
+    connection_info: ConnectionInfo,
+    recover_bus: Arc<AtomicBool>, // Set to have the diag server thread recover the bus
+    post_session: PostSessionGuard,
+    clock: Arc<dyn Clock>,
+    thread: DiagThread,
 }
 
 #[derive(Debug, Clone)]
@@ -400,10 +490,14 @@ impl KWP2000ECU {
     fn set_diag_session_mode(
         &mut self,
         mode: DiagSession,
+        timeout_ms: Option<u32>,
     ) -> std::result::Result<(), ProtocolError> {
-        match start_diag_session::set_diag_session(&self, mode) {
+        match self.run_command_timeout(Service::StartDiagSession.into(), &[mode as u8], timeout_ms)
+        {
             Ok(_) => {
                 *self.curr_session_type.write().unwrap() = mode; // Switch diagnostic modes!
+                *self.security_level.write().unwrap() = None; // Changing session re-locks the ECU
+                self.post_session.on_session_changed();
                 Ok(())
             }
             Err(e) => {
@@ -413,40 +507,359 @@ impl KWP2000ECU {
         }
     }
 
+    /// Reads DTCs from the ECU using Read Diagnostic Trouble Codes By Status (0x18),
+    /// only returning DTCs matching `status_mask` within `group`
+    pub fn read_errors_by_status(
+        &self,
+        status_mask: u8,
+        group: clear_diag_information::DTCGroup,
+    ) -> ProtocolResult<Vec<DTC>> {
+        read_dtc_by_status::read_dtc_by_status(self, status_mask, group)
+    }
+
+    /// Controls an input / output of the ECU.
+    /// See [io_control::io_control_local]
+    pub fn io_control_local(&self, lid: u8, param: u8, state: &[u8]) -> ProtocolResult<Vec<u8>> {
+        io_control::io_control_local(self, lid, param, state)
+    }
+
+    /// Reads the common identification records of the ECU.
+    /// See [read_ecu_identification::read_identification]
+    pub fn identification(&self) -> ProtocolResult<read_ecu_identification::KwpIdentification> {
+        read_ecu_identification::read_identification(self)
+    }
+
+    /// Functionally broadcasts an ECUReset of type `kind` on the global ID,
+    /// returning the IDs of the ECUs which acknowledged it within `collect_ms`
+    pub fn ecu_reset_functional(&self, kind: u8, collect_ms: u32) -> ProtocolResult<Vec<u32>> {
+        match self.global_id {
+            Some(id) => super::ecu_reset_functional(&self.comm_server, id, kind, collect_ms),
+            None => Err(ProtocolError::CustomError(
+                "No global ID configured for functional requests".into(),
+            )),
+        }
+    }
+
+    /// Sets if clearing DTCs should be refused whilst the engine is running (Off by default).
+    /// See [DiagServer::clear_errors](super::DiagServer::clear_errors)
+    pub fn set_clear_engine_guard(&self, enabled: bool) {
+        self.clear_engine_guard.store(enabled, Relaxed);
+    }
+
+    pub fn is_clear_engine_guard_enabled(&self) -> bool {
+        self.clear_engine_guard.load(Relaxed)
+    }
+
+    /// Sets if responses whose service ID does not match the request should be rejected (Default).
+    ///
+    /// When disabled, mismatched responses are returned as is (With a warning logged),
+    /// which is useful for ECUs that do not respond with the correct service ID
+    pub fn set_strict_response_matching(&self, strict: bool) {
+        self.strict_response_matching.store(strict, Relaxed);
+    }
+
+    /// Sets if the diag server waits for the final response when the ECU asks it to
+    /// wait (Response pending, Default). When disabled, the pending negative response
+    /// ($7F xx $78) is returned to the caller immediately, which is useful when
+    /// testing the pending behavior of the ECU itself
+    pub fn set_handle_response_pending(&self, handle: bool) {
+        self.handle_response_pending.store(handle, Relaxed);
+    }
+
+    /// Sets how long to wait after the ECU accepts a session change before sending
+    /// the next request. See [IFACE_CFG::POST_SESSION_DELAY_MS]
+    pub fn set_post_session_delay_ms(&self, delay_ms: u32) {
+        self.post_session.set_delay_ms(delay_ms);
+    }
+
+    /// Sets if the first request after a session change is retried once when the ECU
+    /// is still busy. See [IFACE_CFG::POST_SESSION_RETRY]
+    pub fn set_post_session_retry(&self, retry: bool) {
+        self.post_session.set_retry(retry);
+    }
+
+    /// Sets P3min, the minimum time in ms between the ECU's response and the next request.
+    /// Some ECUs drop requests which are sent too quickly after a response
+    pub fn set_p3_min_ms(&self, p3_min_ms: u32) {
+        self.p3_min_ms.store(p3_min_ms, Relaxed);
+    }
+
+    /// Blocks until P3min has elapsed since `last_resp`
+    fn wait_p3(opts: &ResponseOptions, last_resp: Option<u64>, p3_min_ms: u32) {
+        if let Some(last) = last_resp {
+            let elapsed = opts.clock.elapsed_ms(last);
+            if elapsed < p3_min_ms as u64 {
+                opts.clock.sleep_ms(p3_min_ms as u64 - elapsed);
+            }
+        }
+    }
+
+    /// Clock used for the diag server's timeouts and delays
+    pub(crate) fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+
+    pub fn get_stats(&self) -> Stats {
+        *self.stats.read().unwrap()
+    }
+
+    pub fn reset_stats(&self) {
+        *self.stats.write().unwrap() = Stats::default();
+    }
+
+    /// Stops the diag server, waiting for it to close the interface.
+    /// Returns the error closing the interface failed with, if any
+    pub fn close(&mut self) -> ProtocolResult<()> {
+        self.exit_diag_session();
+        super::join_diag_thread(&self.thread)
+    }
+
+    /// Returns the parameters negotiated when the diagnostic session was started
+    pub fn connection_info(&self) -> ConnectionInfo {
+        self.connection_info.clone()
+    }
+
     pub fn get_session_type(&self) -> DiagSession {
         *self.curr_session_type.read().unwrap()
     }
-}
 
-impl ProtocolServer for KWP2000ECU {
-    type Command = Service;
-    type Error = KwpNegativeCode;
-    fn start_diag_session(
+    /// Returns a snapshot of the current session, which can be resumed
+    /// with [KWP2000ECU::resume_diag_session]
+    pub fn get_state(&self) -> DiagSessionState {
+        DiagSessionState {
+            protocol: DiagProtocol::KWP2000,
+            session_type: self.get_session_type() as u8,
+            security_level: *self.security_level.read().unwrap(),
+            p2_max_ms: None,
+            p2_ext_max_ms: None,
+        }
+    }
+
+    /// Starts the diag server, assuming the ECU is already in the session described by `state`
+    pub fn resume_diag_session(
         comm_server: &Box<dyn ComServer>,
         interface_type: InterfaceType,
         interface_cfg: InterfaceConfig,
         tx_flags: Option<Vec<PayloadFlag>>,
         diag_cfg: DiagCfg,
+        state: &DiagSessionState,
     ) -> ProtocolResult<Self> {
-        if interface_type != InterfaceType::IsoTp && interface_type != InterfaceType::Iso14230 {
+        if state.protocol != DiagProtocol::KWP2000 {
             return Err(ProtocolError::CustomError(
-                "KWP2000 Can only be executed over ISO-TP or ISO14230".into(),
+                "Session state is not from a KWP2000 session".into(),
             ));
         }
+        if DiagSession::from_byte(state.session_type).is_none() {
+            return Err(ProtocolError::CustomError(format!(
+                "Unknown KWP2000 session type 0x{:02X}",
+                state.session_type
+            )));
+        }
+        Self::start_session(
+            comm_server,
+            interface_type,
+            interface_cfg,
+            tx_flags,
+            diag_cfg,
+            Some(state),
+        )
+    }
+
+    /// Runs a command, giving up waiting for the ECU's response after `timeout_ms`.
+    /// If `timeout_ms` is None, this waits for as long as the diag server takes to respond
+    pub fn run_command_timeout(
+        &self,
+        cmd: u8,
+        args: &[u8],
+        timeout_ms: Option<u32>,
+    ) -> ProtocolResult<Vec<u8>> {
+        self.run_request(CommandRequest::new(cmd, args, true), timeout_ms)
+    }
 
-        let mut dyn_interface =
-            DynamicInterface::new(comm_server, interface_type, &interface_cfg)?.clone_box();
-        if interface_type == InterfaceType::IsoTp {
-            dyn_interface.add_filter(FilterType::IsoTP {
-                id: diag_cfg.recv_id,
-                mask: 0xFFFF,
-                fc: diag_cfg.send_id,
-            })?;
+    /// Runs a command, ignoring any unrelated frames received before the ECU's
+    /// response to the command, for up to `deadline_ms`
+    pub fn run_command_await_sid(
+        &self,
+        cmd: u8,
+        args: &[u8],
+        deadline_ms: u32,
+    ) -> ProtocolResult<Vec<u8>> {
+        self.run_request(
+            CommandRequest {
+                await_sid_ms: Some(deadline_ms),
+                ..CommandRequest::new(cmd, args, true)
+            },
+            None,
+        )
+    }
+
+    /// Runs a command, checking the ECU's positive response is `expected_len` bytes long.
+    /// If `expected_len` is None, the protocol's default length for the command is checked
+    /// (If it has one)
+    pub fn run_command_expect_len(
+        &self,
+        cmd: u8,
+        args: &[u8],
+        expected_len: Option<usize>,
+    ) -> ProtocolResult<Vec<u8>> {
+        self.run_request(
+            CommandRequest {
+                expected_len,
+                ..CommandRequest::new(cmd, args, true)
+            },
+            None,
+        )
+    }
+
+    fn run_request(
+        &self,
+        request: CommandRequest,
+        timeout_ms: Option<u32>,
+    ) -> ProtocolResult<Vec<u8>> {
+        self.post_session
+            .run(|| self.send_request(request.clone(), timeout_ms))
+    }
+
+    fn send_request(
+        &self,
+        mut request: CommandRequest,
+        timeout_ms: Option<u32>,
+    ) -> ProtocolResult<Vec<u8>> {
+        let _guard = self.cmd_mutex.lock().unwrap(); // We are allowed to send / receive!
+        let seq = self.correlator.tag(&mut request);
+        if self.cmd_tx.send(request).is_err() {
+            return Err(ProtocolError::CustomError("Channel Tx failed".into()));
+        }
+        let resp = match self
+            .correlator
+            .await_response(&self.cmd_rx, seq, timeout_ms)
+        {
+            Some(r) => r?,
+            None => {
+                self.stats.write().unwrap().timeouts += 1;
+                return Err(ProtocolError::Timeout);
+            }
+        };
+        if resp[0] == 0x7F {
+            let neg_code = KwpNegativeCode::from_byte(resp[2]);
+            Err(ProtocolError::ProtocolError(Box::new(neg_code)))
         } else {
+            Ok(resp)
+        }
+    }
+
+    /// Sends a command to the ECU without waiting for a response. Used for
+    /// requests where the ECU is told not to send a positive response.
+    ///
+    /// If `nrc_window_ms` is set, a negative response received within that window is
+    /// returned as an error. See [ResponseOptions::nrc_window_ms]
+    pub fn send_command(
+        &self,
+        cmd: u8,
+        args: &[u8],
+        nrc_window_ms: Option<u32>,
+    ) -> ProtocolResult<()> {
+        let _guard = self.cmd_mutex.lock().unwrap(); // We are allowed to send / receive!
+        let mut request = CommandRequest {
+            nrc_window_ms,
+            ..CommandRequest::new(cmd, args, false)
+        };
+        let seq = self.correlator.tag(&mut request);
+        if self.cmd_tx.send(request).is_err() {
+            return Err(ProtocolError::CustomError("Channel Tx failed".into()));
+        }
+        match self.correlator.await_response(&self.cmd_rx, seq, None) {
+            Some(r) => r.map(|_| ()),
+            None => Err(ProtocolError::CustomError("Channel Rx failed".into())),
+        }
+    }
+
+    /// Recovers the bus (After the CAN controller went bus-off), then re-enters
+    /// the extended diagnostic session
+    pub fn reconnect(&mut self) -> ProtocolResult<()> {
+        {
+            let _guard = self.cmd_mutex.lock().unwrap(); // No requests whilst recovering
+            super::request_bus_recovery(&self.recover_bus, 2000, &*self.clock)?;
+        }
+        self.establish_session(&ConnectTimer::new(&InterfaceConfig::new()))
+    }
+
+    /// Enters the extended diagnostic session and checks the ECU responds to
+    /// tester present, within the connection time budget
+    fn establish_session(&mut self, timer: &ConnectTimer) -> ProtocolResult<()> {
+        let timeout = timer.remaining("session control")?;
+        self.set_diag_session_mode(DiagSession::Extended, timeout)
+            .map_err(|e| timer.annotate(e, "session control"))?;
+        let timeout = timer.remaining("tester present")?;
+        if let Err(e) = self.run_command_timeout(Service::TesterPresent.into(), &[0x01], timeout) {
+            if e.is_timeout() {
+                return Err(timer.annotate(e, "tester present"));
+            }
+            println!("Warning. ECU did not approve of tester present - {:?}", e);
+        }
+        Ok(())
+    }
+}
+
+impl KWP2000ECU {
+    /// Starts the diag server. If `resume` is set, then the ECU is assumed to already
+    /// be in the session described by the state, and no handshake is done with the ECU
+    fn start_session(
+        comm_server: &Box<dyn ComServer>,
+        interface_type: InterfaceType,
+        mut interface_cfg: InterfaceConfig,
+        mut tx_flags: Option<Vec<PayloadFlag>>,
+        diag_cfg: DiagCfg,
+        resume: Option<&DiagSessionState>,
+    ) -> ProtocolResult<Self> {
+        if interface_type != InterfaceType::IsoTp && interface_type != InterfaceType::Iso14230 {
             return Err(ProtocolError::CustomError(
-                "KWP2000 over ISO14230 is a WIP".into(),
+                "KWP2000 Can only be executed over ISO-TP or ISO14230".into(),
             ));
         }
+        super::check_interface_support(comm_server, interface_type)?;
+        let addressing =
+            super::apply_addressing(interface_type, &mut interface_cfg, &mut tx_flags, &diag_cfg)?;
+        println!("KWP2000 - Using {:?} addressing", addressing);
+        let filter_mask = match interface_cfg.get_param_or_default(IFACE_CFG::EXT_CAN_ADDR, 0) {
+            0 => 0xFFFF,
+            _ => 0x1FFF_FFFF, // 29 bit CAN IDs
+        };
+        super::validate_tx_flags(interface_type, &interface_cfg, &tx_flags, &diag_cfg)?;
+
+        let timer = ConnectTimer::new(&interface_cfg);
+        let step_server = comm_server.clone_box();
+        let step_cfg = interface_cfg.clone();
+        let mut dyn_interface = timer.run_step(
+            "interface open",
+            move || {
+                let mut dyn_interface = if DryRunInterface::is_enabled(&step_cfg) {
+                    let mut iface = DryRunInterface::new(step_server, DiagProtocol::KWP2000);
+                    super::close_on_err(&mut iface, |i| i.setup(&step_cfg))?;
+                    iface
+                } else {
+                    DynamicInterface::new(&step_server, interface_type, &step_cfg)?.clone_box()
+                };
+                if interface_type == InterfaceType::IsoTp {
+                    super::close_on_err(&mut dyn_interface, |i| {
+                        i.add_filter(FilterType::IsoTP {
+                            id: diag_cfg.recv_id,
+                            mask: filter_mask,
+                            fc: diag_cfg.send_id,
+                        })
+                    })?;
+                    Ok(dyn_interface)
+                } else {
+                    let _ = dyn_interface.close();
+                    Err(ProtocolError::CustomError(
+                        "KWP2000 over ISO14230 is a WIP".into(),
+                    ))
+                }
+            },
+            |mut dyn_interface| {
+                let _ = dyn_interface.close();
+            },
+        )?;
 
         let should_run = Arc::new(AtomicBool::new(true));
         let should_run_t = should_run.clone();
@@ -455,44 +868,98 @@ impl ProtocolServer for KWP2000ECU {
         let last_error_t = last_error.clone();
 
         let (channel_tx_sender, channel_tx_receiver): (
-            Sender<(u8, Vec<u8>, bool)>,
-            Receiver<(u8, Vec<u8>, bool)>,
+            Sender<CommandRequest>,
+            Receiver<CommandRequest>,
         ) = mpsc::channel();
         let (channel_rx_sender, channel_rx_receiver): (
-            Sender<ProtocolResult<Vec<u8>>>,
-            Receiver<ProtocolResult<Vec<u8>>>,
+            Sender<CommandResponse>,
+            Receiver<CommandResponse>,
         ) = mpsc::channel();
 
         let session_type = Arc::new(RwLock::new(DiagSession::Default));
         let session_type_t = session_type.clone();
 
+        let strict_matching = Arc::new(AtomicBool::new(true));
+        let strict_matching_t = strict_matching.clone();
+        let handle_pending = Arc::new(AtomicBool::new(true));
+        let handle_pending_t = handle_pending.clone();
+        let resp_opts = ResponseOptions::new(&interface_cfg);
+        let clock = resp_opts.clock.clone();
+        super::wait_for_bus_idle(&mut dyn_interface, &interface_cfg, &*resp_opts.clock);
+
+        let stats = Arc::new(RwLock::new(Stats::default()));
+        let stats_t = stats.clone();
+
+        let p3_min_ms = Arc::new(AtomicU32::new(
+            interface_cfg.get_param_or_default(IFACE_CFG::KWP_P3_MIN_MS, 0),
+        ));
+        let p3_min_ms_t = p3_min_ms.clone();
+
+        let connection_info = ConnectionInfo::new(
+            DiagProtocol::KWP2000,
+            interface_type,
+            &interface_cfg,
+            &diag_cfg,
+        );
+
+        let recover_bus = Arc::new(AtomicBool::new(false));
+        let recover_bus_t = recover_bus.clone();
+        let recover_cfg = interface_cfg.clone();
+
         // Enter extended diagnostic session (Full features)
         let s_id = diag_cfg.send_id;
-        std::thread::spawn(move || {
+        let thread = std::thread::spawn(move || {
             println!("KWP2000 Diag server start!");
-            let mut timer = Instant::now();
+            let mut timer = resp_opts.clock.now_ms();
+            let mut last_resp: Option<u64> = None; // Time the last response was received
             while should_run_t.load(Relaxed) {
+                if recover_bus_t.load(Relaxed) {
+                    let res = dyn_interface.recover_bus(&recover_cfg).and_then(|_| {
+                        dyn_interface.add_filter(FilterType::IsoTP {
+                            id: diag_cfg.recv_id,
+                            mask: filter_mask,
+                            fc: diag_cfg.send_id,
+                        })
+                    });
+                    if let Err(e) = res {
+                        eprintln!("KWP2000 - Could not recover the bus: {}", e);
+                        *last_error_t.write().unwrap() = Some(ProtocolError::CommError(e));
+                    }
+                    recover_bus_t.store(false, Relaxed);
+                }
                 if let Ok(data) = channel_tx_receiver.try_recv() {
+                    Self::wait_p3(&resp_opts, last_resp, p3_min_ms_t.load(Relaxed));
                     let res = Self::run_command_resp(
                         &mut dyn_interface,
                         &tx_flags,
                         s_id,
-                        data.0,
-                        &data.1,
-                        data.2,
+                        data.cmd,
+                        &data.args,
+                        data.response_required,
+                        &ResponseOptions {
+                            strict_matching: strict_matching_t.load(Relaxed),
+                            handle_response_pending: handle_pending_t.load(Relaxed),
+                            await_sid_ms: data.await_sid_ms,
+                            expected_len: data.expected_len,
+                            nrc_window_ms: data.nrc_window_ms,
+                            ..resp_opts.clone()
+                        },
+                        &stats_t,
                     );
-                    if channel_rx_sender.send(res).is_err() {
+                    last_resp = Some(resp_opts.clock.now_ms());
+                    if channel_rx_sender.send((data.seq, res)).is_err() {
                         *last_error_t.write().unwrap() =
                             Some(ProtocolError::CustomError("Sender channel died".into()));
                         break;
                     }
                 }
-                if timer.elapsed().as_millis() >= 2000
+                if resp_opts.clock.elapsed_ms(timer) >= 2000
                     && *session_type_t.read().unwrap() != DiagSession::Default
                 {
-                    timer = Instant::now();
+                    timer = resp_opts.clock.now_ms();
                     //if let Err(e) = Self::run_command_iso_tp(comm_server.as_ref(), 0x001C, Service::TesterPresent.into(), &[0x02], false) {
 
+                    Self::wait_p3(&resp_opts, last_resp, p3_min_ms_t.load(Relaxed));
                     let tp_cmd = match diag_cfg.global_id {
                         // Global tester present - No response from ECU
                         Some(x) => Self::run_command_resp(
@@ -502,6 +969,8 @@ impl ProtocolServer for KWP2000ECU {
                             Service::TesterPresent.into(),
                             &[0x02],
                             false,
+                            &resp_opts,
+                            &stats_t,
                         ),
                         None => Self::run_command_resp(
                             &mut dyn_interface,
@@ -510,12 +979,16 @@ impl ProtocolServer for KWP2000ECU {
                             Service::TesterPresent.into(),
                             &[0x01],
                             true,
+                            &resp_opts,
+                            &stats_t,
                         ),
                     };
+                    last_resp = Some(resp_opts.clock.now_ms());
                     if let Err(e) = tp_cmd {
                         if e.is_timeout() {
                             println!("Lost connection with ECU! - {:?}", e);
                             // Try to regain connection
+                            Self::wait_p3(&resp_opts, last_resp, p3_min_ms_t.load(Relaxed));
                             if Self::run_command_resp(
                                 &mut dyn_interface,
                                 &tx_flags,
@@ -523,6 +996,8 @@ impl ProtocolServer for KWP2000ECU {
                                 Service::StartDiagSession.into(),
                                 &[0x92],
                                 true,
+                                &resp_opts,
+                                &stats_t,
                             )
                             .is_err()
                             {
@@ -531,6 +1006,7 @@ impl ProtocolServer for KWP2000ECU {
                             } else {
                                 println!("Regained connection to the ECU!");
                             }
+                            last_resp = Some(resp_opts.clock.now_ms());
                         } else {
                             println!("Warning. ECU did not approve of tester present - {:?}", e);
                         }
@@ -539,7 +1015,7 @@ impl ProtocolServer for KWP2000ECU {
                 std::thread::sleep(std::time::Duration::from_micros(100))
             }
             println!("Diag server stop!");
-            let _res = dyn_interface.close();
+            dyn_interface.close()
         });
 
         let mut ecu = KWP2000ECU {
@@ -547,71 +1023,77 @@ impl ProtocolServer for KWP2000ECU {
             last_error,
             cmd_tx: channel_tx_sender,
             cmd_rx: Arc::new(channel_rx_receiver),
+            correlator: RequestCorrelator::new(clock.clone()),
             send_id: diag_cfg.send_id,
             curr_session_type: session_type, // Assumed,
             cmd_mutex: Arc::new(Mutex::new(())),
+            strict_response_matching: strict_matching,
+            handle_response_pending: handle_pending,
+            security_level: Arc::new(RwLock::new(None)),
+            clear_engine_guard: Arc::new(AtomicBool::new(false)),
+            stats,
+            comm_server: comm_server.clone_box(),
+            global_id: diag_cfg.global_id,
+            p3_min_ms,
+            connection_info,
+            recover_bus,
+            post_session: PostSessionGuard::new(&interface_cfg, clock.clone()),
+            clock,
+            thread: Arc::new(Mutex::new(Some(thread))),
         };
 
-        if let Err(e) = ecu.set_diag_session_mode(DiagSession::Extended) {
+        if let Some(state) = resume {
+            // ECU should already be in this session
+            if let Some(mode) = DiagSession::from_byte(state.session_type) {
+                *ecu.curr_session_type.write().unwrap() = mode;
+            }
+            *ecu.security_level.write().unwrap() = state.security_level;
+        } else if let Err(e) = ecu.establish_session(&timer) {
             println!("KWP2000 - Couldn't set the ECU in extended diag mode!");
             ecu.should_run.store(false, Relaxed);
             return Err(e);
         }
+        let state = ecu.get_state();
+        let name = format!("{:?}", ecu.get_session_type());
+        ecu.connection_info
+            .set_session(&state, name, resume.is_some());
         Ok(ecu)
     }
+}
+
+impl ProtocolServer for KWP2000ECU {
+    type Command = Service;
+    type Error = KwpNegativeCode;
+    fn start_diag_session(
+        comm_server: &Box<dyn ComServer>,
+        interface_type: InterfaceType,
+        interface_cfg: InterfaceConfig,
+        tx_flags: Option<Vec<PayloadFlag>>,
+        diag_cfg: DiagCfg,
+    ) -> ProtocolResult<Self> {
+        Self::start_session(
+            comm_server,
+            interface_type,
+            interface_cfg,
+            tx_flags,
+            diag_cfg,
+            None,
+        )
+    }
 
     fn exit_diag_session(&mut self) {
         self.should_run.store(false, Relaxed);
     }
 
     fn run_command(&self, cmd: u8, args: &[u8]) -> ProtocolResult<Vec<u8>> {
-        let _guard = self.cmd_mutex.lock().unwrap(); // We are allowed to send / receive!
-        if self.cmd_tx.send((cmd, Vec::from(args), true)).is_err() {
-            return Err(ProtocolError::CustomError("Channel Tx failed".into()));
-        }
-        let resp = self.cmd_rx.recv().unwrap()?;
-        if resp[0] == 0x7F {
-            let neg_code = KwpNegativeCode::from_byte(resp[2]);
-            Err(ProtocolError::ProtocolError(Box::new(neg_code)))
-        } else {
-            Ok(resp)
-        }
+        self.run_command_timeout(cmd, args, None)
     }
 
     fn read_errors(&self) -> ProtocolResult<Vec<DTC>> {
-        // 0x02 - Request Hex DTCs as 2 bytes
-        // 0xFF00 - Request all DTCs (Mandatory per KWP2000)
-        let mut bytes = self.run_command(Service::ReadDTCByStatus.into(), &[0x02, 0xFF, 0x00])?;
-        bytes.drain(..1);
-        let count = bytes[0] as usize;
-        bytes.drain(0..1);
-
-        let mut res: Vec<DTC> = Vec::new();
-        for _ in 0..count {
-            let name = format!("{:02X}{:02X}", bytes[0], bytes[1]);
-            let status = bytes[2];
-            //let flag = (status >> 4 & 0b00000001) > 0;
-            //0b011
-            let storage_state = (status >> 5) & 0b0000011;
-
-            let state = match storage_state {
-                1 => DTCState::Stored,
-                2 => DTCState::Pending,
-                3 => DTCState::Permanent,
-                _ => DTCState::None,
-            };
-
-            let mil = (status >> 7 & 0b00000001) > 0;
-
-            res.push(DTC {
-                error: name,
-                state,
-                check_engine_on: mil,
-                id: ((bytes[0] as u32) << 8) | bytes[1] as u32,
-            });
-            bytes.drain(0..3); // DTC is 3 bytes (1 for status, 2 for the ID)
-        }
-        Ok(res)
+        self.read_errors_by_status(
+            read_dtc_by_status::STATUS_ALL_HEX_DTCS,
+            clear_diag_information::DTCGroup::All,
+        )
     }
 
     fn is_in_diag_session(&self) -> bool {
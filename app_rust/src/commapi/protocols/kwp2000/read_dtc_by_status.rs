@@ -0,0 +1,69 @@
+use crate::commapi::protocols::{
+    DTCState, DtcCode, ProtocolError, ProtocolResult, ProtocolServer, DTC,
+};
+
+use super::{clear_diag_information::DTCGroup, KWP2000ECU};
+
+/*
+The service, Read Diagnostic Trouble Codes By Status ($18), is used by the
+diagnostic tool to read DTCs and their status from the ECU's memory,
+filtered by a status request and a group of DTCs.
+
+Response: 0x58 <count> [<DTC high> <DTC low> <status>]...
+*/
+
+/// Status request byte asking the ECU for all DTCs
+/// in 2 byte hex format, regardless of their status (Mandatory per KWP2000)
+pub const STATUS_ALL_HEX_DTCS: u8 = 0x02;
+
+/// Decodes the storage state of a DTC from its KWP2000 status byte (Bits 5 and 6)
+pub(crate) fn dtc_state_from_status(status: u8) -> DTCState {
+    match (status >> 5) & 0b0000011 {
+        1 => DTCState::Stored,
+        2 => DTCState::Pending,
+        3 => DTCState::Permanent,
+        _ => DTCState::None,
+    }
+}
+
+/// Reads DTCs matching `status_mask` within `group` from the ECU
+pub fn read_dtc_by_status(
+    ecu: &KWP2000ECU,
+    status_mask: u8,
+    group: DTCGroup,
+) -> ProtocolResult<Vec<DTC>> {
+    let group = group as u16;
+    let bytes = ecu.run_command(
+        super::Service::ReadDTCByStatus.into(),
+        &[status_mask, (group >> 8) as u8, group as u8],
+    )?;
+    if bytes.len() < 2 {
+        return Err(ProtocolError::InvalidResponseSize {
+            expect: 2,
+            actual: bytes.len(),
+        });
+    }
+    let count = bytes[1] as usize;
+    let records = &bytes[2..];
+    if records.len() < count * 3 {
+        // DTC is 3 bytes (1 for status, 2 for the ID)
+        return Err(ProtocolError::InvalidResponseSize {
+            expect: count * 3 + 2,
+            actual: bytes.len(),
+        });
+    }
+
+    Ok(records
+        .chunks_exact(3)
+        .take(count)
+        .map(|record| {
+            let status = record[2];
+            DTC {
+                error: DTC::format_code(u16::from_be_bytes([record[0], record[1]])),
+                state: dtc_state_from_status(status),
+                check_engine_on: (status >> 7 & 0b00000001) > 0,
+                id: DtcCode::from_obd_bytes(&[record[0], record[1]]),
+            }
+        })
+        .collect())
+}
@@ -1,4 +1,7 @@
-use crate::commapi::protocols::{ProtocolError, ProtocolResult, ProtocolServer};
+use crate::commapi::{
+    iface::format_hex_compact,
+    protocols::{ProtocolError, ProtocolResult, ProtocolServer},
+};
 
 use super::{bcd_decode, bcd_decode_slice, KWP2000ECU};
 
@@ -184,7 +187,7 @@ fn read_fingerprint(ecu: &KWP2000ECU, cmd: u8) -> ProtocolResult<CodeFingerprint
         suppliers.push(ToolSupplier {
             id: format!("{:02X}", res[0]),
             programming_date: bcd_decode_slice(&res[1..=3]),
-            serial_number: format!("{:02X}{:02X}{:02X}{:02X}", res[4], res[5], res[6], res[7]),
+            serial_number: format_hex_compact(&res[4..8]),
         });
         res.drain(0..9);
     }
@@ -194,3 +197,107 @@ fn read_fingerprint(ecu: &KWP2000ECU, cmd: u8) -> ProtocolResult<CodeFingerprint
         suppliers,
     })
 }
+
+// Identification options defined by ISO14230-3, which are shared by most manufacturers
+const ISO_VIN: u8 = 0x90;
+const ISO_ECU_HARDWARE_NUMBER: u8 = 0x91;
+const ISO_SUPPLIER_HARDWARE_NUMBER: u8 = 0x92;
+const ISO_SUPPLIER_SOFTWARE_NUMBER: u8 = 0x94;
+
+/// Identification data of an ECU, collected from multiple identification records.
+/// Fields are None if the ECU does not support the record they are read from
+#[derive(Debug, Clone, Default)]
+pub struct KwpIdentification {
+    /// Vehicle identification number (0x90, or the original VIN 0x88 if not present)
+    pub vin: Option<String>,
+    /// Vehicle manufacturer ECU hardware number (0x91), or the ECU part number (0x86)
+    pub hardware_number: Option<String>,
+    /// System supplier ECU hardware number (0x92)
+    pub supplier_hardware_number: Option<String>,
+    /// System supplier ECU software number (0x94)
+    pub software_number: Option<String>,
+    /// Diagnostic variant code of the ECU (0x89), which identifies its coding
+    pub coding: Option<u32>,
+    /// Supplier ID (0x86 or 0x87)
+    pub supplier_id: Option<u8>,
+    /// Boot software identification (0x9E)
+    pub boot_software: Option<String>,
+}
+
+/// Parses an identification record holding ASCII text, removing the padding
+/// (Spaces, 0x00 or 0xFF) around the text
+pub fn parse_ascii_record(res: &[u8]) -> ProtocolResult<String> {
+    if res.len() < 3 {
+        return Err(ProtocolError::InvalidResponseSize {
+            expect: 3,
+            actual: res.len(),
+        });
+    }
+    let text: String = res[2..]
+        .iter()
+        .filter(|x| **x != 0x00 && **x != 0xFF)
+        .map(|x| *x as char)
+        .collect();
+    Ok(text.trim().to_string())
+}
+
+/// Parses the diagnostic variant code record (0x89)
+pub fn parse_variant_code_record(res: &[u8]) -> ProtocolResult<u32> {
+    if res.len() != 6 {
+        return Err(ProtocolError::InvalidResponseSize {
+            expect: 6,
+            actual: res.len(),
+        });
+    }
+    Ok((res[2] as u32) << 24 | (res[3] as u32) << 16 | (res[4] as u32) << 8 | res[5] as u32)
+}
+
+/// Reads a raw identification record. Returns None if the ECU rejected the
+/// identification option with a negative response
+fn read_record(ecu: &KWP2000ECU, option: u8) -> ProtocolResult<Option<Vec<u8>>> {
+    match ecu.run_command(super::Service::ReadECUID.into(), &[option]) {
+        Ok(res) => Ok(Some(res)),
+        Err(e) if e.get_nrc().is_some() => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn read_ascii(ecu: &KWP2000ECU, option: u8) -> ProtocolResult<Option<String>> {
+    Ok(read_record(ecu, option)?
+        .and_then(|r| parse_ascii_record(&r).ok())
+        .filter(|s| !s.is_empty()))
+}
+
+/// Reads the common identification records of the ECU into a [KwpIdentification].
+/// Records that the ECU does not support, or that cannot be parsed, are left empty
+pub fn read_identification(ecu: &KWP2000ECU) -> ProtocolResult<KwpIdentification> {
+    let mut id = KwpIdentification {
+        vin: read_ascii(ecu, ISO_VIN)?,
+        hardware_number: read_ascii(ecu, ISO_ECU_HARDWARE_NUMBER)?,
+        supplier_hardware_number: read_ascii(ecu, ISO_SUPPLIER_HARDWARE_NUMBER)?,
+        software_number: read_ascii(ecu, ISO_SUPPLIER_SOFTWARE_NUMBER)?,
+        coding: read_record(ecu, IdentificationType::DiagVariantCode as u8)?
+            .and_then(|r| parse_variant_code_record(&r).ok()),
+        supplier_id: None,
+        boot_software: read_ascii(ecu, IdentificationType::BootSoftwareId as u8)?,
+    };
+    if id.vin.is_none() {
+        id.vin = read_ascii(ecu, IdentificationType::OriginalVin as u8)?;
+    }
+    // Daimler ECUs store the part number and supplier in their own records
+    match read_dcs_id(ecu) {
+        Ok(dcs) => {
+            id.supplier_id = Some(dcs.supplier_id);
+            if id.hardware_number.is_none() {
+                id.hardware_number = Some(dcs.part_number);
+            }
+        }
+        Err(e) if e.is_timeout() => return Err(e),
+        Err(_) => {
+            if let Ok(dcx) = read_dcx_mmc_id(ecu) {
+                id.supplier_id = Some(dcx.supplier_id);
+            }
+        }
+    }
+    Ok(id)
+}
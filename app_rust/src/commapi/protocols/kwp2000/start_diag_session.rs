@@ -16,6 +16,19 @@ pub enum DiagSession {
     Extended = 0x92,
 }
 
+impl DiagSession {
+    pub(crate) fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            0x81 => Some(DiagSession::Default),
+            0x85 => Some(DiagSession::Flash),
+            0x89 => Some(DiagSession::Standby),
+            0x90 => Some(DiagSession::Passive),
+            0x92 => Some(DiagSession::Extended),
+            _ => None,
+        }
+    }
+}
+
 /// Attempts to set the diagnostic session type of the ECU
 pub fn set_diag_session(ecu: &KWP2000ECU, mode: DiagSession) -> ProtocolResult<()> {
     ecu.run_command(super::Service::StartDiagSession.into(), &[mode as u8])?;
@@ -1,18 +1,38 @@
-use std::fmt::Display;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering::Relaxed},
+        mpsc, Arc, Mutex, RwLock,
+    },
+    time::Duration,
+};
 
 use comm_api::ComServerError;
 use kwp2000::KWP2000ECU;
+use obd2::ObdServer;
 use uds::UDSECU;
 
-use self::{kwp2000::read_ecu_identification, uds::read_data};
+use self::{
+    clock::{Clock, SystemClock},
+    kwp2000::read_ecu_identification,
+    uds::{identification, read_data},
+};
 
 use super::{
-    comm_api::{self, ComServer},
-    iface::{Interface, InterfaceConfig, InterfacePayload, InterfaceType, PayloadFlag},
+    comm_api::{self, ComServer, FilterType},
+    iface::{
+        format_hex_compact, hex_dump, BufferType, CanbusInterface, DiagTransport, Interface,
+        InterfaceConfig, InterfacePayload, InterfaceResult, InterfaceType, PayloadFlag, IFACE_CFG,
+    },
+    isotp::{self, AddressingMode, FrameLayout},
 };
 
+pub mod clock;
 pub mod kwp2000;
 pub mod obd2;
+pub mod report;
+pub mod security;
 pub mod uds;
 pub mod vin;
 
@@ -23,6 +43,10 @@ pub enum ProtocolError {
     CustomError(String),
     InvalidResponseSize { expect: usize, actual: usize },
     Timeout,
+    StepTimeout { step: String }, // Timeout whilst establishing a diagnostic session
+    VerifyMismatch { written: Vec<u8>, read: Vec<u8> }, // Value read back differs from the written value
+    BusOff, // CAN controller went bus-off, and needs recovering with DiagServer::reconnect
+    NoAck,  // Adapter or gateway refused to pass the request on to the ECU
 }
 
 impl ProtocolError {
@@ -33,13 +57,39 @@ impl ProtocolError {
             ProtocolError::CustomError(_) => false,
             ProtocolError::InvalidResponseSize { expect, actual } => false,
             ProtocolError::Timeout => true,
+            ProtocolError::StepTimeout { .. } => true,
+            ProtocolError::VerifyMismatch { .. } => false,
+            ProtocolError::BusOff => false,
+            ProtocolError::NoAck => false,
         }
     }
 }
 
+/// Layer of the diagnostic stack a [ProtocolError] comes from. See [ProtocolError::layer]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ErrorLayer {
+    /// The adapter or bus failed to deliver the request or the response (Adapter errors,
+    /// timeouts, bus-off). Often resolved by resending, or by reconnecting
+    Transport,
+    /// The ECU answered, but rejected the request (Negative response), or its response was
+    /// malformed. Resending the same request gets the same answer
+    Protocol,
+    /// The response was valid, but not what the caller needed, or the request was refused
+    /// before it was sent
+    Application,
+}
+
 impl From<ComServerError> for ProtocolError {
     fn from(x: ComServerError) -> Self {
-        ProtocolError::CommError(x)
+        if x.is_bus_off() {
+            ProtocolError::BusOff
+        } else if x.kind() == comm_api::ComErrorKind::NoAck {
+            ProtocolError::NoAck
+        } else if x.kind() == comm_api::ComErrorKind::Timeout {
+            ProtocolError::Timeout
+        } else {
+            ProtocolError::CommError(x)
+        }
     }
 }
 
@@ -47,21 +97,233 @@ unsafe impl Send for ProtocolError {}
 unsafe impl Sync for ProtocolError {}
 
 impl ProtocolError {
+    /// Returns the negative response code the ECU replied with, if this error
+    /// was caused by a negative response
+    pub fn get_nrc(&self) -> Option<u8> {
+        match self {
+            ProtocolError::ProtocolError(e) => Some(e.get_byte()),
+            _ => None,
+        }
+    }
+
+    /// Returns which layer of the diagnostic stack the error comes from. Only [ErrorLayer::Transport]
+    /// errors are worth retrying, see [ProtocolError::is_transient] for which of them are
+    pub fn layer(&self) -> ErrorLayer {
+        match self {
+            ProtocolError::CommError(_)
+            | ProtocolError::Timeout
+            | ProtocolError::StepTimeout { .. }
+            | ProtocolError::BusOff
+            | ProtocolError::NoAck => ErrorLayer::Transport,
+            ProtocolError::ProtocolError(_) | ProtocolError::InvalidResponseSize { .. } => {
+                ErrorLayer::Protocol
+            }
+            ProtocolError::CustomError(_) | ProtocolError::VerifyMismatch { .. } => {
+                ErrorLayer::Application
+            }
+        }
+    }
+
+    /// Returns true if the error may not happen again if the command is resent, such as the
+    /// ECU not responding in time or the adapter failing to send the request. These are the
+    /// [ErrorLayer::Transport] errors, apart from a bus-off (The bus has to be recovered first),
+    /// a connection step timeout (The connection's time budget is already spent) and adapter
+    /// errors of a known kind, such as the adapter being unplugged. Negative responses are the
+    /// ECU's answer to the request, so are never transient
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ProtocolError::BusOff | ProtocolError::StepTimeout { .. } => false,
+            ProtocolError::CommError(e) => e.kind() == comm_api::ComErrorKind::Other,
+            e => e.layer() == ErrorLayer::Transport,
+        }
+    }
+
     pub fn get_text(&self) -> String {
         match self {
-            ProtocolError::CommError(e) => e.to_string(),
+            ProtocolError::CommError(e) => match e.get_help() {
+                Some(help) => format!("{} ({})", help, e),
+                None => e.to_string(),
+            },
             ProtocolError::ProtocolError(e) => e.get_desc(),
             ProtocolError::Timeout => "Communication timeout".into(),
+            ProtocolError::StepTimeout { step } => {
+                format!("Communication timeout during {}", step)
+            }
             ProtocolError::CustomError(s) => s.clone(),
             ProtocolError::InvalidResponseSize { expect, actual } => {
                 format!("Expected {} bytes, got {} bytes", expect, actual)
             }
+            ProtocolError::VerifyMismatch { written, read } => format!(
+                "Value read back {} does not match the written value {}",
+                hex_dump(read),
+                hex_dump(written)
+            ),
+            ProtocolError::BusOff => {
+                "CAN bus is off. Check the wiring, then reconnect to the ECU".into()
+            }
+            ProtocolError::NoAck => "Request was not acknowledged by the adapter".into(),
         }
     }
 }
 
 pub type ProtocolResult<T> = std::result::Result<T, ProtocolError>;
 
+/// Keeps track of the total time budget for establishing a diagnostic session.
+///
+/// The budget is set with [IFACE_CFG::CONNECT_TIMEOUT_MS]. If it is not set (or 0),
+/// each step of the connection is allowed to take as long as it needs
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectTimer {
+    clock: Arc<dyn Clock>,
+    start: u64,
+    budget_ms: u32,
+}
+
+impl ConnectTimer {
+    pub fn new(cfg: &InterfaceConfig) -> Self {
+        Self::with_clock(cfg, Arc::new(SystemClock::new()))
+    }
+
+    pub fn with_clock(cfg: &InterfaceConfig, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            start: clock.now_ms(),
+            clock,
+            budget_ms: cfg.get_param_or_default(IFACE_CFG::CONNECT_TIMEOUT_MS, 0),
+        }
+    }
+
+    /// Returns the time left in the budget for the next step, or None if there is no time limit
+    pub fn remaining(&self, step: &str) -> ProtocolResult<Option<u32>> {
+        if self.budget_ms == 0 {
+            return Ok(None);
+        }
+        let elapsed = self.clock.elapsed_ms(self.start).min(u32::MAX as u64) as u32;
+        if elapsed >= self.budget_ms {
+            Err(ProtocolError::StepTimeout { step: step.into() })
+        } else {
+            Ok(Some(self.budget_ms - elapsed))
+        }
+    }
+
+    /// Converts a timeout error into a timeout for the connection step
+    pub fn annotate(&self, e: ProtocolError, step: &str) -> ProtocolError {
+        if e.is_timeout() {
+            ProtocolError::StepTimeout { step: step.into() }
+        } else {
+            e
+        }
+    }
+
+    /// Runs a blocking step (Such as opening the interface) on a separate thread, giving
+    /// up on it if the remaining budget expires before it completes.
+    ///
+    /// If the step completes after it was given up on, its result is passed to `discard`,
+    /// so that whatever it opened can be closed (Otherwise the adapter channel stays open)
+    pub fn run_step<T, F, C>(&self, step: &str, func: F, discard: C) -> ProtocolResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> ProtocolResult<T> + Send + 'static,
+        C: FnOnce(T) + Send + 'static,
+    {
+        match self.remaining(step)? {
+            None => func(),
+            Some(timeout) => {
+                let (tx, rx) = mpsc::channel();
+                // Taken (None) once the step is given up on
+                let tx = Arc::new(Mutex::new(Some(tx)));
+                let tx_t = tx.clone();
+                std::thread::spawn(move || {
+                    let res = func();
+                    match tx_t.lock().unwrap().as_ref() {
+                        Some(tx) => {
+                            let _ = tx.send(res);
+                        }
+                        None => {
+                            if let Ok(v) = res {
+                                discard(v)
+                            }
+                        }
+                    }
+                });
+                match rx.recv_timeout(Duration::from_millis(timeout as u64)) {
+                    Ok(res) => res,
+                    Err(_) => {
+                        tx.lock().unwrap().take();
+                        // The step may have completed just before it was given up on
+                        match rx.try_recv() {
+                            Ok(res) => res,
+                            Err(_) => {
+                                eprintln!("DIAG - Connection stalled during {}", step);
+                                Err(ProtocolError::StepTimeout { step: step.into() })
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Runs `func` on `interface`, closing the interface if it fails. Used whilst setting up
+/// an interface, so that a failed setup does not leave the adapter channel open
+pub(crate) fn close_on_err<T, F>(interface: &mut Box<dyn Interface>, func: F) -> ProtocolResult<T>
+where
+    F: FnOnce(&mut Box<dyn Interface>) -> InterfaceResult<T>,
+{
+    func(interface).map_err(|e| {
+        let _ = interface.close();
+        e.into()
+    })
+}
+
+/// How often [wait_for_bus_idle] checks for frames
+const IDLE_POLL_MS: u64 = 10;
+/// Default of [IFACE_CFG::IDLE_MAX_WAIT_MS]
+const DEFAULT_IDLE_MAX_WAIT_MS: u32 = 2000;
+
+/// Waits for the ECU to stop sending before the first request of a session, so diagnostic
+/// frames are not injected in the middle of its traffic. Returns true once no frames have
+/// been received for [IFACE_CFG::WAIT_FOR_IDLE_MS], or false if the bus did not go quiet
+/// within [IFACE_CFG::IDLE_MAX_WAIT_MS] (The session is started anyway).
+///
+/// Only frames which pass the interface's filters are seen, so this listens for the traffic
+/// of the ECU being connected to. Frames received whilst waiting are discarded
+pub(crate) fn wait_for_bus_idle(
+    interface: &mut Box<dyn Interface>,
+    cfg: &InterfaceConfig,
+    clock: &dyn Clock,
+) -> bool {
+    let window_ms = cfg.get_param_or_default(IFACE_CFG::WAIT_FOR_IDLE_MS, 0) as u64;
+    if window_ms == 0 {
+        return true;
+    }
+    let max_wait_ms =
+        cfg.get_param_or_default(IFACE_CFG::IDLE_MAX_WAIT_MS, DEFAULT_IDLE_MAX_WAIT_MS) as u64;
+    let start = clock.now_ms();
+    let mut last_frame = start;
+    loop {
+        if clock.elapsed_ms(last_frame) >= window_ms {
+            return true;
+        }
+        if clock.elapsed_ms(start) >= max_wait_ms {
+            eprintln!(
+                "DIAG - Bus did not go idle within {} ms, starting the session anyway",
+                max_wait_ms
+            );
+            return false;
+        }
+        match interface.recv_data(16, 0) {
+            Ok(frames) if !frames.is_empty() => last_frame = clock.now_ms(),
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("DIAG - Could not listen for bus idle: {}", e);
+                return false;
+            }
+        }
+        clock.sleep_ms(IDLE_POLL_MS);
+    }
+}
+
 pub trait Selectable: Into<u8> {
     fn get_desc(&self) -> String;
     fn get_name(&self) -> String;
@@ -82,9 +344,62 @@ pub trait ECUCommand: Selectable {
     fn get_cmd_list() -> Vec<Self>;
 }
 
+/// Protocol agnostic description of a service supported by a diagnostic server
+#[derive(Debug, Clone)]
+pub struct CommandInfo {
+    pub sid: u8,
+    pub name: String,
+    pub desc: String,
+    pub caution: CautionLevel,
+}
+
+impl CommandInfo {
+    /// Builds the command list of a protocol
+    pub fn list_for<T: ECUCommand>() -> Vec<Self> {
+        T::get_cmd_list()
+            .into_iter()
+            .map(|c| Self {
+                name: c.get_name(),
+                desc: c.get_desc(),
+                caution: c.get_caution_level(),
+                sid: c.into(),
+            })
+            .collect()
+    }
+}
+
+/// Name and caution level of a service. See [describe_service]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ServiceInfo {
+    pub sid: u8,
+    pub name: &'static str,
+    pub caution: CautionLevel,
+}
+
+/// Looks up the name and caution level of service `sid` in the service table of `protocol`
+/// ([uds::UDS_SERVICES], [kwp2000::KWP_SERVICES] or [obd2::OBD_SERVICES]). Returns None
+/// for services the protocol does not define, such as manufacturer specific services
+pub fn describe_service(protocol: DiagProtocol, sid: u8) -> Option<ServiceInfo> {
+    let table = match protocol {
+        DiagProtocol::KWP2000 => kwp2000::KWP_SERVICES,
+        DiagProtocol::UDS => uds::UDS_SERVICES,
+        DiagProtocol::OBD2 => obd2::OBD_SERVICES,
+    };
+    table
+        .iter()
+        .find(|(s, _, _)| *s == sid)
+        .map(|(sid, name, caution)| ServiceInfo {
+            sid: *sid,
+            name,
+            caution: *caution,
+        })
+}
+
 pub trait CommandError {
     fn get_desc(&self) -> String;
     fn get_help(&self) -> Option<String>;
+    /// Returns the raw negative response code byte this error was created from
+    fn get_byte(&self) -> u8;
     fn from_byte(b: u8) -> Self
     where
         Self: Sized;
@@ -96,6 +411,53 @@ impl std::fmt::Debug for Box<dyn CommandError> {
     }
 }
 
+/// Negative response whose description was overridden by the caller
+/// (For manufacturer specific NRCs)
+#[derive(Debug, Clone)]
+pub struct NrcOverride {
+    code: u8,
+    desc: String,
+    help: Option<String>,
+}
+
+impl CommandError for NrcOverride {
+    fn get_desc(&self) -> String {
+        self.desc.clone()
+    }
+
+    fn get_help(&self) -> Option<String> {
+        self.help.clone()
+    }
+
+    fn get_byte(&self) -> u8 {
+        self.code
+    }
+
+    fn from_byte(b: u8) -> Self {
+        Self {
+            code: b,
+            desc: format!("Negative response 0x{:02X}", b),
+            help: None,
+        }
+    }
+}
+
+/// Replaces the description of a negative response error with the one in `overrides`
+/// for its NRC, if there is one. Other errors are returned as is
+pub fn apply_nrc_overrides(err: ProtocolError, overrides: &HashMap<u8, String>) -> ProtocolError {
+    match err {
+        ProtocolError::ProtocolError(e) => match overrides.get(&e.get_byte()) {
+            Some(desc) => ProtocolError::ProtocolError(Box::new(NrcOverride {
+                code: e.get_byte(),
+                desc: desc.clone(),
+                help: e.get_help(),
+            })),
+            None => ProtocolError::ProtocolError(e),
+        },
+        _ => err,
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum DTCState {
     None,
@@ -104,12 +466,93 @@ pub enum DTCState {
     Permanent,
 }
 
+/// A DTC identifier, stored in its 3 byte (UDS) form. 2 byte (OBD-II / KWP2000) codes are
+/// stored with the last (Failure type) byte set to 0
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct DtcCode(u32);
+
+impl DtcCode {
+    /// Failure type byte (ISO 15031-6 / SAE J2012 DTC failure type) of codes which have
+    /// no failure type, such as OBD-II codes. 0x00 means "No sub type information"
+    pub const NO_FAILURE_TYPE: u8 = 0x00;
+
+    /// Parses an SAE J2012 code, such as `P0420`. The failure type can follow the code, either
+    /// directly (`P042013`) or after a dash (`P0420-13`), otherwise it is [DtcCode::NO_FAILURE_TYPE].
+    /// Returns None if the code is not valid
+    pub fn from_sae_string(code: &str) -> Option<Self> {
+        let code = code.trim();
+        let mut chars = code.chars();
+        let category = match chars.next()?.to_ascii_uppercase() {
+            'P' => 0,
+            'C' => 1,
+            'B' => 2,
+            'U' => 3,
+            _ => return None,
+        };
+        let rest: String = chars.filter(|c| *c != '-').collect();
+        if !(rest.len() == 4 || rest.len() == 6) || !rest.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        let digits = u32::from_str_radix(&rest[..4], 16).ok()?;
+        if digits >> 12 > 3 {
+            return None; // Second character is only 2 bits (0-3)
+        }
+        let failure_type = match rest.get(4..) {
+            Some(ft) if !ft.is_empty() => u8::from_str_radix(ft, 16).ok()?,
+            _ => Self::NO_FAILURE_TYPE,
+        };
+        Some(Self((category << 14 | digits) << 8 | failure_type as u32))
+    }
+
+    pub fn from_bytes(bytes: &[u8; 3]) -> Self {
+        Self((bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32)
+    }
+
+    /// Creates a code from its 2 byte (OBD-II / KWP2000) form
+    pub fn from_obd_bytes(bytes: &[u8; 2]) -> Self {
+        Self::from_bytes(&[bytes[0], bytes[1], 0x00])
+    }
+
+    pub fn as_uds_bytes(&self) -> [u8; 3] {
+        [(self.0 >> 16) as u8, (self.0 >> 8) as u8, self.0 as u8]
+    }
+
+    /// Returns the 2 byte (OBD-II / KWP2000) form of the code, without the failure type byte
+    pub fn as_obd_bytes(&self) -> [u8; 2] {
+        [(self.0 >> 16) as u8, (self.0 >> 8) as u8]
+    }
+
+    /// Returns the failure type byte, the last byte of the 3 byte (UDS) form, which says
+    /// how the component failed (For example 0x13 - Circuit open)
+    pub fn failure_type(&self) -> u8 {
+        self.0 as u8
+    }
+
+    /// Returns the same code with its failure type byte set to `failure_type`, for example
+    /// to query the UDS extended data of an OBD-II code
+    pub fn with_failure_type(&self, failure_type: u8) -> Self {
+        Self(self.0 & 0xFFFF00 | failure_type as u32)
+    }
+
+    /// Formats the code as an SAE J2012 code, for example `P0420`
+    pub fn as_obd_string(&self) -> String {
+        let [a, b] = self.as_obd_bytes();
+        let prefix = match (a >> 6) & 0b11 {
+            0 => 'P', // Powertrain
+            1 => 'C', // Chassis
+            2 => 'B', // Body
+            _ => 'U', // Network
+        };
+        format!("{}{}{:1X}{:02X}", prefix, (a >> 4) & 0b11, a & 0x0F, b)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct DTC {
     pub(crate) error: String,
     pub(crate) state: DTCState,
     pub(crate) check_engine_on: bool,
-    pub(crate) id: u32,
+    pub(crate) id: DtcCode,
 }
 
 impl Display for DTC {
@@ -122,120 +565,1870 @@ impl Display for DTC {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-pub struct DiagCfg {
-    pub send_id: u32,
-    pub recv_id: u32,
-    pub global_id: Option<u32>,
+impl DTC {
+    /// Formats a raw 2 byte DTC (As returned by the ECU) as an ISO 15031-6 code.
+    /// The top 2 bits are the category (P/C/B/U), the next 2 bits the first digit,
+    /// and the remaining nibbles the rest of the code (EG: `0xC123` is `U0123`)
+    pub fn format_code(raw: u16) -> String {
+        DtcCode::from_obd_bytes(&raw.to_be_bytes()).as_obd_string()
+    }
+
+    /// Returns true if `name` (For example from a DTC database) refers to this DTC.
+    /// Names may carry a prefix, and may either be the formatted code (`P2001`)
+    /// or the raw hex of the DTC (`2001`). The raw hex is only compared for names which
+    /// are not a formatted code, so `P0101` (Raw `0101`) does not match `U0101`
+    pub fn matches_name(&self, name: &str) -> bool {
+        if name.ends_with(&self.error) {
+            return true;
+        }
+        !ends_with_dtc_code(name)
+            && (name.ends_with(&format_hex_compact(&self.id.as_obd_bytes()))
+                || name.ends_with(&format_hex_compact(&self.id.as_uds_bytes())))
+    }
+
+    /// Returns true if the DTC is stored or permanent in the ECU (Not just pending)
+    pub fn is_confirmed(&self) -> bool {
+        matches!(self.state, DTCState::Stored | DTCState::Permanent)
+    }
 }
 
-#[derive(Debug, Copy, Clone)]
-pub enum DiagProtocol {
-    KWP2000,
-    UDS,
+/// Returns true if `name` ends with a formatted DTC code (P/C/B/U followed by 4 hex digits).
+/// B and C are hex digits themselves, so a code preceded by another hex digit is raw hex instead
+fn ends_with_dtc_code(name: &str) -> bool {
+    let name = name.as_bytes();
+    if name.len() < 5 {
+        return false;
+    }
+    let (prefix, code) = name.split_at(name.len() - 5);
+    matches!(code[0], b'P' | b'C' | b'B' | b'U')
+        && code[1..].iter().all(u8::is_ascii_hexdigit)
+        && !prefix.last().map_or(false, u8::is_ascii_hexdigit)
+}
+
+/// Returns only the confirmed (Stored or permanent) DTCs
+pub fn filter_confirmed(dtcs: &[DTC]) -> Vec<DTC> {
+    dtcs.iter().filter(|x| x.is_confirmed()).cloned().collect()
 }
 
+/// Sorts DTCs by their ID
+pub fn sort_by_id(dtcs: &mut [DTC]) {
+    dtcs.sort_by_key(|x| x.id)
+}
+
+/// Removes DTCs with the same ID (For example when merging multiple reads),
+/// keeping the first occurrence of each
+pub fn dedup(dtcs: Vec<DTC>) -> Vec<DTC> {
+    let mut seen = std::collections::HashSet::new();
+    dtcs.into_iter().filter(|x| seen.insert(x.id)).collect()
+}
+
+/// Change of a DTC between two reads of the fault memory
 #[derive(Debug, Clone)]
-pub enum DiagServer {
-    KWP2000(KWP2000ECU),
-    UDS(UDSECU),
+pub enum DtcChange {
+    /// DTC was not present in the previous read
+    Added(DTC),
+    /// DTC is no longer present
+    Removed(DTC),
+    /// DTC is still present, but its state or warning lamp changed
+    StatusChanged { old: DTC, new: DTC },
 }
 
-impl DiagServer {
-    pub fn new(
-        protocol: DiagProtocol,
-        comm_server: &Box<dyn ComServer>,
-        interface_type: InterfaceType,
-        interface_cfg: InterfaceConfig,
-        tx_flags: Option<Vec<PayloadFlag>>,
-        diag_cfg: DiagCfg,
-    ) -> ProtocolResult<Self> {
-        Ok(match protocol {
-            DiagProtocol::KWP2000 => Self::KWP2000(KWP2000ECU::start_diag_session(
-                comm_server,
-                interface_type,
-                interface_cfg,
-                tx_flags,
-                diag_cfg,
-            )?),
-            DiagProtocol::UDS => Self::UDS(UDSECU::start_diag_session(
-                comm_server,
-                interface_type,
-                interface_cfg,
-                tx_flags,
-                diag_cfg,
-            )?),
-        })
+/// Compares two reads of the fault memory, matching DTCs by their ID
+pub fn diff_dtcs(old: &[DTC], new: &[DTC]) -> Vec<DtcChange> {
+    let mut changes = Vec::new();
+    for dtc in new {
+        match old.iter().find(|x| x.id == dtc.id) {
+            None => changes.push(DtcChange::Added(dtc.clone())),
+            Some(prev) => {
+                if prev.state != dtc.state || prev.check_engine_on != dtc.check_engine_on {
+                    changes.push(DtcChange::StatusChanged {
+                        old: prev.clone(),
+                        new: dtc.clone(),
+                    })
+                }
+            }
+        }
     }
-
-    pub fn get_name<'a>(&self) -> &'a str {
-        match self {
-            Self::KWP2000(_) => "KWP2000",
-            Self::UDS(_) => "UDS",
+    for dtc in old {
+        if !new.iter().any(|x| x.id == dtc.id) {
+            changes.push(DtcChange::Removed(dtc.clone()))
         }
     }
+    changes
+}
 
-    pub fn kill_diag_server(&mut self) {
-        match self {
-            Self::KWP2000(s) => s.exit_diag_session(),
-            Self::UDS(s) => s.exit_diag_session(),
+/// Result of clearing DTCs and re-reading them from the ECU
+#[derive(Debug, Clone)]
+pub struct ClearResult {
+    /// DTCs that were present before clearing, and are no longer present
+    pub cleared: Vec<DTC>,
+    /// DTCs that are present after clearing (For example permanent DTCs
+    /// that need drive cycles to clear, or faults that are still active)
+    pub persisted: Vec<DTC>,
+}
+
+/// Version information of an ECU. Fields are None if the ECU does not provide them
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EcuVersions {
+    pub hardware: Option<String>,
+    pub software: Option<String>,
+    pub bootloader: Option<String>,
+    /// Serial number of the ECU (UDS only)
+    pub serial_number: Option<String>,
+    /// Date the ECU was manufactured, as YYYY-MM-DD (UDS only)
+    pub manufacturing_date: Option<String>,
+}
+
+/// Counters of the traffic a diag server has sent and received
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Requests sent to the ECU
+    pub tx: u64,
+    /// Responses received from the ECU
+    pub rx: u64,
+    /// Requests which the ECU did not respond to in time (Or responded to with the wrong response)
+    pub timeouts: u64,
+    /// Negative responses received from the ECU
+    pub nrc_count: u64,
+    /// Times the ECU asked us to wait for its response (Response pending)
+    pub retries: u64,
+    /// Time between sending the last request which was answered, and receiving the
+    /// ECU's final response to it (Including any time spent waiting on response pending)
+    pub last_latency: Option<Duration>,
+}
+
+/// A command sent to an ECU's diag server thread
+#[derive(Debug, Clone)]
+pub(crate) struct CommandRequest {
+    pub cmd: u8,
+    pub args: Vec<u8>,
+    /// Wait for the ECU's response to the command
+    pub response_required: bool,
+    /// See [ResponseOptions::await_sid_ms]
+    pub await_sid_ms: Option<u32>,
+    /// See [ResponseOptions::expected_len]
+    pub expected_len: Option<usize>,
+    /// See [ResponseOptions::nrc_window_ms]
+    pub nrc_window_ms: Option<u32>,
+    /// Sequence number assigned by [RequestCorrelator::tag], which the diag server
+    /// thread returns with the response
+    pub seq: u64,
+}
+
+impl CommandRequest {
+    pub fn new(cmd: u8, args: &[u8], response_required: bool) -> Self {
+        Self {
+            cmd,
+            args: Vec::from(args),
+            response_required,
+            await_sid_ms: None,
+            expected_len: None,
+            nrc_window_ms: None,
+            seq: 0,
         }
     }
+}
 
-    pub fn run_cmd(&mut self, cmd: u8, args: &[u8]) -> ProtocolResult<Vec<u8>> {
-        match self {
-            Self::KWP2000(s) => s.run_command(cmd, args),
-            Self::UDS(s) => s.run_command(cmd, args),
+/// A response from an ECU's diag server thread, with the sequence number of its request
+pub(crate) type CommandResponse = (u64, ProtocolResult<Vec<u8>>);
+
+/// Matches responses from an ECU's diag server thread to the requests they answer.
+///
+/// Each request is tagged with a sequence number before it is sent. A response that
+/// arrives for another request still in flight is parked until that request collects it,
+/// and responses to requests that already gave up waiting (Timed out) are discarded,
+/// so a late response is never returned for the wrong request
+#[derive(Debug, Clone)]
+pub(crate) struct RequestCorrelator {
+    next_seq: Arc<AtomicU64>,
+    in_flight: Arc<Mutex<HashSet<u64>>>,
+    parked: Arc<Mutex<HashMap<u64, ProtocolResult<Vec<u8>>>>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl RequestCorrelator {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            next_seq: Arc::new(AtomicU64::new(0)),
+            in_flight: Arc::new(Mutex::new(HashSet::new())),
+            parked: Arc::new(Mutex::new(HashMap::new())),
+            clock,
         }
     }
 
-    pub fn into_kwp(&mut self) -> Option<&mut KWP2000ECU> {
-        match self {
-            Self::KWP2000(s) => Some(s),
-            Self::UDS(s) => None,
+    /// Assigns the next sequence number to `request`, and marks it as in flight
+    pub fn tag(&self, request: &mut CommandRequest) -> u64 {
+        let seq = self.next_seq.fetch_add(1, Relaxed);
+        request.seq = seq;
+        self.in_flight.lock().unwrap().insert(seq);
+        seq
+    }
+
+    /// Waits for the response to the request tagged `seq`, for up to `timeout_ms` (Forever if None).
+    /// Returns None if the request timed out, or if the diag server thread has stopped
+    pub fn await_response(
+        &self,
+        rx: &mpsc::Receiver<CommandResponse>,
+        seq: u64,
+        timeout_ms: Option<u32>,
+    ) -> Option<ProtocolResult<Vec<u8>>> {
+        let deadline = timeout_ms.map(|t| self.clock.now_ms() + t as u64);
+        let res = loop {
+            if let Some(res) = self.parked.lock().unwrap().remove(&seq) {
+                break Some(res);
+            }
+            let received = match deadline {
+                None => rx.recv().ok(),
+                Some(d) => match d.saturating_sub(self.clock.now_ms()) {
+                    0 => None,
+                    left => rx.recv_timeout(Duration::from_millis(left)).ok(),
+                },
+            };
+            match received {
+                Some((s, res)) if s == seq => break Some(res),
+                Some((s, res)) => {
+                    if self.in_flight.lock().unwrap().contains(&s) {
+                        self.parked.lock().unwrap().insert(s, res);
+                    } else {
+                        eprintln!("DIAG - Discarding late response to request #{}", s);
+                    }
+                }
+                None => break None,
+            }
+        };
+        self.in_flight.lock().unwrap().remove(&seq);
+        res
+    }
+}
+
+/// Options for how [ProtocolServer::run_command_resp] treats the ECU's response
+#[derive(Debug, Clone)]
+pub struct ResponseOptions {
+    /// Reject responses whose service ID does not match the request
+    pub strict_matching: bool,
+    /// Largest response in bytes that is accepted from the ECU
+    pub max_response_len: usize,
+    /// If set, unrelated frames received before the response to the request are
+    /// discarded, for up to this many milliseconds
+    pub await_sid_ms: Option<u32>,
+    /// If set, positive responses which are not exactly this many bytes long are rejected.
+    /// Otherwise, the length is checked against [ProtocolServer::expected_response_len]
+    pub expected_len: Option<usize>,
+    /// If set, commands sent without waiting for a response still listen for this many
+    /// milliseconds afterwards, in case the ECU rejects the command with a negative response
+    pub nrc_window_ms: Option<u32>,
+    /// Wait for the final response when the ECU asks us to wait (Response pending). When
+    /// disabled, the pending negative response is returned to the caller as is
+    pub handle_response_pending: bool,
+    /// Discard frames left in the receive buffer before sending the request. See [IFACE_CFG::FLUSH_RX]
+    pub flush_rx: bool,
+    /// Milliseconds to wait for the response to a request
+    pub response_timeout_ms: u32,
+    /// Milliseconds to wait for the next response each time the ECU asks us to wait
+    /// (Response pending)
+    pub pending_timeout_ms: u32,
+    /// Time source for timeouts computed by the diag server
+    pub clock: Arc<dyn Clock>,
+}
+
+impl ResponseOptions {
+    pub const DEFAULT_MAX_RESPONSE_LEN: u32 = 0x10000;
+    pub const DEFAULT_RESPONSE_TIMEOUT_MS: u32 = 2000;
+
+    /// Creates default options, with the max response length from the interface configuration
+    pub fn new(cfg: &InterfaceConfig) -> Self {
+        Self {
+            strict_matching: true,
+            await_sid_ms: None,
+            expected_len: None,
+            nrc_window_ms: None,
+            handle_response_pending: true,
+            flush_rx: cfg.get_param_or_default(IFACE_CFG::FLUSH_RX, 1) > 0,
+            response_timeout_ms: Self::DEFAULT_RESPONSE_TIMEOUT_MS,
+            pending_timeout_ms: Self::DEFAULT_RESPONSE_TIMEOUT_MS,
+            clock: Arc::new(SystemClock::new()),
+            max_response_len: cfg
+                .get_param_or_default(IFACE_CFG::MAX_RESPONSE_LEN, Self::DEFAULT_MAX_RESPONSE_LEN)
+                as usize,
         }
     }
+}
 
-    pub fn read_errors(&self) -> ProtocolResult<Vec<DTC>> {
-        match self {
-            Self::KWP2000(s) => s.read_errors(),
-            Self::UDS(s) => s.read_errors(),
+#[derive(Debug, Clone, Copy)]
+pub struct DiagCfg {
+    pub send_id: u32,
+    pub recv_id: u32,
+    pub global_id: Option<u32>,
+    /// Revision of the protocol the ECU implements, if known
+    pub variant: ProtocolVariant,
+}
+
+/// Delay after a successful session change, and the optional single retry of the first
+/// request after it, for ECUs which reject requests whilst they re-initialize
+#[derive(Debug, Clone)]
+pub(crate) struct PostSessionGuard {
+    delay_ms: Arc<AtomicU32>,
+    retry: Arc<AtomicBool>,
+    armed: Arc<AtomicBool>, // The next request is the first after a session change
+    clock: Arc<dyn Clock>,
+}
+
+impl PostSessionGuard {
+    /// Time to wait before the retry, if no delay is configured
+    const RETRY_DELAY_MS: u32 = 100;
+
+    pub fn new(cfg: &InterfaceConfig, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            delay_ms: Arc::new(AtomicU32::new(
+                cfg.get_param_or_default(IFACE_CFG::POST_SESSION_DELAY_MS, 0),
+            )),
+            retry: Arc::new(AtomicBool::new(
+                cfg.get_param_or_default(IFACE_CFG::POST_SESSION_RETRY, 0) > 0,
+            )),
+            armed: Arc::new(AtomicBool::new(false)),
+            clock,
         }
     }
 
-    pub fn clear_errors(&self) -> ProtocolResult<()> {
-        match self {
-            Self::KWP2000(s) => s.clear_errors(),
-            Self::UDS(s) => s.clear_errors(),
+    pub fn set_delay_ms(&self, delay_ms: u32) {
+        self.delay_ms.store(delay_ms, Relaxed)
+    }
+
+    pub fn set_retry(&self, retry: bool) {
+        self.retry.store(retry, Relaxed)
+    }
+
+    /// Called once the ECU has accepted a session change
+    pub fn on_session_changed(&self) {
+        let delay = self.delay_ms.load(Relaxed);
+        if delay > 0 {
+            self.clock.sleep_ms(delay as u64);
         }
+        self.armed.store(self.retry.load(Relaxed), Relaxed);
     }
 
-    pub fn get_variant_id(&self) -> ProtocolResult<u32> {
-        match self {
-            Self::KWP2000(s) => {
-                read_ecu_identification::read_dcx_mmc_id(&s).map(|x| x.diag_information as u32)
+    /// Runs a request, running it again once if it is the first request after a session
+    /// change and the ECU responded with busyRepeatRequest or conditionsNotCorrect
+    pub fn run<F: FnMut() -> ProtocolResult<Vec<u8>>>(&self, mut f: F) -> ProtocolResult<Vec<u8>> {
+        let armed = self.armed.swap(false, Relaxed);
+        match f() {
+            Err(e) if armed && matches!(e.get_nrc(), Some(0x21) | Some(0x22)) => {
+                let delay = self.delay_ms.load(Relaxed).max(Self::RETRY_DELAY_MS);
+                self.clock.sleep_ms(delay as u64);
+                f()
             }
-            Self::UDS(s) => read_data::read_variant_id(s),
+            res => res,
         }
     }
+}
 
-    pub fn get_dtc_env_data(&self, dtc: &DTC) -> ProtocolResult<Vec<u8>> {
-        match self {
-            Self::KWP2000(s) => kwp2000::read_status_dtc::read_status_dtc(s, dtc),
-            Self::UDS(s) => Err(ProtocolError::CustomError(
-                "Not implemented (get_dtc_env_data)".into(),
-            )), // TODO
+/// Handle of a diag server's thread, which returns the result of closing the interface
+/// once the diag server stops. Shared by clones of the diag server, so any of them can join it
+pub(crate) type DiagThread = Arc<Mutex<Option<std::thread::JoinHandle<InterfaceResult<()>>>>>;
+
+/// Waits for a diag server's thread to stop (It must already have been told to),
+/// returning the error closing the interface failed with, if any.
+/// Returns immediately if the thread was already joined
+pub(crate) fn join_diag_thread(thread: &DiagThread) -> ProtocolResult<()> {
+    let handle = match thread.lock().unwrap().take() {
+        Some(h) => h,
+        None => return Ok(()),
+    };
+    match handle.join() {
+        Ok(res) => res.map_err(ProtocolError::CommError),
+        Err(_) => Err(ProtocolError::CustomError(
+            "Diag server thread panicked".into(),
+        )),
+    }
+}
+
+/// Shared flag for asking a long running operation (Such as flashing) to stop.
+/// Clones share the same flag, so the token can be cancelled from another thread
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Relaxed)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Relaxed)
+    }
+}
+
+/// Asks a diag server thread to recover its bus (By setting `recover_bus`), then waits
+/// up to `timeout_ms` for the thread to clear the flag once it has done so
+pub(crate) fn request_bus_recovery(
+    recover_bus: &AtomicBool,
+    timeout_ms: u64,
+    clock: &dyn Clock,
+) -> ProtocolResult<()> {
+    recover_bus.store(true, Relaxed);
+    let start = clock.now_ms();
+    while recover_bus.load(Relaxed) {
+        if clock.elapsed_ms(start) >= timeout_ms {
+            recover_bus.store(false, Relaxed);
+            return Err(ProtocolError::Timeout);
         }
+        clock.sleep_ms(1);
     }
+    Ok(())
 }
 
-impl Drop for DiagServer {
-    fn drop(&mut self) {
-        println!("Drop for Diag Server called!");
-        self.kill_diag_server()
+/// Checks that the adapter behind `comm_server` supports `interface_type`, so a diagnostic
+/// session is not started on an interface the adapter cannot drive (Which otherwise only
+/// shows up as timeouts once frames are sent)
+pub fn check_interface_support(
+    comm_server: &Box<dyn ComServer>,
+    interface_type: InterfaceType,
+) -> ProtocolResult<()> {
+    let caps = comm_server.get_capabilities();
+    let (supported, desc) = match interface_type {
+        InterfaceType::Can => (caps.can, "raw CAN"),
+        // Adapters without ISO-TP of their own get it in software over raw CAN
+        InterfaceType::IsoTp if caps.iso15765 != comm_api::Capability::Yes => {
+            (caps.can, "ISO-TP (ISO15765) or raw CAN")
+        }
+        InterfaceType::IsoTp => (caps.iso15765, "ISO-TP (ISO15765)"),
+        InterfaceType::Iso14230 => (caps.iso14230, "ISO14230 (KWP2000 K-Line)"),
+        InterfaceType::Iso9141 => (caps.iso9141, "ISO9141 (K-Line)"),
+    };
+    match supported {
+        comm_api::Capability::Yes => Ok(()),
+        _ => Err(ProtocolError::CustomError(format!(
+            "Adapter {} does not support {}",
+            caps.get_name(),
+            desc
+        ))),
     }
 }
 
+/// Checks that `tx_flags` agree with each other and with how the interface is configured,
+/// so that contradictory settings are reported when connecting rather than as timeouts
+pub fn validate_tx_flags(
+    interface_type: InterfaceType,
+    cfg: &InterfaceConfig,
+    tx_flags: &Option<Vec<PayloadFlag>>,
+    diag_cfg: &DiagCfg,
+) -> ProtocolResult<()> {
+    let conflict = |msg: String| Err(ProtocolError::CustomError(msg));
+    let flags = tx_flags.as_deref().unwrap_or_default();
+    if interface_type != InterfaceType::IsoTp {
+        if let Some(f) = flags.first() {
+            return conflict(format!(
+                "Payload flag {:?} is only supported over ISO-TP, not {:?}",
+                f, interface_type
+            ));
+        }
+    }
+    let mut addr_ext = None;
+    let mut length_prefix = None;
+    for f in flags {
+        match *f {
+            PayloadFlag::ISOTP_ADDR_EXTENSION(ext) => match addr_ext.replace(ext) {
+                Some(prev) if prev != ext => {
+                    return conflict(format!(
+                        "Conflicting ISO-TP address extensions 0x{:02X} and 0x{:02X}",
+                        prev, ext
+                    ))
+                }
+                _ => {}
+            },
+            PayloadFlag::LENGTH_PREFIXED(width) => {
+                if width != 1 && width != 2 {
+                    return conflict(format!(
+                        "Length prefix must be 1 or 2 bytes wide, not {}",
+                        width
+                    ));
+                }
+                match length_prefix.replace(width) {
+                    Some(prev) if prev != width => {
+                        return conflict(format!(
+                            "Conflicting length prefixes of {} and {} bytes",
+                            prev, width
+                        ))
+                    }
+                    _ => {}
+                }
+            }
+            PayloadFlag::ISOTP_PAD_FRAME
+            | PayloadFlag::ISOTP_EXT_ADDR
+            | PayloadFlag::ISOTP_RX_ADDR_EXTENSION(_) => {}
+        }
+    }
+    if matches!(interface_type, InterfaceType::Can | InterfaceType::IsoTp) {
+        // Standard (11 bit) CAN IDs, unless the interface uses extended (29 bit) IDs
+        if cfg.get_param_or_default(IFACE_CFG::EXT_CAN_ADDR, 0) == 0 {
+            for id in &[diag_cfg.send_id, diag_cfg.recv_id] {
+                if *id > 0x7FF {
+                    return conflict(format!(
+                        "CAN ID 0x{:X} needs extended (29 bit) addressing, but EXT_CAN_ADDR is not set",
+                        id
+                    ));
+                }
+            }
+        }
+        let dlc = cfg.get_param_or_default(IFACE_CFG::ISOTP_CAN_DLC, 8);
+        if interface_type == InterfaceType::IsoTp && dlc > 8 {
+            return conflict(format!(
+                "ISOTP_CAN_DLC of {} needs CAN-FD, which the adapter's ISO-TP interface does not support",
+                dlc
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Configures the interface and payload flags for the addressing mode set in `cfg`
+/// (See [AddressingMode::from_cfg]), after checking the mode can be used with the
+/// interface and CAN IDs
+pub(crate) fn apply_addressing(
+    interface_type: InterfaceType,
+    cfg: &mut InterfaceConfig,
+    tx_flags: &mut Option<Vec<PayloadFlag>>,
+    diag_cfg: &DiagCfg,
+) -> ProtocolResult<AddressingMode> {
+    let mode = AddressingMode::from_cfg(cfg).map_err(ProtocolError::CustomError)?;
+    if mode != AddressingMode::Normal && interface_type != InterfaceType::IsoTp {
+        return Err(ProtocolError::CustomError(format!(
+            "{:?} addressing is only supported over ISO-TP",
+            mode
+        )));
+    }
+    let mut add_flags = |flags: &[PayloadFlag]| {
+        tx_flags
+            .get_or_insert_with(Vec::new)
+            .extend_from_slice(flags)
+    };
+    match mode {
+        AddressingMode::Normal => {}
+        AddressingMode::NormalFixed => {
+            // 0x18DA<target><source>. The ECU responds with the addresses swapped
+            let (send, recv) = (diag_cfg.send_id, diag_cfg.recv_id);
+            let is_fixed = |id: u32| id >> 16 == 0x18DA;
+            let swapped = ((recv & 0xFF) << 8) | ((recv >> 8) & 0xFF);
+            if !is_fixed(send) || !is_fixed(recv) || send & 0xFFFF != swapped {
+                return Err(ProtocolError::CustomError(format!(
+                    "CAN IDs 0x{:X} / 0x{:X} are not a normal fixed address pair (0x18DA<target><source>)",
+                    send, recv
+                )));
+            }
+            cfg.add_param(IFACE_CFG::EXT_CAN_ADDR, 1);
+        }
+        AddressingMode::Extended { target, source } => {
+            cfg.add_param(IFACE_CFG::EXT_ISOTP_ADDR, 1);
+            add_flags(&[
+                PayloadFlag::ISOTP_ADDR_EXTENSION(target),
+                PayloadFlag::ISOTP_RX_ADDR_EXTENSION(source),
+            ]);
+        }
+        AddressingMode::Mixed { address_extension } => {
+            cfg.add_param(IFACE_CFG::EXT_ISOTP_ADDR, 1);
+            add_flags(&[PayloadFlag::ISOTP_ADDR_EXTENSION(address_extension)]);
+        }
+    }
+    Ok(mode)
+}
+
+/// Attempts to find the CAN ID an ECU responds on, by sending a TesterPresent to `send_id`
+/// over raw CAN (500kbps) and returning the ID of the first valid diagnostic response.
+///
+/// Only responses from IDs in `candidates` are accepted. If `candidates` is empty,
+/// then a response from any CAN ID other than `send_id` is accepted
+pub fn detect_recv_id(
+    comm_server: &Box<dyn ComServer>,
+    send_id: u32,
+    candidates: &[u32],
+) -> ProtocolResult<u32> {
+    let mut iface = open_raw_can(comm_server)?;
+    let res = detect_recv_id_on(&mut iface, send_id, candidates, &SystemClock::new());
+    if let Err(e) = iface.close() {
+        eprintln!("Could not close CAN interface after ID detection: {}", e);
+    }
+    res
+}
+
+/// Opens a raw CAN (500kbps) interface, which listens to all CAN IDs
+fn open_raw_can(comm_server: &Box<dyn ComServer>) -> ProtocolResult<Box<dyn Interface>> {
+    let mut cfg = InterfaceConfig::new();
+    cfg.add_param(IFACE_CFG::BAUDRATE, 500_000);
+    cfg.add_param(IFACE_CFG::EXT_CAN_ADDR, 0);
+
+    let mut iface = CanbusInterface::new(comm_server.clone_box())?;
+    iface.setup(&cfg)?;
+    // Listen to everything, we filter the responses ourselves
+    iface.add_filter(FilterType::Pass {
+        id: 0x0000,
+        mask: 0x0000,
+    })?;
+    Ok(iface)
+}
+
+fn detect_recv_id_on(
+    iface: &mut Box<dyn Interface>,
+    send_id: u32,
+    candidates: &[u32],
+    clock: &dyn Clock,
+) -> ProtocolResult<u32> {
+    iface.clear_buffer(BufferType::RX)?;
+    // ISO-TP single frame TesterPresent (UDS and KWP2000 both respond to 0x3E)
+    iface.send_data(
+        &[InterfacePayload::new(
+            send_id,
+            &[0x02, 0x3E, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        )],
+        0,
+    )?;
+
+    let start = clock.now_ms();
+    while clock.elapsed_ms(start) < 1000 {
+        for frame in iface.recv_data(100, 100).unwrap_or_default() {
+            if frame.id == send_id || (!candidates.is_empty() && !candidates.contains(&frame.id)) {
+                continue;
+            }
+            // Positive TesterPresent response, or a negative response to it
+            let valid = match frame.data.as_slice() {
+                [_, 0x7E, ..] => true,
+                [_, 0x7F, 0x3E, ..] => true,
+                _ => false,
+            };
+            if valid {
+                println!(
+                    "Detected receive ID 0x{:04X} for send ID 0x{:04X}",
+                    frame.id, send_id
+                );
+                return Ok(frame.id);
+            }
+        }
+    }
+    Err(ProtocolError::Timeout)
+}
+
+/// Functionally broadcasts an ECUReset of type `kind` to `global_id` over raw CAN (500kbps),
+/// returning the IDs of the ECUs which acknowledged the reset within `collect_ms`.
+///
+/// ECUs which reset without sending a positive response are not included
+pub fn ecu_reset_functional(
+    comm_server: &Box<dyn ComServer>,
+    global_id: u32,
+    kind: u8,
+    collect_ms: u32,
+) -> ProtocolResult<Vec<u32>> {
+    let mut iface = open_raw_can(comm_server)?;
+    let res = ecu_reset_functional_on(&mut iface, global_id, kind, collect_ms, &SystemClock::new());
+    if let Err(e) = iface.close() {
+        eprintln!(
+            "Could not close CAN interface after functional reset: {}",
+            e
+        );
+    }
+    res
+}
+
+fn ecu_reset_functional_on(
+    iface: &mut Box<dyn Interface>,
+    global_id: u32,
+    kind: u8,
+    collect_ms: u32,
+    clock: &dyn Clock,
+) -> ProtocolResult<Vec<u32>> {
+    iface.clear_buffer(BufferType::RX)?;
+    // ISO-TP single frame ECUReset (0x11 for both UDS and KWP2000)
+    iface.send_data(
+        &[InterfacePayload::new(
+            global_id,
+            &[0x02, 0x11, kind, 0x00, 0x00, 0x00, 0x00, 0x00],
+        )],
+        0,
+    )?;
+
+    let mut responders = Vec::new();
+    let start = clock.now_ms();
+    while clock.elapsed_ms(start) < collect_ms as u64 {
+        for frame in iface.recv_data(100, 100).unwrap_or_default() {
+            if frame.id == global_id || responders.contains(&frame.id) {
+                continue;
+            }
+            match frame.data.as_slice() {
+                [_, 0x51, ..] => responders.push(frame.id),
+                [_, 0x7F, 0x11, nrc, ..] => eprintln!(
+                    "ECU 0x{:04X} rejected the functional reset (NRC 0x{:02X})",
+                    frame.id, nrc
+                ),
+                _ => {}
+            }
+        }
+    }
+    println!(
+        "Functional reset acknowledged by {} ECU(s): {:04X?}",
+        responders.len(),
+        responders
+    );
+    Ok(responders)
+}
+
+/// Result of scanning one ECU with [vehicle_dtc_scan]
+#[derive(Debug, Clone)]
+pub struct EcuReport {
+    pub send_id: u32,
+    pub recv_id: u32,
+    /// Name of the ECU. Not known to the scan, so it is None unless set by the caller
+    /// (For example, from the ECU's variant definition)
+    pub name: Option<String>,
+    /// Protocol the ECU was connected with. None if it could not be connected to
+    pub protocol: Option<DiagProtocol>,
+    /// Version information, if the ECU provides it
+    pub identity: Option<EcuVersions>,
+    pub dtcs: Vec<DTC>,
+    /// Why the ECU could not be connected to, or its DTCs could not be read
+    pub error: Option<String>,
+}
+
+/// DTCs of every ECU in the vehicle. See [vehicle_dtc_scan], and
+/// [VehicleReport::to_json] for exporting it
+#[derive(Debug, Clone, Default)]
+pub struct VehicleReport {
+    /// VIN, as reported by the first ECU that provides it
+    pub vin: Option<String>,
+    pub ecus: Vec<EcuReport>,
+}
+
+impl VehicleReport {
+    /// Number of DTCs stored across all ECUs
+    pub fn total_dtcs(&self) -> usize {
+        self.ecus.iter().map(|e| e.dtcs.len()).sum()
+    }
+
+    /// ECUs which could not be connected to, or whose DTCs could not be read
+    pub fn failed(&self) -> Vec<&EcuReport> {
+        self.ecus.iter().filter(|e| e.error.is_some()).collect()
+    }
+}
+
+/// Starts a diag server for `diag_cfg` over ISO-TP. The protocol is chosen by the ECU's
+/// [ProtocolVariant]. If it is unknown, UDS is tried first, then KWP2000
+fn connect_for_scan(
+    comm_server: &Box<dyn ComServer>,
+    interface_cfg: &InterfaceConfig,
+    diag_cfg: &DiagCfg,
+) -> ProtocolResult<DiagServer> {
+    let protocol = match diag_cfg.variant {
+        ProtocolVariant::Unknown => {
+            return DiagServer::connect_auto(
+                comm_server,
+                InterfaceType::IsoTp,
+                interface_cfg.clone(),
+                None,
+                *diag_cfg,
+            )
+        }
+        ProtocolVariant::Iso14230 => DiagProtocol::KWP2000,
+        _ => DiagProtocol::UDS,
+    };
+    DiagServer::new(
+        protocol,
+        comm_server,
+        InterfaceType::IsoTp,
+        interface_cfg.clone(),
+        None,
+        *diag_cfg,
+    )
+}
+
+/// Connects to each ECU in `known_ecus` in turn, reading its identity and DTCs.
+///
+/// ECUs which cannot be connected to, or whose DTCs cannot be read, are still
+/// in the report, with their error set, so one faulty ECU does not stop the scan.
+/// The identity is best effort, and is None if the ECU rejects the requests for it
+pub fn vehicle_dtc_scan(
+    comm_server: &Box<dyn ComServer>,
+    interface_cfg: &InterfaceConfig,
+    known_ecus: &[DiagCfg],
+) -> VehicleReport {
+    let mut vin = None;
+    let ecus = known_ecus
+        .iter()
+        .map(|diag_cfg| {
+            let mut report = EcuReport {
+                send_id: diag_cfg.send_id,
+                recv_id: diag_cfg.recv_id,
+                name: None,
+                protocol: None,
+                identity: None,
+                dtcs: Vec::new(),
+                error: None,
+            };
+            let mut server = match connect_for_scan(comm_server, interface_cfg, diag_cfg) {
+                Ok(s) => s,
+                Err(e) => {
+                    report.error = Some(e.get_text());
+                    return report;
+                }
+            };
+            report.protocol = Some(server.connection_info().protocol);
+            report.identity = server.versions().ok();
+            if vin.is_none() {
+                vin = server.read_vin().ok();
+            }
+            match server.read_errors() {
+                Ok(dtcs) => report.dtcs = dtcs,
+                Err(e) => report.error = Some(e.get_text()),
+            }
+            server.kill_diag_server();
+            println!(
+                "Scanned ECU 0x{:04X} - {} DTC(s)",
+                diag_cfg.send_id,
+                report.dtcs.len()
+            );
+            report
+        })
+        .collect();
+    VehicleReport { vin, ecus }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DiagProtocol {
+    KWP2000,
+    UDS,
+    /// Generic OBD-II (SAE J1979) services, over ISO-TP
+    OBD2,
+}
+
+/// Revision of the standard an ECU's diagnostic server implements. Services which were added
+/// in a later revision than the ECU's are refused without being sent. If the revision is
+/// unknown, every service is assumed to be supported
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProtocolVariant {
+    Unknown,
+    /// KWP2000 (ISO 14230-3)
+    Iso14230,
+    /// UDS (ISO 14229-1:2006)
+    Iso14229_2006,
+    /// UDS (ISO 14229-1:2013), which added RequestFileTransfer
+    Iso14229_2013,
+    /// UDS (ISO 14229-1:2020), which added Authentication
+    Iso14229_2020,
+}
+
+impl Default for ProtocolVariant {
+    fn default() -> Self {
+        Self::Unknown
+    }
+}
+
+impl ProtocolVariant {
+    /// Returns the UDS revision a service was added in, for services added after 2006
+    fn uds_service_revision(sid: u8) -> Option<Self> {
+        match sid {
+            0x29 => Some(Self::Iso14229_2020), // Authentication
+            0x38 => Some(Self::Iso14229_2013), // RequestFileTransfer
+            _ => None,
+        }
+    }
+
+    /// Returns false if the service `sid` cannot be supported by an ECU implementing this revision
+    pub fn supports_service(&self, sid: u8) -> bool {
+        match self {
+            Self::Unknown | Self::Iso14230 => true,
+            uds => Self::uds_service_revision(sid).map_or(true, |added| *uds >= added),
+        }
+    }
+}
+
+/// Snapshot of a negotiated diagnostic session, which can be used to resume the
+/// session in another [DiagServer] without re-negotiating with the ECU
+#[derive(Debug, Copy, Clone)]
+pub struct DiagSessionState {
+    pub protocol: DiagProtocol,
+    /// Raw diagnostic session type byte
+    pub session_type: u8,
+    /// Security access level that has been unlocked, if any
+    pub security_level: Option<u8>,
+    /// P2 server max timing in ms that the ECU reported when entering the session (UDS only)
+    pub p2_max_ms: Option<u16>,
+    /// P2* (Extended, after response pending) server max timing in ms (UDS only)
+    pub p2_ext_max_ms: Option<u32>,
+}
+
+/// Parameters that were negotiated when the diagnostic session was started
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub protocol: DiagProtocol,
+    pub interface_type: InterfaceType,
+    /// Baud rate of the interface, if one was configured
+    pub baud_rate: Option<u32>,
+    pub send_id: u32,
+    pub recv_id: u32,
+    /// Name of the diagnostic session the ECU was put in
+    pub session_name: String,
+    /// Raw diagnostic session type byte
+    pub session_type: u8,
+    /// P2 server max timing in ms reported by the ECU (UDS only)
+    pub p2_max_ms: Option<u16>,
+    /// P2* server max timing in ms reported by the ECU (UDS only)
+    pub p2_ext_max_ms: Option<u32>,
+    /// Security access level that was unlocked when connecting, if any
+    pub security_level: Option<u8>,
+    /// True if the session was resumed from a [DiagSessionState] rather than negotiated
+    pub resumed: bool,
+    /// Largest request that fits in a single ISO-TP frame, given the addressing mode and
+    /// CAN frame size (None for K-Line interfaces). Longer requests are segmented
+    pub single_frame_capacity: Option<usize>,
+    /// Largest request the transport can carry. See [DiagServer::max_request_len]
+    pub max_request_len: usize,
+    /// ISO-TP separation time (ST_min byte) configured for the interface. See
+    /// [InterfaceConfig::fc_st_min]
+    pub st_min: u8,
+    /// Revision of the protocol the ECU was configured as implementing
+    pub variant: ProtocolVariant,
+}
+
+impl ConnectionInfo {
+    pub(crate) fn new(
+        protocol: DiagProtocol,
+        interface_type: InterfaceType,
+        cfg: &InterfaceConfig,
+        diag_cfg: &DiagCfg,
+    ) -> Self {
+        Self {
+            protocol,
+            interface_type,
+            baud_rate: cfg.get_param(IFACE_CFG::BAUDRATE).ok(),
+            send_id: diag_cfg.send_id,
+            recv_id: diag_cfg.recv_id,
+            session_name: String::new(),
+            session_type: 0,
+            p2_max_ms: None,
+            p2_ext_max_ms: None,
+            security_level: None,
+            resumed: false,
+            single_frame_capacity: match interface_type {
+                InterfaceType::Can | InterfaceType::IsoTp => {
+                    Some(FrameLayout::from_cfg(cfg).single_frame_capacity())
+                }
+                _ => None,
+            },
+            st_min: cfg.fc_st_min(),
+            max_request_len: match interface_type {
+                // One CAN frame
+                InterfaceType::Can => isotp::CLASSIC_CAN_DLC,
+                // Only software ISO-TP is known to send the First Frame escape sequence
+                InterfaceType::IsoTp
+                    if cfg.get_param_or_default(IFACE_CFG::SOFTWARE_ISOTP, 0) > 0 =>
+                {
+                    isotp::MAX_ESCAPED_MESSAGE_LEN
+                }
+                InterfaceType::IsoTp => isotp::MAX_MESSAGE_LEN,
+                // 1 byte length in the header
+                InterfaceType::Iso14230 => 0xFF,
+                // ISO9141-2 messages carry at most 7 data bytes
+                InterfaceType::Iso9141 => 7,
+            },
+            variant: diag_cfg.variant,
+        }
+    }
+
+    /// Fills in the session parameters, once the session has been established
+    pub(crate) fn set_session(&mut self, state: &DiagSessionState, name: String, resumed: bool) {
+        self.session_name = name;
+        self.session_type = state.session_type;
+        self.p2_max_ms = state.p2_max_ms;
+        self.p2_ext_max_ms = state.p2_ext_max_ms;
+        self.security_level = state.security_level;
+        self.resumed = resumed;
+    }
+}
+
+impl Display for ConnectionInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Connected: {:?}, {} session",
+            self.protocol, self.session_name
+        )?;
+        if let Some(p2) = self.p2_max_ms {
+            write!(f, ", P2={}ms", p2)?;
+        }
+        if let Some(p2_ext) = self.p2_ext_max_ms {
+            write!(f, ", P2*={}ms", p2_ext)?;
+        }
+        if let Some(level) = self.security_level {
+            write!(f, ", security level {} unlocked", level)?;
+        }
+        Ok(())
+    }
+}
+
+/// Delay before the first retry of a [RetryingDiagServer] command. Each further retry
+/// waits twice as long as the last
+const RETRY_BASE_DELAY_MS: u64 = 50;
+
+/// Longest delay between two retries of a [RetryingDiagServer] command
+const RETRY_MAX_DELAY_MS: u64 = 1000;
+
+/// Runs `f` until it succeeds, fails with an error that is not transient (See
+/// [ProtocolError::is_transient]), or has been retried `retries` times, backing off
+/// exponentially between attempts
+fn retry_transient<T>(
+    retries: u32,
+    clock: &dyn Clock,
+    mut f: impl FnMut() -> ProtocolResult<T>,
+) -> ProtocolResult<T> {
+    let mut delay_ms = RETRY_BASE_DELAY_MS;
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Err(e) if e.is_transient() && attempt < retries => {
+                attempt += 1;
+                eprintln!(
+                    "DIAG - {}. Retrying in {}ms ({}/{})",
+                    e.get_text(),
+                    delay_ms,
+                    attempt,
+                    retries
+                );
+                clock.sleep_ms(delay_ms);
+                delay_ms = (delay_ms * 2).min(RETRY_MAX_DELAY_MS);
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Sends `requests` in order with `send`, waiting at least `gap` between one request and the
+/// next. Stops at the first request which fails. See [DiagServer::pipeline]
+fn run_pipeline<F>(
+    requests: &[(u8, Vec<u8>)],
+    gap: Duration,
+    clock: &dyn Clock,
+    mut send: F,
+) -> ProtocolResult<()>
+where
+    F: FnMut(u8, &[u8]) -> ProtocolResult<()>,
+{
+    // The clock counts in milliseconds, so sub-millisecond gaps are rounded up
+    let gap_ms = (gap.as_micros() as u64 + 999) / 1000;
+    for (i, (cmd, args)) in requests.iter().enumerate() {
+        if i > 0 && gap_ms > 0 {
+            clock.sleep_ms(gap_ms);
+        }
+        send(*cmd, args)?;
+    }
+    Ok(())
+}
+
+/// Runs commands on a [DiagServer], retrying those which fail with a transient error.
+/// Created with [DiagServer::with_retries]
+#[derive(Debug)]
+pub struct RetryingDiagServer<'a> {
+    server: &'a DiagServer,
+    retries: u32,
+    clock: Arc<dyn Clock>,
+}
+
+impl<'a> RetryingDiagServer<'a> {
+    /// Runs a command, resending it up to the configured number of times if it times out,
+    /// is not acknowledged ([ProtocolError::NoAck]) or the adapter fails to send it
+    pub fn run_cmd(&self, cmd: u8, args: &[u8]) -> ProtocolResult<Vec<u8>> {
+        retry_transient(self.retries, self.clock.as_ref(), || match self.server {
+            DiagServer::KWP2000(s) => s.run_command(cmd, args),
+            DiagServer::UDS(s) => s.run_command(cmd, args),
+            DiagServer::OBD2(s) => s.run_command(cmd, args),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum DiagServer {
+    KWP2000(KWP2000ECU),
+    UDS(UDSECU),
+    OBD2(ObdServer),
+}
+
+impl DiagServer {
+    pub fn new(
+        protocol: DiagProtocol,
+        comm_server: &Box<dyn ComServer>,
+        interface_type: InterfaceType,
+        interface_cfg: InterfaceConfig,
+        tx_flags: Option<Vec<PayloadFlag>>,
+        diag_cfg: DiagCfg,
+    ) -> ProtocolResult<Self> {
+        Ok(match protocol {
+            DiagProtocol::KWP2000 => Self::KWP2000(KWP2000ECU::start_diag_session(
+                comm_server,
+                interface_type,
+                interface_cfg,
+                tx_flags,
+                diag_cfg,
+            )?),
+            DiagProtocol::UDS => Self::UDS(UDSECU::start_diag_session(
+                comm_server,
+                interface_type,
+                interface_cfg,
+                tx_flags,
+                diag_cfg,
+            )?),
+            DiagProtocol::OBD2 => Self::OBD2(ObdServer::start_diag_session(
+                comm_server,
+                interface_type,
+                interface_cfg,
+                tx_flags,
+                diag_cfg,
+            )?),
+        })
+    }
+
+    /// Starts a diag server without knowing which protocol the ECU speaks. UDS is tried
+    /// first (Entering the extended session), and if the ECU does not answer, or rejects the
+    /// session request as not supported, KWP2000 is tried instead. Any other error is returned
+    /// without trying KWP2000, as it would fail the same way.
+    ///
+    /// UDS is skipped if `interface_type` is not ISO-TP. Use [DiagServer::get_name]
+    /// to find which protocol was selected
+    pub fn connect_auto(
+        comm_server: &Box<dyn ComServer>,
+        interface_type: InterfaceType,
+        interface_cfg: InterfaceConfig,
+        tx_flags: Option<Vec<PayloadFlag>>,
+        diag_cfg: DiagCfg,
+    ) -> ProtocolResult<Self> {
+        if interface_type == InterfaceType::IsoTp {
+            match Self::new(
+                DiagProtocol::UDS,
+                comm_server,
+                interface_type,
+                interface_cfg.clone(),
+                tx_flags.clone(),
+                diag_cfg,
+            ) {
+                // serviceNotSupported, or subFunctionNotSupported (KWP2000 ECUs
+                // have no extended session 0x03)
+                Err(e) if e.is_timeout() || matches!(e.get_nrc(), Some(0x11) | Some(0x12)) => {
+                    println!(
+                        "DIAG - ECU does not speak UDS ({}), trying KWP2000",
+                        e.get_text()
+                    )
+                }
+                res => return res,
+            }
+        }
+        Self::new(
+            DiagProtocol::KWP2000,
+            comm_server,
+            interface_type,
+            interface_cfg,
+            tx_flags,
+            diag_cfg,
+        )
+    }
+
+    /// Starts a diag server that assumes the ECU is already in the session described by
+    /// `state` (Saved from another diag server with [DiagServer::get_state]),
+    /// so the session and security access are not negotiated again
+    pub fn from_state(
+        state: DiagSessionState,
+        comm_server: &Box<dyn ComServer>,
+        interface_type: InterfaceType,
+        interface_cfg: InterfaceConfig,
+        tx_flags: Option<Vec<PayloadFlag>>,
+        diag_cfg: DiagCfg,
+    ) -> ProtocolResult<Self> {
+        Ok(match state.protocol {
+            DiagProtocol::KWP2000 => Self::KWP2000(KWP2000ECU::resume_diag_session(
+                comm_server,
+                interface_type,
+                interface_cfg,
+                tx_flags,
+                diag_cfg,
+                &state,
+            )?),
+            DiagProtocol::UDS => Self::UDS(UDSECU::resume_diag_session(
+                comm_server,
+                interface_type,
+                interface_cfg,
+                tx_flags,
+                diag_cfg,
+                &state,
+            )?),
+            // No session to resume
+            DiagProtocol::OBD2 => Self::OBD2(ObdServer::start_diag_session(
+                comm_server,
+                interface_type,
+                interface_cfg,
+                tx_flags,
+                diag_cfg,
+            )?),
+        })
+    }
+
+    /// Returns a snapshot of the current session state, which can be used
+    /// with [DiagServer::from_state]
+    pub fn get_state(&self) -> DiagSessionState {
+        match self {
+            Self::KWP2000(s) => s.get_state(),
+            Self::UDS(s) => s.get_state(),
+            Self::OBD2(s) => s.get_state(),
+        }
+    }
+
+    /// Recovers the bus (For example after [ProtocolError::BusOff]), then
+    /// re-establishes the diagnostic session with the ECU
+    pub fn reconnect(&mut self) -> ProtocolResult<()> {
+        match self {
+            Self::KWP2000(s) => s.reconnect(),
+            Self::UDS(s) => s.reconnect(),
+            Self::OBD2(_) => Err(obd2_unsupported("reconnect")),
+        }
+    }
+
+    /// Returns the parameters negotiated when the diagnostic session was started
+    pub fn connection_info(&self) -> ConnectionInfo {
+        match self {
+            Self::KWP2000(s) => s.connection_info(),
+            Self::UDS(s) => s.connection_info(),
+            Self::OBD2(s) => s.connection_info(),
+        }
+    }
+
+    /// Largest request (Service ID and parameters) that can be sent without failing
+    /// mid-transfer, so a large write can be split up front (Or refused) instead.
+    ///
+    /// This is the limit of the transport: 4095 bytes over ISO-TP (Without the First Frame
+    /// escape sequence, which only software ISO-TP sends), and a single frame on K-Line.
+    /// Block lengths the ECU advertises for a transfer (RequestDownload's
+    /// maxNumberOfBlockLength) only apply to TransferData, and are returned by the request itself
+    pub fn max_request_len(&self) -> usize {
+        self.connection_info().max_request_len
+    }
+
+    pub fn get_name<'a>(&self) -> &'a str {
+        match self {
+            Self::KWP2000(_) => "KWP2000",
+            Self::UDS(_) => "UDS",
+            Self::OBD2(_) => "OBD2",
+        }
+    }
+
+    /// Clock the diag server uses for its timeouts and delays
+    pub(crate) fn clock(&self) -> Arc<dyn Clock> {
+        match self {
+            Self::KWP2000(s) => s.clock(),
+            Self::UDS(s) => s.clock(),
+            Self::OBD2(s) => s.clock(),
+        }
+    }
+
+    /// Stops the diag server. This only signals the diag server's thread to stop,
+    /// which then closes the interface itself, so this never blocks on the interface
+    pub fn kill_diag_server(&mut self) {
+        match self {
+            Self::KWP2000(s) => s.exit_diag_session(),
+            Self::UDS(s) => s.exit_diag_session(),
+            Self::OBD2(s) => s.exit_diag_session(),
+        }
+    }
+
+    /// Stops the diag server and closes the interface, freeing the adapter. Unlike dropping
+    /// the diag server, this waits for the interface to be closed, and returns the error
+    /// if closing it failed
+    pub fn close(mut self) -> ProtocolResult<()> {
+        match &mut self {
+            Self::KWP2000(s) => s.close(),
+            Self::UDS(s) => s.close(),
+            Self::OBD2(s) => s.close(),
+        }
+    }
+
+    pub fn run_cmd(&mut self, cmd: u8, args: &[u8]) -> ProtocolResult<Vec<u8>> {
+        match self {
+            Self::KWP2000(s) => s.run_command(cmd, args),
+            Self::UDS(s) => s.run_command(cmd, args),
+            Self::OBD2(s) => s.run_command(cmd, args),
+        }
+    }
+
+    /// Returns true if the ECU is expected to send a response to the request.
+    /// This is false when the request has the suppress positive response bit set
+    fn response_required(&self, cmd: u8, args: &[u8]) -> bool {
+        match self {
+            // Tester present with 0x02 - No response from ECU
+            Self::KWP2000(_) => !(cmd == 0x3E && args.first() == Some(&0x02)),
+            Self::UDS(_) => match cmd {
+                // Services with a sub function byte, where bit 7 is suppressPosRspMsgIndicationBit.
+                // Not ReadDTCInformation (0x19), whose report type uses all 8 bits
+                0x10 | 0x11 | 0x27 | 0x28 | 0x29 | 0x31 | 0x3E | 0x83 | 0x85 | 0x86 | 0x87 => {
+                    args.first().map(|b| b & 0x80 == 0).unwrap_or(true)
+                }
+                _ => true,
+            },
+            // OBD-II has no way to suppress the response
+            Self::OBD2(_) => true,
+        }
+    }
+
+    /// Sends service `sid` once with each sub function in `range`, returning the ECU's
+    /// response to each. Sub functions the ECU does not know are returned as errors with
+    /// NRC 0x12 (subFunctionNotSupported). See [is_sub_function_not_supported].
+    ///
+    /// Services with [CautionLevel::Alert] are never sent, and every sub function is
+    /// returned as an error instead. Suppressed positive responses (Bit 7 of the sub
+    /// function) are returned as an empty response, unless the ECU rejects the request
+    pub fn try_subfunctions(
+        &mut self,
+        sid: u8,
+        range: std::ops::RangeInclusive<u8>,
+    ) -> Vec<(u8, ProtocolResult<Vec<u8>>)> {
+        let caution = self
+            .available_commands()
+            .into_iter()
+            .find(|c| c.sid == sid)
+            .map(|c| c.caution);
+        range
+            .map(|sub| {
+                let res = if caution == Some(CautionLevel::Alert) {
+                    Err(ProtocolError::CustomError(format!(
+                        "Service 0x{:02X} is too dangerous to probe",
+                        sid
+                    )))
+                } else if self.response_required(sid, &[sub]) {
+                    self.run_cmd(sid, &[sub])
+                } else {
+                    self.run_cmd_no_wait(sid, &[sub], Some(SUB_FUNCTION_NRC_WINDOW_MS))
+                        .map(|_| Vec::new())
+                };
+                (sub, res)
+            })
+            .collect()
+    }
+
+    /// Runs a list of requests back to back.
+    ///
+    /// Requests are always sent in the order they are given. Requests which suppress the
+    /// positive response are sent without waiting, so they go out back to back,
+    /// whilst requests that expect a response block until it is received before
+    /// the next request is sent. Each request is sent at least the interface's ST_min
+    /// ([ConnectionInfo::st_min]) after the previous one. Frame separation within each
+    /// request is handled by the ISO-TP layer as normal.
+    ///
+    /// The first error aborts the pipeline, and remaining requests are not sent
+    pub fn pipeline(&mut self, requests: &[(u8, Vec<u8>)]) -> ProtocolResult<()> {
+        let gap = isotp::st_min_duration(self.connection_info().st_min);
+        let clock = self.clock();
+        run_pipeline(requests, gap, clock.as_ref(), |cmd, args| {
+            if self.response_required(cmd, args) {
+                self.run_cmd(cmd, args).map(|_| ())
+            } else {
+                self.run_cmd_no_wait(cmd, args, None)
+            }
+        })
+    }
+
+    /// Sends a command to the ECU, without waiting for a response from it.
+    ///
+    /// If `nrc_window_ms` is set, frames received within that many milliseconds of sending are
+    /// checked, and a negative response to the command is returned as an error. This catches
+    /// commands the ECU rejected, which would otherwise fail silently
+    pub fn run_cmd_no_wait(
+        &mut self,
+        cmd: u8,
+        args: &[u8],
+        nrc_window_ms: Option<u32>,
+    ) -> ProtocolResult<()> {
+        match self {
+            Self::KWP2000(s) => s.send_command(cmd, args, nrc_window_ms),
+            Self::UDS(s) => s.send_command(cmd, args, nrc_window_ms),
+            Self::OBD2(_) => Err(obd2_unsupported("run_cmd_no_wait")),
+        }
+    }
+
+    pub fn into_kwp(&mut self) -> Option<&mut KWP2000ECU> {
+        match self {
+            Self::KWP2000(s) => Some(s),
+            Self::UDS(_) | Self::OBD2(_) => None,
+        }
+    }
+
+    /// See [KWP2000ECU::set_strict_response_matching]
+    pub fn set_strict_response_matching(&self, strict: bool) {
+        match self {
+            Self::KWP2000(s) => s.set_strict_response_matching(strict),
+            Self::UDS(s) => s.set_strict_response_matching(strict),
+            Self::OBD2(_) => {} // OBD-II responses are always matched by service ID
+        }
+    }
+
+    /// See [KWP2000ECU::set_handle_response_pending]
+    pub fn set_handle_response_pending(&self, handle: bool) {
+        match self {
+            Self::KWP2000(s) => s.set_handle_response_pending(handle),
+            Self::UDS(s) => s.set_handle_response_pending(handle),
+            Self::OBD2(_) => {} // OBD-II always waits for the final response
+        }
+    }
+
+    /// Runs a command, ignoring unrelated frames received before the ECU's response
+    /// (For example, other diagnostic traffic on the bus), for up to `deadline_ms`
+    pub fn run_cmd_await_sid(
+        &mut self,
+        cmd: u8,
+        args: &[u8],
+        deadline_ms: u32,
+    ) -> ProtocolResult<Vec<u8>> {
+        match self {
+            Self::KWP2000(s) => s.run_command_await_sid(cmd, args, deadline_ms),
+            Self::UDS(s) => s.run_command_await_sid(cmd, args, deadline_ms),
+            Self::OBD2(_) => Err(obd2_unsupported("run_cmd_await_sid")),
+        }
+    }
+
+    /// Functionally broadcasts an ECUReset of type `kind` on the diag server's global ID,
+    /// returning the IDs of the ECUs which acknowledged it within `collect_ms`.
+    /// See [ecu_reset_functional]
+    pub fn ecu_reset_functional(&self, kind: u8, collect_ms: u32) -> ProtocolResult<Vec<u32>> {
+        match self {
+            Self::KWP2000(s) => s.ecu_reset_functional(kind, collect_ms),
+            Self::UDS(s) => s.ecu_reset_functional(kind, collect_ms),
+            Self::OBD2(_) => Err(obd2_unsupported("ecu_reset_functional")),
+        }
+    }
+
+    /// Returns a wrapper which retries commands up to `retries` times if they fail with
+    /// a transient error. See [RetryingDiagServer]. Retries back off on the diag server's clock
+    pub fn with_retries(&self, retries: u32) -> RetryingDiagServer<'_> {
+        RetryingDiagServer {
+            server: self,
+            retries,
+            clock: self.clock(),
+        }
+    }
+
+    /// Runs a command, checking the length of the ECU's positive response.
+    /// See [UDSECU::run_command_expect_len]
+    pub fn run_cmd_expect_len(
+        &mut self,
+        cmd: u8,
+        args: &[u8],
+        expected_len: Option<usize>,
+    ) -> ProtocolResult<Vec<u8>> {
+        match self {
+            Self::KWP2000(s) => s.run_command_expect_len(cmd, args, expected_len),
+            Self::UDS(s) => s.run_command_expect_len(cmd, args, expected_len),
+            Self::OBD2(s) => s.run_command_expect_len(cmd, args, expected_len),
+        }
+    }
+
+    /// Returns all the commands supported by the diag server's protocol
+    pub fn available_commands(&self) -> Vec<CommandInfo> {
+        let variant = self.connection_info().variant;
+        let cmds = match self {
+            Self::KWP2000(_) => CommandInfo::list_for::<kwp2000::Service>(),
+            Self::UDS(_) => CommandInfo::list_for::<uds::UDSCommand>(),
+            Self::OBD2(_) => CommandInfo::list_for::<obd2::OBDCmd>(),
+        };
+        cmds.into_iter()
+            .filter(|c| variant.supports_service(c.sid))
+            .collect()
+    }
+
+    /// Returns the traffic counters of the diag server
+    pub fn stats(&self) -> Stats {
+        match self {
+            Self::KWP2000(s) => s.get_stats(),
+            Self::UDS(s) => s.get_stats(),
+            Self::OBD2(s) => s.get_stats(),
+        }
+    }
+
+    /// Returns the round trip time of the last request the ECU answered. See [Stats::last_latency]
+    pub fn last_latency(&self) -> Option<Duration> {
+        self.stats().last_latency
+    }
+
+    pub fn reset_stats(&self) {
+        match self {
+            Self::KWP2000(s) => s.reset_stats(),
+            Self::UDS(s) => s.reset_stats(),
+            Self::OBD2(s) => s.reset_stats(),
+        }
+    }
+
+    pub fn read_errors(&self) -> ProtocolResult<Vec<DTC>> {
+        match self {
+            Self::KWP2000(s) => s.read_errors(),
+            Self::UDS(s) => s.read_errors(),
+            Self::OBD2(s) => s.read_errors(),
+        }
+    }
+
+    /// Clears the DTCs on the ECU.
+    ///
+    /// If the engine guard is enabled (See [DiagServer::set_clear_engine_guard]), this is
+    /// refused whilst the engine is running, as clearing DTCs mid drive can hide active
+    /// faults. Use [DiagServer::force_clear_errors] to clear them regardless
+    pub fn clear_errors(&self) -> ProtocolResult<()> {
+        self.check_engine_off()?;
+        self.force_clear_errors()
+    }
+
+    /// Clears the DTCs on the ECU, without checking if the engine is running
+    pub fn force_clear_errors(&self) -> ProtocolResult<()> {
+        match self {
+            Self::KWP2000(s) => s.clear_errors(),
+            Self::UDS(s) => s.clear_errors(),
+            Self::OBD2(s) => s.clear_errors(),
+        }
+    }
+
+    /// Sets if clearing DTCs should be refused whilst the engine is running (Off by default)
+    pub fn set_clear_engine_guard(&self, enabled: bool) {
+        match self {
+            Self::KWP2000(s) => s.set_clear_engine_guard(enabled),
+            Self::UDS(s) => s.set_clear_engine_guard(enabled),
+            Self::OBD2(s) => s.set_clear_engine_guard(enabled),
+        }
+    }
+
+    /// Reads the engine speed in RPM from the ECU (UDS DID 0xF40C, the OBD-II engine
+    /// speed PID). Returns None if the ECU does not report the engine speed
+    pub fn read_engine_rpm(&self) -> ProtocolResult<Option<u32>> {
+        match self {
+            // KWP2000 has no standard identifier for the engine speed
+            Self::KWP2000(_) => Ok(None),
+            Self::UDS(s) => match read_data::read_did(s, ENGINE_RPM_DID) {
+                Ok(r) if r.len() >= 2 => Ok(Some(((r[0] as u32) << 8 | r[1] as u32) / 4)),
+                // Length of the DID's data, after the service ID and DID
+                Ok(r) => Err(ProtocolError::InvalidResponseSize {
+                    expect: 2,
+                    actual: r.len(),
+                }),
+                Err(e) if e.get_nrc().is_some() => Ok(None),
+                Err(e) => Err(e),
+            },
+            Self::OBD2(s) => s.read_engine_rpm(),
+        }
+    }
+
+    /// Returns an error if the engine guard is enabled, and the engine is running.
+    /// If the engine speed cannot be read, a warning is logged and the check passes
+    fn check_engine_off(&self) -> ProtocolResult<()> {
+        let enabled = match self {
+            Self::KWP2000(s) => s.is_clear_engine_guard_enabled(),
+            Self::UDS(s) => s.is_clear_engine_guard_enabled(),
+            Self::OBD2(s) => s.is_clear_engine_guard_enabled(),
+        };
+        if !enabled {
+            return Ok(());
+        }
+        match self.read_engine_rpm()? {
+            Some(rpm) if rpm >= ENGINE_RUNNING_RPM => Err(ProtocolError::CustomError(format!(
+                "Engine is running ({} RPM). Stop the engine before clearing DTCs, or force the clear",
+                rpm
+            ))),
+            Some(_) => Ok(()),
+            None => {
+                eprintln!("DIAG - Warning. Engine speed is unknown, clearing DTCs anyway");
+                Ok(())
+            }
+        }
+    }
+
+    /// Clears the DTCs on the ECU, then re-reads them to check what was actually cleared.
+    /// If `force` is set, the engine guard is bypassed. See [DiagServer::clear_errors]
+    pub fn clear_and_verify(&self, force: bool) -> ProtocolResult<ClearResult> {
+        if !force {
+            self.check_engine_off()?;
+        }
+        let before = self.read_errors()?;
+        self.force_clear_errors()?;
+        self.clock().sleep_ms(500); // Give the ECU time to clear its memory
+        let persisted = self.read_errors()?;
+        let cleared = before
+            .into_iter()
+            .filter(|dtc| !persisted.iter().any(|x| x.id == dtc.id))
+            .collect();
+        Ok(ClearResult { cleared, persisted })
+    }
+
+    pub fn get_variant_id(&self) -> ProtocolResult<u32> {
+        match self {
+            Self::KWP2000(s) => {
+                read_ecu_identification::read_dcx_mmc_id(&s).map(|x| x.diag_information as u32)
+            }
+            Self::UDS(s) => read_data::read_variant_id(s),
+            Self::OBD2(_) => Err(obd2_unsupported("get_variant_id")),
+        }
+    }
+
+    /// Reads the VIN stored in the ECU
+    pub fn read_vin(&self) -> ProtocolResult<String> {
+        let raw = match self {
+            Self::KWP2000(s) => {
+                // Current VIN, falling back to the original VIN the ECU was programmed with
+                let cmd = kwp2000::Service::ReadECUID.into();
+                let mut res = s
+                    .run_command(cmd, &[0x90])
+                    .or_else(|_| s.run_command(cmd, &[0x88]))?;
+                res.drain(0..std::cmp::min(2, res.len()));
+                res
+            }
+            Self::UDS(s) => read_data::read_did(s, 0xF190)?,
+            Self::OBD2(s) => {
+                // Service 09 PID 02. Drop the SID, PID and number of data items
+                let mut res = s.run_command(0x09, &[0x02])?;
+                res.drain(0..std::cmp::min(3, res.len()));
+                res
+            }
+        };
+        vin::normalize_vin(&raw)
+    }
+
+    /// Reads the DTCs of the ECU every `interval_ms` until `token` is cancelled, calling `cb`
+    /// with each change to the fault memory since the previous read.
+    ///
+    /// The first read is the baseline, so DTCs that are already stored are not reported.
+    /// Returns the error if the DTCs cannot be read
+    pub fn watch_dtcs<F: FnMut(DtcChange)>(
+        &self,
+        interval_ms: u32,
+        token: &CancelToken,
+        mut cb: F,
+    ) -> ProtocolResult<()> {
+        let mut last = self.read_errors()?;
+        while !token.is_cancelled() {
+            self.clock().sleep_ms(interval_ms as u64);
+            let curr = self.read_errors()?;
+            diff_dtcs(&last, &curr).into_iter().for_each(&mut cb);
+            last = curr;
+        }
+        Ok(())
+    }
+
+    /// Reads the hardware, software and bootloader versions of the ECU.
+    ///
+    /// UDS ECUs are read from the vehicle manufacturer versions (0xF191 / 0xF189),
+    /// falling back to the supplier versions (0xF193 / 0xF195). KWP2000 ECUs are read
+    /// from their identification records. Versions the ECU does not provide are None.
+    /// The serial number and manufacturing date (0xF18C / 0xF18B) are only read from UDS ECUs
+    pub fn versions(&self) -> ProtocolResult<EcuVersions> {
+        match self {
+            Self::KWP2000(s) => {
+                let id = s.identification()?;
+                Ok(EcuVersions {
+                    hardware: id.hardware_number.or(id.supplier_hardware_number),
+                    software: id.software_number,
+                    bootloader: id.boot_software,
+                    serial_number: None,
+                    manufacturing_date: None,
+                })
+            }
+            Self::UDS(s) => {
+                let read_first = |dids: &[u16]| -> ProtocolResult<Option<String>> {
+                    for did in dids {
+                        if let Some(v) = identification::read_identification_did(s, *did)? {
+                            return Ok(Some(v));
+                        }
+                    }
+                    Ok(None)
+                };
+                Ok(EcuVersions {
+                    hardware: read_first(&[0xF191, 0xF193])?,
+                    software: read_first(&[0xF189, 0xF195])?,
+                    bootloader: read_first(&[0xF180])?,
+                    serial_number: read_first(&[identification::SERIAL_NUMBER_DID])?,
+                    manufacturing_date: read_first(&[identification::MANUFACTURING_DATE_DID])?,
+                })
+            }
+            Self::OBD2(_) => Err(obd2_unsupported("versions")),
+        }
+    }
+
+    pub fn get_dtc_env_data(&self, dtc: &DTC) -> ProtocolResult<Vec<u8>> {
+        match self {
+            Self::KWP2000(s) => kwp2000::read_status_dtc::read_status_dtc(s, dtc),
+            Self::UDS(s) => s.read_dtc_snapshot(dtc),
+            // Freeze frames (Service 02) are read by PID, not by DTC
+            Self::OBD2(_) => Err(obd2_unsupported("get_dtc_env_data")),
+        }
+    }
+
+    /// Reads all the DTCs from the ECU, along with the environment data for each DTC.
+    ///
+    /// If the environment data cannot be read for a DTC, then it is returned
+    /// with empty environment data rather than failing the entire read
+    pub fn read_errors_with_env(&self) -> ProtocolResult<Vec<(DTC, Vec<u8>)>> {
+        Ok(self
+            .read_errors()?
+            .into_iter()
+            .map(|dtc| {
+                let env = self.get_dtc_env_data(&dtc).unwrap_or_else(|e| {
+                    eprintln!(
+                        "Could not read env data for {} - {}",
+                        dtc.error,
+                        e.get_text()
+                    );
+                    Vec::new()
+                });
+                (dtc, env)
+            })
+            .collect())
+    }
+
+    /// Reads the fault memory of the ECU as a plain text report, resolving DTC
+    /// descriptions from `db` if given. See [report::fault_report]
+    pub fn fault_report(&self, db: Option<&report::DtcDatabase>) -> ProtocolResult<String> {
+        report::fault_report(self, db)
+    }
+}
+
+/// Error for [DiagServer] functions which have no OBD-II equivalent
+fn obd2_unsupported(func: &str) -> ProtocolError {
+    ProtocolError::CustomError(format!("{} is not supported over OBD-II", func))
+}
+
+impl Drop for DiagServer {
+    fn drop(&mut self) {
+        log::debug!("Drop for Diag Server called!");
+        // Only signals the diag server thread to stop, which closes the interface itself,
+        // so dropping never sends anything or waits on the interface
+        self.kill_diag_server();
+    }
+}
+
+/// Returns true if `data` is a positive or negative response to the service `cmd`
+fn is_response_to(cmd: u8, data: &[u8]) -> bool {
+    match data {
+        [0x7F, sid, ..] => *sid == cmd,
+        [sid, ..] => *sid == cmd.wrapping_add(0x40),
+        _ => false,
+    }
+}
+
+/// Returns the data of a positive response, after the service ID. Services such as
+/// writes may respond with only the service ID, in which case this is empty
+pub fn response_payload(res: &[u8]) -> &[u8] {
+    res.get(1..).unwrap_or_default()
+}
+
+/// UDS DID of the OBD-II engine speed PID (0x0C)
+const ENGINE_RPM_DID: u16 = 0xF40C;
+
+/// Engine speed at or above which the engine is considered running (Above cranking speed)
+const ENGINE_RUNNING_RPM: u32 = 400;
+
+/// How long [DiagServer::try_subfunctions] listens for a rejection of sub functions
+/// which suppress the positive response
+const SUB_FUNCTION_NRC_WINDOW_MS: u32 = 100;
+
+/// Returns true if the ECU rejected a request because it does not support its sub function
+pub fn is_sub_function_not_supported(res: &ProtocolResult<Vec<u8>>) -> bool {
+    matches!(res, Err(e) if e.get_nrc() == Some(0x12))
+}
+
+/// Discards frames left in the receive buffer before a request is sent, if enabled in `opts`
+fn flush_stale_frames(interface: &mut dyn DiagTransport, opts: &ResponseOptions) {
+    if opts.flush_rx {
+        match interface.flush_rx() {
+            Ok(0) => {}
+            Ok(n) => eprintln!("DIAG - Discarded {} stale frame(s) before sending", n),
+            Err(e) => eprintln!("DIAG - Could not flush the receive buffer: {}", e),
+        }
+    }
+}
+
+/// Builds the payload sent to the ECU for a command
+fn build_request(
+    flags: &Option<Vec<PayloadFlag>>,
+    send_id: u32,
+    cmd: u8,
+    args: &[u8],
+) -> InterfacePayload {
+    let mut tx_data = vec![cmd];
+    tx_data.extend_from_slice(args);
+    let mut tx = InterfacePayload::new(send_id, &tx_data);
+    if let Some(f) = flags {
+        tx.flags = f.clone();
+    }
+    tx
+}
+
+/// Removes the address extension byte from the start of a response, if the request expected
+/// one (`rx_ext`) and the interface left it in the response.
+///
+/// Interfaces which strip the byte mark the response with [PayloadFlag::ISOTP_ADDR_EXTENSION],
+/// so those responses are unchanged. Without this, the service ID and NRC of responses from extended
+/// addressing ECUs are read one byte early, and responsePending is not detected
+fn strip_addr_extension(rx_ext: Option<u8>, mut res: InterfacePayload) -> InterfacePayload {
+    if let Some(ext) = rx_ext {
+        if res.get_addr_extension().is_none() && res.data.first() == Some(&ext) {
+            res.data.remove(0);
+            res.flags.push(PayloadFlag::ISOTP_ADDR_EXTENSION(ext));
+        }
+    }
+    res
+}
+
+/// Discards frames which are not a response to `cmd`, starting with `res`, for up to
+/// [ResponseOptions::await_sid_ms]. Returns the first frame which is a response to `cmd`.
+/// If `await_sid_ms` is not set, `res` is returned as is
+fn skip_unrelated_frames(
+    interface: &mut dyn DiagTransport,
+    cmd: u8,
+    rx_ext: Option<u8>,
+    mut res: InterfacePayload,
+    opts: &ResponseOptions,
+    stats: &RwLock<Stats>,
+) -> ProtocolResult<InterfacePayload> {
+    let wait_ms = match opts.await_sid_ms {
+        Some(w) => w,
+        None => return Ok(res),
+    };
+    let start = opts.clock.now_ms();
+    while !is_response_to(cmd, &res.data) {
+        eprintln!("DIAG - Discarding unrelated frame {}", res);
+        let elapsed = opts.clock.elapsed_ms(start).min(wait_ms as u64) as u32;
+        let frame = if elapsed < wait_ms {
+            interface
+                .recv_data(1, wait_ms - elapsed)
+                .map_err(ProtocolError::CommError)?
+                .into_iter()
+                .next()
+        } else {
+            None
+        };
+        match frame {
+            Some(f) => {
+                stats.write().unwrap().rx += 1;
+                res = strip_addr_extension(rx_ext, f);
+            }
+            // Nothing else was received before the deadline
+            None => {
+                stats.write().unwrap().timeouts += 1;
+                return Err(ProtocolError::Timeout);
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// NRC reported for negative responses which are too short to contain one
+const UNKNOWN_NRC: u8 = 0x00;
+
+/// Most consecutive ResponsePending (NRC 0x78) responses waited out for one request,
+/// so an ECU which never sends its final response cannot block the diag server forever
+const MAX_PENDING: u32 = 10;
+
+/// Returns the NRC of a negative response (`7F <sid> <nrc>`), or [UNKNOWN_NRC]
+/// if the response is malformed
+fn negative_response_code(data: &[u8]) -> u8 {
+    data.get(2).copied().unwrap_or(UNKNOWN_NRC)
+}
+
 pub trait ProtocolServer: Sized {
     type Command: Selectable + ECUCommand;
     type Error: CommandError + 'static;
@@ -252,58 +2445,1552 @@ pub trait ProtocolServer: Sized {
     fn is_in_diag_session(&self) -> bool;
     fn get_last_error(&self) -> Option<String>;
 
+    /// Returns the length of the positive response to a command, for commands
+    /// whose response is always the same length
+    fn expected_response_len(_cmd: u8, _args: &[u8]) -> Option<usize> {
+        None
+    }
+
+    /// Sends a command to the ECU, without checking for a response. Returns the address
+    /// extension byte responses to the command start with, if any
+    fn run_command_send_only(
+        interface: &mut dyn DiagTransport,
+        flags: &Option<Vec<PayloadFlag>>,
+        send_id: u32,
+        cmd: u8,
+        args: &[u8],
+    ) -> ProtocolResult<Option<u8>> {
+        let tx = build_request(flags, send_id, cmd, args);
+        let rx_ext = tx.get_rx_addr_extension();
+        interface
+            .send_data(&[tx], 0)
+            .map(|_| rx_ext)
+            .map_err(ProtocolError::from)
+    }
+
+    /// Listens for `window_ms` after a command was sent without waiting for a response,
+    /// returning the NRC as an error if the ECU sent a negative response to it.
+    /// Any other frames received in the window are discarded. `rx_ext` is the address extension
+    /// byte responses start with, if any
+    fn check_no_negative_response(
+        interface: &mut dyn DiagTransport,
+        cmd: u8,
+        rx_ext: Option<u8>,
+        window_ms: u32,
+        opts: &ResponseOptions,
+        stats: &RwLock<Stats>,
+    ) -> ProtocolResult<()> {
+        let start = opts.clock.now_ms();
+        loop {
+            let elapsed = opts.clock.elapsed_ms(start);
+            if elapsed >= window_ms as u64 {
+                return Ok(());
+            }
+            let frames = interface
+                .recv_data(1, window_ms - elapsed as u32)
+                .map_err(ProtocolError::CommError)?;
+            for frame in frames {
+                stats.write().unwrap().rx += 1;
+                let frame = strip_addr_extension(rx_ext, frame);
+                if frame.data.first() == Some(&0x7F) && frame.data.get(1) == Some(&cmd) {
+                    stats.write().unwrap().nrc_count += 1;
+                    return Err(ProtocolError::ProtocolError(Box::new(
+                        Self::Error::from_byte(negative_response_code(&frame.data)),
+                    )));
+                }
+                eprintln!("DIAG - Discarding unrelated frame {}", frame);
+            }
+        }
+    }
+
+    /// Sends a functional (Broadcast) request to `global_id`, then collects the responses of
+    /// every ECU which answers within `window_ms`, keyed by the CAN ID they responded from.
+    ///
+    /// Unlike [ProtocolServer::run_command_resp], a second responder is not treated as a
+    /// mismatched response. ECUs which ask to wait (Response pending) are waited for until the
+    /// window ends. Frames which are not a response to `cmd` are discarded
+    fn run_command_functional(
+        interface: &mut dyn DiagTransport,
+        flags: &Option<Vec<PayloadFlag>>,
+        global_id: u32,
+        cmd: u8,
+        args: &[u8],
+        window_ms: u32,
+        opts: &ResponseOptions,
+        stats: &RwLock<Stats>,
+    ) -> ProtocolResult<HashMap<u32, ProtocolResult<Vec<u8>>>> {
+        flush_stale_frames(interface, opts);
+        stats.write().unwrap().tx += 1;
+        let tx = build_request(flags, global_id, cmd, args);
+        let rx_ext = tx.get_rx_addr_extension();
+        interface.send_data(&[tx], 0).map_err(ProtocolError::from)?;
+        let mut responses = HashMap::new();
+        let start = opts.clock.now_ms();
+        loop {
+            let elapsed = opts.clock.elapsed_ms(start);
+            if elapsed >= window_ms as u64 {
+                return Ok(responses);
+            }
+            let frames = interface
+                .recv_data(16, window_ms - elapsed as u32)
+                .map_err(ProtocolError::from)?;
+            for frame in frames {
+                let frame = strip_addr_extension(rx_ext, frame);
+                if frame.id == global_id || !is_response_to(cmd, &frame.data) {
+                    continue;
+                }
+                stats.write().unwrap().rx += 1;
+                let res = if frame.data[0] != 0x7F {
+                    Ok(frame.data)
+                } else if negative_response_code(&frame.data) == 0x78 {
+                    stats.write().unwrap().retries += 1;
+                    continue; // Final response from this ECU follows
+                } else {
+                    stats.write().unwrap().nrc_count += 1;
+                    Err(ProtocolError::ProtocolError(Box::new(
+                        Self::Error::from_byte(negative_response_code(&frame.data)),
+                    )))
+                };
+                responses.insert(frame.id, res);
+            }
+        }
+    }
+
     fn run_command_resp(
-        interface: &mut Box<dyn Interface>,
+        interface: &mut dyn DiagTransport,
         flags: &Option<Vec<PayloadFlag>>,
         send_id: u32,
         cmd: u8,
         args: &[u8],
         receive_require: bool,
+        opts: &ResponseOptions,
+        stats: &RwLock<Stats>,
     ) -> std::result::Result<Vec<u8>, ProtocolError> {
-        let mut tx_data = vec![cmd];
-        tx_data.extend_from_slice(args);
-        let mut tx = InterfacePayload::new(send_id, &tx_data);
-        if let Some(f) = flags {
-            tx.flags = f.clone();
-        }
+        flush_stale_frames(interface, opts);
+        stats.write().unwrap().tx += 1;
         if !receive_require {
-            interface
-                .send_data(&[tx], 0)
-                .map(|_| vec![])
-                .map_err(ProtocolError::CommError)
+            let rx_ext = Self::run_command_send_only(interface, flags, send_id, cmd, args)?;
+            if let Some(window_ms) = opts.nrc_window_ms {
+                Self::check_no_negative_response(interface, cmd, rx_ext, window_ms, opts, stats)?;
+            }
+            Ok(vec![])
         } else {
-            // Await max 1 second for response
-            let mut res = interface.send_recv_data(tx, 0, 2000)?;
-            if res.data[0] == 0x7F && res.data[2] == 0x78 {
-                // ResponsePending
-                println!("DIAG - ECU is processing request - Waiting!");
-                match interface.recv_data(1, 2000) {
-                    Ok(data) => {
-                        if let Some(d) = data.get(0) {
-                            res = d.clone();
-                        } else {
-                            return Err(ProtocolError::ProtocolError(Box::new(
-                                Self::Error::from_byte(res.data[2]),
-                            )));
+            let tx = build_request(flags, send_id, cmd, args);
+            let rx_ext = tx.get_rx_addr_extension();
+            let sent_at = opts.clock.now_ms();
+            let mut res = match interface.send_recv_data(tx, 0, opts.response_timeout_ms) {
+                Ok(r) => strip_addr_extension(rx_ext, r),
+                Err(e) if e.is_bus_off() => return Err(ProtocolError::BusOff),
+                Err(e) if e.kind() == comm_api::ComErrorKind::NoAck => {
+                    return Err(ProtocolError::NoAck)
+                }
+                // Rejected on its First Frame, before the response was buffered (See the length
+                // check below). As the ECU did respond, unlike the arm below this is not counted
+                // as a timeout
+                Err(e) if e.kind() == comm_api::ComErrorKind::MessageTooLong => {
+                    return Err(ProtocolError::InvalidResponseSize {
+                        expect: opts.max_response_len,
+                        actual: e.rejected_len().unwrap_or_default(),
+                    })
+                }
+                Err(e) => {
+                    stats.write().unwrap().timeouts += 1;
+                    return Err(ProtocolError::from(e));
+                }
+            };
+            stats.write().unwrap().rx += 1;
+            res = skip_unrelated_frames(interface, cmd, rx_ext, res, opts, stats)?;
+            if opts.handle_response_pending {
+                let mut pending = 0;
+                // ResponsePending. Any other negative response is handled below
+                while res.data.first() == Some(&0x7F) && negative_response_code(&res.data) == 0x78 {
+                    if pending == MAX_PENDING {
+                        eprintln!(
+                            "DIAG - ECU is still processing request after {} pending responses",
+                            pending
+                        );
+                        stats.write().unwrap().timeouts += 1;
+                        return Err(ProtocolError::Timeout);
+                    }
+                    pending += 1;
+                    println!("DIAG - ECU is processing request - Waiting!");
+                    stats.write().unwrap().retries += 1;
+                    match interface.recv_data(1, opts.pending_timeout_ms) {
+                        Ok(data) => {
+                            if let Some(d) = data.into_iter().next() {
+                                stats.write().unwrap().rx += 1;
+                                res = strip_addr_extension(rx_ext, d);
+                                res = skip_unrelated_frames(
+                                    interface, cmd, rx_ext, res, opts, stats,
+                                )?;
+                            } else {
+                                eprintln!(
+                                    "DIAG - No response within {} ms of the ECU asking us to wait",
+                                    opts.pending_timeout_ms
+                                );
+                                stats.write().unwrap().timeouts += 1;
+                                return Err(ProtocolError::Timeout);
+                            }
                         }
+                        Err(e) => return Err(ProtocolError::from(e)),
                     }
-                    Err(e) => return Err(ProtocolError::CommError(e)),
                 }
             }
+            let latency = Duration::from_millis(opts.clock.elapsed_ms(sent_at));
+            stats.write().unwrap().last_latency = Some(latency);
+            if res.data.is_empty() {
+                return Err(ProtocolError::InvalidResponseSize {
+                    expect: 1,
+                    actual: 0,
+                });
+            }
+            if res.data.len() > opts.max_response_len {
+                // Adapters reassemble ISO-TP themselves, so this is the earliest we can check.
+                // The software ISO-TP interface rejects the First Frame instead, before the
+                // response is buffered
+                return Err(ProtocolError::InvalidResponseSize {
+                    expect: opts.max_response_len,
+                    actual: res.data.len(),
+                });
+            }
             if res.data[0] == 0x7F {
                 // Still error :(
+                if res.data.get(1).map_or(false, |sid| *sid != cmd) {
+                    eprintln!(
+                        "DIAG - Warning. Negative response is for a different request. Send: {:02X} - Recv: {}",
+                        cmd, res
+                    );
+                }
+                if res.data.len() < 3 {
+                    eprintln!("DIAG - Warning. Negative response has no NRC: {}", res);
+                }
+                stats.write().unwrap().nrc_count += 1;
                 Err(ProtocolError::ProtocolError(Box::new(
-                    Self::Error::from_byte(res.data[2]),
+                    Self::Error::from_byte(negative_response_code(&res.data)),
                 )))
-            } else if res.data[0] == (cmd + 0x40) {
+            } else if res.data[0] == cmd.wrapping_add(0x40) {
+                match opts
+                    .expected_len
+                    .or_else(|| Self::expected_response_len(cmd, args))
+                {
+                    Some(len) if len != res.data.len() => Err(ProtocolError::InvalidResponseSize {
+                        expect: len,
+                        actual: res.data.len(),
+                    }),
+                    _ => Ok(res.data),
+                }
+            } else if !opts.strict_matching {
+                eprintln!(
+                    "DIAG - Warning. Command response did not match request, returning it anyway. Send: {:02X} - Recv: {}",
+                    cmd, res
+                );
                 Ok(res.data)
             } else {
                 eprintln!(
-                    "DIAG - Command response did not match request? Send: {:02X} - Recv: {:02X}",
-                    cmd, res.data[0]
+                    "DIAG - Command response did not match request? Send: {:02X} - Recv: {}",
+                    cmd, res
                 );
+                stats.write().unwrap().timeouts += 1;
                 Err(ProtocolError::Timeout)
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, VecDeque},
+        sync::{mpsc, Arc, Mutex, RwLock},
+        time::Duration,
+    };
+
+    use common::schema::diag::dtc::ECUDTC;
+
+    use super::{
+        clock::{Clock, MockClock},
+        report::DtcDatabase,
+        uds::{UDSNegativeCode, UDSECU},
+        CommandError, CommandRequest, ConnectTimer, DTCState, DiagProtocol, DtcChange, DtcCode,
+        ProtocolError, ProtocolServer, ProtocolVariant, RequestCorrelator, ResponseOptions, Stats,
+        DTC,
+    };
+    use crate::commapi::{
+        comm_api::{
+            CanFrame, Capability, ComServer, ComServerError, DeviceCapabilities, FilterType,
+            ISO15765Data,
+        },
+        iface::{
+            format_hex, format_hex_compact, hex_dump, BufferType, Interface, InterfaceConfig,
+            InterfacePayload, InterfaceResult, PayloadFlag, IFACE_CFG,
+        },
+    };
+
+    /// Interface which records sent payloads, and replies with queued responses
+    #[derive(Debug, Clone, Default)]
+    struct MockInterface {
+        sent: Arc<Mutex<Vec<InterfacePayload>>>,
+        responses: Arc<Mutex<VecDeque<InterfacePayload>>>,
+        /// Frames already in the receive buffer before the request, which a flush discards
+        stale: Arc<Mutex<VecDeque<InterfacePayload>>>,
+    }
+
+    impl Interface for MockInterface {
+        fn setup(&mut self, _cfg: &InterfaceConfig) -> InterfaceResult<()> {
+            Ok(())
+        }
+
+        fn send_data(
+            &mut self,
+            data: &[InterfacePayload],
+            _timeout: u32,
+        ) -> InterfaceResult<usize> {
+            self.sent.lock().unwrap().extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn recv_data(
+            &mut self,
+            max: usize,
+            _timeout: u32,
+        ) -> InterfaceResult<Vec<InterfacePayload>> {
+            if let Some(frame) = self.stale.lock().unwrap().pop_front() {
+                return Ok(vec![frame]);
+            }
+            let mut responses = self.responses.lock().unwrap();
+            let count = max.min(responses.len());
+            Ok(responses.drain(..count).collect())
+        }
+
+        fn add_filter(&mut self, _f: FilterType) -> InterfaceResult<u32> {
+            Ok(0)
+        }
+
+        fn rem_filter(&mut self, _f_id: u32) -> InterfaceResult<()> {
+            Ok(())
+        }
+
+        fn close(&mut self) -> InterfaceResult<()> {
+            Ok(())
+        }
+
+        fn clear_buffer(&mut self, _buffer_type: BufferType) -> InterfaceResult<()> {
+            Ok(())
+        }
+
+        fn flush_rx(&mut self) -> InterfaceResult<usize> {
+            // Queued responses are what the ECU sends after the request, so are kept
+            let mut stale = self.stale.lock().unwrap();
+            let flushed = stale.len();
+            stale.clear();
+            Ok(flushed)
+        }
+
+        fn get_server(&self) -> Box<dyn ComServer> {
+            Box::new(MockServer)
+        }
+
+        fn clone_box(&self) -> Box<dyn Interface> {
+            Box::new(self.clone())
+        }
+    }
+
+    /// Adapter behind [MockInterface]. Every request succeeds, and nothing is ever received
+    #[derive(Debug, Clone, Copy)]
+    struct MockServer;
+
+    impl ComServer for MockServer {
+        fn open_device(&mut self) -> Result<(), ComServerError> {
+            Ok(())
+        }
+
+        fn close_device(&mut self) -> Result<(), ComServerError> {
+            Ok(())
+        }
+
+        fn send_can_packets(
+            &mut self,
+            data: &[CanFrame],
+            _timeout_ms: u32,
+        ) -> Result<usize, ComServerError> {
+            Ok(data.len())
+        }
+
+        fn is_connected(&self) -> bool {
+            true
+        }
+
+        fn read_can_packets(
+            &self,
+            _timeout_ms: u32,
+            _max_msgs: usize,
+        ) -> Result<Vec<CanFrame>, ComServerError> {
+            Ok(vec![])
+        }
+
+        fn send_iso15765_data(
+            &self,
+            data: &[ISO15765Data],
+            _timeout_ms: u32,
+        ) -> Result<usize, ComServerError> {
+            Ok(data.len())
+        }
+
+        fn read_iso15765_packets(
+            &self,
+            _timeout_ms: u32,
+            _max_msgs: usize,
+        ) -> Result<Vec<ISO15765Data>, ComServerError> {
+            Ok(vec![])
+        }
+
+        fn open_can_interface(
+            &mut self,
+            _bus_speed: u32,
+            _is_ext_can: bool,
+        ) -> Result<(), ComServerError> {
+            Ok(())
+        }
+
+        fn close_can_interface(&mut self) -> Result<(), ComServerError> {
+            Ok(())
+        }
+
+        fn open_iso15765_interface(
+            &mut self,
+            _bus_speed: u32,
+            _is_ext_can: bool,
+            _ext_addressing: bool,
+        ) -> Result<(), ComServerError> {
+            Ok(())
+        }
+
+        fn close_iso15765_interface(&mut self) -> Result<(), ComServerError> {
+            Ok(())
+        }
+
+        fn add_can_filter(&mut self, _f: FilterType) -> Result<u32, ComServerError> {
+            Ok(0)
+        }
+
+        fn rem_can_filter(&mut self, _filter_idx: u32) -> Result<(), ComServerError> {
+            Ok(())
+        }
+
+        fn add_iso15765_filter(&mut self, _f: FilterType) -> Result<u32, ComServerError> {
+            Ok(0)
+        }
+
+        fn rem_iso15765_filter(&mut self, _filter_idx: u32) -> Result<(), ComServerError> {
+            Ok(())
+        }
+
+        fn set_iso15765_params(
+            &mut self,
+            _separation_time_min: u32,
+            _block_size: u32,
+        ) -> Result<(), ComServerError> {
+            Ok(())
+        }
+
+        fn set_iso15765_wait_limit(&mut self, _wft_max: u32) -> Result<(), ComServerError> {
+            Ok(())
+        }
+
+        fn clear_can_rx_buffer(&self) -> Result<(), ComServerError> {
+            Ok(())
+        }
+
+        fn clear_can_tx_buffer(&self) -> Result<(), ComServerError> {
+            Ok(())
+        }
+
+        fn clear_iso15765_rx_buffer(&self) -> Result<(), ComServerError> {
+            Ok(())
+        }
+
+        fn clear_iso15765_tx_buffer(&self) -> Result<(), ComServerError> {
+            Ok(())
+        }
+
+        fn read_battery_voltage(&self) -> Result<f32, ComServerError> {
+            Ok(12.0)
+        }
+
+        fn clone_box(&self) -> Box<dyn ComServer> {
+            Box::new(*self)
+        }
+
+        fn get_capabilities(&self) -> DeviceCapabilities {
+            DeviceCapabilities {
+                name: "Mock".into(),
+                vendor: "OpenVehicleDiag".into(),
+                library_path: String::new(),
+                device_fw_version: String::new(),
+                library_version: String::new(),
+                j1850vpw: Capability::No,
+                j1850pwm: Capability::No,
+                can: Capability::Yes,
+                iso15765: Capability::Yes,
+                iso9141: Capability::No,
+                iso14230: Capability::No,
+                ip: Capability::No,
+                battery_voltage: Capability::Yes,
+            }
+        }
+
+        fn get_api(&self) -> &str {
+            "Mock"
+        }
+    }
+
+    #[test]
+    fn test_dtc_code_prefix() {
+        assert_eq!(
+            DtcCode::from_obd_bytes(&[0x04, 0x20]).as_obd_string(),
+            "P0420"
+        );
+        assert_eq!(
+            DtcCode::from_obd_bytes(&[0x41, 0x23]).as_obd_string(),
+            "C0123"
+        );
+        assert_eq!(
+            DtcCode::from_obd_bytes(&[0x9A, 0xBC]).as_obd_string(),
+            "B1ABC"
+        );
+        assert_eq!(
+            DtcCode::from_obd_bytes(&[0xE0, 0x01]).as_obd_string(),
+            "U2001"
+        );
+    }
+
+    #[test]
+    fn test_dtc_code_bytes() {
+        let code = DtcCode::from_bytes(&[0xC1, 0x00, 0x87]);
+        assert_eq!(code.as_uds_bytes(), [0xC1, 0x00, 0x87]);
+        assert_eq!(code.as_obd_bytes(), [0xC1, 0x00]);
+        assert_eq!(code.as_obd_string(), "U0100");
+        assert_eq!(
+            DtcCode::from_obd_bytes(&[0x12, 0x34]).as_uds_bytes(),
+            [0x12, 0x34, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_format_hex() {
+        let data = [0x00, 0x0A, 0xF1, 0x90];
+        assert_eq!(format_hex(&data), "00 0A F1 90");
+        assert_eq!(format_hex_compact(&data), "000AF190");
+        assert_eq!(hex_dump(&data), "[00 0A F1 90]");
+        assert_eq!(format_hex(&[]), "");
+        assert_eq!(format_hex_compact(&[0x05]), "05");
+    }
+
+    #[test]
+    fn test_dtc_code_sae_string() {
+        for code in &["P0420", "C0123", "B1ABC", "U3FFF"] {
+            let dtc = DtcCode::from_sae_string(code).unwrap();
+            assert_eq!(dtc.as_obd_string(), *code);
+            assert_eq!(dtc.failure_type(), DtcCode::NO_FAILURE_TYPE);
+            assert_eq!(DtcCode::from_obd_bytes(&dtc.as_obd_bytes()), dtc);
+        }
+        let dtc = DtcCode::from_sae_string("U0100-87").unwrap();
+        assert_eq!(dtc.as_uds_bytes(), [0xC1, 0x00, 0x87]);
+        assert_eq!(DtcCode::from_sae_string("u010087"), Some(dtc));
+        assert_eq!(
+            DtcCode::from_sae_string("U0100")
+                .unwrap()
+                .with_failure_type(0x87),
+            dtc
+        );
+        assert_eq!(dtc.with_failure_type(0x00).as_obd_bytes(), [0xC1, 0x00]);
+
+        for invalid in &["", "X0420", "P420", "P4420", "P04G0", "P04201"] {
+            assert_eq!(DtcCode::from_sae_string(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new();
+        let start = clock.now_ms();
+        clock.sleep_ms(2000);
+        clock.clone().advance(500);
+        assert_eq!(clock.elapsed_ms(start), 2500);
+    }
+
+    #[test]
+    fn test_connect_timer_budget() {
+        let clock = MockClock::new();
+        let mut cfg = InterfaceConfig::new();
+        cfg.add_param(IFACE_CFG::CONNECT_TIMEOUT_MS, 1000);
+        let timer = ConnectTimer::with_clock(&cfg, Arc::new(clock.clone()));
+
+        assert_eq!(timer.remaining("open").unwrap(), Some(1000));
+        clock.advance(600);
+        assert_eq!(timer.remaining("open").unwrap(), Some(400));
+        clock.advance(400);
+        assert!(matches!(
+            timer.remaining("open"),
+            Err(ProtocolError::StepTimeout { .. })
+        ));
+    }
+
+    #[test]
+    fn test_connect_step_timeout() {
+        let mut cfg = InterfaceConfig::new();
+        cfg.add_param(IFACE_CFG::CONNECT_TIMEOUT_MS, 50);
+        let timer = ConnectTimer::with_clock(&cfg, Arc::new(MockClock::new()));
+        let (discard_tx, discard_rx) = mpsc::channel();
+
+        let res = timer.run_step(
+            "open",
+            || {
+                std::thread::sleep(Duration::from_millis(200));
+                Ok(1u32)
+            },
+            move |v| discard_tx.send(v).unwrap(),
+        );
+        assert!(matches!(res, Err(ProtocolError::StepTimeout { .. })));
+        // The step completed after it was given up on, so its result is discarded
+        assert_eq!(discard_rx.recv_timeout(Duration::from_secs(2)).unwrap(), 1);
+
+        let res = timer.run_step("open", || Ok(2u32), |_| panic!("Step was not given up on"));
+        assert_eq!(res.unwrap(), 2);
+    }
+
+    #[test]
+    fn test_send_only() {
+        let mock = MockInterface::default();
+        mock.responses
+            .lock()
+            .unwrap()
+            .push_back(InterfacePayload::new(0x7E8, &[0x7E, 0x00]));
+        let mut iface: Box<dyn Interface> = Box::new(mock.clone());
+        let flags = Some(vec![PayloadFlag::ISOTP_PAD_FRAME]);
+
+        let rx_ext = UDSECU::run_command_send_only(&mut iface, &flags, 0x7E0, 0x3E, &[0x80]);
+        assert_eq!(rx_ext.unwrap(), None);
+        let stats = RwLock::new(Stats::default());
+        let res = UDSECU::run_command_resp(
+            &mut iface,
+            &flags,
+            0x7E0,
+            0x3E,
+            &[0x80],
+            false,
+            &ResponseOptions::new(&InterfaceConfig::new()),
+            &stats,
+        )
+        .unwrap();
+        assert!(res.is_empty());
+        assert_eq!(stats.read().unwrap().tx, 1);
+        assert_eq!(stats.read().unwrap().rx, 0);
+
+        let sent = mock.sent.lock().unwrap();
+        assert_eq!(sent.len(), 2);
+        for payload in sent.iter() {
+            assert_eq!(payload.id, 0x7E0);
+            assert_eq!(payload.data, vec![0x3E, 0x80]);
+            assert!(payload.is_flag_set(PayloadFlag::ISOTP_PAD_FRAME));
+        }
+        // Response was never read
+        assert_eq!(mock.responses.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_send_only_nrc_window() {
+        let mock = MockInterface::default();
+        mock.responses.lock().unwrap().extend(vec![
+            InterfacePayload::new(0x7E8, &[0x7E, 0x00]), // Unrelated
+            InterfacePayload::new(0x7E8, &[0x7F, 0x85, 0x22]),
+        ]);
+        let mut iface: Box<dyn Interface> = Box::new(mock.clone());
+        let stats = RwLock::new(Stats::default());
+        let opts = ResponseOptions {
+            nrc_window_ms: Some(50),
+            ..ResponseOptions::new(&InterfaceConfig::new())
+        };
+        let err = UDSECU::run_command_resp(
+            &mut iface,
+            &None,
+            0x7E0,
+            0x85,
+            &[0x82],
+            false,
+            &opts,
+            &stats,
+        )
+        .unwrap_err();
+        assert_eq!(err.get_nrc(), Some(0x22));
+        assert_eq!(stats.read().unwrap().nrc_count, 1);
+
+        // Nothing received in the window
+        UDSECU::run_command_resp(
+            &mut iface,
+            &None,
+            0x7E0,
+            0x85,
+            &[0x82],
+            false,
+            &opts,
+            &stats,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_response_pending_disabled() {
+        let mock = MockInterface::default();
+        mock.responses.lock().unwrap().extend(vec![
+            InterfacePayload::new(0x7E8, &[0x7F, 0x31, 0x78]),
+            InterfacePayload::new(0x7E8, &[0x71, 0x01, 0x02, 0x03]),
+        ]);
+        let mut iface: Box<dyn Interface> = Box::new(mock.clone());
+        let stats = RwLock::new(Stats::default());
+        let opts = ResponseOptions {
+            handle_response_pending: false,
+            ..ResponseOptions::new(&InterfaceConfig::new())
+        };
+        let err = UDSECU::run_command_resp(
+            &mut iface,
+            &None,
+            0x7E0,
+            0x31,
+            &[0x01, 0x02, 0x03],
+            true,
+            &opts,
+            &stats,
+        )
+        .unwrap_err();
+        assert_eq!(err.get_nrc(), Some(0x78));
+        assert_eq!(stats.read().unwrap().retries, 0);
+        // Final response is left for the next read
+        assert_eq!(mock.responses.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_response_pending_loop() {
+        let run = |pending: u32| {
+            let mock = MockInterface::default();
+            for _ in 0..pending {
+                mock.responses
+                    .lock()
+                    .unwrap()
+                    .push_back(InterfacePayload::new(0x7E8, &[0x7F, 0x31, 0x78]));
+            }
+            mock.responses
+                .lock()
+                .unwrap()
+                .push_back(InterfacePayload::new(0x7E8, &[0x71, 0x01, 0x02, 0x03]));
+            let mut iface: Box<dyn Interface> = Box::new(mock);
+            let stats = RwLock::new(Stats::default());
+            let res = UDSECU::run_command_resp(
+                &mut iface,
+                &None,
+                0x7E0,
+                0x31,
+                &[0x01, 0x02, 0x03],
+                true,
+                &ResponseOptions::new(&InterfaceConfig::new()),
+                &stats,
+            );
+            let retries = stats.read().unwrap().retries;
+            (res, retries)
+        };
+        // Several pending responses before the final one
+        let (res, retries) = run(3);
+        assert_eq!(res.unwrap(), vec![0x71, 0x01, 0x02, 0x03]);
+        assert_eq!(retries, 3);
+        let (res, _) = run(super::MAX_PENDING);
+        assert!(res.is_ok());
+        // ECU which keeps asking to wait
+        let (res, retries) = run(super::MAX_PENDING + 1);
+        assert!(matches!(res, Err(ProtocolError::Timeout)));
+        assert_eq!(retries, super::MAX_PENDING as u64);
+
+        // ECU which never sends the final response
+        let mock = MockInterface::default();
+        mock.responses
+            .lock()
+            .unwrap()
+            .push_back(InterfacePayload::new(0x7E8, &[0x7F, 0x31, 0x78]));
+        let mut iface: Box<dyn Interface> = Box::new(mock);
+        let stats = RwLock::new(Stats::default());
+        let res = UDSECU::run_command_resp(
+            &mut iface,
+            &None,
+            0x7E0,
+            0x31,
+            &[0x01, 0x02, 0x03],
+            true,
+            &ResponseOptions::new(&InterfaceConfig::new()),
+            &stats,
+        );
+        assert!(matches!(res, Err(ProtocolError::Timeout)));
+        assert_eq!(stats.read().unwrap().timeouts, 1);
+    }
+
+    #[test]
+    fn test_await_sid_skips_unrelated_frames() {
+        let run = |frames: &[&[u8]]| {
+            let mock = MockInterface::default();
+            for f in frames {
+                mock.responses
+                    .lock()
+                    .unwrap()
+                    .push_back(InterfacePayload::new(0x7E8, f));
+            }
+            let mut iface: Box<dyn Interface> = Box::new(mock);
+            let mut opts = ResponseOptions::new(&InterfaceConfig::new());
+            // Time never advances, so only running out of frames ends the wait
+            opts.clock = Arc::new(MockClock::new());
+            opts.await_sid_ms = Some(1000);
+            let stats = RwLock::new(Stats::default());
+            let res = UDSECU::run_command_resp(
+                &mut iface,
+                &None,
+                0x7E0,
+                0x22,
+                &[0xF1, 0x90],
+                true,
+                &opts,
+                &stats,
+            );
+            (res, stats.read().unwrap().timeouts)
+        };
+        // Unrelated frames before and after ResponsePending
+        let (res, _) = run(&[
+            &[0x50, 0x03],
+            &[0x7F, 0x22, 0x78],
+            &[0x7E, 0x00],
+            &[0x62, 0xF1, 0x90, 0x01],
+        ]);
+        assert_eq!(res.unwrap(), vec![0x62, 0xF1, 0x90, 0x01]);
+        // Only an unrelated frame. The interface returns nothing more
+        let (res, timeouts) = run(&[&[0x50, 0x03]]);
+        assert!(matches!(res, Err(ProtocolError::Timeout)));
+        assert_eq!(timeouts, 1);
+    }
+
+    #[test]
+    fn test_addr_extension_response_pending() {
+        // Interface which leaves the address extension byte in responses
+        let mock = MockInterface::default();
+        mock.responses.lock().unwrap().extend(vec![
+            InterfacePayload::new(0x7E8, &[0xF1, 0x7F, 0x31, 0x78]),
+            InterfacePayload::new(0x7E8, &[0xF1, 0x7F, 0x31, 0x22]),
+        ]);
+        let mut iface: Box<dyn Interface> = Box::new(mock.clone());
+        let stats = RwLock::new(Stats::default());
+        let flags = Some(vec![
+            PayloadFlag::ISOTP_ADDR_EXTENSION(0x10),
+            PayloadFlag::ISOTP_RX_ADDR_EXTENSION(0xF1),
+        ]);
+        let err = UDSECU::run_command_resp(
+            &mut iface,
+            &flags,
+            0x7E0,
+            0x31,
+            &[0x01, 0x02, 0x03],
+            true,
+            &ResponseOptions::new(&InterfaceConfig::new()),
+            &stats,
+        )
+        .unwrap_err();
+        // Pending was waited out, and the NRC located after the extension byte
+        assert_eq!(err.get_nrc(), Some(0x22));
+        assert_eq!(stats.read().unwrap().retries, 1);
+        // Measured up to the final response
+        assert!(stats.read().unwrap().last_latency.is_some());
+
+        // Responses the interface already stripped are unchanged
+        let mut res = InterfacePayload::new(0x7E8, &[0xF1, 0x71]);
+        res.flags.push(PayloadFlag::ISOTP_ADDR_EXTENSION(0xF1));
+        assert_eq!(
+            super::strip_addr_extension(Some(0xF1), res).data,
+            vec![0xF1, 0x71]
+        );
+    }
+
+    #[test]
+    fn test_sid_only_response() {
+        let mock = MockInterface::default();
+        mock.responses
+            .lock()
+            .unwrap()
+            .push_back(InterfacePayload::new(0x7E8, &[0x54]));
+        let mut iface: Box<dyn Interface> = Box::new(mock);
+        let res = UDSECU::run_command_resp(
+            &mut iface,
+            &None,
+            0x7E0,
+            0x14,
+            &[0xFF, 0xFF, 0xFF],
+            true,
+            &ResponseOptions::new(&InterfaceConfig::new()),
+            &RwLock::new(Stats::default()),
+        )
+        .unwrap();
+        assert_eq!(res, vec![0x54]);
+        assert!(super::response_payload(&res).is_empty());
+        assert!(super::response_payload(&[]).is_empty());
+        assert_eq!(super::response_payload(&[0x62, 0xF1, 0x90]), &[0xF1, 0x90]);
+    }
+
+    #[test]
+    fn test_multi_frame_positive_response() {
+        use crate::commapi::soft_isotp::SoftIsoTpInterface;
+        let mock = MockInterface::default();
+        let vin = b"WDB2110421A123456";
+        let frames: [&[u8]; 3] = [
+            &[0x10, 0x14, 0x62, 0xF1, 0x90, vin[0], vin[1], vin[2]],
+            &[0x21, vin[3], vin[4], vin[5], vin[6], vin[7], vin[8], vin[9]],
+            &[
+                0x22, vin[10], vin[11], vin[12], vin[13], vin[14], vin[15], vin[16],
+            ],
+        ];
+        for f in &frames {
+            mock.responses
+                .lock()
+                .unwrap()
+                .push_back(InterfacePayload::new(0x7E8, f));
+        }
+        // Software ISO-TP over the raw CAN frames, so the First Frame's PCI is what the ECU sent
+        let mut iface = SoftIsoTpInterface::over_can(Box::new(mock.clone()));
+        iface
+            .add_filter(FilterType::IsoTP {
+                id: 0x7E8,
+                mask: 0xFFFF,
+                fc: 0x7E0,
+            })
+            .unwrap();
+        let res = UDSECU::run_command_resp(
+            &mut iface,
+            &None,
+            0x7E0,
+            0x22,
+            &[0xF1, 0x90],
+            true,
+            &ResponseOptions::new(&InterfaceConfig::new()),
+            &RwLock::new(Stats::default()),
+        )
+        .unwrap();
+        let mut expected = vec![0x62, 0xF1, 0x90];
+        expected.extend_from_slice(vin);
+        assert_eq!(res, expected);
+
+        let sent: Vec<Vec<u8>> = mock
+            .sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|p| p.data.clone())
+            .collect();
+        // Request, then our Flow Control after the First Frame
+        assert_eq!(
+            sent,
+            vec![vec![0x03, 0x22, 0xF1, 0x90], vec![0x30, 0x00, 0x00]]
+        );
+        assert_eq!(mock.sent.lock().unwrap()[1].id, 0x7E0);
+    }
+
+    #[test]
+    fn test_escaped_length_command() {
+        use crate::commapi::{isotp::FrameLayout, soft_isotp::SoftIsoTpInterface};
+        // Both longer than the 4095 bytes a First Frame's 12 bit length describes
+        let mut request = vec![0x31, 0x01, 0xFF, 0x00];
+        request.extend((0..4996).map(|x| x as u8));
+        let mut response = vec![0x71, 0x01, 0xFF, 0x00];
+        response.extend((0..4996).map(|x| (x * 7) as u8));
+
+        let mock = MockInterface::default();
+        let mut ecu_frames = vec![vec![0x30, 0x00, 0x00]]; // Flow Control for the request
+        ecu_frames.extend(FrameLayout::default().segment(&response).unwrap());
+        mock.responses
+            .lock()
+            .unwrap()
+            .extend(ecu_frames.iter().map(|f| InterfacePayload::new(0x7E8, f)));
+        let mut iface = SoftIsoTpInterface::over_can(Box::new(mock.clone()));
+        iface
+            .add_filter(FilterType::IsoTP {
+                id: 0x7E8,
+                mask: 0xFFFF,
+                fc: 0x7E0,
+            })
+            .unwrap();
+        let res = UDSECU::run_command_resp(
+            &mut iface,
+            &None,
+            0x7E0,
+            request[0],
+            &request[1..],
+            true,
+            &ResponseOptions::new(&InterfaceConfig::new()),
+            &RwLock::new(Stats::default()),
+        )
+        .unwrap();
+        assert_eq!(res, response);
+
+        // Request frames, with our Flow Control after the response's First Frame
+        let sent = mock.sent.lock().unwrap();
+        let mut expected = FrameLayout::default().segment(&request).unwrap();
+        assert_eq!(expected[0][..6], [0x10, 0x00, 0x00, 0x00, 0x13, 0x88]);
+        expected.push(vec![0x30, 0x00, 0x00]);
+        let sent_frames: Vec<Vec<u8>> = sent.iter().map(|p| p.data.clone()).collect();
+        assert_eq!(sent_frames, expected);
+        assert!(sent.iter().all(|p| p.id == 0x7E0));
+    }
+
+    #[test]
+    fn test_first_frame_over_max_len() {
+        use crate::commapi::soft_isotp::SoftIsoTpInterface;
+        let mock = MockInterface::default();
+        // First Frame of a 4KiB response
+        mock.responses
+            .lock()
+            .unwrap()
+            .push_back(InterfacePayload::new(
+                0x7E8,
+                &[0x1F, 0xFF, 0x62, 0xF1, 0x90, 0x00, 0x00, 0x00],
+            ));
+        let mut cfg = InterfaceConfig::new();
+        cfg.add_param(IFACE_CFG::MAX_RESPONSE_LEN, 0x100);
+        let mut iface = SoftIsoTpInterface::over_can(Box::new(mock.clone()));
+        iface.setup(&cfg).unwrap();
+        iface
+            .add_filter(FilterType::IsoTP {
+                id: 0x7E8,
+                mask: 0xFFFF,
+                fc: 0x7E0,
+            })
+            .unwrap();
+        let res = UDSECU::run_command_resp(
+            &mut iface,
+            &None,
+            0x7E0,
+            0x22,
+            &[0xF1, 0x90],
+            true,
+            &ResponseOptions::new(&cfg),
+            &RwLock::new(Stats::default()),
+        );
+        match res {
+            Err(ProtocolError::InvalidResponseSize { expect, actual }) => {
+                assert_eq!((expect, actual), (0x100, 0xFFF))
+            }
+            r => panic!("Expected the First Frame to be rejected, got {:?}", r),
+        }
+        // The ECU is told to abort the transfer with an overflow Flow Control
+        assert_eq!(mock.sent.lock().unwrap()[1].data, vec![0x32, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_flow_control_wait() {
+        use crate::commapi::soft_isotp::{SoftIsoTpInterface, RX_CAPACITY};
+        let isotp_filter = FilterType::IsoTP {
+            id: 0x7E8,
+            mask: 0xFFFF,
+            fc: 0x7E0,
+        };
+        // ECU asks us to wait more times than we allow whilst we send a multi-frame request
+        let mock = MockInterface::default();
+        mock.responses
+            .lock()
+            .unwrap()
+            .extend((0..3).map(|_| InterfacePayload::new(0x7E8, &[0x31, 0x00, 0x00])));
+        let mut cfg = InterfaceConfig::new();
+        cfg.add_param(IFACE_CFG::ISOTP_WFT_MAX, 2);
+        let mut iface = SoftIsoTpInterface::over_can(Box::new(mock.clone()));
+        iface.setup(&cfg).unwrap();
+        iface.add_filter(isotp_filter).unwrap();
+        let stats = RwLock::new(Stats::default());
+        let res = UDSECU::run_command_resp(
+            &mut iface,
+            &None,
+            0x7E0,
+            0x2E,
+            &[
+                0xF1, 0x90, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08,
+            ],
+            true,
+            &ResponseOptions::new(&cfg),
+            &stats,
+        );
+        assert!(matches!(res, Err(ProtocolError::Timeout)));
+        assert_eq!(stats.read().unwrap().timeouts, 1);
+        assert_eq!(mock.sent.lock().unwrap().len(), 1); // Only the First Frame
+
+        // Our receive buffer is full when the ECU starts a transfer, so it is told to wait,
+        // then to continue once a message has been read
+        let mock = MockInterface::default();
+        let mut frames = vec![vec![0x02, 0x7E, 0x00]; RX_CAPACITY];
+        frames.push(vec![0x10, 0x09, 0x62, 0xF1, 0x90, 0x01, 0x02, 0x03]);
+        mock.responses
+            .lock()
+            .unwrap()
+            .extend(frames.iter().map(|f| InterfacePayload::new(0x7E8, f)));
+        let mut iface = SoftIsoTpInterface::over_can(Box::new(mock.clone()));
+        iface.add_filter(isotp_filter).unwrap();
+        assert_eq!(iface.recv_data(1, 0).unwrap()[0].data, vec![0x7E, 0x00]);
+        assert_eq!(
+            iface.recv_data(RX_CAPACITY, 0).unwrap().len(),
+            RX_CAPACITY - 1
+        );
+        let sent: Vec<Vec<u8>> = mock
+            .sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|p| p.data.clone())
+            .collect();
+        assert_eq!(sent, vec![vec![0x31, 0x00, 0x00], vec![0x30, 0x00, 0x00]]);
+
+        mock.responses
+            .lock()
+            .unwrap()
+            .push_back(InterfacePayload::new(0x7E8, &[0x21, 0x04, 0x05, 0x06]));
+        assert_eq!(
+            iface.recv_data(1, 0).unwrap()[0].data,
+            vec![0x62, 0xF1, 0x90, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06]
+        );
+    }
+
+    #[test]
+    fn test_wait_for_bus_idle() {
+        let mock = MockInterface::default();
+        let mut iface: Box<dyn Interface> = Box::new(mock.clone());
+        let clock = MockClock::new();
+        let mut cfg = InterfaceConfig::new();
+        // Disabled by default
+        assert!(super::wait_for_bus_idle(&mut iface, &cfg, &clock));
+        assert_eq!(clock.now_ms(), 0);
+
+        cfg.add_param(IFACE_CFG::WAIT_FOR_IDLE_MS, 50);
+        for _ in 0..3 {
+            mock.stale
+                .lock()
+                .unwrap()
+                .push_back(InterfacePayload::new(0x7E8, &[0x03, 0x00]));
+        }
+        // Quiet for 50ms after the last frame (Received at 20ms)
+        assert!(super::wait_for_bus_idle(&mut iface, &cfg, &clock));
+        assert_eq!(clock.now_ms(), 70);
+        assert!(mock.stale.lock().unwrap().is_empty());
+
+        // Bus keeps sending past the max wait
+        cfg.add_param(IFACE_CFG::IDLE_MAX_WAIT_MS, 100);
+        for _ in 0..20 {
+            mock.stale
+                .lock()
+                .unwrap()
+                .push_back(InterfacePayload::new(0x7E8, &[0x03, 0x00]));
+        }
+        let start = clock.now_ms();
+        assert!(!super::wait_for_bus_idle(&mut iface, &cfg, &clock));
+        assert_eq!(clock.elapsed_ms(start), 100);
+    }
+
+    #[test]
+    fn test_dtc_format_code() {
+        assert_eq!(DTC::format_code(0x0101), "P0101");
+        assert_eq!(DTC::format_code(0x4123), "C0123");
+        assert_eq!(DTC::format_code(0x8123), "B0123");
+        assert_eq!(DTC::format_code(0xC123), "U0123");
+        assert_eq!(DTC::format_code(0x0420), "P0420");
+        assert_eq!(DTC::format_code(0xD001), "U1001");
+        assert_eq!(DTC::format_code(0x0000), "P0000");
+        assert_eq!(DTC::format_code(0xFFFF), "U3FFF");
+        assert!((0..=u16::MAX).all(|raw| DTC::format_code(raw).len() == 5));
+
+        // Formatted and raw hex names both resolve the DTC
+        let dtc = DTC {
+            error: DTC::format_code(0xC123),
+            state: DTCState::Stored,
+            check_engine_on: false,
+            id: DtcCode::from_bytes(&[0xC1, 0x23, 0x87]),
+        };
+        assert!(dtc.matches_name("U0123"));
+        assert!(dtc.matches_name("C123"));
+        assert!(dtc.matches_name("C12387"));
+        assert!(!dtc.matches_name("U0124"));
+
+        // Raw hex of one category is the rest of the code in another
+        let dtc = DTC {
+            error: DTC::format_code(0x0101),
+            state: DTCState::Stored,
+            check_engine_on: false,
+            id: DtcCode::from_obd_bytes(&[0x01, 0x01]),
+        };
+        assert!(!dtc.matches_name("U0101"));
+        assert!(!dtc.matches_name("DTC_U0101"));
+        assert!(dtc.matches_name("DTC_0101"));
+        let entry = |name: &str| ECUDTC {
+            error_name: name.into(),
+            summary: String::new(),
+            description: String::new(),
+            envs: Vec::new(),
+        };
+        let db = DtcDatabase::new(vec![entry("U0101"), entry("P0101")]);
+        assert_eq!(db.lookup(&dtc).unwrap().error_name, "P0101");
+        let db = DtcDatabase::new(vec![entry("U0101")]);
+        assert!(db.lookup(&dtc).is_none());
+    }
+
+    #[test]
+    fn test_flush_stale_response() {
+        let mock = MockInterface::default();
+        let mut iface: Box<dyn Interface> = Box::new(mock.clone());
+        let stats = RwLock::new(Stats::default());
+        for flush_rx in &[true, false] {
+            // Late response to an earlier, timed out, read of the same DID
+            mock.stale
+                .lock()
+                .unwrap()
+                .push_back(InterfacePayload::new(0x7E8, &[0x62, 0xF1, 0x90, 0x01]));
+            mock.responses
+                .lock()
+                .unwrap()
+                .push_back(InterfacePayload::new(0x7E8, &[0x62, 0xF1, 0x90, 0x02]));
+            let opts = ResponseOptions {
+                flush_rx: *flush_rx,
+                ..ResponseOptions::new(&InterfaceConfig::new())
+            };
+            let res = UDSECU::run_command_resp(
+                &mut iface,
+                &None,
+                0x7E0,
+                0x22,
+                &[0xF1, 0x90],
+                true,
+                &opts,
+                &stats,
+            )
+            .unwrap();
+            let expected = if *flush_rx { 0x02 } else { 0x01 };
+            assert_eq!(res, vec![0x62, 0xF1, 0x90, expected]);
+            mock.responses.lock().unwrap().clear();
+        }
+    }
+
+    #[test]
+    fn test_functional_multi_ecu() {
+        let mock = MockInterface::default();
+        mock.responses.lock().unwrap().extend(vec![
+            InterfacePayload::new(0x7E8, &[0x7F, 0x3E, 0x78]), // Pending, answers later
+            InterfacePayload::new(0x7E9, &[0x7E, 0x00]),
+            InterfacePayload::new(0x7EA, &[0x7F, 0x3E, 0x12]),
+            InterfacePayload::new(0x7EB, &[0x50, 0x03]), // Unrelated
+            InterfacePayload::new(0x7E8, &[0x7E, 0x00]),
+        ]);
+        let mut iface: Box<dyn Interface> = Box::new(mock);
+        let stats = RwLock::new(Stats::default());
+        let res = UDSECU::run_command_functional(
+            &mut iface,
+            &None,
+            0x7DF,
+            0x3E,
+            &[0x00],
+            50,
+            &ResponseOptions::new(&InterfaceConfig::new()),
+            &stats,
+        )
+        .unwrap();
+        assert_eq!(res.len(), 3);
+        assert_eq!(res[&0x7E8].as_ref().unwrap(), &vec![0x7E, 0x00]);
+        assert_eq!(res[&0x7E9].as_ref().unwrap(), &vec![0x7E, 0x00]);
+        assert_eq!(res[&0x7EA].as_ref().unwrap_err().get_nrc(), Some(0x12));
+        assert_eq!(stats.read().unwrap().nrc_count, 1);
+    }
+
+    fn test_dtc(code: [u8; 2], state: DTCState) -> DTC {
+        let id = DtcCode::from_obd_bytes(&code);
+        DTC {
+            error: id.as_obd_string(),
+            state,
+            check_engine_on: false,
+            id,
+        }
+    }
+
+    #[test]
+    fn test_dtc_utils() {
+        let dtcs = vec![
+            test_dtc([0x04, 0x20], DTCState::Pending),
+            test_dtc([0x01, 0x71], DTCState::Stored),
+            test_dtc([0x03, 0x00], DTCState::Permanent),
+            test_dtc([0x01, 0x71], DTCState::Pending),
+            test_dtc([0x00, 0x10], DTCState::None),
+        ];
+
+        let confirmed = super::filter_confirmed(&dtcs);
+        assert_eq!(
+            confirmed
+                .iter()
+                .map(|x| x.error.as_str())
+                .collect::<Vec<_>>(),
+            vec!["P0171", "P0300"]
+        );
+
+        let mut unique = super::dedup(dtcs);
+        assert_eq!(unique.len(), 4);
+        // First read of P0171 is kept
+        assert!(unique
+            .iter()
+            .any(|x| x.error == "P0171" && x.state == DTCState::Stored));
+
+        super::sort_by_id(&mut unique);
+        assert_eq!(
+            unique.iter().map(|x| x.error.as_str()).collect::<Vec<_>>(),
+            vec!["P0010", "P0171", "P0300", "P0420"]
+        );
+    }
+
+    #[test]
+    fn test_diff_dtcs() {
+        let old = vec![
+            test_dtc([0x04, 0x20], DTCState::Pending),
+            test_dtc([0x01, 0x71], DTCState::Stored),
+            test_dtc([0x03, 0x00], DTCState::Stored),
+        ];
+        let new = vec![
+            test_dtc([0x03, 0x00], DTCState::Stored),
+            test_dtc([0x04, 0x20], DTCState::Stored),
+            test_dtc([0x00, 0x10], DTCState::Pending),
+        ];
+        let changes = super::diff_dtcs(&old, &new);
+        assert_eq!(changes.len(), 3);
+        assert!(matches!(&changes[0], DtcChange::StatusChanged { old, new }
+            if old.error == "P0420" && old.state == DTCState::Pending && new.state == DTCState::Stored));
+        assert!(matches!(&changes[1], DtcChange::Added(x) if x.error == "P0010"));
+        assert!(matches!(&changes[2], DtcChange::Removed(x) if x.error == "P0171"));
+        assert!(super::diff_dtcs(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn test_request_correlation() {
+        let correlator = RequestCorrelator::new(Arc::new(MockClock::new()));
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut timed_out = CommandRequest::new(0x22, &[0xF1, 0x90], true);
+        let mut first = CommandRequest::new(0x22, &[0xF1, 0x89], true);
+        let mut second = CommandRequest::new(0x3E, &[0x00], true);
+        let stale = correlator.tag(&mut timed_out);
+        assert!(correlator.await_response(&rx, stale, Some(1)).is_none());
+        let first = correlator.tag(&mut first);
+        let second = correlator.tag(&mut second);
+
+        tx.send((stale, Ok(vec![0x62, 0xF1, 0x90]))).unwrap();
+        tx.send((second, Ok(vec![0x7E, 0x00]))).unwrap();
+        tx.send((first, Ok(vec![0x62, 0xF1, 0x89]))).unwrap();
+        assert_eq!(
+            correlator
+                .await_response(&rx, first, Some(10))
+                .unwrap()
+                .unwrap(),
+            vec![0x62, 0xF1, 0x89]
+        );
+        // Response to the second request was parked whilst waiting for the first
+        assert_eq!(
+            correlator
+                .await_response(&rx, second, Some(10))
+                .unwrap()
+                .unwrap(),
+            vec![0x7E, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_retry_transient() {
+        let clock = MockClock::new();
+        let mut attempts = 0;
+        let res = super::retry_transient(3, &clock, || {
+            attempts += 1;
+            match attempts {
+                1 => Err(ProtocolError::Timeout),
+                2 => Err(ProtocolError::NoAck),
+                _ => Ok(attempts),
+            }
+        });
+        assert_eq!(res.unwrap(), 3);
+        assert_eq!(clock.now_ms(), 50 + 100); // Backs off exponentially
+
+        // Negative responses are not retried
+        let mut attempts = 0;
+        let res: Result<(), _> = super::retry_transient(3, &clock, || {
+            attempts += 1;
+            Err(ProtocolError::ProtocolError(Box::new(
+                UDSNegativeCode::from_byte(0x22),
+            )))
+        });
+        assert_eq!(res.unwrap_err().get_nrc(), Some(0x22));
+        assert_eq!(attempts, 1);
+
+        let mut attempts = 0;
+        let res: Result<(), _> = super::retry_transient(2, &clock, || {
+            attempts += 1;
+            Err(ProtocolError::Timeout)
+        });
+        assert!(res.unwrap_err().is_timeout());
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn test_pipeline_st_min() {
+        let requests = vec![(0x2E, vec![0x01]), (0x2E, vec![0x02]), (0x31, vec![0x03])];
+        let clock = MockClock::new();
+        let mut sent = Vec::new();
+        super::run_pipeline(&requests, Duration::from_millis(5), &clock, |cmd, args| {
+            sent.push((clock.now_ms(), cmd, args[0]));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            sent,
+            vec![(0, 0x2E, 0x01), (5, 0x2E, 0x02), (10, 0x31, 0x03)]
+        );
+
+        // 100us ST_min, rounded up to the clock's millisecond. The failed request ends the pipeline
+        let clock = MockClock::new();
+        let mut attempts = 0;
+        let res = super::run_pipeline(&requests, Duration::from_micros(100), &clock, |_, _| {
+            attempts += 1;
+            match attempts {
+                1 => Ok(()),
+                _ => Err(ProtocolError::Timeout),
+            }
+        });
+        assert!(res.unwrap_err().is_timeout());
+        assert_eq!(attempts, 2);
+        assert_eq!(clock.now_ms(), 1);
+    }
+
+    #[test]
+    fn test_error_layer() {
+        use super::ErrorLayer;
+        use crate::commapi::comm_api::ComErrorKind;
+        assert_eq!(ProtocolError::Timeout.layer(), ErrorLayer::Transport);
+        assert_eq!(ProtocolError::BusOff.layer(), ErrorLayer::Transport);
+        assert_eq!(ProtocolError::NoAck.layer(), ErrorLayer::Transport);
+        // Transport errors are retried, unless the bus has to be recovered first
+        assert!(ProtocolError::NoAck.is_transient());
+        assert!(!ProtocolError::BusOff.is_transient());
+        let nack = ComServerError::from_kind(ComErrorKind::NoAck, "Rejected".into());
+        assert!(matches!(ProtocolError::from(nack), ProtocolError::NoAck));
+        let timeout = ComServerError::timeout("No response".into());
+        assert!(matches!(
+            ProtocolError::from(timeout),
+            ProtocolError::Timeout
+        ));
+        let nrc = ProtocolError::ProtocolError(Box::new(UDSNegativeCode::from_byte(0x31)));
+        assert_eq!(nrc.layer(), ErrorLayer::Protocol);
+        let short = ProtocolError::InvalidResponseSize {
+            expect: 3,
+            actual: 1,
+        };
+        assert_eq!(short.layer(), ErrorLayer::Protocol);
+        assert_eq!(
+            ProtocolError::CustomError("No VIN".into()).layer(),
+            ErrorLayer::Application
+        );
+    }
+
+    #[test]
+    fn test_scan_report_json() {
+        use super::{EcuReport, EcuVersions, VehicleReport};
+        let report = VehicleReport {
+            vin: Some("WDD2110161A123456".into()),
+            ecus: vec![
+                EcuReport {
+                    send_id: 0x7E0,
+                    recv_id: 0x7E8,
+                    name: Some("ECM".into()),
+                    protocol: Some(DiagProtocol::UDS),
+                    identity: Some(EcuVersions {
+                        software: Some("1037364152".into()),
+                        ..Default::default()
+                    }),
+                    dtcs: vec![DTC {
+                        error: "042013".into(),
+                        state: DTCState::Stored,
+                        check_engine_on: true,
+                        id: DtcCode::from_bytes(&[0x04, 0x20, 0x13]),
+                    }],
+                    error: None,
+                },
+                EcuReport {
+                    send_id: 0x7E1,
+                    recv_id: 0x7E9,
+                    name: None,
+                    protocol: None,
+                    identity: None,
+                    dtcs: vec![],
+                    error: Some("Timeout".into()),
+                },
+            ],
+        };
+        let json: serde_json::Value = serde_json::from_str(&report.to_json().unwrap()).unwrap();
+        assert_eq!(json["schema_version"], 1);
+        assert_eq!(json["vin"], "WDD2110161A123456");
+        let ecm = &json["ecus"][0];
+        assert_eq!(ecm["send_id"], 0x7E0);
+        assert_eq!(ecm["protocol"], "UDS");
+        assert_eq!(ecm["identity"]["software"], "1037364152");
+        assert_eq!(ecm["identity"]["hardware"], serde_json::Value::Null);
+        assert_eq!(ecm["dtcs"][0]["code"], "P0420");
+        assert_eq!(ecm["dtcs"][0]["failure_type"], 0x13);
+        assert_eq!(ecm["dtcs"][0]["status"], "stored");
+        assert_eq!(ecm["dtcs"][0]["mil"], true);
+        assert_eq!(json["ecus"][1]["error"], "Timeout");
+        assert_eq!(json["ecus"][1]["protocol"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_service_tables() {
+        use super::{describe_service, CautionLevel, CommandInfo, DiagProtocol, ECUCommand};
+        // Tables must agree with the service enums
+        fn check<T: ECUCommand>(protocol: DiagProtocol) {
+            for cmd in CommandInfo::list_for::<T>() {
+                let info = describe_service(protocol, cmd.sid).expect(&cmd.name);
+                assert_eq!(info.caution, cmd.caution, "{}", cmd.name);
+            }
+        }
+        check::<super::uds::UDSCommand>(DiagProtocol::UDS);
+        check::<super::kwp2000::Service>(DiagProtocol::KWP2000);
+        check::<super::obd2::OBDCmd>(DiagProtocol::OBD2);
+
+        let info = describe_service(DiagProtocol::UDS, 0x27).unwrap();
+        assert_eq!(info.name, "Security access");
+        assert_eq!(info.caution, CautionLevel::Alert);
+        assert_eq!(describe_service(DiagProtocol::UDS, 0xBA), None);
+    }
+
+    #[test]
+    fn test_protocol_variant_services() {
+        assert!(ProtocolVariant::Unknown.supports_service(0x29));
+        assert!(ProtocolVariant::Iso14229_2020.supports_service(0x29));
+        assert!(!ProtocolVariant::Iso14229_2013.supports_service(0x29));
+        assert!(ProtocolVariant::Iso14229_2013.supports_service(0x38));
+        assert!(!ProtocolVariant::Iso14229_2006.supports_service(0x38));
+        assert!(ProtocolVariant::Iso14229_2006.supports_service(0x22));
+    }
+
+    #[test]
+    fn test_nrc_overrides() {
+        let err = || ProtocolError::ProtocolError(Box::new(UDSNegativeCode::from_byte(0xF1)));
+        let standard = err().get_text();
+
+        let mut overrides = HashMap::new();
+        overrides.insert(0xF1, "Immobilizer not learned".to_string());
+        let res = super::apply_nrc_overrides(err(), &overrides);
+        assert_eq!(res.get_text(), "Immobilizer not learned");
+        assert_eq!(res.get_nrc(), Some(0xF1));
+
+        // Only the registered NRC is changed
+        overrides.clear();
+        overrides.insert(0x22, "Engine must be running".to_string());
+        assert_eq!(
+            super::apply_nrc_overrides(err(), &overrides).get_text(),
+            standard
+        );
+    }
+}
@@ -0,0 +1,131 @@
+use crate::commapi::{
+    comm_api::{ComServer, FilterType},
+    iface::{
+        BufferType, Interface, InterfaceConfig, InterfacePayload, IsoTPInterface, PayloadFlag,
+        IFACE_CFG,
+    },
+    protocols::{DiagCfg, ProtocolError, ProtocolResult, ProtocolVariant},
+};
+
+/// Transports OBD-II can be detected on.
+///
+/// Only ISO 15765 (CAN) can be probed. ISO 9141, ISO 14230 (K-Line) and J1850 are not
+/// supported yet, as the K-Line interfaces are not implemented
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ObdProtocol {
+    Iso15765Std500k, // 11bit CAN IDs, 500kbps
+    Iso15765Ext500k, // 29bit CAN IDs, 500kbps
+    Iso15765Std250k, // 11bit CAN IDs, 250kbps
+    Iso15765Ext250k, // 29bit CAN IDs, 250kbps
+}
+
+impl ObdProtocol {
+    /// Protocols in the order they are probed in
+    pub const ALL: [ObdProtocol; 4] = [
+        Self::Iso15765Std500k,
+        Self::Iso15765Ext500k,
+        Self::Iso15765Std250k,
+        Self::Iso15765Ext250k,
+    ];
+
+    pub fn get_baudrate(&self) -> u32 {
+        match self {
+            Self::Iso15765Std500k | Self::Iso15765Ext500k => 500_000,
+            Self::Iso15765Std250k | Self::Iso15765Ext250k => 250_000,
+        }
+    }
+
+    pub fn is_ext_can(&self) -> bool {
+        matches!(self, Self::Iso15765Ext500k | Self::Iso15765Ext250k)
+    }
+
+    /// Returns the IDs used to talk to the engine ECU. The send ID is the functional
+    /// (Broadcast) request ID
+    pub fn get_diag_cfg(&self) -> DiagCfg {
+        if self.is_ext_can() {
+            DiagCfg {
+                send_id: 0x18DB33F1,
+                recv_id: 0x18DAF110,
+                global_id: None,
+                variant: ProtocolVariant::Unknown,
+            }
+        } else {
+            DiagCfg {
+                send_id: 0x07DF,
+                recv_id: 0x07E8,
+                global_id: None,
+                variant: ProtocolVariant::Unknown,
+            }
+        }
+    }
+
+    /// Applies the protocol's bus settings on top of `cfg`
+    pub fn apply_to(&self, cfg: &InterfaceConfig) -> InterfaceConfig {
+        let mut res = cfg.clone();
+        res.add_param(IFACE_CFG::BAUDRATE, self.get_baudrate());
+        res.add_param(IFACE_CFG::EXT_CAN_ADDR, self.is_ext_can() as u32);
+        res
+    }
+}
+
+/// Probes each [ObdProtocol] with a Mode 01 PID 0x00 request, returning the first
+/// one which gets a valid response
+pub fn detect_protocol(
+    comm_server: &Box<dyn ComServer>,
+    interface_cfg: &InterfaceConfig,
+) -> ProtocolResult<ObdProtocol> {
+    for protocol in ObdProtocol::ALL.iter() {
+        println!("OBD - Probing {:?}", protocol);
+        match probe(comm_server, interface_cfg, *protocol) {
+            Ok(()) => {
+                println!("OBD - Detected {:?}", protocol);
+                return Ok(*protocol);
+            }
+            Err(e) => eprintln!("OBD - {:?} did not respond: {}", protocol, e.get_text()),
+        }
+    }
+    Err(ProtocolError::CustomError(
+        "No OBD-II protocol responded".into(),
+    ))
+}
+
+fn probe(
+    comm_server: &Box<dyn ComServer>,
+    interface_cfg: &InterfaceConfig,
+    protocol: ObdProtocol,
+) -> ProtocolResult<()> {
+    let diag_cfg = protocol.get_diag_cfg();
+    let cfg = protocol.apply_to(interface_cfg);
+    let mut iface = IsoTPInterface::new_for_cfg(comm_server.clone_box(), &cfg)?;
+    iface.setup(&cfg)?;
+    let res = probe_on(&mut iface, diag_cfg);
+    if let Err(e) = iface.close() {
+        eprintln!("Could not close ISO-TP interface after OBD probe: {}", e);
+    }
+    res
+}
+
+fn probe_on(iface: &mut Box<dyn Interface>, diag_cfg: DiagCfg) -> ProtocolResult<()> {
+    // Flow control goes to the engine ECU's physical ID
+    let fc = if diag_cfg.recv_id > 0x7FF {
+        0x18DA10F1
+    } else {
+        0x07E0
+    };
+    iface.add_filter(FilterType::IsoTP {
+        id: diag_cfg.recv_id,
+        mask: 0xFFFFFFFF,
+        fc,
+    })?;
+    iface.clear_buffer(BufferType::RX)?;
+    let mut req = InterfacePayload::new(diag_cfg.send_id, &[0x01, 0x00]);
+    req.flags = vec![PayloadFlag::ISOTP_PAD_FRAME];
+    let res = iface.send_recv_data(req, 0, 1000)?;
+    match res.data.as_slice() {
+        [0x41, 0x00, _, _, _, _, ..] => Ok(()),
+        _ => Err(ProtocolError::CustomError(format!(
+            "Invalid response to PID 0x00: {}",
+            res
+        ))),
+    }
+}
@@ -22,10 +22,14 @@ use self::{
 };
 
 use super::{
-    CommandError, DTCState, DiagCfg, ECUCommand, ProtocolResult, ProtocolServer, Selectable, DTC,
+    clock::Clock, join_diag_thread, response_payload, uds::UDSNegativeCode, CautionLevel,
+    CommandError, ConnectionInfo, DTCState, DiagCfg, DiagProtocol, DiagSessionState, DiagThread,
+    DtcCode, ECUCommand, ProtocolResult, ProtocolServer, ResponseOptions, Selectable, Stats, DTC,
 };
 
 pub mod codes;
+pub mod detect_protocol;
+pub mod readiness;
 pub mod service01;
 pub mod service02;
 pub mod service03;
@@ -39,6 +43,31 @@ pub mod service10;
 
 pub type OBDError<T> = ProtocolResult<T>;
 
+/// Name and caution level of each OBD-II service. See [OBDCmd] for why none need caution
+pub const OBD_SERVICES: &[(u8, &str, CautionLevel)] = &[
+    (0x01, "Show current data", CautionLevel::None),
+    (0x02, "Show freeze frame data", CautionLevel::None),
+    (0x03, "Show DTCs", CautionLevel::None),
+    (0x04, "Clear DTCs", CautionLevel::None),
+    (
+        0x05,
+        "Test results, O2 sensor monitoring",
+        CautionLevel::None,
+    ),
+    (0x06, "Test results, other monitoring", CautionLevel::None),
+    (0x07, "Show pending DTCs", CautionLevel::None),
+    (
+        0x08,
+        "Control operation of on-board systems",
+        CautionLevel::None,
+    ),
+    (0x09, "Request vehicle information", CautionLevel::None),
+    (0x0A, "Permanent DTCs", CautionLevel::None),
+];
+
+/// PID of service 01 reporting the engine speed
+const ENGINE_RPM_PID: u8 = 0x0C;
+
 // Helper function to get bits from byte array, in order MSB to LSB
 pub(crate) fn get_obd_bits(src: &[u8]) -> Vec<bool> {
     let mut res = Vec::new();
@@ -62,22 +91,41 @@ where
 #[derive(Debug, Clone, Copy)]
 pub enum ObdError {
     CmdNotSupported,
+    /// Negative response from the ECU, with its negative response code
+    NegativeResponse(u8),
 }
 
 impl CommandError for ObdError {
     fn get_desc(&self) -> String {
-        "OBD Command not supported by ECU".into()
+        match self {
+            ObdError::CmdNotSupported => "OBD Command not supported by ECU".into(),
+            // OBD-II uses the same negative response codes as UDS (ISO 15031-5)
+            ObdError::NegativeResponse(b) => UDSNegativeCode::from_byte(*b).get_desc(),
+        }
     }
 
     fn get_help(&self) -> Option<String> {
-        Some("OBD Command not supported".into())
+        match self {
+            ObdError::CmdNotSupported => Some("OBD Command not supported".into()),
+            ObdError::NegativeResponse(b) => UDSNegativeCode::from_byte(*b).get_help(),
+        }
+    }
+
+    fn get_byte(&self) -> u8 {
+        match self {
+            ObdError::CmdNotSupported => 0x11, // Service not supported
+            ObdError::NegativeResponse(b) => *b,
+        }
     }
 
-    fn from_byte(_b: u8) -> Self
+    fn from_byte(b: u8) -> Self
     where
         Self: Sized,
     {
-        Self::CmdNotSupported
+        match b {
+            0x11 => Self::CmdNotSupported,
+            _ => Self::NegativeResponse(b),
+        }
     }
 }
 
@@ -161,6 +209,11 @@ pub struct ObdServer {
     cmd_tx: Sender<(u8, Vec<u8>, bool)>,
     cmd_rx: Arc<Receiver<ProtocolResult<Vec<u8>>>>,
     cmd_mutex: Arc<Mutex<()>>,
+    stats: Arc<RwLock<Stats>>,
+    clear_engine_guard: Arc<AtomicBool>,
+    connection_info: ConnectionInfo,
+    clock: Arc<dyn Clock>,
+    thread: DiagThread,
     s01: Option<Service01>,
     s02: Option<Service02>,
     s03: Option<Service03>,
@@ -187,6 +240,25 @@ impl ObdServer {
         }
     }
 
+    /// Detects which transport the vehicle uses for OBD-II. See [detect_protocol::detect_protocol]
+    pub fn detect_protocol(
+        comm_server: &Box<dyn ComServer>,
+        interface_cfg: &InterfaceConfig,
+    ) -> ProtocolResult<detect_protocol::ObdProtocol> {
+        detect_protocol::detect_protocol(comm_server, interface_cfg)
+    }
+
+    /// Reads the readiness monitor status of the ECU (MIL, DTC count and monitor completion)
+    pub fn read_readiness(&self) -> ProtocolResult<service01::Readiness> {
+        if let Some(s) = &self.s01 {
+            s.get_readiness(self)
+        } else {
+            Err(ProtocolError::CustomError(
+                "Service not supported by ECU".into(),
+            ))
+        }
+    }
+
     /// Return type
     /// .0 - SID supported?
     /// .1 - SID ID
@@ -210,43 +282,93 @@ impl ObdServer {
         return res;
     }
 
-    // Used for services 03, 07 and 0A
-    fn decode_dtc_resp(&self, bytes: &[u8], state: DTCState, res: &mut Vec<DTC>) {
-        let num_dtcs = bytes[0];
-        if num_dtcs == 0 {
-            return;
+    pub fn get_dtc_desc(dtc: &DTC) -> String {
+        codes::get_dtc_desc(dtc)
+    }
+
+    /// Clears the stored DTCs and freeze frames (Service 04). This also turns off the MIL
+    pub fn clear_errors(&self) -> ProtocolResult<()> {
+        Service04::clear_dtcs(self)
+    }
+
+    /// Reads the engine speed in RPM (Service 01 PID 0x0C).
+    /// Returns None if the ECU does not report the engine speed
+    pub fn read_engine_rpm(&self) -> ProtocolResult<Option<u32>> {
+        match self.run_command(0x01, &[ENGINE_RPM_PID]) {
+            // SID, PID, then the speed in 1/4 RPM
+            Ok(r) if r.len() >= 4 => Ok(Some(((r[2] as u32) << 8 | r[3] as u32) / 4)),
+            Ok(r) => Err(ProtocolError::InvalidResponseSize {
+                expect: 4,
+                actual: r.len(),
+            }),
+            Err(e) if e.get_nrc().is_some() => Ok(None),
+            Err(e) => Err(e),
         }
-        for idx in 0..num_dtcs as usize {
-            let a = bytes[idx * 2 + 1];
-            let b = bytes[idx * 2 + 2];
-            let char = match (a & 0b11000000) >> 6 {
-                0 => 'P',
-                1 => 'C',
-                2 => 'B',
-                _ => 'U',
-            };
-
-            let second = match (a & 0b00001100) >> 4 {
-                0 => '0',
-                1 => '1',
-                2 => '2',
-                _ => '3',
-            };
-            // STD Hex representation
-            let third = format!("{:1X}", a & 0b00001111);
-            let fourth = format!("{:02X}", b);
-
-            res.push(DTC {
-                error: format!("{}{}{}{}", char, second, third, fourth),
-                state: state,
-                check_engine_on: state == DTCState::Stored || state == DTCState::Permanent,
-                id: (a as u32) << 8 | b as u32,
-            })
+    }
+
+    /// Runs a command, checking the length of the ECU's positive response if `expected_len`
+    /// is set. See [UDSECU::run_command_expect_len](super::uds::UDSECU::run_command_expect_len)
+    pub fn run_command_expect_len(
+        &self,
+        cmd: u8,
+        args: &[u8],
+        expected_len: Option<usize>,
+    ) -> ProtocolResult<Vec<u8>> {
+        let res = self.run_command(cmd, args)?;
+        match expected_len {
+            Some(len) if res.len() != len => Err(ProtocolError::InvalidResponseSize {
+                expect: len,
+                actual: res.len(),
+            }),
+            _ => Ok(res),
         }
     }
 
-    pub fn get_dtc_desc(dtc: &DTC) -> String {
-        codes::get_dtc_desc(dtc)
+    /// Sets if clearing DTCs should be refused whilst the engine is running (Off by default).
+    /// See [DiagServer::clear_errors](super::DiagServer::clear_errors)
+    pub fn set_clear_engine_guard(&self, enabled: bool) {
+        self.clear_engine_guard.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn is_clear_engine_guard_enabled(&self) -> bool {
+        self.clear_engine_guard.load(Ordering::Relaxed)
+    }
+
+    /// Clock used for the diag server's timeouts and delays
+    pub(crate) fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+
+    pub fn get_stats(&self) -> Stats {
+        *self.stats.read().unwrap()
+    }
+
+    pub fn reset_stats(&self) {
+        *self.stats.write().unwrap() = Stats::default();
+    }
+
+    /// Returns the parameters the diag server was started with
+    pub fn connection_info(&self) -> ConnectionInfo {
+        self.connection_info.clone()
+    }
+
+    /// OBD-II has no diagnostic sessions or security access, so the state is always
+    /// the same. Resuming it just starts a new diag server
+    pub fn get_state(&self) -> DiagSessionState {
+        DiagSessionState {
+            protocol: DiagProtocol::OBD2,
+            session_type: 0,
+            security_level: None,
+            p2_max_ms: None,
+            p2_ext_max_ms: None,
+        }
+    }
+
+    /// Stops the diag server, waiting for it to close the interface.
+    /// Returns the error closing the interface failed with, if any
+    pub fn close(&mut self) -> ProtocolResult<()> {
+        self.exit_diag_session();
+        join_diag_thread(&self.thread)
     }
 }
 
@@ -271,12 +393,15 @@ impl ProtocolServer for ObdServer {
         let mut dyn_interface =
             DynamicInterface::new(comm_server, interface_type, &interface_cfg)?.clone_box();
         if interface_type == InterfaceType::IsoTp {
-            dyn_interface.add_filter(FilterType::IsoTP {
-                id: diag_cfg.recv_id,
-                mask: 0xFFFF,
-                fc: diag_cfg.send_id,
+            super::close_on_err(&mut dyn_interface, |i| {
+                i.add_filter(FilterType::IsoTP {
+                    id: diag_cfg.recv_id,
+                    mask: 0xFFFF,
+                    fc: diag_cfg.send_id,
+                })
             })?;
         } else {
+            let _ = dyn_interface.close();
             return Err(ProtocolError::CustomError(
                 "OBD-II over ISO9141 is a WIP".into(),
             ));
@@ -297,8 +422,20 @@ impl ProtocolServer for ObdServer {
             Receiver<ProtocolResult<Vec<u8>>>,
         ) = mpsc::channel();
 
+        let stats = Arc::new(RwLock::new(Stats::default()));
+        let stats_t = stats.clone();
+        let resp_opts = ResponseOptions::new(&interface_cfg);
+        let clock = resp_opts.clock.clone();
+        let mut connection_info = ConnectionInfo::new(
+            DiagProtocol::OBD2,
+            interface_type,
+            &interface_cfg,
+            &diag_cfg,
+        );
+        connection_info.session_name = "OBD-II".into();
+
         let s_id = diag_cfg.send_id;
-        std::thread::spawn(move || {
+        let thread = std::thread::spawn(move || {
             println!("OBD2 server start!");
             while should_run_t.load(Ordering::Relaxed) {
                 if let Ok(data) = channel_tx_receiver.try_recv() {
@@ -309,6 +446,8 @@ impl ProtocolServer for ObdServer {
                         data.0,
                         &data.1,
                         data.2,
+                        &resp_opts,
+                        &stats_t,
                     );
                     if channel_rx_sender.send(res).is_err() {
                         *last_error_t.write().unwrap() =
@@ -319,7 +458,7 @@ impl ProtocolServer for ObdServer {
                 std::thread::sleep(std::time::Duration::from_micros(100))
             }
             println!("OBD2 Server stop!");
-            let _res = dyn_interface.close();
+            dyn_interface.close()
         });
 
         let mut server = ObdServer {
@@ -327,6 +466,11 @@ impl ProtocolServer for ObdServer {
             cmd_mutex: Arc::new(Mutex::new(())),
             cmd_rx: Arc::new(channel_rx_receiver),
             cmd_tx: channel_tx_sender,
+            stats,
+            clear_engine_guard: Arc::new(AtomicBool::new(false)),
+            connection_info,
+            clock,
+            thread: Arc::new(Mutex::new(Some(thread))),
             s01: None,
             s02: None,
             s03: None,
@@ -338,7 +482,7 @@ impl ProtocolServer for ObdServer {
             s09: None,
             s10: None,
         };
-        std::thread::sleep(std::time::Duration::from_millis(100)); // Wait for diag server to start
+        server.clock.sleep_ms(100); // Wait for diag server to start
 
         if let Some(r) = Service01::init(&server) {
             server.s01 = Some(r)
@@ -359,34 +503,24 @@ impl ProtocolServer for ObdServer {
         if self.cmd_tx.send((cmd, Vec::from(args), true)).is_err() {
             return Err(ProtocolError::CustomError("Channel Tx failed".into()));
         }
-        let resp = self.cmd_rx.recv().unwrap()?;
-        if resp[0] == 0x7F {
-            Err(ProtocolError::ProtocolError(Box::new(ObdError::from_byte(
-                0,
-            ))))
-        } else {
-            Ok(resp)
+        let resp = match self.cmd_rx.recv() {
+            Ok(r) => r?,
+            Err(_) => return Err(ProtocolError::CustomError("Channel Rx failed".into())),
+        };
+        match resp.first() {
+            None => Err(ProtocolError::InvalidResponseSize {
+                expect: 1,
+                actual: 0,
+            }),
+            Some(0x7F) => Err(ProtocolError::ProtocolError(Box::new(ObdError::from_byte(
+                super::negative_response_code(&resp),
+            )))),
+            Some(_) => Ok(resp),
         }
     }
 
     fn read_errors(&self) -> super::ProtocolResult<Vec<super::DTC>> {
-        let mut res: Vec<DTC> = Vec::new();
-        if let Ok(resp) = self.run_command(0x03, &[]) {
-            //  Stored DTCs
-            println!("S03: {:02X?}", resp);
-            self.decode_dtc_resp(&resp[1..], DTCState::Stored, &mut res);
-        }
-        if let Ok(resp) = self.run_command(0x07, &[]) {
-            // Pending DTCs
-            println!("S07: {:02X?}", resp);
-            self.decode_dtc_resp(&resp[1..], DTCState::Pending, &mut res);
-        }
-        if let Ok(resp) = self.run_command(0x0A, &[]) {
-            // Permanent DTCs
-            println!("S0A: {:02X?}", resp);
-            self.decode_dtc_resp(&resp[1..], DTCState::Permanent, &mut res);
-        }
-        return Ok(res);
+        read_dtc_modes(|mode| self.run_command(mode, &[]))
     }
 
     fn is_in_diag_session(&self) -> bool {
@@ -398,8 +532,82 @@ impl ProtocolServer for ObdServer {
     }
 }
 
+// Used for services 03, 07 and 0A. The number of DTCs, followed by 2 bytes per DTC
+fn decode_dtc_resp(bytes: &[u8], state: DTCState, res: &mut Vec<DTC>) {
+    let num_dtcs = bytes.first().copied().unwrap_or(0) as usize;
+    let records = bytes.get(1..).unwrap_or_default().chunks_exact(2);
+    if records.len() < num_dtcs {
+        eprintln!(
+            "OBD - ECU reported {} DTCs, but only returned {}",
+            num_dtcs,
+            records.len()
+        );
+    }
+    for record in records.take(num_dtcs) {
+        let id = DtcCode::from_obd_bytes(&[record[0], record[1]]);
+        res.push(DTC {
+            error: id.as_obd_string(),
+            state,
+            check_engine_on: state == DTCState::Stored || state == DTCState::Permanent,
+            id,
+        })
+    }
+}
+
+/// Reads the stored DTCs (Mode 03), then the pending (Mode 07) and permanent (Mode 0A) DTCs,
+/// running each mode with `run`. Older ECUs may not support modes 07 and 0A, and either reject
+/// them or never respond, so those modes are skipped on a negative response or a timeout
+fn read_dtc_modes<F>(mut run: F) -> ProtocolResult<Vec<DTC>>
+where
+    F: FnMut(u8) -> ProtocolResult<Vec<u8>>,
+{
+    let mut res: Vec<DTC> = Vec::new();
+    let resp = run(0x03)?;
+    decode_dtc_resp(response_payload(&resp), DTCState::Stored, &mut res);
+    for (mode, state) in &[(0x07, DTCState::Pending), (0x0A, DTCState::Permanent)] {
+        match run(*mode) {
+            Ok(resp) => decode_dtc_resp(response_payload(&resp), *state, &mut res),
+            Err(e) if e.is_timeout() || e.get_nrc().is_some() => eprintln!(
+                "OBD - Skipping mode {:02X}, as the ECU does not support it: {}",
+                mode,
+                e.get_text()
+            ),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(res)
+}
+
 impl Drop for ObdServer {
     fn drop(&mut self) {
         self.exit_diag_session();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{read_dtc_modes, CommandError, DTCState, ObdError, ProtocolError};
+
+    #[test]
+    fn test_read_dtc_modes() {
+        // Mode 07 times out, and mode 0A is rejected. The stored DTCs are still returned
+        let res = read_dtc_modes(|mode| match mode {
+            0x03 => Ok(vec![0x43, 0x02, 0x01, 0x01, 0x03, 0x00]),
+            0x07 => Err(ProtocolError::Timeout),
+            _ => Err(ProtocolError::ProtocolError(Box::new(ObdError::from_byte(
+                0x12,
+            )))),
+        })
+        .unwrap();
+        let codes: Vec<&str> = res.iter().map(|d| d.error.as_str()).collect();
+        assert_eq!(codes, vec!["P0101", "P0300"]);
+        assert!(res.iter().all(|d| d.state == DTCState::Stored));
+
+        // Transport errors are not skipped
+        let res = read_dtc_modes(|mode| match mode {
+            0x03 => Ok(vec![0x43, 0x00]),
+            _ => Err(ProtocolError::BusOff),
+        });
+        assert!(matches!(res, Err(ProtocolError::BusOff)));
+    }
+}
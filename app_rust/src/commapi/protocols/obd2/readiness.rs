@@ -0,0 +1,161 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufReader, BufWriter},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::commapi::protocols::ProtocolResult;
+
+use super::{service01::Readiness, ObdServer};
+
+// A drive cycle often has to be split over several trips (Or days) before every readiness
+// monitor completes, so the tracker state is kept in a JSON file between sessions, and
+// each session continues where the last one stopped
+
+/// A readiness monitor which was seen going from incomplete to complete
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MonitorCompletion {
+    pub name: String,
+    /// Seconds since the Unix epoch of the first sample in which the monitor was complete
+    pub completed_at: u64,
+}
+
+/// Tracks the readiness monitors (Service 01 PID 01) over a drive cycle, recording
+/// when each monitor completes.
+///
+/// Monitors which are already complete in the first sample are not reported, as
+/// it is unknown when they completed
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReadinessTracker {
+    /// Completion state of each supported monitor in the last sample
+    monitors: BTreeMap<String, bool>,
+    /// Every completion seen, in the order they were seen
+    completions: Vec<MonitorCompletion>,
+    /// Time of the first sample (Seconds since the Unix epoch)
+    started_at: Option<u64>,
+    /// Time of the last sample (Seconds since the Unix epoch)
+    last_sample_at: Option<u64>,
+}
+
+fn unix_time_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl ReadinessTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Restores a tracker which was saved with [ReadinessTracker::save]
+    pub fn load(path: &str) -> std::io::Result<Self> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+
+    /// Saves the tracker so the drive cycle can be continued in a later session
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        Ok(serde_json::to_writer_pretty(
+            BufWriter::new(File::create(path)?),
+            self,
+        )?)
+    }
+
+    /// Records a readiness sample taken at `timestamp` (Seconds since the Unix epoch),
+    /// returning the monitors which completed since the last sample.
+    ///
+    /// A monitor going back to incomplete (For example, after the DTCs were cleared)
+    /// is reported again when it next completes
+    pub fn record(&mut self, readiness: &Readiness, timestamp: u64) -> Vec<MonitorCompletion> {
+        let first_sample = self.last_sample_at.is_none();
+        self.started_at.get_or_insert(timestamp);
+        self.last_sample_at = Some(timestamp);
+        let mut res = Vec::new();
+        for m in readiness
+            .continuous
+            .iter()
+            .chain(readiness.non_continuous.iter())
+            .filter(|m| m.supported)
+        {
+            let was_complete = self.monitors.insert(m.name.to_string(), m.complete);
+            if m.complete && !first_sample && was_complete != Some(true) {
+                res.push(MonitorCompletion {
+                    name: m.name.to_string(),
+                    completed_at: timestamp,
+                })
+            }
+        }
+        self.completions.extend(res.iter().cloned());
+        res
+    }
+
+    /// Reads the readiness monitors from the ECU and records them. See [ReadinessTracker::record]
+    pub fn sample(&mut self, server: &ObdServer) -> ProtocolResult<Vec<MonitorCompletion>> {
+        let readiness = server.read_readiness()?;
+        Ok(self.record(&readiness, unix_time_now()))
+    }
+
+    /// Every completion recorded since the tracker was created
+    pub fn completions(&self) -> &[MonitorCompletion] {
+        &self.completions
+    }
+
+    /// Supported monitors which were incomplete in the last sample
+    pub fn incomplete(&self) -> Vec<&str> {
+        self.monitors
+            .iter()
+            .filter(|(_, complete)| !**complete)
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    /// True if every supported monitor was complete in the last sample
+    pub fn is_ready(&self) -> bool {
+        self.last_sample_at.is_some() && self.monitors.values().all(|c| *c)
+    }
+
+    /// Time of the first sample (Seconds since the Unix epoch)
+    pub fn started_at(&self) -> Option<u64> {
+        self.started_at
+    }
+
+    /// Time of the last sample (Seconds since the Unix epoch)
+    pub fn last_sample_at(&self) -> Option<u64> {
+        self.last_sample_at
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Readiness, ReadinessTracker};
+
+    #[test]
+    fn test_readiness_tracker() {
+        let mut tracker = ReadinessTracker::new();
+        // Misfire and catalyst monitors incomplete. The first sample never reports completions
+        let res = tracker.record(&Readiness::from_bytes(0x00, 0x17, 0x01, 0x01), 100);
+        assert!(res.is_empty());
+        assert_eq!(tracker.incomplete(), vec!["Catalyst", "Misfire"]);
+
+        // State survives being saved and restored between sessions
+        let json = serde_json::to_string(&tracker).unwrap();
+        let mut tracker: ReadinessTracker = serde_json::from_str(&json).unwrap();
+
+        let res = tracker.record(&Readiness::from_bytes(0x00, 0x07, 0x01, 0x00), 200);
+        let names: Vec<&str> = res.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["Misfire", "Catalyst"]);
+        assert!(res.iter().all(|c| c.completed_at == 200));
+        assert!(tracker.is_ready());
+        assert_eq!(tracker.started_at(), Some(100));
+
+        // Already complete monitors are not reported again
+        assert!(tracker
+            .record(&Readiness::from_bytes(0x00, 0x07, 0x01, 0x00), 300)
+            .is_empty());
+        assert_eq!(tracker.completions().len(), 2);
+    }
+}
@@ -882,6 +882,91 @@ impl PidList {
     }
 }
 
+/// Status of a single OBD readiness monitor
+#[derive(Debug, Copy, Clone)]
+pub struct MonitorStatus {
+    pub name: &'static str,
+    pub supported: bool,
+    pub complete: bool,
+}
+
+/// Decoded readiness monitor status (Service 01 PID 01)
+#[derive(Debug, Clone)]
+pub struct Readiness {
+    /// Malfunction indicator lamp (Check engine light) on?
+    pub mil_on: bool,
+    /// Number of emissions related DTCs stored
+    pub dtc_count: u8,
+    /// True for diesel (compression ignition) engines, false for petrol (spark ignition)
+    pub compression_ignition: bool,
+    /// Monitors that run continuously (Misfire, fuel system, components)
+    pub continuous: Vec<MonitorStatus>,
+    /// Monitors that only run under certain conditions. These differ between
+    /// spark and compression ignition engines
+    pub non_continuous: Vec<MonitorStatus>,
+}
+
+impl Readiness {
+    const CONTINUOUS: [&'static str; 3] = ["Misfire", "Fuel system", "Components"];
+    const SPARK_MONITORS: [&'static str; 8] = [
+        "Catalyst",
+        "Heated catalyst",
+        "Evaporative system",
+        "Secondary air system",
+        "A/C refrigerant",
+        "Oxygen sensor",
+        "Oxygen sensor heater",
+        "EGR system",
+    ];
+    const COMPRESSION_MONITORS: [&'static str; 8] = [
+        "NMHC catalyst",
+        "NOx/SCR monitor",
+        "Reserved",
+        "Boost pressure",
+        "Reserved",
+        "Exhaust gas sensor",
+        "PM filter",
+        "EGR and/or VVT system",
+    ];
+
+    /// Decodes the 4 data bytes (A-D) of PID 01
+    pub fn from_bytes(a: u8, b: u8, c: u8, d: u8) -> Self {
+        let compression_ignition = b & 0b00001000 != 0;
+        // Monitor test incomplete bits are set when the monitor is NOT complete
+        let continuous = Self::CONTINUOUS
+            .iter()
+            .enumerate()
+            .map(|(bit, name)| MonitorStatus {
+                name,
+                supported: b & (1 << bit) != 0,
+                complete: b & (1 << (bit + 4)) == 0,
+            })
+            .collect();
+        let names = if compression_ignition {
+            Self::COMPRESSION_MONITORS
+        } else {
+            Self::SPARK_MONITORS
+        };
+        let non_continuous = names
+            .iter()
+            .enumerate()
+            .filter(|(_, name)| **name != "Reserved")
+            .map(|(bit, name)| MonitorStatus {
+                name,
+                supported: c & (1 << bit) != 0,
+                complete: d & (1 << bit) == 0,
+            })
+            .collect();
+        Self {
+            mil_on: a & 0b10000000 != 0,
+            dtc_count: a & 0b01111111,
+            compression_ignition,
+            continuous,
+            non_continuous,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Service01 {
     supported_pids: Vec<bool>,
@@ -964,6 +1049,20 @@ impl Service01 {
         Ok(PID_LIST.parse_pid(pid, &bytes[2..]))
     }
 
+    pub fn get_readiness(&self, s: &ObdServer) -> OBDError<Readiness> {
+        self.check_service_supported(0x01)?;
+        let bytes = s.run_command(0x01, &[0x01])?;
+        if bytes.len() < 6 {
+            return Err(ProtocolError::InvalidResponseSize {
+                expect: 6,
+                actual: bytes.len(),
+            });
+        }
+        Ok(Readiness::from_bytes(
+            bytes[2], bytes[3], bytes[4], bytes[5],
+        ))
+    }
+
     pub fn get_supported_chartable_pids(&self) -> Vec<(u8, Vec<&'static str>)> {
         (0x01..0xFF as u8)
             .filter(|x| self.check_service_supported(*x).is_ok())
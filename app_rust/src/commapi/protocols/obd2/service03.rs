@@ -1,4 +1,4 @@
-use crate::commapi::protocols::{DTCState, ProtocolServer, DTC};
+use crate::commapi::protocols::{DTCState, DtcCode, ProtocolServer, DTC};
 
 use super::{OBDError, ObdServer};
 
@@ -24,7 +24,7 @@ impl Service03 {
                 error: format!("{}{:1X}{:1X}{:2X}", prefix, n1, n2, bytes[1]),
                 state: DTCState::Stored, // TODO Fix this
                 check_engine_on: true,
-                id: bytes[1] as u32,
+                id: DtcCode::from_obd_bytes(&[bytes[0], bytes[1]]),
             };
             bytes.drain(0..2);
             res.push(dtc);
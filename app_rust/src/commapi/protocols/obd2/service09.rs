@@ -1,4 +1,7 @@
-use crate::commapi::protocols::{ProtocolError, ProtocolServer};
+use crate::commapi::{
+    iface::{format_hex_compact, hex_dump},
+    protocols::{ProtocolError, ProtocolServer},
+};
 
 use super::{get_obd_bits, OBDError, ObdError, ObdServer, ObdService};
 
@@ -19,7 +22,7 @@ impl ObdService for Service09 {
     fn init(s: &ObdServer) -> Option<Self> {
         println!("Attempt init service 09!");
         let res = s.run_command(0x09, &[0x00]).ok()?;
-        println!("Service 09 init bytes: {:02X?}", &res[2..]); // Drop SID and CID
+        println!("Service 09 init bytes: {}", hex_dump(&res[2..])); // Drop SID and CID
         let bits = get_obd_bits(&res[2..]);
         let s09 = Service09 {
             supported_pids: bits,
@@ -91,10 +94,7 @@ impl Service09 {
             // Valid number of bytes
             for _ in 0..count {
                 // CVN format
-                res.push(format!(
-                    "{:02X}{:02X}{:02X}{:02X}",
-                    raw[0], raw[1], raw[2], raw[3]
-                ));
+                res.push(format_hex_compact(&raw[0..4]));
                 raw.drain(0..4);
             }
             Ok(res)
@@ -0,0 +1,222 @@
+//! Plain text fault memory reports, for pasting into tickets or sharing with colleagues,
+//! and JSON exports of vehicle scans, for other scan tools and report generators
+
+use std::{collections::HashMap, fmt::Write};
+
+use common::schema::{diag::dtc::ECUDTC, variant::ECUVariantDefinition};
+use serde::{Deserialize, Serialize};
+
+use crate::commapi::iface::hex_dump;
+
+use super::{DTCState, DiagProtocol, DiagServer, EcuReport, ProtocolResult, VehicleReport, DTC};
+
+/// DTC descriptions, used to resolve the DTCs read from an ECU
+#[derive(Debug, Clone, Default)]
+pub struct DtcDatabase {
+    errors: Vec<ECUDTC>,
+}
+
+impl DtcDatabase {
+    pub fn new(errors: Vec<ECUDTC>) -> Self {
+        Self { errors }
+    }
+
+    /// Creates a database from the DTC list of an ECU variant
+    pub fn from_variant(variant: &ECUVariantDefinition) -> Self {
+        Self::new(variant.errors.clone())
+    }
+
+    /// Looks up the description of a DTC. See [DTC::matches_name]
+    pub fn lookup(&self, dtc: &DTC) -> Option<&ECUDTC> {
+        self.errors.iter().find(|x| dtc.matches_name(&x.error_name))
+    }
+}
+
+/// Reads the fault memory of the ECU and formats it as a multi-line report.
+///
+/// Identity and environment data are best effort. If the ECU rejects those
+/// requests, the report says so rather than failing. Only reading the
+/// DTCs themselves must succeed
+pub fn fault_report(server: &DiagServer, db: Option<&DtcDatabase>) -> ProtocolResult<String> {
+    let dtcs = server.read_errors_with_env()?;
+    let mut report = String::new();
+    // Writing to a String cannot fail
+    let _ = write_header(&mut report, server);
+    let _ = writeln!(report, "Stored DTCs: {}", dtcs.len());
+    for (dtc, env) in &dtcs {
+        let _ = write_dtc(&mut report, dtc, env, db);
+    }
+    Ok(report)
+}
+
+fn write_header(report: &mut String, server: &DiagServer) -> std::fmt::Result {
+    writeln!(report, "=== Fault memory report ===")?;
+    writeln!(report, "{}", server.connection_info())?;
+    match server.read_vin() {
+        Ok(vin) => writeln!(report, "VIN: {}", vin)?,
+        Err(e) => writeln!(report, "VIN: Unavailable ({})", e.get_text())?,
+    }
+    match server.get_variant_id() {
+        Ok(id) => writeln!(report, "Variant ID: 0x{:06X}", id)?,
+        Err(e) => writeln!(report, "Variant ID: Unavailable ({})", e.get_text())?,
+    }
+    writeln!(report)
+}
+
+fn write_dtc(
+    report: &mut String,
+    dtc: &DTC,
+    env: &[u8],
+    db: Option<&DtcDatabase>,
+) -> std::fmt::Result {
+    match db.and_then(|db| db.lookup(dtc)) {
+        Some(desc) => {
+            writeln!(report, "{} - {}", desc.error_name, desc.summary)?;
+            if !desc.description.is_empty() {
+                writeln!(report, "    {}", desc.description)?;
+            }
+        }
+        None => writeln!(report, "{} - Unknown DTC", dtc.error)?,
+    }
+    writeln!(
+        report,
+        "    Status: {:?}, MIL: {}",
+        dtc.state,
+        if dtc.check_engine_on { "On" } else { "Off" }
+    )?;
+    if !env.is_empty() {
+        writeln!(report, "    Environment data: {}", hex_dump(env))?;
+    }
+    Ok(())
+}
+
+/// Version of the JSON export schema ([ScanExport]). Bumped whenever a field is
+/// removed or changes meaning. Adding fields does not change the version
+pub const SCAN_EXPORT_SCHEMA_VERSION: u32 = 1;
+
+/// JSON export of a [VehicleReport]
+///
+/// ```text
+/// {
+///   "schema_version": 1,
+///   "vin": "WDD2110161A123456" | null,
+///   "ecus": [{
+///     "send_id": 2016, "recv_id": 2024,          CAN IDs
+///     "name": "ECM" | null,
+///     "protocol": "UDS" | "KWP2000" | null,      null if the ECU could not be connected to
+///     "identity": { "hardware", "software", "bootloader",
+///                   "serial_number", "manufacturing_date" } | null,   Each a string or null
+///     "dtcs": [{
+///       "code": "P0420",                         SAE J2012 code
+///       "failure_type": 19,                      0 if the code has none
+///       "status": "stored" | "pending" | "permanent" | "none",
+///       "mil": true,                             Warning lamp requested
+///       "description": "..." | null
+///     }],
+///     "error": "..." | null                      Why the ECU could not be scanned
+///   }]
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanExport {
+    pub schema_version: u32,
+    pub vin: Option<String>,
+    pub ecus: Vec<EcuExport>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EcuExport {
+    pub send_id: u32,
+    pub recv_id: u32,
+    pub name: Option<String>,
+    pub protocol: Option<String>,
+    pub identity: Option<IdentityExport>,
+    pub dtcs: Vec<DtcExport>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdentityExport {
+    pub hardware: Option<String>,
+    pub software: Option<String>,
+    pub bootloader: Option<String>,
+    pub serial_number: Option<String>,
+    pub manufacturing_date: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DtcExport {
+    pub code: String,
+    pub failure_type: u8,
+    pub status: String,
+    pub mil: bool,
+    pub description: Option<String>,
+}
+
+fn export_dtc(dtc: &DTC, db: Option<&DtcDatabase>) -> DtcExport {
+    let status = match dtc.state {
+        DTCState::Stored => "stored",
+        DTCState::Pending => "pending",
+        DTCState::Permanent => "permanent",
+        DTCState::None => "none",
+    };
+    DtcExport {
+        code: dtc.id.as_obd_string(),
+        failure_type: dtc.id.failure_type(),
+        status: status.into(),
+        mil: dtc.check_engine_on,
+        description: db.and_then(|db| db.lookup(dtc)).map(|d| d.summary.clone()),
+    }
+}
+
+fn export_ecu(ecu: &EcuReport, db: Option<&DtcDatabase>) -> EcuExport {
+    EcuExport {
+        send_id: ecu.send_id,
+        recv_id: ecu.recv_id,
+        name: ecu.name.clone(),
+        protocol: ecu.protocol.map(|p| match p {
+            DiagProtocol::KWP2000 => "KWP2000".into(),
+            DiagProtocol::UDS => "UDS".into(),
+            DiagProtocol::OBD2 => "OBD2".into(),
+        }),
+        identity: ecu.identity.as_ref().map(|v| IdentityExport {
+            hardware: v.hardware.clone(),
+            software: v.software.clone(),
+            bootloader: v.bootloader.clone(),
+            serial_number: v.serial_number.clone(),
+            manufacturing_date: v.manufacturing_date.clone(),
+        }),
+        dtcs: ecu.dtcs.iter().map(|d| export_dtc(d, db)).collect(),
+        error: ecu.error.clone(),
+    }
+}
+
+impl VehicleReport {
+    /// Converts the report to the export schema. DTC descriptions are looked up in
+    /// the database of each ECU in `dbs` (Keyed by the ECU's send ID)
+    pub fn to_export(&self, dbs: &HashMap<u32, DtcDatabase>) -> ScanExport {
+        ScanExport {
+            schema_version: SCAN_EXPORT_SCHEMA_VERSION,
+            vin: self.vin.clone(),
+            ecus: self
+                .ecus
+                .iter()
+                .map(|e| export_ecu(e, dbs.get(&e.send_id)))
+                .collect(),
+        }
+    }
+
+    /// Exports the report as JSON, without DTC descriptions. See [ScanExport] for the schema
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        self.to_json_with_descriptions(&HashMap::new())
+    }
+
+    /// Exports the report as JSON, describing DTCs with the database of each ECU in `dbs`.
+    /// See [VehicleReport::to_export]
+    pub fn to_json_with_descriptions(
+        &self,
+        dbs: &HashMap<u32, DtcDatabase>,
+    ) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(&self.to_export(dbs))
+    }
+}
@@ -0,0 +1,99 @@
+//! Seed and key algorithms for security access (0x27).
+//!
+//! Key computation is ECU specific, however some simple published algorithms are
+//! reused by many ECUs. Proprietary algorithms can be supplied as a closure, which
+//! also implements [KeyAlgorithm].
+
+use super::{ProtocolError, ProtocolResult};
+
+pub trait KeyAlgorithm {
+    /// Computes the key to send for `seed`, which the ECU returned for security `level`
+    fn compute_key(&self, level: u8, seed: &[u8]) -> ProtocolResult<Vec<u8>>;
+}
+
+impl<F> KeyAlgorithm for F
+where
+    F: Fn(u8, &[u8]) -> Vec<u8>,
+{
+    fn compute_key(&self, level: u8, seed: &[u8]) -> ProtocolResult<Vec<u8>> {
+        Ok(self(level, seed))
+    }
+}
+
+/// Reads a seed of up to 4 bytes as a big endian number
+fn seed_to_u32(seed: &[u8]) -> ProtocolResult<u32> {
+    if seed.is_empty() || seed.len() > 4 {
+        return Err(ProtocolError::CustomError(format!(
+            "Key algorithm requires a 1-4 byte seed, got {} bytes",
+            seed.len()
+        )));
+    }
+    Ok(seed.iter().fold(0u32, |acc, b| acc << 8 | *b as u32))
+}
+
+/// Writes `key` as a big endian number with the same length as the seed
+fn u32_to_key(key: u32, len: usize) -> Vec<u8> {
+    key.to_be_bytes()[4 - len..].to_vec()
+}
+
+/// Key is the seed XORed with the mask. The mask is repeated over longer seeds
+#[derive(Debug, Clone)]
+pub struct XorMask {
+    pub mask: Vec<u8>,
+}
+
+impl KeyAlgorithm for XorMask {
+    fn compute_key(&self, _level: u8, seed: &[u8]) -> ProtocolResult<Vec<u8>> {
+        if self.mask.is_empty() {
+            return Err(ProtocolError::CustomError("XOR mask is empty".into()));
+        }
+        Ok(seed
+            .iter()
+            .zip(self.mask.iter().cycle())
+            .map(|(s, m)| s ^ m)
+            .collect())
+    }
+}
+
+/// Key is the seed (Up to 4 bytes, big endian) plus the mask, wrapping on overflow
+#[derive(Debug, Copy, Clone)]
+pub struct AddMask {
+    pub mask: u32,
+}
+
+impl KeyAlgorithm for AddMask {
+    fn compute_key(&self, _level: u8, seed: &[u8]) -> ProtocolResult<Vec<u8>> {
+        let key = seed_to_u32(seed)?.wrapping_add(self.mask);
+        Ok(u32_to_key(key, seed.len()))
+    }
+}
+
+/// The common rotate-shift "Seed & Key" algorithm. For each round, the seed (Up to 4 bytes,
+/// big endian) is shifted left by one bit, and XORed with the mask if the bit shifted
+/// out was set
+#[derive(Debug, Copy, Clone)]
+pub struct ShiftXor {
+    pub mask: u32,
+    pub rounds: u8,
+}
+
+impl KeyAlgorithm for ShiftXor {
+    fn compute_key(&self, _level: u8, seed: &[u8]) -> ProtocolResult<Vec<u8>> {
+        let bits = seed.len() as u32 * 8;
+        let top_bit = 1u32 << (bits.max(8) - 1);
+        let value_mask = if bits >= 32 {
+            u32::MAX
+        } else {
+            (1 << bits) - 1
+        };
+        let mut key = seed_to_u32(seed)?;
+        for _ in 0..self.rounds {
+            let carry = key & top_bit != 0;
+            key = (key << 1) & value_mask;
+            if carry {
+                key ^= self.mask & value_mask;
+            }
+        }
+        Ok(u32_to_key(key, seed.len()))
+    }
+}
@@ -0,0 +1,66 @@
+use crate::commapi::protocols::{ProtocolError, ProtocolResult, ProtocolServer};
+
+use super::UDSECU;
+
+// The service, Access Timing Parameter ($83), is used to read and change the timing parameters
+// of the active diagnostic session. The timing parameter record is the same format as in the
+// Diagnostic Session Control ($10) positive response, P2 in 1ms resolution and P2* in 10ms resolution.
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TimingValues {
+    /// Max time for the ECU to start its response
+    pub p2_max_ms: u16,
+    /// Max time for the ECU to start its response after sending a response pending
+    pub p2_ext_max_ms: u32,
+}
+
+impl TimingValues {
+    fn from_record(record: &[u8]) -> Option<Self> {
+        if record.len() < 4 {
+            return None;
+        }
+        Some(Self {
+            p2_max_ms: (record[0] as u16) << 8 | record[1] as u16,
+            p2_ext_max_ms: ((record[2] as u32) << 8 | record[3] as u32) * 10,
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SessionTimings {
+    /// Timings the ECU is currently using
+    pub current: TimingValues,
+    /// Extended (Limit) timings that the ECU supports, if the ECU reports them
+    pub limits: Option<TimingValues>,
+}
+
+fn read_timing_set(ecu: &UDSECU, sub_fn: u8) -> ProtocolResult<TimingValues> {
+    let res = ecu.run_command(super::UDSCommand::AccessTimingParameter.into(), &[sub_fn])?;
+    TimingValues::from_record(&res[std::cmp::min(2, res.len())..]).ok_or(
+        ProtocolError::InvalidResponseSize {
+            expect: 6,
+            actual: res.len(),
+        },
+    )
+}
+
+/// Reads the timings of the active session using Access Timing Parameter.
+///
+/// If the ECU does not support reading the currently active timings, then this falls back
+/// to the timings the ECU reported when the current session was started
+pub fn read_timing(ecu: &UDSECU) -> ProtocolResult<SessionTimings> {
+    let current = match read_timing_set(ecu, 0x03) {
+        // readCurrentlyActiveTimingParameters
+        Ok(t) => t,
+        Err(e) => match *ecu.session_timing.read().unwrap() {
+            Some((p2, p2_ext)) => TimingValues {
+                p2_max_ms: p2,
+                p2_ext_max_ms: p2_ext,
+            },
+            None => return Err(e),
+        },
+    };
+    // readExtendedTimingParameterSet
+    let limits = read_timing_set(ecu, 0x01).ok();
+    Ok(SessionTimings { current, limits })
+}
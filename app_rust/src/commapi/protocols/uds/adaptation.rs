@@ -0,0 +1,135 @@
+use std::{collections::BTreeMap, fmt::Display};
+
+use crate::commapi::{
+    iface::hex_dump,
+    protocols::{CommandError, ProtocolError, ProtocolResult},
+};
+
+use super::{diag_session_control::DiagSession, read_data, write_data, UDSNegativeCode, UDSECU};
+
+// Adaptation channels and long coding (VAG style) are stored in data identifiers.
+// Reading them is allowed in any session, but writing them requires a non default
+// session, and usually security access.
+
+/// Channel of the long coding string
+pub const LONG_CODING_CHANNEL: u16 = 0;
+
+/// DID the long coding is stored in
+const LONG_CODING_DID: u16 = 0x0600;
+
+/// Returns the DID an adaptation channel is stored in
+pub fn adaptation_did(channel: u16) -> u16 {
+    match channel {
+        LONG_CODING_CHANNEL => LONG_CODING_DID,
+        _ => channel,
+    }
+}
+
+/// Reads the value of an adaptation channel
+pub fn read_adaptation(ecu: &UDSECU, channel: u16) -> ProtocolResult<Vec<u8>> {
+    read_data::read_did(ecu, adaptation_did(channel))
+}
+
+/// Writes the value of an adaptation channel. The ECU must be in a non default
+/// session, and have security access granted for most ECUs
+pub fn write_adaptation(ecu: &UDSECU, channel: u16, value: &[u8]) -> ProtocolResult<()> {
+    if ecu.get_session_type() == DiagSession::Default {
+        return Err(ProtocolError::CustomError(format!(
+            "Adaptation channel {} can only be written in the extended session",
+            channel
+        )));
+    }
+    write_data::write_did(ecu, adaptation_did(channel), value).map_err(|e| {
+        let nrc = e.get_nrc();
+        if nrc == Some(UDSNegativeCode::SecurityAccessDenied.get_byte()) {
+            ProtocolError::CustomError(format!(
+                "Adaptation channel {} is locked. Unlock the ECU with security access first",
+                channel
+            ))
+        } else if nrc == Some(UDSNegativeCode::ServiceNotSupportedActiveSession.get_byte()) {
+            ProtocolError::CustomError(format!(
+                "ECU does not allow writing adaptation channel {} in the current session",
+                channel
+            ))
+        } else {
+            e
+        }
+    })
+}
+
+/// Values of a set of coding / adaptation DIDs at one point in time, for comparing
+/// the ECU's coding before and after a change
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CodingSnapshot {
+    values: BTreeMap<u16, Vec<u8>>,
+}
+
+/// A DID whose value differs between two [CodingSnapshot]s.
+/// None means the DID was not part of that snapshot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DidChange {
+    pub did: u16,
+    pub old: Option<Vec<u8>>,
+    pub new: Option<Vec<u8>>,
+}
+
+impl Display for DidChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let fmt_value = |v: &Option<Vec<u8>>| match v {
+            Some(x) => hex_dump(x),
+            None => "nothing".into(),
+        };
+        write!(
+            f,
+            "DID 0x{:04X} changed from {} to {}",
+            self.did,
+            fmt_value(&self.old),
+            fmt_value(&self.new)
+        )
+    }
+}
+
+impl CodingSnapshot {
+    /// Reads each DID in `dids`. Fails if any of them cannot be read, so that
+    /// a snapshot is always complete
+    pub fn capture(ecu: &UDSECU, dids: &[u16]) -> ProtocolResult<Self> {
+        let mut values = BTreeMap::new();
+        for did in dids {
+            values.insert(*did, read_data::read_did(ecu, *did)?);
+        }
+        Ok(Self { values })
+    }
+
+    /// Returns the value of `did` in the snapshot
+    pub fn get(&self, did: u16) -> Option<&[u8]> {
+        self.values.get(&did).map(|x| x.as_slice())
+    }
+
+    /// Returns the DIDs whose value is different in `other` (The newer snapshot),
+    /// including DIDs which are only in one of the snapshots
+    pub fn diff(&self, other: &CodingSnapshot) -> Vec<DidChange> {
+        let mut dids: Vec<u16> = self
+            .values
+            .keys()
+            .chain(other.values.keys())
+            .copied()
+            .collect();
+        dids.sort_unstable();
+        dids.dedup();
+        dids.into_iter()
+            .filter_map(|did| {
+                let old = self.values.get(&did);
+                let new = other.values.get(&did);
+                if old == new {
+                    None
+                } else {
+                    Some(DidChange {
+                        did,
+                        old: old.cloned(),
+                        new: new.cloned(),
+                    })
+                }
+            })
+            .collect()
+    }
+}
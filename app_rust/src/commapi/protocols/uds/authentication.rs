@@ -0,0 +1,50 @@
+use crate::commapi::protocols::{ProtocolResult, ProtocolServer};
+
+use super::UDSECU;
+
+// The service, Authentication ($29), was added in ISO 14229-1:2020 and replaces SecurityAccess
+// on newer ECUs. Rather than a seed / key exchange, the tester proves its identity using
+// PKI certificates and a challenge / response. The crypto itself is left to the caller,
+// this only handles the framing of each step.
+
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
+pub enum AuthSubFunction {
+    DeAuthenticate,
+    VerifyCertificateUnidirectional,
+    VerifyCertificateBidirectional,
+    ProofOfOwnership,
+    TransmitCertificate,
+    RequestChallengeForAuthentication,
+    VerifyProofOfOwnershipUnidirectional,
+    VerifyProofOfOwnershipBidirectional,
+    AuthenticationConfiguration,
+}
+
+impl AuthSubFunction {
+    pub(crate) fn to_byte(&self) -> u8 {
+        match &self {
+            AuthSubFunction::DeAuthenticate => 0x00,
+            AuthSubFunction::VerifyCertificateUnidirectional => 0x01,
+            AuthSubFunction::VerifyCertificateBidirectional => 0x02,
+            AuthSubFunction::ProofOfOwnership => 0x03,
+            AuthSubFunction::TransmitCertificate => 0x04,
+            AuthSubFunction::RequestChallengeForAuthentication => 0x05,
+            AuthSubFunction::VerifyProofOfOwnershipUnidirectional => 0x06,
+            AuthSubFunction::VerifyProofOfOwnershipBidirectional => 0x07,
+            AuthSubFunction::AuthenticationConfiguration => 0x08,
+        }
+    }
+}
+
+/// Runs an authentication sub function, with `data` being the raw parameters for the sub function
+/// (Communication configuration, certificate, proof of ownership etc...).
+///
+/// The returned bytes are the positive response with the SID and sub function stripped,
+/// so the first byte is the authenticationReturnParameter
+pub fn authenticate(ecu: &UDSECU, sub_fn: AuthSubFunction, data: &[u8]) -> ProtocolResult<Vec<u8>> {
+    let mut args = vec![sub_fn.to_byte()];
+    args.extend_from_slice(data);
+    let mut res = ecu.run_command(super::UDSCommand::Authentication.into(), &args)?;
+    res.drain(0..std::cmp::min(2, res.len())); // SID + Sub function
+    Ok(res)
+}
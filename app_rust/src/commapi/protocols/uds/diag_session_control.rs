@@ -18,7 +18,7 @@ pub enum DiagSession {
 }
 
 impl DiagSession {
-    fn to_byte(&self) -> u8 {
+    pub(crate) fn to_byte(&self) -> u8 {
         match &self {
             DiagSession::Default => 0x01,
             DiagSession::Programming => 0x02,
@@ -28,6 +28,17 @@ impl DiagSession {
             DiagSession::SystemSupplier(x) => *x,
         }
     }
+
+    pub(crate) fn from_byte(b: u8) -> Self {
+        match b {
+            0x01 => DiagSession::Default,
+            0x02 => DiagSession::Programming,
+            0x03 => DiagSession::Extended,
+            0x04 => DiagSession::SafetySystem,
+            (0x60..=0x7E) => DiagSession::SystemSupplier(b),
+            _ => DiagSession::VehicleSpecific(b),
+        }
+    }
 }
 
 /// Attempts to set the diagnostic session type of the ECU
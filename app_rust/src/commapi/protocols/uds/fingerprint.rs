@@ -0,0 +1,188 @@
+use std::fmt::Display;
+
+use crate::commapi::protocols::{ProtocolError, ProtocolResult};
+
+use super::{read_data, write_data, UDSECU};
+
+// Flash tools write a fingerprint to the ECU when programming it, recording which tester
+// programmed it and when. ISO 14229-1 defines separate DIDs for the tester serial number and
+// the programming date, but most manufacturers also (Or only) store a combined fingerprint,
+// whose layout they define. The common layout is used here, which is the programming
+// date (BCD YY MM DD) followed by the tester serial number / repair shop code in ASCII.
+
+/// Fingerprint of the last programming session (Manufacturer defined layout)
+pub const FINGERPRINT_DID: u16 = 0xF15A;
+/// applicationSoftwareFingerprintDataIdentifier
+pub const APP_SOFTWARE_FINGERPRINT_DID: u16 = 0xF184;
+/// repairShopCodeOrTesterSerialNumberDataIdentifier
+const TESTER_SERIAL_DID: u16 = 0xF198;
+/// programmingDateDataIdentifier
+const PROGRAMMING_DATE_DID: u16 = 0xF199;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ProgrammingDate {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+}
+
+impl Display for ProgrammingDate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+/// Record of who programmed the ECU, and when.
+/// Fields are None if the ECU does not store them, or was never programmed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Fingerprint {
+    /// Tester serial number or repair shop code of the tool that programmed the ECU
+    pub tester_id: Option<String>,
+    pub programming_date: Option<ProgrammingDate>,
+}
+
+impl Fingerprint {
+    /// Returns false if the ECU has no fingerprint (For example, an ECU which was
+    /// never programmed has its fingerprint erased to 0xFF)
+    pub fn is_programmed(&self) -> bool {
+        self.tester_id.is_some() || self.programming_date.is_some()
+    }
+}
+
+/// Decodes a BCD date (YY MM DD, or YYYY MM DD). Returns None if the date is
+/// erased (All 0x00 or 0xFF), or is not a valid date
+pub fn decode_bcd_date(raw: &[u8]) -> Option<ProgrammingDate> {
+    let mut digits = Vec::with_capacity(raw.len());
+    for b in raw {
+        let (hi, lo) = (b >> 4, b & 0x0F);
+        if hi > 9 || lo > 9 {
+            return None;
+        }
+        digits.push((hi * 10 + lo) as u16);
+    }
+    let (year, month, day) = match digits[..] {
+        [yy, mm, dd] => (2000 + yy, mm, dd),
+        [cc, yy, mm, dd] => (cc * 100 + yy, mm, dd),
+        _ => return None,
+    };
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    Some(ProgrammingDate {
+        year,
+        month: month as u8,
+        day: day as u8,
+    })
+}
+
+fn encode_bcd(value: u16) -> u8 {
+    ((value / 10 % 10) << 4 | value % 10) as u8
+}
+
+/// Parses a fingerprint record (BCD YY MM DD, then the tester ID in ASCII)
+fn parse_fingerprint(raw: &[u8]) -> Fingerprint {
+    Fingerprint {
+        programming_date: raw.get(..3).and_then(decode_bcd_date),
+        tester_id: raw.get(3..).and_then(read_data::decode_ascii),
+    }
+}
+
+/// Encodes a fingerprint into its record. A missing date is written as erased (0xFF)
+fn encode_fingerprint(fp: &Fingerprint) -> ProtocolResult<Vec<u8>> {
+    let mut res = match fp.programming_date {
+        Some(d) if (2000..2100).contains(&d.year) => vec![
+            encode_bcd(d.year),
+            encode_bcd(d.month as u16),
+            encode_bcd(d.day as u16),
+        ],
+        Some(d) => {
+            return Err(ProtocolError::CustomError(format!(
+                "Programming date {} cannot be stored in the fingerprint",
+                d
+            )))
+        }
+        None => vec![0xFF; 3],
+    };
+    if let Some(id) = &fp.tester_id {
+        if !id.is_ascii() {
+            return Err(ProtocolError::CustomError(format!(
+                "Tester ID '{}' is not ASCII",
+                id
+            )));
+        }
+        res.extend_from_slice(id.as_bytes());
+    }
+    Ok(res)
+}
+
+/// Reads a DID, returning None if the ECU rejected it with a negative response
+fn read_optional(ecu: &UDSECU, did: u16) -> ProtocolResult<Option<Vec<u8>>> {
+    match read_data::read_did(ecu, did) {
+        Ok(r) => Ok(Some(r)),
+        Err(e) if e.get_nrc().is_some() => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads the fingerprint of the ECU.
+///
+/// The standard tester serial number (0xF198) and programming date (0xF199) DIDs are read
+/// first, and anything they do not provide is taken from the fingerprint record (0xF15A),
+/// or the application software fingerprint (0xF184) if the ECU has no fingerprint record
+pub fn read_fingerprint(ecu: &UDSECU) -> ProtocolResult<Fingerprint> {
+    let mut fp = Fingerprint {
+        tester_id: read_data::read_ascii_did(ecu, TESTER_SERIAL_DID)?,
+        programming_date: read_optional(ecu, PROGRAMMING_DATE_DID)?
+            .and_then(|r| decode_bcd_date(&r)),
+    };
+    if fp.tester_id.is_some() && fp.programming_date.is_some() {
+        return Ok(fp);
+    }
+    let record = match read_optional(ecu, FINGERPRINT_DID)? {
+        Some(r) => Some(r),
+        None => read_optional(ecu, APP_SOFTWARE_FINGERPRINT_DID)?,
+    };
+    if let Some(record) = record {
+        let parsed = parse_fingerprint(&record);
+        fp.tester_id = fp.tester_id.or(parsed.tester_id);
+        fp.programming_date = fp.programming_date.or(parsed.programming_date);
+    }
+    Ok(fp)
+}
+
+/// Writes the fingerprint record (0xF15A) of the ECU. This is done during programming,
+/// before the download, so the ECU must be in the programming session and unlocked
+pub fn write_fingerprint(ecu: &UDSECU, fp: &Fingerprint) -> ProtocolResult<()> {
+    write_data::write_did(ecu, FINGERPRINT_DID, &encode_fingerprint(fp)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decode_bcd_date, encode_fingerprint, parse_fingerprint, Fingerprint, ProgrammingDate,
+    };
+
+    #[test]
+    fn test_fingerprint_records() {
+        let fp = parse_fingerprint(&[0x21, 0x03, 0x14, b'T', b'S', b'1', 0x00]);
+        let date = ProgrammingDate {
+            year: 2021,
+            month: 3,
+            day: 14,
+        };
+        assert_eq!(fp.programming_date, Some(date));
+        assert_eq!(fp.tester_id.as_deref(), Some("TS1"));
+        assert_eq!(
+            encode_fingerprint(&fp).unwrap(),
+            vec![0x21, 0x03, 0x14, b'T', b'S', b'1']
+        );
+        assert_eq!(date.to_string(), "2021-03-14");
+
+        // Never programmed
+        let erased = parse_fingerprint(&[0xFF; 10]);
+        assert_eq!(erased, Fingerprint::default());
+        assert!(!erased.is_programmed());
+        assert!(decode_bcd_date(&[0x00, 0x00, 0x00]).is_none());
+        assert!(decode_bcd_date(&[0x20, 0x21, 0x12, 0x31]).is_some());
+    }
+}
@@ -0,0 +1,322 @@
+use crate::commapi::iface::hex_dump;
+use crate::commapi::protocols::{ProtocolError, ProtocolResult};
+
+use super::{
+    diag_session_control::DiagSession,
+    fingerprint::decode_bcd_date,
+    read_data::{self, DidLookup, DidRow},
+    UDSECU,
+};
+
+// ISO 14229-1 Annex C defines the identification DIDs (0xF180-0xF19F), but not how most of
+// them are encoded. Most ECUs store them as ASCII, but some manufacturers store numbers as BCD
+// (For example, Bosch software numbers such as 1037xxxxxx), and dates are always BCD
+
+/// bootSoftwareIdentificationDataIdentifier
+pub const BOOT_SOFTWARE_ID_DID: u16 = 0xF180;
+/// applicationSoftwareIdentificationDataIdentifier
+pub const APP_SOFTWARE_ID_DID: u16 = 0xF181;
+/// applicationDataIdentificationDataIdentifier
+pub const APP_DATA_ID_DID: u16 = 0xF182;
+/// ECUSerialNumberDataIdentifier
+pub const SERIAL_NUMBER_DID: u16 = 0xF18C;
+/// ECUManufacturingDateDataIdentifier
+pub const MANUFACTURING_DATE_DID: u16 = 0xF18B;
+
+/// How the value of an identification DID is encoded
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DidEncoding {
+    /// ASCII text, padded with spaces, 0x00 or 0xFF
+    Ascii,
+    /// ASCII text, or a BCD number if the value is not printable
+    AsciiOrBcd,
+    /// Number of modules, followed by one fixed length identifier per module
+    /// (Each [DidEncoding::AsciiOrBcd]). See [decode_module_ids]
+    ModuleList,
+    /// BCD date (YYMMDD or YYYYMMDD). Dates stored as ASCII digits are also accepted
+    BcdDate,
+    /// Single byte diagnostic session type
+    Session,
+    /// Packed binary data, shown as hex
+    Hex,
+}
+
+/// A well-known identification DID
+#[derive(Debug, Copy, Clone)]
+pub struct IdentificationDid {
+    pub did: u16,
+    pub name: &'static str,
+    pub encoding: DidEncoding,
+}
+
+const fn id_did(did: u16, name: &'static str, encoding: DidEncoding) -> IdentificationDid {
+    IdentificationDid {
+        did,
+        name,
+        encoding,
+    }
+}
+
+/// Identification DIDs defined by ISO 14229-1, in DID order
+pub const IDENTIFICATION_DIDS: [IdentificationDid; 27] = [
+    id_did(0xF180, "Boot software ID", DidEncoding::ModuleList),
+    id_did(0xF181, "Application software ID", DidEncoding::ModuleList),
+    id_did(0xF182, "Application data ID", DidEncoding::ModuleList),
+    id_did(0xF183, "Boot software fingerprint", DidEncoding::Hex),
+    id_did(0xF184, "Application software fingerprint", DidEncoding::Hex),
+    id_did(0xF185, "Application data fingerprint", DidEncoding::Hex),
+    id_did(0xF186, "Active diagnostic session", DidEncoding::Session),
+    id_did(0xF187, "Spare part number", DidEncoding::AsciiOrBcd),
+    id_did(0xF188, "ECU software number", DidEncoding::AsciiOrBcd),
+    id_did(0xF189, "ECU software version", DidEncoding::AsciiOrBcd),
+    id_did(0xF18A, "System supplier", DidEncoding::Ascii),
+    id_did(0xF18B, "ECU manufacturing date", DidEncoding::BcdDate),
+    id_did(0xF18C, "ECU serial number", DidEncoding::AsciiOrBcd),
+    id_did(0xF18D, "Supported functional units", DidEncoding::Hex),
+    id_did(0xF18E, "Kit assembly part number", DidEncoding::AsciiOrBcd),
+    id_did(0xF190, "VIN", DidEncoding::Ascii),
+    id_did(0xF191, "ECU hardware number", DidEncoding::AsciiOrBcd),
+    id_did(0xF192, "Supplier hardware number", DidEncoding::AsciiOrBcd),
+    id_did(0xF193, "Supplier hardware version", DidEncoding::AsciiOrBcd),
+    id_did(0xF194, "Supplier software number", DidEncoding::AsciiOrBcd),
+    id_did(0xF195, "Supplier software version", DidEncoding::AsciiOrBcd),
+    id_did(0xF196, "Type approval number", DidEncoding::Ascii),
+    id_did(0xF197, "System name / engine type", DidEncoding::Ascii),
+    id_did(0xF198, "Tester serial number", DidEncoding::Ascii),
+    id_did(0xF199, "Programming date", DidEncoding::BcdDate),
+    id_did(0xF19D, "ECU installation date", DidEncoding::BcdDate),
+    id_did(0xF19E, "ODX file", DidEncoding::Ascii),
+];
+
+/// Returns the table entry of an identification DID, if it is a well-known one
+pub fn lookup(did: u16) -> Option<&'static IdentificationDid> {
+    IDENTIFICATION_DIDS.iter().find(|d| d.did == did)
+}
+
+/// Decodes a BCD number into its digits, ignoring trailing 0xFF padding.
+/// Returns None if any nibble is not a decimal digit
+pub fn decode_bcd(raw: &[u8]) -> Option<String> {
+    let end = raw.iter().rposition(|b| *b != 0xFF)? + 1;
+    let mut res = String::with_capacity(end * 2);
+    for b in &raw[..end] {
+        for digit in &[b >> 4, b & 0x0F] {
+            if *digit > 9 {
+                return None;
+            }
+            res.push((b'0' + digit) as char);
+        }
+    }
+    Some(res)
+}
+
+fn is_printable(raw: &[u8]) -> bool {
+    raw.iter().all(|b| matches!(b, 0x20..=0x7E | 0x00 | 0xFF))
+}
+
+/// Decodes a BCD date, or a date stored as ASCII digits (YYMMDD or YYYYMMDD)
+fn decode_date(raw: &[u8]) -> Option<String> {
+    let bcd: Vec<u8> = if raw.iter().all(|b| b.is_ascii_digit()) && raw.len() % 2 == 0 {
+        raw.chunks_exact(2)
+            .map(|d| (d[0] - b'0') << 4 | (d[1] - b'0'))
+            .collect()
+    } else {
+        raw.to_vec()
+    };
+    decode_bcd_date(&bcd).map(|d| d.to_string())
+}
+
+/// Decodes a software / data identification (0xF180-0xF182), which starts with the number
+/// of modules, followed by an identifier of the same length for each module.
+///
+/// If the count does not divide the rest of the value evenly, the ECU does not use a count
+/// byte, and the whole value is decoded as one identifier. Erased identifiers are returned
+/// as an empty string, so each identifier stays at the index of its module
+fn decode_module_ids(raw: &[u8]) -> Vec<String> {
+    let decode = |id: &[u8]| DidEncoding::AsciiOrBcd.decode(id).unwrap_or_default();
+    match raw.split_first() {
+        Some((count, ids)) if *count > 0 && !ids.is_empty() && ids.len() % *count as usize == 0 => {
+            ids.chunks(ids.len() / *count as usize)
+                .map(decode)
+                .collect()
+        }
+        Some(_) => vec![decode(raw)],
+        None => Vec::new(),
+    }
+}
+
+fn decode_session(raw: &[u8]) -> Option<String> {
+    let b = *raw.first()?;
+    Some(match DiagSession::from_byte(b) {
+        DiagSession::Default => "Default session".into(),
+        DiagSession::Programming => "Programming session".into(),
+        DiagSession::Extended => "Extended session".into(),
+        DiagSession::SafetySystem => "Safety system session".into(),
+        DiagSession::VehicleSpecific(x) => format!("Vehicle manufacturer session 0x{:02X}", x),
+        DiagSession::SystemSupplier(x) => format!("System supplier session 0x{:02X}", x),
+    })
+}
+
+impl DidEncoding {
+    /// Decodes a raw value. Returns None if the value is empty or erased,
+    /// or is not valid for the encoding
+    pub fn decode(&self, raw: &[u8]) -> Option<String> {
+        match self {
+            Self::Ascii if is_printable(raw) => read_data::decode_ascii(raw),
+            Self::Ascii | Self::Hex => Some(hex_dump(raw)).filter(|_| !raw.is_empty()),
+            Self::AsciiOrBcd if is_printable(raw) => read_data::decode_ascii(raw),
+            Self::AsciiOrBcd => decode_bcd(raw).or_else(|| Self::Hex.decode(raw)),
+            Self::ModuleList => Some(decode_module_ids(raw).join(", ")).filter(|s| !s.is_empty()),
+            Self::BcdDate => decode_date(raw),
+            Self::Session => decode_session(raw),
+        }
+    }
+}
+
+/// Decodes the value of an identification DID. DIDs which are not in
+/// [IDENTIFICATION_DIDS] are decoded as ASCII
+fn decode_identification(did: u16, raw: &[u8]) -> Option<String> {
+    lookup(did)
+        .map_or(DidEncoding::Ascii, |d| d.encoding)
+        .decode(raw)
+}
+
+/// [DidLookup] for the identification DIDs of ISO 14229-1
+#[derive(Debug, Copy, Clone, Default)]
+pub struct IdentificationDids;
+
+impl DidLookup for IdentificationDids {
+    fn get_name(&self, did: u16) -> Option<String> {
+        lookup(did).map(|d| d.name.to_string())
+    }
+
+    fn decode(&self, did: u16, raw: &[u8]) -> Option<String> {
+        lookup(did).and_then(|d| d.encoding.decode(raw))
+    }
+}
+
+/// Reads and decodes an identification DID. Returns None if the ECU rejected the DID
+/// with a negative response, or if it is empty
+pub fn read_identification_did(ecu: &UDSECU, did: u16) -> ProtocolResult<Option<String>> {
+    match read_data::read_did(ecu, did) {
+        Ok(r) => Ok(decode_identification(did, &r)),
+        Err(e) if e.get_nrc().is_some() => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads an identification DID which must be present, failing if the ECU's value is empty
+fn read_required(ecu: &UDSECU, did: u16, what: &str) -> ProtocolResult<String> {
+    let raw = read_data::read_did(ecu, did)?;
+    decode_identification(did, &raw).ok_or_else(|| {
+        ProtocolError::CustomError(format!("ECU has no {} stored ({})", what, hex_dump(&raw)))
+    })
+}
+
+/// Reads the serial number of the ECU (0xF18C). Serial numbers stored as
+/// packed BCD rather than ASCII are returned as their digits
+pub fn read_serial_number(ecu: &UDSECU) -> ProtocolResult<String> {
+    read_required(ecu, SERIAL_NUMBER_DID, "serial number")
+}
+
+/// Reads the date the ECU was manufactured (0xF18B), as YYYY-MM-DD
+pub fn read_manufacturing_date(ecu: &UDSECU) -> ProtocolResult<String> {
+    read_required(ecu, MANUFACTURING_DATE_DID, "manufacturing date")
+}
+
+/// Reads a software / data identification DID, returning the identifier of each module.
+/// See [decode_module_ids]
+pub fn read_module_ids(ecu: &UDSECU, did: u16) -> ProtocolResult<Vec<String>> {
+    read_data::read_did(ecu, did).map(|r| decode_module_ids(&r))
+}
+
+/// Reads the identification of the boot software (0xF180), one identifier per module
+pub fn read_boot_software_id(ecu: &UDSECU) -> ProtocolResult<Vec<String>> {
+    read_module_ids(ecu, BOOT_SOFTWARE_ID_DID)
+}
+
+/// Reads the identification of the application software (0xF181), one identifier per module
+pub fn read_application_software_id(ecu: &UDSECU) -> ProtocolResult<Vec<String>> {
+    read_module_ids(ecu, APP_SOFTWARE_ID_DID)
+}
+
+/// Reads the identification of the application data (0xF182), one identifier per module
+pub fn read_application_data_id(ecu: &UDSECU) -> ProtocolResult<Vec<String>> {
+    read_module_ids(ecu, APP_DATA_ID_DID)
+}
+
+/// Reads every identification DID in [IDENTIFICATION_DIDS], returning the decoded
+/// value of each DID the ECU supports
+pub fn read_identification(ecu: &UDSECU) -> Vec<DidRow> {
+    let dids: Vec<u16> = IDENTIFICATION_DIDS.iter().map(|d| d.did).collect();
+    read_data::read_did_table(ecu, &dids, Some(&IdentificationDids))
+        .into_iter()
+        .filter(|r| r.error.is_none())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_identification, decode_module_ids};
+
+    #[test]
+    fn test_identification_decoding() {
+        // Spare part number in ASCII, padded with spaces
+        assert_eq!(
+            decode_identification(0xF187, b"8E0907115D  ").as_deref(),
+            Some("8E0907115D")
+        );
+        // Bosch software number in BCD
+        assert_eq!(
+            decode_identification(0xF188, &[0x10, 0x37, 0x36, 0x41, 0x52, 0xFF]).as_deref(),
+            Some("1037364152")
+        );
+        assert_eq!(
+            decode_identification(0xF186, &[0x03]).as_deref(),
+            Some("Extended session")
+        );
+        assert_eq!(
+            decode_identification(0xF18B, &[0x19, 0x11, 0x27]).as_deref(),
+            Some("2019-11-27")
+        );
+        assert_eq!(
+            decode_identification(0xF190, b"WVWZZZ1JZXW000001").as_deref(),
+            Some("WVWZZZ1JZXW000001")
+        );
+        // Dates and serial numbers stored as ASCII, or packed BCD
+        assert_eq!(
+            decode_identification(0xF18B, b"20191127").as_deref(),
+            Some("2019-11-27")
+        );
+        assert_eq!(
+            decode_identification(0xF18C, &[0x01, 0x23, 0x45, 0x67]).as_deref(),
+            Some("01234567")
+        );
+        assert_eq!(
+            decode_identification(0xF18C, b"SN0012345").as_deref(),
+            Some("SN0012345")
+        );
+        // Module count prefixed software identification
+        assert_eq!(
+            decode_module_ids(b"\x02BOOT0001BOOT0002"),
+            vec!["BOOT0001", "BOOT0002"]
+        );
+        assert_eq!(
+            decode_module_ids(&[0x02, 0x10, 0x37, 0xFF, 0xFF]),
+            vec!["1037", ""]
+        );
+        assert_eq!(
+            decode_identification(0xF181, b"\x01SW0815").as_deref(),
+            Some("SW0815")
+        );
+        // No count byte
+        assert_eq!(decode_module_ids(b"SW1234567"), vec!["SW1234567"]);
+        // Erased values
+        assert_eq!(decode_identification(0xF189, &[0xFF; 8]), None);
+        assert_eq!(decode_identification(0xF199, &[0x00, 0x00, 0x00]), None);
+        // Neither ASCII nor BCD
+        assert_eq!(
+            decode_identification(0xF194, &[0x0A, 0xBC]).as_deref(),
+            Some("[0A BC]")
+        );
+    }
+}
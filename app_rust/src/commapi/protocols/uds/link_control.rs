@@ -0,0 +1,116 @@
+use crate::commapi::protocols::{ProtocolError, ProtocolResult, ProtocolServer};
+
+use super::UDSECU;
+
+// The service, Link Control ($87), is used to switch the diagnostic link to a different baud rate
+// (Usually for faster programming). The tester first verifies the ECU can switch to the new baud
+// rate, then tells it to transition. Once the ECU responds to the transition, both sides switch.
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LinkControlMode {
+    /// Verify the transition to a fixed (Predefined) baud rate
+    VerifyFixedParameter,
+    /// Verify the transition to a specific baud rate
+    VerifySpecificParameter,
+    /// Transition to the verified baud rate
+    TransitionMode,
+}
+
+impl LinkControlMode {
+    pub(crate) fn to_byte(&self) -> u8 {
+        match self {
+            Self::VerifyFixedParameter => 0x01,
+            Self::VerifySpecificParameter => 0x02,
+            Self::TransitionMode => 0x03,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LinkBaud {
+    Can125k,
+    Can250k,
+    Can500k,
+    Can1M,
+    /// Any baud rate, in bits per second
+    Specific(u32),
+}
+
+impl LinkBaud {
+    pub fn get_baudrate(&self) -> u32 {
+        match self {
+            Self::Can125k => 125_000,
+            Self::Can250k => 250_000,
+            Self::Can500k => 500_000,
+            Self::Can1M => 1_000_000,
+            Self::Specific(b) => *b,
+        }
+    }
+
+    /// Returns the linkControlModeIdentifier of fixed baud rates
+    pub(crate) fn get_fixed_id(&self) -> Option<u8> {
+        match self {
+            Self::Can125k => Some(0x10),
+            Self::Can250k => Some(0x11),
+            Self::Can500k => Some(0x12),
+            Self::Can1M => Some(0x13),
+            Self::Specific(_) => None,
+        }
+    }
+}
+
+/// Runs a link control step. Verification steps require `baud`. When transitioning, `baud`
+/// can be None to use the previously verified baud rate.
+///
+/// After a successful transition, the diag server re-opens its interface at the new baud rate
+pub fn link_control(
+    ecu: &UDSECU,
+    mode: LinkControlMode,
+    baud: Option<LinkBaud>,
+) -> ProtocolResult<()> {
+    let target = match (mode, baud) {
+        (_, Some(b)) => b.get_baudrate(),
+        (LinkControlMode::TransitionMode, None) => {
+            ecu.link_baud.read().unwrap().ok_or_else(|| {
+                ProtocolError::CustomError(
+                    "Link transition requested without a verified baud".into(),
+                )
+            })?
+        }
+        (_, None) => {
+            return Err(ProtocolError::CustomError(
+                "Link verification needs a baud rate".into(),
+            ))
+        }
+    };
+
+    let mut args = vec![mode.to_byte()];
+    match mode {
+        LinkControlMode::VerifyFixedParameter => {
+            let id = baud.and_then(|b| b.get_fixed_id()).ok_or_else(|| {
+                ProtocolError::CustomError("Fixed link verification needs a fixed baud".into())
+            })?;
+            args.push(id);
+        }
+        LinkControlMode::VerifySpecificParameter => {
+            if target > 0xFFFFFF {
+                return Err(ProtocolError::CustomError(format!(
+                    "Baud rate {} is too large for link control",
+                    target
+                )));
+            }
+            args.extend_from_slice(&target.to_be_bytes()[1..]);
+        }
+        LinkControlMode::TransitionMode => {}
+    }
+
+    ecu.run_command(super::UDSCommand::LinkControl.into(), &args)?;
+    if mode == LinkControlMode::TransitionMode {
+        println!("UDS - Switching link to {} bps", target);
+        *ecu.link_baud.write().unwrap() = None;
+        *ecu.relink_baud.write().unwrap() = Some(target);
+    } else {
+        *ecu.link_baud.write().unwrap() = Some(target);
+    }
+    Ok(())
+}
@@ -1,24 +1,39 @@
 use self::diag_session_control::DiagSession;
 use super::{
-    CautionLevel, CommandError, DiagCfg, ECUCommand, ProtocolError, ProtocolResult, ProtocolServer,
-    Selectable, DTC,
+    clock::Clock, CautionLevel, CommandError, CommandRequest, CommandResponse, ConnectTimer,
+    ConnectionInfo, DiagCfg, DiagProtocol, DiagSessionState, DiagThread, ECUCommand,
+    PostSessionGuard, ProtocolError, ProtocolResult, ProtocolServer, ProtocolVariant,
+    RequestCorrelator, ResponseOptions, Selectable, Stats, DTC,
 };
 use crate::commapi::{
     comm_api::{ComServer, FilterType},
-    iface::{InterfaceConfig, InterfaceType, IsoTPInterface, PayloadFlag},
+    dry_run::DryRunInterface,
+    iface::{InterfaceConfig, InterfaceType, IsoTPInterface, PayloadFlag, IFACE_CFG},
 };
 use std::sync::atomic::Ordering::Relaxed;
 use std::{
+    collections::HashMap,
     sync::{
         atomic::AtomicBool,
         mpsc::{self, Receiver, Sender},
         Arc, Mutex, RwLock,
     },
-    time::Instant,
 };
 
+pub mod access_timing;
+pub mod adaptation;
+pub mod authentication;
 pub mod diag_session_control;
+pub mod fingerprint;
+pub mod identification;
+pub mod link_control;
 pub mod read_data;
+pub mod read_dtc_information;
+pub mod response_on_event;
+pub mod security_access;
+pub mod upload_download;
+pub mod write_data;
+pub mod write_memory;
 
 #[derive(Copy, Clone, Debug, Eq, PartialOrd, PartialEq)]
 /// UDS Commands AKA SID (Service identifiers)
@@ -45,7 +60,9 @@ pub enum UDSCommand {
     WriteMemoryByAddress,
     TesterPresent,
     RequestFileTransfer,
+    AccessTimingParameter,
     ControlDTCSetting,
+    ResponseOnEvent,
     LinkControl,
 }
 
@@ -78,7 +95,9 @@ impl Selectable for UDSCommand {
             UDSCommand::WriteMemoryByAddress => {}
             UDSCommand::TesterPresent => {}
             UDSCommand::RequestFileTransfer => {}
+            UDSCommand::AccessTimingParameter => {}
             UDSCommand::ControlDTCSetting => {}
+            UDSCommand::ResponseOnEvent => {}
             UDSCommand::LinkControl => {}
         }
         "--TODO--".into()
@@ -115,8 +134,10 @@ impl Into<u8> for UDSCommand {
             UDSCommand::TransferExit => 0x37,
             UDSCommand::WriteMemoryByAddress => 0x3D,
             UDSCommand::TesterPresent => 0x3E,
-            UDSCommand::RequestFileTransfer => 0x3F,
+            UDSCommand::RequestFileTransfer => 0x38,
+            UDSCommand::AccessTimingParameter => 0x83,
             UDSCommand::ControlDTCSetting => 0x85,
+            UDSCommand::ResponseOnEvent => 0x86,
             UDSCommand::LinkControl => 0x87,
         }
     }
@@ -147,7 +168,9 @@ impl ECUCommand for UDSCommand {
             UDSCommand::WriteMemoryByAddress => CautionLevel::Alert,
             UDSCommand::TesterPresent => CautionLevel::None,
             UDSCommand::RequestFileTransfer => CautionLevel::Alert,
+            UDSCommand::AccessTimingParameter => CautionLevel::Warn,
             UDSCommand::ControlDTCSetting => CautionLevel::Warn,
+            UDSCommand::ResponseOnEvent => CautionLevel::Warn,
             UDSCommand::LinkControl => CautionLevel::Warn,
         }
     }
@@ -176,12 +199,53 @@ impl ECUCommand for UDSCommand {
             Self::WriteMemoryByAddress,
             //Self::TesterPresent,
             Self::RequestFileTransfer,
+            Self::AccessTimingParameter,
             Self::ControlDTCSetting,
+            Self::ResponseOnEvent,
             Self::LinkControl,
         ]
     }
 }
 
+/// Names and caution levels of the UDS services, for displaying services without
+/// going through [UDSCommand]. See [crate::commapi::protocols::describe_service]
+pub const UDS_SERVICES: &[(u8, &str, CautionLevel)] = &[
+    (0x10, "Diagnostic session control", CautionLevel::Warn),
+    (0x11, "ECU reset", CautionLevel::Alert),
+    (0x14, "Clear diagnostic information", CautionLevel::None),
+    (0x19, "Read DTC information", CautionLevel::None),
+    (0x22, "Read data by identifier", CautionLevel::None),
+    (0x23, "Read memory by address", CautionLevel::Warn),
+    (0x24, "Read scaling data by identifier", CautionLevel::Warn),
+    (0x27, "Security access", CautionLevel::Alert),
+    (0x28, "Communication control", CautionLevel::Warn),
+    (0x29, "Authentication", CautionLevel::Alert),
+    (0x2A, "Read data by periodic identifier", CautionLevel::Warn),
+    (
+        0x2C,
+        "Dynamically define data identifier",
+        CautionLevel::Alert,
+    ),
+    (0x2E, "Write data by identifier", CautionLevel::Alert),
+    (
+        0x2F,
+        "Input output control by identifier",
+        CautionLevel::Alert,
+    ),
+    (0x31, "Routine control", CautionLevel::Warn),
+    (0x34, "Request download", CautionLevel::Alert),
+    (0x35, "Request upload", CautionLevel::Alert),
+    (0x36, "Transfer data", CautionLevel::Alert),
+    (0x37, "Request transfer exit", CautionLevel::Alert),
+    (0x38, "Request file transfer", CautionLevel::Alert),
+    (0x3D, "Write memory by address", CautionLevel::Alert),
+    (0x3E, "Tester present", CautionLevel::None),
+    (0x83, "Access timing parameter", CautionLevel::Warn),
+    (0x85, "Control DTC setting", CautionLevel::Warn),
+    (0x86, "Response on event", CautionLevel::Warn),
+    (0x87, "Link control", CautionLevel::Warn),
+];
+
 #[derive(Copy, Debug, Clone, Eq, PartialOrd, PartialEq)]
 /// All possible UDS Negative responses an ECU can return
 /// when trying to run a command
@@ -199,6 +263,20 @@ pub enum UDSNegativeCode {
     InvalidKey,
     ExceedNumberOfAttempts,
     RequiredTimeDelayNotExpired,
+    CertificateInvalidTimePeriod,
+    CertificateInvalidSignature,
+    CertificateInvalidChainOfTrust,
+    CertificateInvalidType,
+    CertificateInvalidFormat,
+    CertificateInvalidContent,
+    CertificateInvalidScope,
+    CertificateInvalidCertificate,
+    OwnershipVerificationFailed,
+    ChallengeCalculationFailed,
+    SettingAccessRightsFailed,
+    SessionKeyCreationFailed,
+    ConfigurationDataUsageFailed,
+    DeAuthenticationFailed,
     UploadDownloadNotAccepted,
     TransferDataSuspended,
     GeneralProgrammingFailure,
@@ -246,6 +324,36 @@ impl CommandError for UDSNegativeCode {
             UDSNegativeCode::InvalidKey => "Invalid key",
             UDSNegativeCode::ExceedNumberOfAttempts => "Exceeded number of access attempts",
             UDSNegativeCode::RequiredTimeDelayNotExpired => "Security timeout has not expired",
+            UDSNegativeCode::CertificateInvalidTimePeriod => {
+                "Certificate verification failed - Invalid time period"
+            }
+            UDSNegativeCode::CertificateInvalidSignature => {
+                "Certificate verification failed - Invalid signature"
+            }
+            UDSNegativeCode::CertificateInvalidChainOfTrust => {
+                "Certificate verification failed - Invalid chain of trust"
+            }
+            UDSNegativeCode::CertificateInvalidType => {
+                "Certificate verification failed - Invalid type"
+            }
+            UDSNegativeCode::CertificateInvalidFormat => {
+                "Certificate verification failed - Invalid format"
+            }
+            UDSNegativeCode::CertificateInvalidContent => {
+                "Certificate verification failed - Invalid content"
+            }
+            UDSNegativeCode::CertificateInvalidScope => {
+                "Certificate verification failed - Invalid scope"
+            }
+            UDSNegativeCode::CertificateInvalidCertificate => {
+                "Certificate verification failed - Invalid certificate (Revoked)"
+            }
+            UDSNegativeCode::OwnershipVerificationFailed => "Ownership verification failed",
+            UDSNegativeCode::ChallengeCalculationFailed => "Challenge calculation failed",
+            UDSNegativeCode::SettingAccessRightsFailed => "Setting access rights failed",
+            UDSNegativeCode::SessionKeyCreationFailed => "Session key creation / derivation failed",
+            UDSNegativeCode::ConfigurationDataUsageFailed => "Configuration data usage failed",
+            UDSNegativeCode::DeAuthenticationFailed => "De-authentication failed",
             UDSNegativeCode::UploadDownloadNotAccepted => "Upload/Download is not accepted",
             UDSNegativeCode::TransferDataSuspended => "Transfer operation halted",
             UDSNegativeCode::GeneralProgrammingFailure => "Programming error",
@@ -300,6 +408,20 @@ impl CommandError for UDSNegativeCode {
             UDSNegativeCode::InvalidKey => {}
             UDSNegativeCode::ExceedNumberOfAttempts => {}
             UDSNegativeCode::RequiredTimeDelayNotExpired => {}
+            UDSNegativeCode::CertificateInvalidTimePeriod => {}
+            UDSNegativeCode::CertificateInvalidSignature => {}
+            UDSNegativeCode::CertificateInvalidChainOfTrust => {}
+            UDSNegativeCode::CertificateInvalidType => {}
+            UDSNegativeCode::CertificateInvalidFormat => {}
+            UDSNegativeCode::CertificateInvalidContent => {}
+            UDSNegativeCode::CertificateInvalidScope => {}
+            UDSNegativeCode::CertificateInvalidCertificate => {}
+            UDSNegativeCode::OwnershipVerificationFailed => {}
+            UDSNegativeCode::ChallengeCalculationFailed => {}
+            UDSNegativeCode::SettingAccessRightsFailed => {}
+            UDSNegativeCode::SessionKeyCreationFailed => {}
+            UDSNegativeCode::ConfigurationDataUsageFailed => {}
+            UDSNegativeCode::DeAuthenticationFailed => {}
             UDSNegativeCode::UploadDownloadNotAccepted => {}
             UDSNegativeCode::TransferDataSuspended => {}
             UDSNegativeCode::GeneralProgrammingFailure => {}
@@ -333,6 +455,67 @@ impl CommandError for UDSNegativeCode {
         None
     }
 
+    fn get_byte(&self) -> u8 {
+        match &self {
+            UDSNegativeCode::GeneralReject => 0x10,
+            UDSNegativeCode::ServiceNotSupported => 0x11,
+            UDSNegativeCode::SubFunctionNotSupported => 0x12,
+            UDSNegativeCode::IncorrectMessageLength => 0x13,
+            UDSNegativeCode::ResponseTooLong => 0x14,
+            UDSNegativeCode::BusyRepeatRequest => 0x21,
+            UDSNegativeCode::ConditionsNotCorrect => 0x22,
+            UDSNegativeCode::RequestSequenceError => 0x24,
+            UDSNegativeCode::NoResponseSubnetComponent => 0x25,
+            UDSNegativeCode::FailurePreventsExecutionOfRequestedAction => 0x26,
+            UDSNegativeCode::RequestOutOfRange => 0x31,
+            UDSNegativeCode::SecurityAccessDenied => 0x33,
+            UDSNegativeCode::InvalidKey => 0x35,
+            UDSNegativeCode::ExceedNumberOfAttempts => 0x36,
+            UDSNegativeCode::RequiredTimeDelayNotExpired => 0x37,
+            UDSNegativeCode::CertificateInvalidTimePeriod => 0x50,
+            UDSNegativeCode::CertificateInvalidSignature => 0x51,
+            UDSNegativeCode::CertificateInvalidChainOfTrust => 0x52,
+            UDSNegativeCode::CertificateInvalidType => 0x53,
+            UDSNegativeCode::CertificateInvalidFormat => 0x54,
+            UDSNegativeCode::CertificateInvalidContent => 0x55,
+            UDSNegativeCode::CertificateInvalidScope => 0x56,
+            UDSNegativeCode::CertificateInvalidCertificate => 0x57,
+            UDSNegativeCode::OwnershipVerificationFailed => 0x58,
+            UDSNegativeCode::ChallengeCalculationFailed => 0x59,
+            UDSNegativeCode::SettingAccessRightsFailed => 0x5A,
+            UDSNegativeCode::SessionKeyCreationFailed => 0x5B,
+            UDSNegativeCode::ConfigurationDataUsageFailed => 0x5C,
+            UDSNegativeCode::DeAuthenticationFailed => 0x5D,
+            UDSNegativeCode::UploadDownloadNotAccepted => 0x70,
+            UDSNegativeCode::TransferDataSuspended => 0x71,
+            UDSNegativeCode::GeneralProgrammingFailure => 0x72,
+            UDSNegativeCode::WrongBlockSequenceCounter => 0x73,
+            UDSNegativeCode::ResponsePending => 0x78,
+            UDSNegativeCode::SubFunctionNotSupportedActiveSession => 0x7E,
+            UDSNegativeCode::ServiceNotSupportedActiveSession => 0x7F,
+            UDSNegativeCode::RpmTooHigh => 0x81,
+            UDSNegativeCode::RpmTooLow => 0x82,
+            UDSNegativeCode::EngineIsRunning => 0x83,
+            UDSNegativeCode::EngineIsNotRunning => 0x84,
+            UDSNegativeCode::EngineRunTimeTooLow => 0x85,
+            UDSNegativeCode::TempTooHigh => 0x86,
+            UDSNegativeCode::TempTooLow => 0x87,
+            UDSNegativeCode::SpeedTooHigh => 0x88,
+            UDSNegativeCode::SpeedTooLow => 0x89,
+            UDSNegativeCode::ThrottleTooHigh => 0x8A,
+            UDSNegativeCode::ThrottleTooLow => 0x8B,
+            UDSNegativeCode::TransmissionNotInNeutral => 0x8C,
+            UDSNegativeCode::TransmissionNotInGear => 0x8D,
+            UDSNegativeCode::BrakeNotApplied => 0x8F,
+            UDSNegativeCode::ShifterNotInPark => 0x90,
+            UDSNegativeCode::TorqueConverterClutchLocked => 0x91,
+            UDSNegativeCode::VoltageTooHigh => 0x92,
+            UDSNegativeCode::VoltageTooLow => 0x93,
+            UDSNegativeCode::ReservedSpecificConditionsIncorrect => 0x94,
+            UDSNegativeCode::Reserved(b) => *b,
+        }
+    }
+
     fn from_byte<'a>(b: u8) -> Self
     where
         Self: Sized,
@@ -360,7 +543,21 @@ impl CommandError for UDSNegativeCode {
             0x36 => Self::ExceedNumberOfAttempts,
             0x37 => Self::RequiredTimeDelayNotExpired,
             // Reserved data link security (38-4F)
-            // Reserved (50-6F)
+            0x50 => Self::CertificateInvalidTimePeriod,
+            0x51 => Self::CertificateInvalidSignature,
+            0x52 => Self::CertificateInvalidChainOfTrust,
+            0x53 => Self::CertificateInvalidType,
+            0x54 => Self::CertificateInvalidFormat,
+            0x55 => Self::CertificateInvalidContent,
+            0x56 => Self::CertificateInvalidScope,
+            0x57 => Self::CertificateInvalidCertificate,
+            0x58 => Self::OwnershipVerificationFailed,
+            0x59 => Self::ChallengeCalculationFailed,
+            0x5A => Self::SettingAccessRightsFailed,
+            0x5B => Self::SessionKeyCreationFailed,
+            0x5C => Self::ConfigurationDataUsageFailed,
+            0x5D => Self::DeAuthenticationFailed,
+            // Reserved (5E-6F)
             0x70 => Self::UploadDownloadNotAccepted,
             0x71 => Self::TransferDataSuspended,
             0x72 => Self::GeneralProgrammingFailure,
@@ -400,11 +597,30 @@ impl CommandError for UDSNegativeCode {
 pub struct UDSECU {
     should_run: Arc<AtomicBool>,
     last_error: Arc<RwLock<Option<ProtocolError>>>,
-    cmd_tx: Sender<(u8, Vec<u8>, bool)>,
-    cmd_rx: Arc<Receiver<ProtocolResult<Vec<u8>>>>,
+    cmd_tx: Sender<CommandRequest>,
+    cmd_rx: Arc<Receiver<CommandResponse>>,
+    correlator: RequestCorrelator,
     curr_session_type: Arc<RwLock<DiagSession>>,
     send_id: u32,
     cmd_mutex: Arc<Mutex<()>>,
+    strict_response_matching: Arc<AtomicBool>,
+    handle_response_pending: Arc<AtomicBool>,
+    security_level: Arc<RwLock<Option<u8>>>,
+    clear_engine_guard: Arc<AtomicBool>,
+    stats: Arc<RwLock<Stats>>,
+    comm_server: Box<dyn ComServer>,
+    global_id: Option<u32>,
+    session_timing: Arc<RwLock<Option<(u16, u32)>>>, // P2 and P2* in ms
+    link_baud: Arc<RwLock<Option<u32>>>,             // Baud rate verified by link control
+    relink_baud: Arc<RwLock<Option<u32>>>,           // Baud rate the interface should switch to
+    nrc_overrides: Arc<RwLock<HashMap<u8, String>>>, // Caller provided NRC descriptions
+    roe_active: Arc<AtomicBool>,                     // Response on event is running
+    roe_events: Arc<RwLock<Vec<Vec<u8>>>>,           // Collected event responses
+    connection_info: ConnectionInfo,
+    recover_bus: Arc<AtomicBool>, // Set to have the diag server thread recover the bus
+    post_session: PostSessionGuard,
+    clock: Arc<dyn Clock>,
+    thread: DiagThread,
 }
 
 impl UDSECU {
@@ -416,10 +632,23 @@ impl UDSECU {
     fn set_diag_session_mode(
         &mut self,
         mode: DiagSession,
+        timeout_ms: Option<u32>,
     ) -> std::result::Result<(), ProtocolError> {
-        match diag_session_control::set_diag_session(&self, mode) {
-            Ok(_) => {
+        match self.run_command_timeout(
+            UDSCommand::DiagnosticSessionControl.into(),
+            &[mode.to_byte()],
+            timeout_ms,
+        ) {
+            Ok(res) => {
                 *self.curr_session_type.write().unwrap() = mode; // Switch diagnostic modes!
+                *self.security_level.write().unwrap() = None; // Changing session re-locks the ECU
+                self.post_session.on_session_changed();
+                if res.len() >= 6 {
+                    // P2 is in 1ms resolution, P2* is in 10ms resolution
+                    let p2 = (res[2] as u16) << 8 | res[3] as u16;
+                    let p2_ext = ((res[4] as u32) << 8 | res[5] as u32) * 10;
+                    *self.session_timing.write().unwrap() = Some((p2, p2_ext));
+                }
                 Ok(())
             }
             Err(e) => {
@@ -429,35 +658,626 @@ impl UDSECU {
         }
     }
 
+    /// Functionally broadcasts an ECUReset of type `kind` on the global ID,
+    /// returning the IDs of the ECUs which acknowledged it within `collect_ms`
+    pub fn ecu_reset_functional(&self, kind: u8, collect_ms: u32) -> ProtocolResult<Vec<u32>> {
+        match self.global_id {
+            Some(id) => super::ecu_reset_functional(&self.comm_server, id, kind, collect_ms),
+            None => Err(ProtocolError::CustomError(
+                "No global ID configured for functional requests".into(),
+            )),
+        }
+    }
+
+    /// Sets if clearing DTCs should be refused whilst the engine is running (Off by default).
+    /// See [DiagServer::clear_errors](super::DiagServer::clear_errors)
+    pub fn set_clear_engine_guard(&self, enabled: bool) {
+        self.clear_engine_guard.store(enabled, Relaxed);
+    }
+
+    pub fn is_clear_engine_guard_enabled(&self) -> bool {
+        self.clear_engine_guard.load(Relaxed)
+    }
+
+    /// Sets if responses whose service ID does not match the request should be rejected (Default).
+    ///
+    /// When disabled, mismatched responses are returned as is (With a warning logged),
+    /// which is useful for ECUs that do not respond with the correct service ID
+    pub fn set_strict_response_matching(&self, strict: bool) {
+        self.strict_response_matching.store(strict, Relaxed);
+    }
+
+    /// Sets if the diag server waits for the final response when the ECU asks it to
+    /// wait (Response pending, Default). When disabled, the pending negative response
+    /// ($7F xx $78) is returned to the caller immediately, which is useful when
+    /// testing the pending behavior of the ECU itself
+    pub fn set_handle_response_pending(&self, handle: bool) {
+        self.handle_response_pending.store(handle, Relaxed);
+    }
+
+    /// Sets how long to wait after the ECU accepts a session change before sending
+    /// the next request. See [IFACE_CFG::POST_SESSION_DELAY_MS]
+    pub fn set_post_session_delay_ms(&self, delay_ms: u32) {
+        self.post_session.set_delay_ms(delay_ms);
+    }
+
+    /// Sets if the first request after a session change is retried once when the ECU
+    /// is still busy. See [IFACE_CFG::POST_SESSION_RETRY]
+    pub fn set_post_session_retry(&self, retry: bool) {
+        self.post_session.set_retry(retry);
+    }
+
+    /// Clock used for the diag server's timeouts and delays
+    pub(crate) fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+
+    pub fn get_stats(&self) -> Stats {
+        *self.stats.read().unwrap()
+    }
+
+    pub fn reset_stats(&self) {
+        *self.stats.write().unwrap() = Stats::default();
+    }
+
+    /// Stops the diag server, waiting for it to close the interface.
+    /// Returns the error closing the interface failed with, if any
+    pub fn close(&mut self) -> ProtocolResult<()> {
+        self.exit_diag_session();
+        super::join_diag_thread(&self.thread)
+    }
+
+    /// Returns the parameters negotiated when the diagnostic session was started
+    pub fn connection_info(&self) -> ConnectionInfo {
+        self.connection_info.clone()
+    }
+
     pub fn get_session_type(&self) -> DiagSession {
         *self.curr_session_type.read().unwrap()
     }
+
+    /// Returns the security access level (The seed request sub function) which was last
+    /// unlocked, or None if the ECU is locked. Changing session and resetting the ECU
+    /// lock it again
+    pub fn security_level(&self) -> Option<u8> {
+        *self.security_level.read().unwrap()
+    }
+
+    /// Fails with a descriptive error unless security access `level` is unlocked, for
+    /// checking before a privileged request rather than relying on the ECU's negative response
+    pub fn require_security_level(&self, level: u8) -> ProtocolResult<()> {
+        match self.security_level() {
+            Some(l) if l == level => Ok(()),
+            Some(l) => Err(ProtocolError::CustomError(format!(
+                "Security access required (Level 0x{:02X}, but level 0x{:02X} is unlocked)",
+                level, l
+            ))),
+            None => Err(ProtocolError::CustomError(format!(
+                "Security access required (Level 0x{:02X})",
+                level
+            ))),
+        }
+    }
+
+    /// Returns a snapshot of the current session, which can be resumed
+    /// with [UDSECU::resume_diag_session]
+    pub fn get_state(&self) -> DiagSessionState {
+        let timing = *self.session_timing.read().unwrap();
+        DiagSessionState {
+            protocol: DiagProtocol::UDS,
+            session_type: self.get_session_type().to_byte(),
+            security_level: self.security_level(),
+            p2_max_ms: timing.map(|t| t.0),
+            p2_ext_max_ms: timing.map(|t| t.1),
+        }
+    }
+
+    /// Starts the diag server, assuming the ECU is already in the session described by `state`
+    pub fn resume_diag_session(
+        comm_server: &Box<dyn ComServer>,
+        interface_type: InterfaceType,
+        interface_cfg: InterfaceConfig,
+        tx_flags: Option<Vec<PayloadFlag>>,
+        diag_cfg: DiagCfg,
+        state: &DiagSessionState,
+    ) -> ProtocolResult<Self> {
+        if state.protocol != DiagProtocol::UDS {
+            return Err(ProtocolError::CustomError(
+                "Session state is not from a UDS session".into(),
+            ));
+        }
+        Self::start_session(
+            comm_server,
+            interface_type,
+            interface_cfg,
+            tx_flags,
+            diag_cfg,
+            Some(state),
+        )
+    }
+
+    /// Runs a command, giving up waiting for the ECU's response after `timeout_ms`.
+    /// If `timeout_ms` is None, this waits for as long as the diag server takes to respond
+    pub fn run_command_timeout(
+        &self,
+        cmd: u8,
+        args: &[u8],
+        timeout_ms: Option<u32>,
+    ) -> ProtocolResult<Vec<u8>> {
+        self.run_request(CommandRequest::new(cmd, args, true), timeout_ms)
+    }
+
+    /// Runs a command, ignoring any unrelated frames received before the ECU's
+    /// response to the command, for up to `deadline_ms`
+    pub fn run_command_await_sid(
+        &self,
+        cmd: u8,
+        args: &[u8],
+        deadline_ms: u32,
+    ) -> ProtocolResult<Vec<u8>> {
+        self.run_request(
+            CommandRequest {
+                await_sid_ms: Some(deadline_ms),
+                ..CommandRequest::new(cmd, args, true)
+            },
+            None,
+        )
+    }
+
+    /// Runs a command, checking the ECU's positive response is `expected_len` bytes long.
+    /// If `expected_len` is None, the protocol's default length for the command is checked
+    /// (If it has one)
+    pub fn run_command_expect_len(
+        &self,
+        cmd: u8,
+        args: &[u8],
+        expected_len: Option<usize>,
+    ) -> ProtocolResult<Vec<u8>> {
+        self.run_request(
+            CommandRequest {
+                expected_len,
+                ..CommandRequest::new(cmd, args, true)
+            },
+            None,
+        )
+    }
+
+    fn run_request(
+        &self,
+        request: CommandRequest,
+        timeout_ms: Option<u32>,
+    ) -> ProtocolResult<Vec<u8>> {
+        self.check_service_supported(request.cmd)?;
+        let cmd = request.cmd;
+        self.post_session
+            .run(|| self.send_request(request.clone(), timeout_ms))
+            .map(|res| {
+                if cmd == UDSCommand::ECUReset.into() {
+                    // The ECU restarts in the default session, locked
+                    *self.curr_session_type.write().unwrap() = DiagSession::Default;
+                    *self.security_level.write().unwrap() = None;
+                }
+                res
+            })
+            .map_err(|e| {
+                let overrides = self.nrc_overrides.read().unwrap();
+                if overrides.is_empty() {
+                    e
+                } else {
+                    super::apply_nrc_overrides(e, &overrides)
+                }
+            })
+    }
+
+    /// Refuses services which were added in a later revision of UDS than the one the ECU
+    /// was configured as implementing. See [ProtocolVariant]
+    fn check_service_supported(&self, cmd: u8) -> ProtocolResult<()> {
+        let variant = self.connection_info.variant;
+        if variant.supports_service(cmd) {
+            Ok(())
+        } else {
+            Err(ProtocolError::CustomError(format!(
+                "Service 0x{:02X} is not supported by ECUs implementing {:?}",
+                cmd, variant
+            )))
+        }
+    }
+
+    fn send_request(
+        &self,
+        mut request: CommandRequest,
+        timeout_ms: Option<u32>,
+    ) -> ProtocolResult<Vec<u8>> {
+        let _guard = self.cmd_mutex.lock().unwrap(); // We are allowed to send / receive!
+        let seq = self.correlator.tag(&mut request);
+        if self.cmd_tx.send(request).is_err() {
+            return Err(ProtocolError::CustomError("Channel Tx failed".into()));
+        }
+        let resp = match self
+            .correlator
+            .await_response(&self.cmd_rx, seq, timeout_ms)
+        {
+            Some(r) => r?,
+            None => {
+                self.stats.write().unwrap().timeouts += 1;
+                return Err(ProtocolError::Timeout);
+            }
+        };
+        if resp[0] == 0x7F {
+            let neg_code = UDSNegativeCode::from_byte(resp[2]);
+            Err(ProtocolError::ProtocolError(Box::new(neg_code)))
+        } else {
+            Ok(resp)
+        }
+    }
+
+    /// Registers descriptions for manufacturer specific NRCs, which replace the standard
+    /// description of errors returned by the ECU with those NRCs
+    pub fn set_nrc_overrides(&mut self, map: HashMap<u8, String>) {
+        *self.nrc_overrides.write().unwrap() = map;
+    }
+
+    /// Sends a command to the ECU without waiting for a response. Used for
+    /// requests where the ECU is told not to send a positive response.
+    ///
+    /// If `nrc_window_ms` is set, a negative response received within that window is
+    /// returned as an error. See [ResponseOptions::nrc_window_ms]
+    pub fn send_command(
+        &self,
+        cmd: u8,
+        args: &[u8],
+        nrc_window_ms: Option<u32>,
+    ) -> ProtocolResult<()> {
+        self.check_service_supported(cmd)?;
+        let _guard = self.cmd_mutex.lock().unwrap(); // We are allowed to send / receive!
+        let mut request = CommandRequest {
+            nrc_window_ms,
+            ..CommandRequest::new(cmd, args, false)
+        };
+        let seq = self.correlator.tag(&mut request);
+        if self.cmd_tx.send(request).is_err() {
+            return Err(ProtocolError::CustomError("Channel Tx failed".into()));
+        }
+        match self.correlator.await_response(&self.cmd_rx, seq, None) {
+            Some(r) => r.map(|_| ()),
+            None => Err(ProtocolError::CustomError("Channel Rx failed".into())),
+        }
+    }
+
+    /// Recovers the bus (After the CAN controller went bus-off), then re-enters
+    /// the extended diagnostic session
+    pub fn reconnect(&mut self) -> ProtocolResult<()> {
+        {
+            let _guard = self.cmd_mutex.lock().unwrap(); // No requests whilst recovering
+            super::request_bus_recovery(&self.recover_bus, 2000, &*self.clock)?;
+        }
+        self.establish_session(&ConnectTimer::new(&InterfaceConfig::new()))
+    }
+
+    /// Reads the ECU's session timings. See [access_timing::read_timing]
+    pub fn read_timing(&self) -> ProtocolResult<access_timing::SessionTimings> {
+        access_timing::read_timing(self)
+    }
+
+    /// Writes `data` directly to the ECU's memory at `addr` using WriteMemoryByAddress (0x3D).
+    /// See [write_memory::write_memory]
+    pub fn write_memory(
+        &self,
+        addr: u32,
+        data: &[u8],
+        fmt: write_memory::AddrLenFormat,
+    ) -> ProtocolResult<()> {
+        write_memory::write_memory(self, addr, data, fmt)
+    }
+
+    /// Runs a link control (0x87) step. See [link_control::link_control]
+    pub fn link_control(
+        &self,
+        mode: link_control::LinkControlMode,
+        baud: Option<link_control::LinkBaud>,
+    ) -> ProtocolResult<()> {
+        link_control::link_control(self, mode, baud)
+    }
+
+    /// Sets up and starts a response on event (0x86).
+    /// See [response_on_event::setup_response_on_event]
+    pub fn setup_response_on_event(
+        &self,
+        event: response_on_event::RoeEvent,
+        service_to_respond: &[u8],
+    ) -> ProtocolResult<()> {
+        response_on_event::setup_response_on_event(self, event, service_to_respond)
+    }
+
+    /// Stops all response on event events on the ECU
+    pub fn stop_response_on_event(&self) -> ProtocolResult<()> {
+        response_on_event::stop_response_on_event(self)
+    }
+
+    /// Returns the event responses collected since the last poll
+    pub fn poll_events(&self) -> Vec<Vec<u8>> {
+        response_on_event::poll_events(self)
+    }
+
+    /// Unlocks a security access (0x27) level, computing the key with `algorithm`.
+    /// See [security_access::unlock_security_access]
+    pub fn unlock_security_access(
+        &self,
+        level: u8,
+        algorithm: &dyn super::security::KeyAlgorithm,
+    ) -> ProtocolResult<()> {
+        security_access::unlock_security_access(self, level, algorithm)
+    }
+
+    /// Runs an authentication (0x29) sub function, returning the authenticationReturnParameter
+    /// byte, followed by any other data the ECU responded with (Challenge, certificate etc...)
+    pub fn authenticate(
+        &self,
+        sub_fn: authentication::AuthSubFunction,
+        data: &[u8],
+    ) -> ProtocolResult<Vec<u8>> {
+        authentication::authenticate(self, sub_fn, data)
+    }
+
+    /// Enters the extended diagnostic session and checks the ECU responds to
+    /// tester present, within the connection time budget
+    fn establish_session(&mut self, timer: &ConnectTimer) -> ProtocolResult<()> {
+        let timeout = timer.remaining("session control")?;
+        self.set_diag_session_mode(DiagSession::Extended, timeout)
+            .map_err(|e| timer.annotate(e, "session control"))?;
+        let timeout = timer.remaining("tester present")?;
+        if let Err(e) = self.run_command_timeout(UDSCommand::TesterPresent.into(), &[0x00], timeout)
+        {
+            if e.is_timeout() {
+                return Err(timer.annotate(e, "tester present"));
+            }
+            println!("Warning. ECU did not approve of tester present - {:?}", e);
+        }
+        Ok(())
+    }
+
+    /// Reads an adaptation channel. See [adaptation::read_adaptation]
+    pub fn read_adaptation(&self, channel: u16) -> ProtocolResult<Vec<u8>> {
+        adaptation::read_adaptation(self, channel)
+    }
+
+    /// Writes an adaptation channel, checking the ECU is in a session which allows it.
+    /// See [adaptation::write_adaptation]
+    pub fn write_adaptation(&self, channel: u16, value: &[u8]) -> ProtocolResult<()> {
+        adaptation::write_adaptation(self, channel, value)
+    }
+
+    /// Downloads `image` to the ECU at `addr`, stopping early if `token` is cancelled.
+    /// See [upload_download::flash_region]
+    pub fn flash_region(
+        &self,
+        addr: u32,
+        image: &[u8],
+        token: super::CancelToken,
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> ProtocolResult<upload_download::FlashOutcome> {
+        upload_download::flash_region(self, addr, image, token, progress)
+    }
+
+    /// Reads `size` bytes of the ECU's memory at `addr` with an upload.
+    /// See [upload_download::upload_region]
+    pub fn upload_region(
+        &self,
+        addr: u32,
+        size: u32,
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> ProtocolResult<Vec<u8>> {
+        upload_download::upload_region(self, addr, size, progress)
+    }
+
+    /// Writes a DID, entering the extended session and unlocking security access first if
+    /// needed. See [write_data::write_did_managed]
+    pub fn write_did_managed(
+        &mut self,
+        did: u16,
+        data: &[u8],
+        security: Option<(u8, &dyn super::security::KeyAlgorithm)>,
+        restore_session: bool,
+    ) -> ProtocolResult<()> {
+        write_data::write_did_managed(self, did, data, security, restore_session)
+    }
+
+    /// Writes a DID and reads it back to confirm the write. See [write_data::write_and_verify]
+    pub fn write_and_verify(&self, did: u16, data: &[u8]) -> ProtocolResult<bool> {
+        write_data::write_and_verify(self, did, data)
+    }
+
+    /// Reads a DID, passing its data to `cb` as it is received.
+    /// See [read_data::read_did_streaming]
+    pub fn read_data_by_identifier_streaming(
+        &self,
+        did: u16,
+        mut cb: impl FnMut(&[u8]),
+    ) -> ProtocolResult<usize> {
+        read_data::read_did_streaming(self, did, &mut cb)
+    }
+
+    /// Reads and decodes the identification DIDs the ECU supports.
+    /// See [identification::read_identification]
+    pub fn read_identification(&self) -> Vec<read_data::DidRow> {
+        identification::read_identification(self)
+    }
+
+    /// Reads the serial number of the ECU. See [identification::read_serial_number]
+    pub fn read_serial_number(&self) -> ProtocolResult<String> {
+        identification::read_serial_number(self)
+    }
+
+    /// Reads the date the ECU was manufactured. See [identification::read_manufacturing_date]
+    pub fn read_manufacturing_date(&self) -> ProtocolResult<String> {
+        identification::read_manufacturing_date(self)
+    }
+
+    /// Reads the boot software identifiers of the ECU. See [identification::read_boot_software_id]
+    pub fn read_boot_software_id(&self) -> ProtocolResult<Vec<String>> {
+        identification::read_boot_software_id(self)
+    }
+
+    /// Reads the application software identifiers of the ECU.
+    /// See [identification::read_application_software_id]
+    pub fn read_application_software_id(&self) -> ProtocolResult<Vec<String>> {
+        identification::read_application_software_id(self)
+    }
+
+    /// Reads the application data identifiers of the ECU.
+    /// See [identification::read_application_data_id]
+    pub fn read_application_data_id(&self) -> ProtocolResult<Vec<String>> {
+        identification::read_application_data_id(self)
+    }
+
+    /// Reads the record of who programmed the ECU, and when. See [fingerprint::read_fingerprint]
+    pub fn read_fingerprint(&self) -> ProtocolResult<fingerprint::Fingerprint> {
+        fingerprint::read_fingerprint(self)
+    }
+
+    /// Writes the fingerprint record of the ECU. See [fingerprint::write_fingerprint]
+    pub fn write_fingerprint(&self, fp: &fingerprint::Fingerprint) -> ProtocolResult<()> {
+        fingerprint::write_fingerprint(self, fp)
+    }
+
+    /// Reads the DTCs whose status matches `status_mask`.
+    /// See [read_dtc_information::read_dtcs_by_status_mask]
+    pub fn read_dtcs_by_status_mask(&self, status_mask: u8) -> ProtocolResult<Vec<DTC>> {
+        read_dtc_information::read_dtcs_by_status_mask(self, status_mask)
+    }
+
+    /// Reads every DTC the ECU supports, regardless of status.
+    /// See [read_dtc_information::read_all_dtcs]
+    pub fn read_all_dtcs(&self) -> ProtocolResult<Vec<DTC>> {
+        read_dtc_information::read_all_dtcs(self)
+    }
+
+    /// Reads the DTCs matching a severity and status mask, most severe first.
+    /// See [read_dtc_information::read_dtc_by_severity]
+    pub fn read_dtc_by_severity(
+        &self,
+        severity_mask: u8,
+        status_mask: u8,
+    ) -> ProtocolResult<Vec<DTC>> {
+        read_dtc_information::read_dtc_by_severity(self, severity_mask, status_mask)
+    }
+
+    /// Reads the severity and functional unit of a DTC.
+    /// See [read_dtc_information::read_dtc_severity]
+    pub fn read_dtc_severity(
+        &self,
+        dtc: super::DtcCode,
+    ) -> ProtocolResult<Option<(read_dtc_information::DtcSeverity, u8)>> {
+        read_dtc_information::read_dtc_severity(self, dtc)
+    }
+
+    /// Reads a single DTC by when it occurred. See [read_dtc_information::read_dtc_by_occurrence]
+    pub fn read_dtc_by_occurrence(
+        &self,
+        which: read_dtc_information::DtcOccurrence,
+    ) -> ProtocolResult<Option<DTC>> {
+        read_dtc_information::read_dtc_by_occurrence(self, which)
+    }
+
+    /// Reads the DTC which most recently failed a test.
+    /// See [read_dtc_information::read_most_recent_test_failed_dtc]
+    pub fn read_most_recent_test_failed_dtc(&self) -> ProtocolResult<Option<DTC>> {
+        read_dtc_information::read_most_recent_test_failed_dtc(self)
+    }
+
+    /// Reads the DTC which was most recently confirmed.
+    /// See [read_dtc_information::read_most_recent_confirmed_dtc]
+    pub fn read_most_recent_confirmed_dtc(&self) -> ProtocolResult<Option<DTC>> {
+        read_dtc_information::read_most_recent_confirmed_dtc(self)
+    }
+
+    /// Reads the snapshot records of a DTC. See [read_dtc_information::read_dtc_snapshot]
+    pub fn read_dtc_snapshot(&self, dtc: &DTC) -> ProtocolResult<Vec<u8>> {
+        read_dtc_information::read_dtc_snapshot(self, dtc)
+    }
+
+    /// Reads the fault detection counter and occurrence records of a DTC.
+    /// See [read_dtc_information::read_dtc_extended]
+    pub fn read_dtc_extended(
+        &self,
+        dtc: super::DtcCode,
+        layout: &read_dtc_information::ExtDataLayout,
+    ) -> ProtocolResult<read_dtc_information::DtcExtendedData> {
+        read_dtc_information::read_dtc_extended(self, dtc, layout)
+    }
+
+    /// Reads a table of DIDs for display, optionally naming and decoding them with `lookup`.
+    /// See [read_data::read_did_table]
+    pub fn read_did_table(
+        &self,
+        dids: &[u16],
+        lookup: Option<&dyn read_data::DidLookup>,
+    ) -> Vec<read_data::DidRow> {
+        read_data::read_did_table(self, dids, lookup)
+    }
+
+    /// Probes a list of candidate DIDs, returning which ones the ECU responded
+    /// positively to. DIDs that return requestOutOfRange are classed as unsupported
+    pub fn enumerate_dids(&self, candidates: &[u16]) -> Vec<(u16, bool)> {
+        candidates
+            .iter()
+            .map(|did| match read_data::read_did(self, *did) {
+                Ok(_) => (*did, true),
+                Err(e) => {
+                    if e.get_nrc() != Some(UDSNegativeCode::RequestOutOfRange.get_byte()) {
+                        println!("DID 0x{:04X} could not be read - {}", did, e.get_text());
+                    }
+                    (*did, false)
+                }
+            })
+            .collect()
+    }
 }
 
-impl ProtocolServer for UDSECU {
-    type Command = UDSCommand;
-    type Error = UDSNegativeCode;
-    fn start_diag_session(
+impl UDSECU {
+    /// Starts the diag server. If `resume` is set, then the ECU is assumed to already
+    /// be in the session described by the state, and no handshake is done with the ECU
+    fn start_session(
         comm_server: &Box<dyn ComServer>,
         interface_type: InterfaceType,
         interface_cfg: InterfaceConfig,
         tx_flags: Option<Vec<PayloadFlag>>,
         diag_cfg: DiagCfg,
+        resume: Option<&DiagSessionState>,
     ) -> ProtocolResult<Self> {
         if interface_type != InterfaceType::IsoTp {
             return Err(ProtocolError::CustomError(
                 "UDS Can only be executed over ISO-TP".into(),
             ));
         }
+        super::check_interface_support(comm_server, interface_type)?;
+        super::validate_tx_flags(interface_type, &interface_cfg, &tx_flags, &diag_cfg)?;
 
-        let mut interface = IsoTPInterface::new(comm_server.clone_box())?;
-
-        interface.setup(&interface_cfg)?;
-        interface.add_filter(FilterType::IsoTP {
-            id: diag_cfg.recv_id,
-            mask: 0xFFFF,
-            fc: diag_cfg.send_id,
-        })?;
+        let timer = ConnectTimer::new(&interface_cfg);
+        let step_server = comm_server.clone_box();
+        let step_cfg = interface_cfg.clone();
+        let mut interface = timer.run_step(
+            "interface open",
+            move || {
+                let mut interface = if DryRunInterface::is_enabled(&step_cfg) {
+                    DryRunInterface::new(step_server, DiagProtocol::UDS)
+                } else {
+                    IsoTPInterface::new_for_cfg(step_server, &step_cfg)?
+                };
+                super::close_on_err(&mut interface, |i| {
+                    i.setup(&step_cfg)?;
+                    i.add_filter(FilterType::IsoTP {
+                        id: diag_cfg.recv_id,
+                        mask: 0xFFFF,
+                        fc: diag_cfg.send_id,
+                    })
+                })?;
+                Ok(interface)
+            },
+            |mut interface| {
+                let _ = interface.close();
+            },
+        )?;
 
         let should_run = Arc::new(AtomicBool::new(true));
         let should_run_t = should_run.clone();
@@ -466,39 +1286,119 @@ impl ProtocolServer for UDSECU {
         let last_error_t = last_error.clone();
 
         let (channel_tx_sender, channel_tx_receiver): (
-            Sender<(u8, Vec<u8>, bool)>,
-            Receiver<(u8, Vec<u8>, bool)>,
+            Sender<CommandRequest>,
+            Receiver<CommandRequest>,
         ) = mpsc::channel();
         let (channel_rx_sender, channel_rx_receiver): (
-            Sender<ProtocolResult<Vec<u8>>>,
-            Receiver<ProtocolResult<Vec<u8>>>,
+            Sender<CommandResponse>,
+            Receiver<CommandResponse>,
         ) = mpsc::channel();
 
         let session_type = Arc::new(RwLock::new(DiagSession::Default));
         let session_type_t = session_type.clone();
 
+        let strict_matching = Arc::new(AtomicBool::new(true));
+        let strict_matching_t = strict_matching.clone();
+        let handle_pending = Arc::new(AtomicBool::new(true));
+        let handle_pending_t = handle_pending.clone();
+        let resp_opts = ResponseOptions::new(&interface_cfg);
+        let clock = resp_opts.clock.clone();
+        super::wait_for_bus_idle(&mut interface, &interface_cfg, &*resp_opts.clock);
+
+        let stats = Arc::new(RwLock::new(Stats::default()));
+        let stats_t = stats.clone();
+
+        let relink_baud = Arc::new(RwLock::new(None));
+        let relink_baud_t = relink_baud.clone();
+        let mut relink_cfg = interface_cfg.clone();
+
+        let roe_active = Arc::new(AtomicBool::new(false));
+        let roe_active_t = roe_active.clone();
+        let roe_events = Arc::new(RwLock::new(Vec::new()));
+        let roe_events_t = roe_events.clone();
+
+        let connection_info =
+            ConnectionInfo::new(DiagProtocol::UDS, interface_type, &interface_cfg, &diag_cfg);
+
+        let recover_bus = Arc::new(AtomicBool::new(false));
+        let recover_bus_t = recover_bus.clone();
+
+        let session_timing: Arc<RwLock<Option<(u16, u32)>>> = Arc::new(RwLock::new(None));
+        let session_timing_t = session_timing.clone();
+
         // Enter extended diagnostic session (Full features)
         let s_id = diag_cfg.send_id;
-        std::thread::spawn(move || {
+        let thread = std::thread::spawn(move || {
             println!("UDS Diag server start!");
-            let mut timer = Instant::now();
+            let mut timer = resp_opts.clock.now_ms();
             while should_run_t.load(Relaxed) {
+                if let Some(baud) = relink_baud_t.write().unwrap().take() {
+                    // Link control transitioned, re-open the interface at the new baud rate
+                    relink_cfg.add_param(IFACE_CFG::BAUDRATE, baud);
+                    let res = interface.close().and_then(|_| {
+                        interface.setup(&relink_cfg)?;
+                        interface.add_filter(FilterType::IsoTP {
+                            id: diag_cfg.recv_id,
+                            mask: 0xFFFF,
+                            fc: diag_cfg.send_id,
+                        })
+                    });
+                    if let Err(e) = res {
+                        eprintln!("UDS - Could not switch interface to {} bps: {}", baud, e);
+                        *last_error_t.write().unwrap() = Some(ProtocolError::CommError(e));
+                        break;
+                    }
+                }
+                if recover_bus_t.load(Relaxed) {
+                    let res = interface.recover_bus(&relink_cfg).and_then(|_| {
+                        interface.add_filter(FilterType::IsoTP {
+                            id: diag_cfg.recv_id,
+                            mask: 0xFFFF,
+                            fc: diag_cfg.send_id,
+                        })
+                    });
+                    if let Err(e) = res {
+                        eprintln!("UDS - Could not recover the bus: {}", e);
+                        *last_error_t.write().unwrap() = Some(ProtocolError::CommError(e));
+                    }
+                    recover_bus_t.store(false, Relaxed);
+                }
                 if let Ok(data) = channel_tx_receiver.try_recv() {
                     let res = Self::run_command_resp(
                         &mut interface,
                         &tx_flags,
                         s_id,
-                        data.0,
-                        &data.1,
-                        data.2,
+                        data.cmd,
+                        &data.args,
+                        data.response_required,
+                        &ResponseOptions {
+                            strict_matching: strict_matching_t.load(Relaxed),
+                            handle_response_pending: handle_pending_t.load(Relaxed),
+                            await_sid_ms: data.await_sid_ms,
+                            expected_len: data.expected_len,
+                            nrc_window_ms: data.nrc_window_ms,
+                            // P2* of the session, once the ECU has told us
+                            pending_timeout_ms: session_timing_t
+                                .read()
+                                .unwrap()
+                                .map_or(resp_opts.pending_timeout_ms, |(_, p2_ext)| p2_ext),
+                            ..resp_opts.clone()
+                        },
+                        &stats_t,
                     );
-                    if channel_rx_sender.send(res).is_err() {
+                    if channel_rx_sender.send((data.seq, res)).is_err() {
                         *last_error_t.write().unwrap() =
                             Some(ProtocolError::CustomError("Sender channel died".into()));
                         break;
                     }
+                } else if roe_active_t.load(Relaxed) {
+                    // Collect any event responses the ECU sent whilst idle
+                    if let Ok(frames) = interface.recv_data(10, 0) {
+                        let mut events = roe_events_t.write().unwrap();
+                        events.extend(frames.into_iter().map(|f| f.data));
+                    }
                 }
-                if timer.elapsed().as_millis() >= 2000
+                if resp_opts.clock.elapsed_ms(timer) >= 2000
                     && *session_type_t.read().unwrap() != DiagSession::Default
                 {
                     if Self::run_command_resp(
@@ -508,17 +1408,19 @@ impl ProtocolServer for UDSECU {
                         UDSCommand::TesterPresent.into(),
                         &[0x00],
                         true,
+                        &resp_opts,
+                        &stats_t,
                     )
                     .is_err()
                     {
                         println!("Lost connection with ECU!");
                     }
-                    timer = Instant::now();
+                    timer = resp_opts.clock.now_ms();
                 }
                 std::thread::sleep(std::time::Duration::from_micros(100))
             }
             println!("UDS Diag server stop!");
-            let _res = interface.close();
+            interface.close()
         });
 
         let mut ecu = UDSECU {
@@ -526,38 +1428,94 @@ impl ProtocolServer for UDSECU {
             last_error,
             cmd_tx: channel_tx_sender,
             cmd_rx: Arc::new(channel_rx_receiver),
+            correlator: RequestCorrelator::new(clock.clone()),
             send_id: diag_cfg.send_id,
             curr_session_type: session_type, // Assumed,
             cmd_mutex: Arc::new(Mutex::new(())),
+            strict_response_matching: strict_matching,
+            handle_response_pending: handle_pending,
+            security_level: Arc::new(RwLock::new(None)),
+            clear_engine_guard: Arc::new(AtomicBool::new(false)),
+            stats,
+            comm_server: comm_server.clone_box(),
+            global_id: diag_cfg.global_id,
+            session_timing,
+            link_baud: Arc::new(RwLock::new(None)),
+            relink_baud,
+            nrc_overrides: Arc::new(RwLock::new(HashMap::new())),
+            roe_active,
+            roe_events,
+            connection_info,
+            recover_bus,
+            post_session: PostSessionGuard::new(&interface_cfg, clock.clone()),
+            clock,
+            thread: Arc::new(Mutex::new(Some(thread))),
         };
 
-        if let Err(e) = ecu.set_diag_session_mode(DiagSession::Extended) {
+        if let Some(state) = resume {
+            // ECU should already be in this session
+            *ecu.curr_session_type.write().unwrap() = DiagSession::from_byte(state.session_type);
+            *ecu.security_level.write().unwrap() = state.security_level;
+            if let (Some(p2), Some(p2_ext)) = (state.p2_max_ms, state.p2_ext_max_ms) {
+                *ecu.session_timing.write().unwrap() = Some((p2, p2_ext));
+            }
+        } else if let Err(e) = ecu.establish_session(&timer) {
             ecu.should_run.store(false, Relaxed);
             return Err(e);
         }
+        let state = ecu.get_state();
+        let name = format!("{:?}", ecu.get_session_type());
+        ecu.connection_info
+            .set_session(&state, name, resume.is_some());
         Ok(ecu)
     }
+}
+
+impl ProtocolServer for UDSECU {
+    type Command = UDSCommand;
+    type Error = UDSNegativeCode;
+
+    fn expected_response_len(cmd: u8, args: &[u8]) -> Option<usize> {
+        let sub_function = args.first().map(|x| x & 0x7F);
+        match (cmd, sub_function) {
+            // ClearDTCInformation - SID only
+            (0x14, _) => Some(1),
+            // ECUReset (enableRapidPowerShutDown) - SID, sub function and powerDownTime
+            (0x11, Some(0x04)) => Some(3),
+            // ECUReset, CommunicationControl, TesterPresent and ControlDTCSetting
+            // SID and sub function
+            (0x11, Some(_)) | (0x28, Some(_)) | (0x3E, Some(_)) | (0x85, Some(_)) => Some(2),
+            _ => None,
+        }
+    }
+
+    fn start_diag_session(
+        comm_server: &Box<dyn ComServer>,
+        interface_type: InterfaceType,
+        interface_cfg: InterfaceConfig,
+        tx_flags: Option<Vec<PayloadFlag>>,
+        diag_cfg: DiagCfg,
+    ) -> ProtocolResult<Self> {
+        Self::start_session(
+            comm_server,
+            interface_type,
+            interface_cfg,
+            tx_flags,
+            diag_cfg,
+            None,
+        )
+    }
 
     fn exit_diag_session(&mut self) {
         self.should_run.store(false, Relaxed);
     }
 
     fn run_command(&self, cmd: u8, args: &[u8]) -> ProtocolResult<Vec<u8>> {
-        let _guard = self.cmd_mutex.lock().unwrap(); // We are allowed to send / receive!
-        if self.cmd_tx.send((cmd, Vec::from(args), true)).is_err() {
-            return Err(ProtocolError::CustomError("Channel Tx failed".into()));
-        }
-        let resp = self.cmd_rx.recv().unwrap()?;
-        if resp[0] == 0x7F {
-            let neg_code = UDSNegativeCode::from_byte(resp[2]);
-            Err(ProtocolError::ProtocolError(Box::new(neg_code)))
-        } else {
-            Ok(resp)
-        }
+        self.run_command_timeout(cmd, args, None)
     }
 
     fn read_errors(&self) -> ProtocolResult<Vec<DTC>> {
-        todo!()
+        self.read_dtcs_by_status_mask(read_dtc_information::STATUS_MASK_ALL)
     }
 
     fn is_in_diag_session(&self) -> bool {
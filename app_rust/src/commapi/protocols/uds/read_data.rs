@@ -1,10 +1,119 @@
-use crate::commapi::protocols::{ProtocolResult, ProtocolServer};
+use crate::commapi::{
+    iface::hex_dump,
+    protocols::{response_payload, ProtocolError, ProtocolResult, ProtocolServer},
+};
 
 use super::UDSECU;
 
 pub fn read_variant_id(ecu: &UDSECU) -> ProtocolResult<u32> {
-    let mut res = ecu.run_command(super::UDSCommand::ReadDataByID.into(), &[0xF1, 0x00])?;
-    println!("{:02X?}", res);
-    res.drain(0..2);
-    Ok((res[0] as u32) << 24 | (res[1] as u32) << 16 | (res[2] as u32) << 8 | res[3] as u32)
+    let res = ecu.run_command(super::UDSCommand::ReadDataByID.into(), &[0xF1, 0x00])?;
+    println!("{}", hex_dump(&res));
+    // Variant ID is read from the low byte of the DID echo onwards
+    match response_payload(&res) {
+        [_, a, b, c, d, ..] => {
+            Ok((*a as u32) << 24 | (*b as u32) << 16 | (*c as u32) << 8 | *d as u32)
+        }
+        _ => Err(ProtocolError::InvalidResponseSize {
+            expect: 6,
+            actual: res.len(),
+        }),
+    }
+}
+
+/// Reads a single data identifier from the ECU.
+/// The returned bytes are the positive response with the SID and DID stripped
+pub fn read_did(ecu: &UDSECU, did: u16) -> ProtocolResult<Vec<u8>> {
+    let mut res = ecu.run_command(
+        super::UDSCommand::ReadDataByID.into(),
+        &[(did >> 8) as u8, did as u8],
+    )?;
+    res.drain(0..std::cmp::min(3, res.len())); // SID + 2 DID bytes
+    Ok(res)
+}
+
+/// Reads a single data identifier, passing its data (With the SID and DID stripped) to `cb`
+/// as it is received. Returns the length of the data.
+///
+/// Adapters with hardware ISO-TP only pass on the response once it is reassembled, so over
+/// those `cb` is called once, with all of the data. Where the application handles the CAN
+/// frames itself, [crate::commapi::isotp::IsoTpReassembler::on_frame_streaming] passes
+/// on the data of each frame as it arrives
+pub fn read_did_streaming(
+    ecu: &UDSECU,
+    did: u16,
+    cb: &mut dyn FnMut(&[u8]),
+) -> ProtocolResult<usize> {
+    let data = read_did(ecu, did)?;
+    if !data.is_empty() {
+        cb(&data);
+    }
+    Ok(data.len())
+}
+
+/// Reads a DID holding ASCII text, removing the padding (Spaces, 0x00 or 0xFF)
+/// around the text. Returns None if the ECU rejected the DID with a negative
+/// response, or if the DID is empty
+pub fn read_ascii_did(ecu: &UDSECU, did: u16) -> ProtocolResult<Option<String>> {
+    match read_did(ecu, did) {
+        Ok(r) => Ok(decode_ascii(&r)),
+        Err(e) if e.get_nrc().is_some() => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Decodes ASCII text, removing the padding (Spaces, 0x00 or 0xFF) around it.
+/// Returns None if there is no text
+pub fn decode_ascii(raw: &[u8]) -> Option<String> {
+    let text: String = raw
+        .iter()
+        .filter(|x| **x != 0x00 && **x != 0xFF)
+        .map(|x| *x as char)
+        .collect();
+    Some(text.trim().to_string()).filter(|s| !s.is_empty())
+}
+
+/// Names and decodes DIDs for display
+pub trait DidLookup {
+    /// Returns the name of a DID, if it is known
+    fn get_name(&self, did: u16) -> Option<String>;
+    /// Decodes the raw value of a DID into a readable string, if the DID is known
+    fn decode(&self, did: u16, raw: &[u8]) -> Option<String>;
+}
+
+/// A single row of a DID table
+#[derive(Debug, Clone)]
+pub struct DidRow {
+    pub did: u16,
+    pub name: Option<String>,
+    /// Raw value of the DID (Empty if it could not be read)
+    pub raw: Vec<u8>,
+    pub decoded: Option<String>,
+    /// Why the DID could not be read
+    pub error: Option<String>,
+}
+
+/// Reads each DID in `dids`, returning a row for each of them. DIDs that cannot
+/// be read still get a row, with the error set
+pub fn read_did_table(ecu: &UDSECU, dids: &[u16], lookup: Option<&dyn DidLookup>) -> Vec<DidRow> {
+    dids.iter()
+        .map(|did| {
+            let name = lookup.and_then(|l| l.get_name(*did));
+            match read_did(ecu, *did) {
+                Ok(raw) => DidRow {
+                    did: *did,
+                    name,
+                    decoded: lookup.and_then(|l| l.decode(*did, &raw)),
+                    raw,
+                    error: None,
+                },
+                Err(e) => DidRow {
+                    did: *did,
+                    name,
+                    raw: Vec::new(),
+                    decoded: None,
+                    error: Some(e.get_text()),
+                },
+            }
+        })
+        .collect()
 }
@@ -0,0 +1,636 @@
+use crate::commapi::{
+    iface::format_hex_compact,
+    protocols::{DTCState, DtcCode, ProtocolError, ProtocolResult, ProtocolServer, DTC},
+};
+
+use super::UDSECU;
+
+// The service, Read DTC Information ($19), reports DTCs and the data the ECU stored with them.
+// The layout of extended data records is manufacturer specific, so callers describe which
+// record numbers hold occurrence information with an [ExtDataLayout]
+//
+// ISO 14229 has no continuation for DTC lists, and most ECUs return every DTC in one response.
+// However, some ECUs whose response buffer is smaller than their fault memory only return the
+// DTCs that fit, and return the rest on repeated requests. To catch this, the number of DTCs
+// is read first (Sub function $01), and reportDTCByStatusMask ($02) is repeated until all of
+// them were returned. This only applies to reading DTCs by status mask
+
+/// reportNumberOfDTCByStatusMask
+const REPORT_NUMBER_OF_DTC_BY_STATUS_MASK: u8 = 0x01;
+/// reportDTCByStatusMask
+const REPORT_DTC_BY_STATUS_MASK: u8 = 0x02;
+/// reportDTCSnapshotRecordByDTCNumber
+const REPORT_SNAPSHOT_BY_DTC: u8 = 0x04;
+/// reportDTCExtDataRecordByDTCNumber
+const REPORT_EXT_DATA_BY_DTC: u8 = 0x06;
+/// reportDTCBySeverityMaskRecord
+const REPORT_DTC_BY_SEVERITY_MASK: u8 = 0x08;
+/// reportSeverityInformationOfDTC
+const REPORT_SEVERITY_OF_DTC: u8 = 0x09;
+/// reportDTCFaultDetectionCounter
+const REPORT_FAULT_DETECTION_COUNTER: u8 = 0x14;
+/// reportSupportedDTC
+const REPORT_SUPPORTED_DTC: u8 = 0x0A;
+/// reportFirstTestFailedDTC
+const REPORT_FIRST_TEST_FAILED_DTC: u8 = 0x0B;
+/// reportFirstConfirmedDTC
+const REPORT_FIRST_CONFIRMED_DTC: u8 = 0x0C;
+/// reportMostRecentTestFailedDTC
+const REPORT_MOST_RECENT_TEST_FAILED_DTC: u8 = 0x0D;
+/// reportMostRecentConfirmedDTC
+const REPORT_MOST_RECENT_CONFIRMED_DTC: u8 = 0x0E;
+
+/// Snapshot record number requesting every snapshot record of a DTC
+const ALL_SNAPSHOT_RECORDS: u8 = 0xFF;
+/// Status mask matching a DTC with any status bit set
+pub const STATUS_MASK_ALL: u8 = 0xFF;
+/// Most responses read when the ECU returns its DTCs over several responses
+const MAX_DTC_PAGES: usize = 16;
+
+/// Decodes the storage state of a DTC from its UDS status byte
+/// (Bit 3 confirmedDTC, bit 2 pendingDTC)
+fn dtc_state_from_status(status: u8) -> DTCState {
+    if status & 0b0000_1000 != 0 {
+        DTCState::Stored
+    } else if status & 0b0000_0100 != 0 {
+        DTCState::Pending
+    } else {
+        DTCState::None
+    }
+}
+
+/// Parses a list of DTC records (`59 <sub function> <availability mask> [<DTC> <status>]...`)
+fn parse_dtc_records(res: &[u8]) -> ProtocolResult<Vec<DTC>> {
+    if res.len() < 3 {
+        return Err(ProtocolError::InvalidResponseSize {
+            expect: 3,
+            actual: res.len(),
+        });
+    }
+    // DTC is 4 bytes (3 for the ID, 1 for status)
+    if (res.len() - 3) % 4 != 0 {
+        return Err(ProtocolError::InvalidResponseSize {
+            expect: res.len() - (res.len() - 3) % 4,
+            actual: res.len(),
+        });
+    }
+    Ok(res[3..]
+        .chunks_exact(4)
+        .map(|record| {
+            let status = record[3];
+            DTC {
+                error: DTC::format_code(u16::from_be_bytes([record[0], record[1]])),
+                state: dtc_state_from_status(status),
+                check_engine_on: status & 0b1000_0000 != 0, // warningIndicatorRequested
+                id: DtcCode::from_bytes(&[record[0], record[1], record[2]]),
+            }
+        })
+        .collect())
+}
+
+/// Parses the number of DTCs from a reportNumberOfDTCByStatusMask response
+/// (`59 01 <availability mask> <format identifier> <count (2 bytes)>`)
+fn parse_dtc_count(res: &[u8]) -> ProtocolResult<u16> {
+    match res {
+        [_, _, _, _, hi, lo, ..] => Ok((*hi as u16) << 8 | *lo as u16),
+        _ => Err(ProtocolError::InvalidResponseSize {
+            expect: 6,
+            actual: res.len(),
+        }),
+    }
+}
+
+/// Reads a DTC list which may be returned over several responses, calling `read_page` until
+/// `expected` DTCs were returned. Stops early if a response has no DTCs which were not
+/// already returned. If `expected` is None, only one response is read
+fn read_dtc_pages<F>(expected: Option<u16>, mut read_page: F) -> ProtocolResult<Vec<DTC>>
+where
+    F: FnMut() -> ProtocolResult<Vec<u8>>,
+{
+    let mut dtcs = parse_dtc_records(&read_page()?)?;
+    let expected = match expected {
+        Some(n) if n as usize > dtcs.len() => n as usize,
+        _ => return Ok(dtcs),
+    };
+    for _ in 1..MAX_DTC_PAGES {
+        let before = dtcs.len();
+        for dtc in parse_dtc_records(&read_page()?)? {
+            if !dtcs.iter().any(|d| d.id == dtc.id) {
+                dtcs.push(dtc);
+            }
+        }
+        if dtcs.len() == before {
+            break;
+        }
+        if dtcs.len() >= expected {
+            return Ok(dtcs);
+        }
+    }
+    eprintln!(
+        "UDS - ECU reported {} DTCs, but only returned {}",
+        expected,
+        dtcs.len()
+    );
+    Ok(dtcs)
+}
+
+/// Reads the DTCs whose status matches `status_mask` (Sub function $02).
+///
+/// Only DTCs with at least one of the status bits in the mask set are returned, so
+/// DTCs which are supported but have not been tested or failed are left out.
+///
+/// If the ECU reports more DTCs (Sub function $01) than it returns, the request is
+/// repeated to read the rest. ECUs which do not support counting DTCs are read once
+pub fn read_dtcs_by_status_mask(ecu: &UDSECU, status_mask: u8) -> ProtocolResult<Vec<DTC>> {
+    let expected = match ecu.run_command(
+        super::UDSCommand::ReadDTCInformation.into(),
+        &[REPORT_NUMBER_OF_DTC_BY_STATUS_MASK, status_mask],
+    ) {
+        Ok(res) => parse_dtc_count(&res).ok(),
+        Err(e) if e.get_nrc().is_some() => None,
+        Err(e) => return Err(e),
+    };
+    read_dtc_pages(expected, || {
+        ecu.run_command(
+            super::UDSCommand::ReadDTCInformation.into(),
+            &[REPORT_DTC_BY_STATUS_MASK, status_mask],
+        )
+    })
+}
+
+/// Reads every DTC the ECU supports, regardless of its status (Sub function $0A).
+///
+/// Unlike [read_dtcs_by_status_mask], this returns DTCs whose status is 0x00
+/// (Never failed), so expect far more DTCs than are stored in the fault memory.
+/// Use [DTC::is_confirmed] to find the stored ones
+pub fn read_all_dtcs(ecu: &UDSECU) -> ProtocolResult<Vec<DTC>> {
+    let res = ecu.run_command(
+        super::UDSCommand::ReadDTCInformation.into(),
+        &[REPORT_SUPPORTED_DTC],
+    )?;
+    parse_dtc_records(&res)
+}
+
+/// Severity bit maintenanceOnly
+pub const SEVERITY_MAINTENANCE_ONLY: u8 = 0x20;
+/// Severity bit checkAtNextHalt
+pub const SEVERITY_CHECK_AT_NEXT_HALT: u8 = 0x40;
+/// Severity bit checkImmediately
+pub const SEVERITY_CHECK_IMMEDIATELY: u8 = 0x80;
+/// Severity mask matching a DTC with any severity bit set
+pub const SEVERITY_MASK_ALL: u8 =
+    SEVERITY_MAINTENANCE_ONLY | SEVERITY_CHECK_AT_NEXT_HALT | SEVERITY_CHECK_IMMEDIATELY;
+
+/// Severity of a DTC, as reported by the ECU. Ordered from least to most severe
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DtcSeverity {
+    /// ECU reports no severity for the DTC
+    NoSeverity,
+    /// Failure only needs to be fixed at the next service
+    MaintenanceOnly,
+    /// Driver should stop at the next safe opportunity
+    CheckAtNextHalt,
+    /// Driver should stop immediately (Safety critical)
+    CheckImmediately,
+}
+
+impl DtcSeverity {
+    /// Decodes the severity byte of a DTC. If the ECU sets more than one
+    /// severity bit, the most severe one is returned
+    pub fn from_byte(severity: u8) -> Self {
+        if severity & SEVERITY_CHECK_IMMEDIATELY != 0 {
+            Self::CheckImmediately
+        } else if severity & SEVERITY_CHECK_AT_NEXT_HALT != 0 {
+            Self::CheckAtNextHalt
+        } else if severity & SEVERITY_MAINTENANCE_ONLY != 0 {
+            Self::MaintenanceOnly
+        } else {
+            Self::NoSeverity
+        }
+    }
+}
+
+/// Parses a list of DTC severity records
+/// (`59 <sub function> <availability mask> [<severity> <functional unit> <DTC> <status>]...`).
+/// Returns the DTC with its severity and functional unit
+fn parse_severity_records(res: &[u8]) -> ProtocolResult<Vec<(DTC, DtcSeverity, u8)>> {
+    if res.len() < 3 {
+        return Err(ProtocolError::InvalidResponseSize {
+            expect: 3,
+            actual: res.len(),
+        });
+    }
+    // Record is 6 bytes (Severity, functional unit, 3 for the ID, 1 for status)
+    if (res.len() - 3) % 6 != 0 {
+        return Err(ProtocolError::InvalidResponseSize {
+            expect: res.len() - (res.len() - 3) % 6,
+            actual: res.len(),
+        });
+    }
+    Ok(res[3..]
+        .chunks_exact(6)
+        .map(|record| {
+            let status = record[5];
+            let dtc = DTC {
+                error: DTC::format_code(u16::from_be_bytes([record[2], record[3]])),
+                state: dtc_state_from_status(status),
+                check_engine_on: status & 0b1000_0000 != 0, // warningIndicatorRequested
+                id: DtcCode::from_bytes(&[record[2], record[3], record[4]]),
+            };
+            (dtc, DtcSeverity::from_byte(record[0]), record[1])
+        })
+        .collect())
+}
+
+/// Reads the DTCs whose severity matches `severity_mask` and whose status matches
+/// `status_mask` (Sub function $08). For example, [SEVERITY_CHECK_IMMEDIATELY] only
+/// returns safety critical DTCs.
+///
+/// DTCs are returned most severe first. Use [read_dtc_severity] to read the severity
+/// of a single DTC
+pub fn read_dtc_by_severity(
+    ecu: &UDSECU,
+    severity_mask: u8,
+    status_mask: u8,
+) -> ProtocolResult<Vec<DTC>> {
+    let res = ecu.run_command(
+        super::UDSCommand::ReadDTCInformation.into(),
+        &[REPORT_DTC_BY_SEVERITY_MASK, severity_mask, status_mask],
+    )?;
+    let mut records = parse_severity_records(&res)?;
+    // Stable, so DTCs of the same severity stay in the order the ECU returned them
+    records.sort_by(|a, b| b.1.cmp(&a.1));
+    Ok(records.into_iter().map(|(dtc, _, _)| dtc).collect())
+}
+
+/// Reads the severity and functional unit of `dtc` (Sub function $09).
+/// Returns None if the ECU has no severity information for the DTC
+pub fn read_dtc_severity(ecu: &UDSECU, dtc: DtcCode) -> ProtocolResult<Option<(DtcSeverity, u8)>> {
+    let [a, b, c] = dtc.as_uds_bytes();
+    let res = ecu.run_command(
+        super::UDSCommand::ReadDTCInformation.into(),
+        &[REPORT_SEVERITY_OF_DTC, a, b, c],
+    )?;
+    Ok(parse_severity_records(&res)?
+        .into_iter()
+        .find(|(d, _, _)| d.id == dtc)
+        .map(|(_, severity, functional_unit)| (severity, functional_unit)))
+}
+
+/// Which single DTC to read with [read_dtc_by_occurrence]. The ECU tracks these
+/// since the fault memory was last cleared
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DtcOccurrence {
+    /// First DTC to fail a test (Sub function $0B)
+    FirstTestFailed,
+    /// First DTC to be confirmed (Sub function $0C)
+    FirstConfirmed,
+    /// Most recent DTC to fail a test (Sub function $0D)
+    MostRecentTestFailed,
+    /// Most recent DTC to be confirmed (Sub function $0E)
+    MostRecentConfirmed,
+}
+
+impl DtcOccurrence {
+    fn sub_function(&self) -> u8 {
+        match self {
+            Self::FirstTestFailed => REPORT_FIRST_TEST_FAILED_DTC,
+            Self::FirstConfirmed => REPORT_FIRST_CONFIRMED_DTC,
+            Self::MostRecentTestFailed => REPORT_MOST_RECENT_TEST_FAILED_DTC,
+            Self::MostRecentConfirmed => REPORT_MOST_RECENT_CONFIRMED_DTC,
+        }
+    }
+}
+
+/// Reads a single DTC by when it occurred (Sub functions $0B-$0E). Returns None if no
+/// DTC has failed (Or been confirmed) since the fault memory was last cleared.
+///
+/// This is much quicker than reading the full DTC list, and is usually
+/// the fault a technician is after
+pub fn read_dtc_by_occurrence(ecu: &UDSECU, which: DtcOccurrence) -> ProtocolResult<Option<DTC>> {
+    let res = ecu.run_command(
+        super::UDSCommand::ReadDTCInformation.into(),
+        &[which.sub_function()],
+    )?;
+    // ECU returns the same record layout as a DTC list, with at most one record
+    Ok(parse_dtc_records(&res)?.into_iter().next())
+}
+
+/// Reads the DTC which most recently failed a test. See [read_dtc_by_occurrence]
+pub fn read_most_recent_test_failed_dtc(ecu: &UDSECU) -> ProtocolResult<Option<DTC>> {
+    read_dtc_by_occurrence(ecu, DtcOccurrence::MostRecentTestFailed)
+}
+
+/// Reads the DTC which was most recently confirmed. See [read_dtc_by_occurrence]
+pub fn read_most_recent_confirmed_dtc(ecu: &UDSECU) -> ProtocolResult<Option<DTC>> {
+    read_dtc_by_occurrence(ecu, DtcOccurrence::MostRecentConfirmed)
+}
+
+/// How an occurrence record stores when the DTC occurred
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TimestampFormat {
+    /// Big endian odometer reading in km
+    OdometerKm,
+    /// Big endian seconds since the Unix epoch
+    UnixTime,
+    /// Big endian operating time in hours
+    OperatingHours,
+}
+
+/// Decoded occurrence timestamp
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OccurrenceTime {
+    OdometerKm(u32),
+    UnixTime(u32),
+    OperatingHours(u32),
+}
+
+impl TimestampFormat {
+    pub fn decode(&self, raw: &[u8]) -> Option<OccurrenceTime> {
+        if raw.is_empty() || raw.len() > 4 {
+            return None;
+        }
+        let value = raw.iter().fold(0u32, |acc, b| acc << 8 | *b as u32);
+        Some(match self {
+            Self::OdometerKm => OccurrenceTime::OdometerKm(value),
+            Self::UnixTime => OccurrenceTime::UnixTime(value),
+            Self::OperatingHours => OccurrenceTime::OperatingHours(value),
+        })
+    }
+}
+
+/// Extended data record numbers the ECU stores occurrence information in
+#[derive(Debug, Clone, Default)]
+pub struct ExtDataLayout {
+    pub first_occurrence: Option<(u8, TimestampFormat)>,
+    pub most_recent_occurrence: Option<(u8, TimestampFormat)>,
+    /// Any other records to read, which are returned undecoded
+    pub other_records: Vec<u8>,
+}
+
+/// An extended data record of a DTC
+#[derive(Debug, Clone)]
+pub struct OccurrenceRecord {
+    pub record_number: u8,
+    pub raw: Vec<u8>,
+    /// Decoded record, if the layout of the record was known and valid
+    pub decoded: Option<OccurrenceTime>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DtcExtendedData {
+    pub dtc: DtcCode,
+    /// Status byte of the DTC (0 if the ECU returned no records)
+    pub status: u8,
+    /// Fault detection counter (-128 to 127). 127 means the test failed, and the DTC
+    /// is about to be set. None if the ECU does not report one for the DTC
+    pub fault_detection_counter: Option<i8>,
+    /// Severity of the DTC. None if the ECU does not report one for the DTC
+    pub severity: Option<DtcSeverity>,
+    /// Functional unit (Group of functions) the DTC belongs to, reported with the severity
+    pub functional_unit: Option<u8>,
+    pub first_occurrence: Option<OccurrenceRecord>,
+    pub most_recent_occurrence: Option<OccurrenceRecord>,
+    /// Records from [ExtDataLayout::other_records] which the ECU returned
+    pub other_records: Vec<OccurrenceRecord>,
+}
+
+/// Reads a single extended data record of `dtc`, returning the DTC status and record data.
+/// Returns None if the ECU has no such record for the DTC
+fn read_ext_record(
+    ecu: &UDSECU,
+    dtc: DtcCode,
+    record_number: u8,
+) -> ProtocolResult<Option<(u8, Vec<u8>)>> {
+    let [a, b, c] = dtc.as_uds_bytes();
+    let res = match ecu.run_command(
+        super::UDSCommand::ReadDTCInformation.into(),
+        &[REPORT_EXT_DATA_BY_DTC, a, b, c, record_number],
+    ) {
+        Ok(r) => r,
+        Err(e) if e.get_nrc() == Some(0x31) => return Ok(None), // Request out of range
+        Err(e) => return Err(e),
+    };
+    // SID, sub function, DTC and status. The record follows if the ECU has it stored
+    if res.len() < 6 {
+        return Err(ProtocolError::InvalidResponseSize {
+            expect: 6,
+            actual: res.len(),
+        });
+    }
+    let status = res[5];
+    match res.get(6) {
+        Some(n) if *n == record_number => Ok(Some((status, Vec::from(&res[7..])))),
+        Some(n) => Err(ProtocolError::CustomError(format!(
+            "ECU returned extended data record 0x{:02X}, requested 0x{:02X}",
+            n, record_number
+        ))),
+        None => Ok(None),
+    }
+}
+
+/// Returns the snapshot records of a reportDTCSnapshotRecordByDTCNumber response
+/// (`59 04 <DTC> <status> [<record number> <number of DIDs> [<DID> <data>]...]...`).
+/// Returns an empty record list if the ECU stored no snapshot for the DTC
+fn parse_snapshot_response(dtc: DtcCode, res: &[u8]) -> ProtocolResult<Vec<u8>> {
+    // SID, sub function, DTC and status
+    if res.len() < 6 {
+        return Err(ProtocolError::InvalidResponseSize {
+            expect: 6,
+            actual: res.len(),
+        });
+    }
+    if res[2..5] != dtc.as_uds_bytes() {
+        return Err(ProtocolError::CustomError(format!(
+            "ECU returned the snapshot of DTC {}, requested {}",
+            format_hex_compact(&res[2..5]),
+            format_hex_compact(&dtc.as_uds_bytes())
+        )));
+    }
+    Ok(Vec::from(&res[6..]))
+}
+
+/// Reads every snapshot (Freeze frame) record of `dtc` (Sub function $04), which holds the
+/// environment data the ECU captured when the DTC was stored.
+///
+/// The records are returned undecoded, as the DIDs in them are manufacturer specific.
+/// Returns an empty record list if the ECU has no snapshot stored for the DTC
+pub fn read_dtc_snapshot(ecu: &UDSECU, dtc: &DTC) -> ProtocolResult<Vec<u8>> {
+    let [a, b, c] = dtc.id.as_uds_bytes();
+    let res = ecu.run_command(
+        super::UDSCommand::ReadDTCInformation.into(),
+        &[REPORT_SNAPSHOT_BY_DTC, a, b, c, ALL_SNAPSHOT_RECORDS],
+    )?;
+    parse_snapshot_response(dtc.id, &res)
+}
+
+/// Reads the fault detection counter of `dtc`, if the ECU reports it
+pub fn read_fault_detection_counter(ecu: &UDSECU, dtc: DtcCode) -> ProtocolResult<Option<i8>> {
+    let res = ecu.run_command(
+        super::UDSCommand::ReadDTCInformation.into(),
+        &[REPORT_FAULT_DETECTION_COUNTER],
+    )?;
+    // Response is a list of DTC (3 bytes) and counter (1 byte) pairs
+    let code = dtc.as_uds_bytes();
+    Ok(res
+        .get(2..)
+        .unwrap_or_default()
+        .chunks_exact(4)
+        .find(|r| r[..3] == code)
+        .map(|r| r[3] as i8))
+}
+
+/// Reads the fault detection counter, severity and the extended data records described by
+/// `layout` of `dtc`. Records the ECU does not have are left empty
+pub fn read_dtc_extended(
+    ecu: &UDSECU,
+    dtc: DtcCode,
+    layout: &ExtDataLayout,
+) -> ProtocolResult<DtcExtendedData> {
+    let fault_detection_counter = match read_fault_detection_counter(ecu, dtc) {
+        Ok(fdc) => fdc,
+        Err(e) if e.get_nrc().is_some() => None, // Sub function not supported
+        Err(e) => return Err(e),
+    };
+    let (severity, functional_unit) = match read_dtc_severity(ecu, dtc) {
+        Ok(Some((severity, functional_unit))) => (Some(severity), Some(functional_unit)),
+        Ok(None) => (None, None),
+        Err(e) if e.get_nrc().is_some() => (None, None), // Sub function not supported
+        Err(e) => return Err(e),
+    };
+    let mut status = 0;
+    let mut read_occurrence = |record_number: u8, fmt: Option<TimestampFormat>| {
+        read_ext_record(ecu, dtc, record_number).map(|r| {
+            r.map(|(s, raw)| {
+                status = s;
+                OccurrenceRecord {
+                    record_number,
+                    decoded: fmt.and_then(|f| f.decode(&raw)),
+                    raw,
+                }
+            })
+        })
+    };
+    let first_occurrence = match layout.first_occurrence {
+        Some((n, fmt)) => read_occurrence(n, Some(fmt))?,
+        None => None,
+    };
+    let most_recent_occurrence = match layout.most_recent_occurrence {
+        Some((n, fmt)) => read_occurrence(n, Some(fmt))?,
+        None => None,
+    };
+    let mut other_records = Vec::new();
+    for n in &layout.other_records {
+        if let Some(r) = read_occurrence(*n, None)? {
+            other_records.push(r)
+        }
+    }
+    Ok(DtcExtendedData {
+        dtc,
+        status,
+        fault_detection_counter,
+        severity,
+        functional_unit,
+        first_occurrence,
+        most_recent_occurrence,
+        other_records,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::{
+        parse_severity_records, parse_snapshot_response, read_dtc_pages, DtcCode, DtcSeverity,
+        ProtocolError,
+    };
+
+    #[test]
+    fn test_paged_dtc_read() {
+        // ECU reports 3 DTCs, but only 2 fit in its first response
+        let mut pages: VecDeque<Vec<u8>> = vec![
+            vec![
+                0x59, 0x02, 0xFF, 0x04, 0x20, 0x00, 0x08, 0x01, 0x71, 0x00, 0x08,
+            ],
+            vec![
+                0x59, 0x02, 0xFF, 0x01, 0x71, 0x00, 0x08, 0xC1, 0x00, 0x00, 0x09,
+            ],
+        ]
+        .into();
+        let dtcs = read_dtc_pages(Some(3), || Ok(pages.pop_front().unwrap())).unwrap();
+        assert!(pages.is_empty());
+        let codes: Vec<String> = dtcs.iter().map(|d| d.id.as_obd_string()).collect();
+        assert_eq!(codes, vec!["P0420", "P0171", "U0100"]);
+
+        // Single response when the count is unknown, or already returned
+        let mut reads = 0;
+        let dtcs = read_dtc_pages(None, || {
+            reads += 1;
+            Ok(vec![0x59, 0x02, 0xFF])
+        })
+        .unwrap();
+        assert!(dtcs.is_empty());
+        assert_eq!(reads, 1);
+
+        // Stops once a response has nothing new, and returns the read error otherwise
+        let mut reads = 0;
+        let dtcs = read_dtc_pages(Some(5), || {
+            reads += 1;
+            Ok(vec![0x59, 0x02, 0xFF, 0x04, 0x20, 0x00, 0x08])
+        })
+        .unwrap();
+        assert_eq!(dtcs.len(), 1);
+        assert_eq!(reads, 2);
+        assert!(read_dtc_pages(Some(5), || Err(ProtocolError::Timeout)).is_err());
+    }
+
+    #[test]
+    fn test_dtc_severity_records() {
+        let records = parse_severity_records(&[
+            0x59, 0x08, 0xFF, 0x20, 0x10, 0x04, 0x20, 0x00, 0x08, 0xC0, 0x33, 0xC1, 0x00, 0x00,
+            0x09,
+        ])
+        .unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].0.id.as_obd_string(), "P0420");
+        assert_eq!(records[0].1, DtcSeverity::MaintenanceOnly);
+        assert_eq!(records[0].2, 0x10);
+        // Most severe bit wins
+        assert_eq!(records[1].1, DtcSeverity::CheckImmediately);
+        assert_eq!(records[1].2, 0x33);
+        assert!(DtcSeverity::CheckImmediately > DtcSeverity::CheckAtNextHalt);
+        assert_eq!(DtcSeverity::from_byte(0x00), DtcSeverity::NoSeverity);
+        // Truncated record
+        assert!(parse_severity_records(&[0x59, 0x08, 0xFF, 0x20, 0x10, 0x04]).is_err());
+    }
+
+    #[test]
+    fn test_dtc_snapshot_response() {
+        let code = DtcCode::from_bytes(&[0x04, 0x20, 0x00]);
+        // Record 01 with one DID (0x0D00 = 0x3C)
+        assert_eq!(
+            parse_snapshot_response(
+                code,
+                &[0x59, 0x04, 0x04, 0x20, 0x00, 0x08, 0x01, 0x01, 0x0D, 0x00, 0x3C]
+            )
+            .unwrap(),
+            vec![0x01, 0x01, 0x0D, 0x00, 0x3C]
+        );
+        // No snapshot stored for the DTC
+        assert!(
+            parse_snapshot_response(code, &[0x59, 0x04, 0x04, 0x20, 0x00, 0x08])
+                .unwrap()
+                .is_empty()
+        );
+        // Snapshot of a different DTC
+        assert!(matches!(
+            parse_snapshot_response(code, &[0x59, 0x04, 0x01, 0x71, 0x00, 0x08, 0x01, 0x00]),
+            Err(ProtocolError::CustomError(_))
+        ));
+        assert!(matches!(
+            parse_snapshot_response(code, &[0x59, 0x04, 0x04, 0x20]),
+            Err(ProtocolError::InvalidResponseSize { expect: 6, .. })
+        ));
+    }
+}
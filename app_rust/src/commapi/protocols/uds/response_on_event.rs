@@ -0,0 +1,68 @@
+use std::sync::atomic::Ordering::Relaxed;
+
+use crate::commapi::protocols::{ProtocolResult, ProtocolServer};
+
+use super::UDSECU;
+
+// The service, Response On Event ($86), asks the ECU to run a service by itself when an event
+// occurs, and send the response to that service to the tester unsolicited. The event is first
+// set up, then started. Whilst running, the diag server collects the event responses which can
+// be retrieved with [poll_events]
+
+/// eventWindowTime - The event stays active until stopped (Or the ECU leaves the session)
+const INFINITE_EVENT_WINDOW: u8 = 0x02;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RoeEvent {
+    /// Triggers when the status of any DTC changes for the bits set in the status mask
+    OnDtcStatusChange { status_mask: u8 },
+    /// Triggers when the value of a data identifier changes
+    OnChangeOfDataIdentifier { did: u16 },
+}
+
+impl RoeEvent {
+    pub(crate) fn get_event_type(&self) -> u8 {
+        match self {
+            Self::OnDtcStatusChange { .. } => 0x01,
+            Self::OnChangeOfDataIdentifier { .. } => 0x03,
+        }
+    }
+
+    pub(crate) fn get_event_record(&self) -> Vec<u8> {
+        match self {
+            Self::OnDtcStatusChange { status_mask } => vec![*status_mask],
+            Self::OnChangeOfDataIdentifier { did } => vec![(did >> 8) as u8, *did as u8],
+        }
+    }
+}
+
+/// Sets up `event` on the ECU, then starts it. When the event occurs, the ECU runs
+/// `service_to_respond` (SID followed by any arguments, for example `[0x19, 0x01, 0xFF]`)
+/// and sends its response, which can be collected with [poll_events]
+pub fn setup_response_on_event(
+    ecu: &UDSECU,
+    event: RoeEvent,
+    service_to_respond: &[u8],
+) -> ProtocolResult<()> {
+    let mut args = vec![event.get_event_type(), INFINITE_EVENT_WINDOW];
+    args.extend_from_slice(&event.get_event_record());
+    args.extend_from_slice(service_to_respond);
+    ecu.run_command(super::UDSCommand::ResponseOnEvent.into(), &args)?;
+    ecu.run_command(super::UDSCommand::ResponseOnEvent.into(), &[0x05])?;
+    ecu.roe_active.store(true, Relaxed);
+    Ok(())
+}
+
+/// Stops all events on the ECU. Events which were already collected can still be polled
+pub fn stop_response_on_event(ecu: &UDSECU) -> ProtocolResult<()> {
+    ecu.roe_active.store(false, Relaxed);
+    ecu.run_command(super::UDSCommand::ResponseOnEvent.into(), &[0x00])?;
+    Ok(())
+}
+
+/// Returns all event responses received since the last poll, oldest first.
+///
+/// Only responses which arrive whilst the diag server is idle are collected
+pub fn poll_events(ecu: &UDSECU) -> Vec<Vec<u8>> {
+    std::mem::take(&mut *ecu.roe_events.write().unwrap())
+}
@@ -0,0 +1,41 @@
+use crate::commapi::protocols::{
+    security::KeyAlgorithm, ProtocolError, ProtocolResult, ProtocolServer,
+};
+
+use super::UDSECU;
+
+// The service, Security Access ($27), unlocks services which are protected by the ECU.
+// The tester requests a seed for a security level (Odd sub function), computes the
+// key from it, and sends the key back (Even sub function, one above the seed request).
+
+/// Unlocks security access `level` (The odd requestSeed sub function), computing
+/// the key from the ECU's seed with `algorithm`.
+///
+/// If the ECU returns a seed of all zeros, the level is already unlocked and no key is sent
+pub fn unlock_security_access(
+    ecu: &UDSECU,
+    level: u8,
+    algorithm: &dyn KeyAlgorithm,
+) -> ProtocolResult<()> {
+    if level % 2 == 0 || level > 0x7D {
+        return Err(ProtocolError::CustomError(format!(
+            "0x{:02X} is not a valid security access seed request",
+            level
+        )));
+    }
+    let res = ecu.run_command(super::UDSCommand::SecurityAccess.into(), &[level])?;
+    let seed = res.get(2..).unwrap_or_default();
+    if seed.is_empty() {
+        return Err(ProtocolError::InvalidResponseSize {
+            expect: 3,
+            actual: res.len(),
+        });
+    }
+    if seed.iter().any(|b| *b != 0) {
+        let mut args = vec![level + 1];
+        args.extend(algorithm.compute_key(level, seed)?);
+        ecu.run_command(super::UDSCommand::SecurityAccess.into(), &args)?;
+    }
+    *ecu.security_level.write().unwrap() = Some(level);
+    Ok(())
+}
@@ -0,0 +1,322 @@
+use crate::commapi::protocols::{CancelToken, ProtocolError, ProtocolResult, ProtocolServer};
+
+use super::UDSECU;
+
+// The services, RequestDownload ($34) and RequestUpload ($35), are used to start a transfer of
+// data to or from the ECU's memory. The ECU responds with the maximum number of bytes it can
+// accept in each TransferData ($36) request. RequestTransferExit ($37) ends the transfer.
+
+/// The dataFormatIdentifier of a download or upload request. Both values are manufacturer specific,
+/// with 0 meaning no compression / encryption
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct DataFormat {
+    /// Compression method (0x0-0xF)
+    pub compression: u8,
+    /// Encryption method (0x0-0xF)
+    pub encryption: u8,
+}
+
+impl DataFormat {
+    pub(crate) fn to_byte(&self) -> u8 {
+        (self.compression & 0x0F) << 4 | (self.encryption & 0x0F)
+    }
+}
+
+fn run_transfer_request(
+    ecu: &UDSECU,
+    cmd: u8,
+    format: DataFormat,
+    address: u32,
+    size: u32,
+) -> ProtocolResult<u32> {
+    let mut args = vec![format.to_byte(), 0x44]; // 4 byte address, 4 byte size
+    args.extend_from_slice(&address.to_be_bytes());
+    args.extend_from_slice(&size.to_be_bytes());
+    let res = ecu.run_command(cmd, &args)?;
+    // lengthFormatIdentifier - Upper nibble is the number of bytes of maxNumberOfBlockLength
+    let len_bytes = (res.get(1).copied().unwrap_or(0) >> 4) as usize;
+    if len_bytes == 0 || len_bytes > 4 || res.len() < 2 + len_bytes {
+        return Err(ProtocolError::InvalidResponseSize {
+            expect: 2 + len_bytes,
+            actual: res.len(),
+        });
+    }
+    Ok(res[2..2 + len_bytes]
+        .iter()
+        .fold(0u32, |acc, b| acc << 8 | *b as u32))
+}
+
+/// Requests to download (Tester -> ECU) `size` bytes to the ECU at `address`.
+/// Returns the maximum number of bytes the ECU accepts per TransferData request
+pub fn request_download(
+    ecu: &UDSECU,
+    format: DataFormat,
+    address: u32,
+    size: u32,
+) -> ProtocolResult<u32> {
+    run_transfer_request(
+        ecu,
+        super::UDSCommand::RequestDownload.into(),
+        format,
+        address,
+        size,
+    )
+}
+
+/// Requests to upload (ECU -> Tester) `size` bytes from the ECU at `address`.
+/// Returns the maximum number of bytes the ECU sends per TransferData response
+pub fn request_upload(
+    ecu: &UDSECU,
+    format: DataFormat,
+    address: u32,
+    size: u32,
+) -> ProtocolResult<u32> {
+    run_transfer_request(
+        ecu,
+        super::UDSCommand::RequestUpload.into(),
+        format,
+        address,
+        size,
+    )
+}
+
+/// How a [flash_region] operation ended
+#[derive(Debug)]
+pub enum FlashStatus {
+    /// The whole image was transferred, and the ECU accepted the transfer exit
+    Completed,
+    /// The cancel token was cancelled between two blocks
+    Cancelled,
+    /// A block could not be transferred
+    Failed(ProtocolError),
+}
+
+/// Result of a [flash_region] operation, recording how far it got so that it can be resumed
+#[derive(Debug)]
+pub struct FlashOutcome {
+    pub status: FlashStatus,
+    /// Number of TransferData blocks the ECU accepted
+    pub blocks_completed: u32,
+    /// Bytes of the image the ECU accepted
+    pub bytes_written: usize,
+    /// Block sequence counter of the next block that would have been sent
+    pub block_seq: u8,
+}
+
+impl FlashOutcome {
+    /// Returns the part of `image` which was not written. To resume, flash this
+    /// to the original address plus [FlashOutcome::bytes_written]
+    pub fn remaining<'a>(&self, image: &'a [u8]) -> &'a [u8] {
+        &image[self.bytes_written.min(image.len())..]
+    }
+}
+
+/// Sends one TransferData block, checking the ECU echoes its sequence counter
+fn transfer_block(ecu: &UDSECU, block_seq: u8, data: &[u8]) -> ProtocolResult<()> {
+    let mut args = vec![block_seq];
+    args.extend_from_slice(data);
+    let res = ecu.run_command(super::UDSCommand::TransferData.into(), &args)?;
+    match res.get(1) {
+        Some(seq) if *seq == block_seq => Ok(()),
+        Some(seq) => Err(ProtocolError::CustomError(format!(
+            "ECU acknowledged block {}, sent block {}",
+            seq, block_seq
+        ))),
+        None => Err(ProtocolError::InvalidResponseSize {
+            expect: 2,
+            actual: res.len(),
+        }),
+    }
+}
+
+/// Downloads `image` to the ECU at `address`, in as many TransferData blocks as the
+/// ECU asks for, then exits the transfer.
+///
+/// `token` is checked between blocks. If it is cancelled, or a block fails, the transfer
+/// is exited early and the returned [FlashOutcome] records how much was written.
+/// `progress` is called after each block with the bytes written so far and the image size.
+///
+/// Only a failure to start the download is returned as an error
+pub fn flash_region(
+    ecu: &UDSECU,
+    address: u32,
+    image: &[u8],
+    token: CancelToken,
+    progress: &mut dyn FnMut(usize, usize),
+) -> ProtocolResult<FlashOutcome> {
+    let max_block_len = request_download(ecu, DataFormat::default(), address, image.len() as u32)?;
+    // Block length includes the service ID and block sequence counter
+    if max_block_len <= 2 {
+        return Err(ProtocolError::CustomError(format!(
+            "ECU block length of {} bytes is too small for TransferData",
+            max_block_len
+        )));
+    }
+    let mut outcome = FlashOutcome {
+        status: FlashStatus::Completed,
+        blocks_completed: 0,
+        bytes_written: 0,
+        block_seq: 1,
+    };
+    for block in image.chunks(max_block_len as usize - 2) {
+        if token.is_cancelled() {
+            outcome.status = FlashStatus::Cancelled;
+            break;
+        }
+        if let Err(e) = transfer_block(ecu, outcome.block_seq, block) {
+            outcome.status = FlashStatus::Failed(e);
+            break;
+        }
+        outcome.blocks_completed += 1;
+        outcome.bytes_written += block.len();
+        outcome.block_seq = outcome.block_seq.wrapping_add(1); // Wraps to 0x00 after 0xFF
+        progress(outcome.bytes_written, image.len());
+    }
+    let exit = ecu.run_command(super::UDSCommand::TransferExit.into(), &[]);
+    match (&outcome.status, exit) {
+        (FlashStatus::Completed, Err(e)) => outcome.status = FlashStatus::Failed(e),
+        (_, Err(e)) => eprintln!("UDS - Transfer exit failed: {}", e.get_text()),
+        (_, Ok(_)) => {}
+    }
+    Ok(outcome)
+}
+
+/// Number of times a block is requested again after the ECU repeated the previous block,
+/// or the request timed out, before the upload is abandoned
+const MAX_BLOCK_RETRIES: u32 = 3;
+
+/// Contents of a TransferData response to an upload block request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UploadBlock<'a> {
+    /// Data of the requested block
+    Data(&'a [u8]),
+    /// The ECU sent the previous block again, which was already accepted
+    Repeated,
+}
+
+/// Checks an upload TransferData response (`76 <block sequence counter> <data>`) is for
+/// `block_seq`, returning the data portion of the response. A repeat of `prev_seq`, which
+/// ECUs send if they think the previous response was lost, is reported as [UploadBlock::Repeated]
+fn parse_upload_block(
+    res: &[u8],
+    block_seq: u8,
+    prev_seq: Option<u8>,
+) -> ProtocolResult<UploadBlock<'_>> {
+    match res.get(1) {
+        Some(seq) if *seq == block_seq => Ok(UploadBlock::Data(&res[2..])),
+        Some(seq) if Some(*seq) == prev_seq => Ok(UploadBlock::Repeated),
+        Some(seq) => Err(ProtocolError::CustomError(format!(
+            "ECU sent block {}, requested block {}",
+            seq, block_seq
+        ))),
+        None => Err(ProtocolError::InvalidResponseSize {
+            expect: 2,
+            actual: res.len(),
+        }),
+    }
+}
+
+/// Requests the upload blocks of a transfer started by [request_upload] until `size` bytes
+/// were received, returning only the data portion of each block
+fn upload_blocks(
+    ecu: &UDSECU,
+    size: usize,
+    progress: &mut dyn FnMut(usize, usize),
+) -> ProtocolResult<Vec<u8>> {
+    let mut data = Vec::with_capacity(size);
+    let mut block_seq = 1u8;
+    let mut prev_seq = None;
+    let mut retries = 0;
+    while data.len() < size {
+        let res = match ecu.run_command(super::UDSCommand::TransferData.into(), &[block_seq]) {
+            Ok(r) => r,
+            // Requesting the same counter again makes the ECU resend the block
+            Err(ProtocolError::Timeout) if retries < MAX_BLOCK_RETRIES => {
+                retries += 1;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        match parse_upload_block(&res, block_seq, prev_seq)? {
+            UploadBlock::Data(block) => {
+                if block.is_empty() || data.len() + block.len() > size {
+                    return Err(ProtocolError::CustomError(format!(
+                        "ECU sent {} bytes in block {}, {} of {} bytes were left to upload",
+                        block.len(),
+                        block_seq,
+                        size - data.len(),
+                        size
+                    )));
+                }
+                data.extend_from_slice(block);
+                prev_seq = Some(block_seq);
+                block_seq = block_seq.wrapping_add(1); // Wraps to 0x00 after 0xFF
+                retries = 0;
+                progress(data.len(), size);
+            }
+            UploadBlock::Repeated if retries < MAX_BLOCK_RETRIES => {
+                eprintln!("UDS - ECU repeated block {:?}, requesting again", prev_seq);
+                retries += 1;
+            }
+            UploadBlock::Repeated => {
+                return Err(ProtocolError::CustomError(format!(
+                    "ECU kept repeating block {:?} instead of sending block {}",
+                    prev_seq, block_seq
+                )))
+            }
+        }
+    }
+    Ok(data)
+}
+
+/// Uploads (Reads) `size` bytes of the ECU's memory at `address`, then exits the transfer.
+///
+/// Each block is checked to be the one that was requested. Blocks which the ECU repeats
+/// are not added to the data again. `progress` is called after each block with the bytes
+/// read so far and `size`
+pub fn upload_region(
+    ecu: &UDSECU,
+    address: u32,
+    size: u32,
+    progress: &mut dyn FnMut(usize, usize),
+) -> ProtocolResult<Vec<u8>> {
+    request_upload(ecu, DataFormat::default(), address, size)?;
+    let res = upload_blocks(ecu, size as usize, progress);
+    let exit = ecu.run_command(super::UDSCommand::TransferExit.into(), &[]);
+    match (res, exit) {
+        (Ok(data), Ok(_)) => Ok(data),
+        (Ok(_), Err(e)) => Err(e),
+        (Err(e), exit) => {
+            if let Err(exit_err) = exit {
+                eprintln!("UDS - Transfer exit failed: {}", exit_err.get_text());
+            }
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_upload_block, UploadBlock};
+
+    #[test]
+    fn test_upload_block_sequence() {
+        assert_eq!(
+            parse_upload_block(&[0x76, 0x02, 0xAA, 0xBB], 0x02, Some(0x01)).unwrap(),
+            UploadBlock::Data(&[0xAA, 0xBB])
+        );
+        // Previous block sent again
+        assert_eq!(
+            parse_upload_block(&[0x76, 0x01, 0xAA, 0xBB], 0x02, Some(0x01)).unwrap(),
+            UploadBlock::Repeated
+        );
+        // Counter wraps after 0xFF
+        assert_eq!(
+            parse_upload_block(&[0x76, 0xFF, 0x01], 0x00, Some(0xFF)).unwrap(),
+            UploadBlock::Repeated
+        );
+        assert!(parse_upload_block(&[0x76, 0x05, 0xAA], 0x02, Some(0x01)).is_err());
+        assert!(parse_upload_block(&[0x76, 0x01, 0xAA], 0x01, None).is_ok());
+        assert!(parse_upload_block(&[0x76], 0x01, None).is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use crate::commapi::protocols::{
+    security::KeyAlgorithm, ProtocolError, ProtocolResult, ProtocolServer,
+};
+
+use super::{diag_session_control::DiagSession, read_data, security_access, UDSECU};
+
+// The service, Write Data By Identifier ($2E), writes a record to the ECU at the given
+// data identifier. The ECU responds with the data identifier that was written.
+
+/// Writes `data` to a single data identifier of the ECU
+pub fn write_did(ecu: &UDSECU, did: u16, data: &[u8]) -> ProtocolResult<()> {
+    let mut args = vec![(did >> 8) as u8, did as u8];
+    args.extend_from_slice(data);
+    ecu.run_command(super::UDSCommand::WriteDataByID.into(), &args)?;
+    Ok(())
+}
+
+/// Writes `data` to a data identifier, first entering the extended session and unlocking
+/// `security` (Security level and key algorithm) if the ECU is not already in that state.
+///
+/// The session is only changed if the ECU is in the default session, as changing from any
+/// other session would lock the ECU again. If `restore_session` is set, and the session was
+/// changed, the ECU is returned to the default session after the write (Even if it failed)
+pub fn write_did_managed(
+    ecu: &mut UDSECU,
+    did: u16,
+    data: &[u8],
+    security: Option<(u8, &dyn KeyAlgorithm)>,
+    restore_session: bool,
+) -> ProtocolResult<()> {
+    let prior_session = ecu.get_session_type();
+    let changed_session = prior_session == DiagSession::Default;
+    if changed_session {
+        ecu.set_diag_session_mode(DiagSession::Extended, None)?;
+    }
+    let res = match security {
+        Some((level, _)) if ecu.security_level() == Some(level) => write_did(ecu, did, data),
+        Some((level, algorithm)) => security_access::unlock_security_access(ecu, level, algorithm)
+            .and_then(|_| write_did(ecu, did, data)),
+        None => write_did(ecu, did, data),
+    };
+    if changed_session && restore_session {
+        let restored = ecu.set_diag_session_mode(prior_session, None);
+        res?;
+        restored
+    } else {
+        res
+    }
+}
+
+/// Writes `data` to a data identifier, then reads it back to check it was stored.
+///
+/// Returns true if the value read back matches. If the ECU stored a different value
+/// (For example, it normalised the value), [ProtocolError::VerifyMismatch] is returned with
+/// the value read back so the caller can decide if it is acceptable. Returns false if the
+/// write succeeded but the ECU refused to read the DID back (Write only DIDs)
+pub fn write_and_verify(ecu: &UDSECU, did: u16, data: &[u8]) -> ProtocolResult<bool> {
+    write_did(ecu, did, data)?;
+    let read = match read_data::read_did(ecu, did) {
+        Ok(r) => r,
+        Err(e) if e.get_nrc().is_some() => {
+            eprintln!(
+                "DID 0x{:04X} was written, but cannot be read back - {}",
+                did,
+                e.get_text()
+            );
+            return Ok(false);
+        }
+        Err(e) => return Err(e),
+    };
+    if read == data {
+        Ok(true)
+    } else {
+        Err(ProtocolError::VerifyMismatch {
+            written: Vec::from(data),
+            read,
+        })
+    }
+}
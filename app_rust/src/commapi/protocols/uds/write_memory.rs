@@ -0,0 +1,86 @@
+use crate::commapi::{
+    iface::hex_dump,
+    protocols::{response_payload, ProtocolError, ProtocolResult, ProtocolServer},
+};
+
+use super::UDSECU;
+
+// The service, WriteMemoryByAddress ($3D), writes data directly into the ECU's memory
+// at the given address. The ECU responds with the addressAndLengthFormatIdentifier,
+// address and size of the request.
+
+/// The addressAndLengthFormatIdentifier of a request, being how many bytes
+/// are used to encode the memory address and memory size
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AddrLenFormat {
+    /// Number of bytes of the memory address (1-4)
+    pub addr_bytes: u8,
+    /// Number of bytes of the memory size (1-4)
+    pub len_bytes: u8,
+}
+
+impl Default for AddrLenFormat {
+    fn default() -> Self {
+        Self {
+            addr_bytes: 4,
+            len_bytes: 4,
+        }
+    }
+}
+
+impl AddrLenFormat {
+    pub(crate) fn to_byte(&self) -> u8 {
+        (self.len_bytes & 0x0F) << 4 | (self.addr_bytes & 0x0F)
+    }
+
+    /// Encodes `address` and `size` according to the format, checking both of them fit
+    pub(crate) fn encode(&self, address: u32, size: u32) -> ProtocolResult<Vec<u8>> {
+        let mut res = vec![self.to_byte()];
+        res.extend_from_slice(&encode_be(address, self.addr_bytes, "address")?);
+        res.extend_from_slice(&encode_be(size, self.len_bytes, "size")?);
+        Ok(res)
+    }
+}
+
+fn encode_be(value: u32, bytes: u8, name: &str) -> ProtocolResult<Vec<u8>> {
+    if bytes == 0 || bytes > 4 {
+        return Err(ProtocolError::CustomError(format!(
+            "Memory {} must be 1-4 bytes long, not {}",
+            name, bytes
+        )));
+    }
+    if bytes < 4 && value >> (bytes * 8) != 0 {
+        return Err(ProtocolError::CustomError(format!(
+            "Memory {} 0x{:08X} does not fit in {} bytes",
+            name, value, bytes
+        )));
+    }
+    Ok(value.to_be_bytes()[4 - bytes as usize..].to_vec())
+}
+
+/// Writes `data` to the ECU's memory at `address`
+pub fn write_memory(
+    ecu: &UDSECU,
+    address: u32,
+    data: &[u8],
+    fmt: AddrLenFormat,
+) -> ProtocolResult<()> {
+    let request = fmt.encode(address, data.len() as u32)?;
+    let mut args = request.clone();
+    args.extend_from_slice(data);
+    let res = ecu.run_command(super::UDSCommand::WriteMemoryByAddress.into(), &args)?;
+    // Positive response echoes the request (Minus the data)
+    if res.len() < 1 + request.len() {
+        return Err(ProtocolError::InvalidResponseSize {
+            expect: 1 + request.len(),
+            actual: res.len(),
+        });
+    }
+    if res[1..1 + request.len()] != request[..] {
+        return Err(ProtocolError::CustomError(format!(
+            "ECU confirmed a different write than requested: {}",
+            hex_dump(response_payload(&res))
+        )));
+    }
+    Ok(())
+}
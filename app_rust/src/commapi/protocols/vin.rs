@@ -1,3 +1,5 @@
+use super::{ProtocolError, ProtocolResult};
+
 #[derive(Debug, Clone)]
 pub struct Vin {
     pub raw: String,
@@ -87,3 +89,18 @@ impl Vin {
         };
     }
 }
+
+/// Normalizes a VIN read from an ECU, removing padding (Null, 0xFF or spaces) the ECU
+/// stores around it. Returns an error if what is left is not a valid 17 character VIN
+pub fn normalize_vin(raw: &[u8]) -> ProtocolResult<String> {
+    let vin: String = String::from_utf8_lossy(raw)
+        .trim_matches(|c: char| c == '\0' || c == '\u{FFFD}' || c.is_whitespace())
+        .to_uppercase();
+    if vin.len() != 17 || !vin.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(ProtocolError::CustomError(format!(
+            "ECU does not store a valid VIN (Read '{}')",
+            vin
+        )));
+    }
+    Ok(vin)
+}
@@ -0,0 +1,136 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    comm_api::{ComServer, ComServerError, FilterType},
+    iface::{BufferType, Interface, InterfaceConfig, InterfacePayload, InterfaceResult},
+};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    Tx,
+    Rx,
+}
+
+/// A single payload sent or received during a recorded session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordEntry {
+    /// Time in ms since the recording was started
+    pub timestamp_ms: u64,
+    pub direction: Direction,
+    pub id: u32,
+    pub data: Vec<u8>,
+}
+
+/// Wraps an interface, recording every payload that is sent or received
+/// to a JSON lines file (One [RecordEntry] per line)
+///
+/// There is no replay server yet to feed a loaded log into, but [SessionRecorder::load]
+/// returns the entries in the order they were recorded so they can be reviewed
+#[derive(Debug)]
+pub struct SessionRecorder {
+    iface: Box<dyn Interface>,
+    file: Arc<Mutex<BufWriter<File>>>,
+    start: Instant,
+}
+
+impl SessionRecorder {
+    pub fn new(iface: Box<dyn Interface>, path: &str) -> InterfaceResult<Self> {
+        let file = File::create(path).map_err(|e| ComServerError {
+            err_code: 1,
+            err_desc: format!("Could not create session log {}: {}", path, e),
+        })?;
+        Ok(Self {
+            iface,
+            file: Arc::new(Mutex::new(BufWriter::new(file))),
+            start: Instant::now(),
+        })
+    }
+
+    /// Loads a session log that was saved by a [SessionRecorder]
+    pub fn load(path: &str) -> std::io::Result<Vec<RecordEntry>> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut res = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            res.push(serde_json::from_str(&line)?);
+        }
+        Ok(res)
+    }
+
+    fn record(&self, direction: Direction, payloads: &[InterfacePayload]) {
+        let mut file = self.file.lock().unwrap();
+        for p in payloads {
+            let entry = RecordEntry {
+                timestamp_ms: p
+                    .elapsed_since(self.start)
+                    .unwrap_or_else(|| self.start.elapsed())
+                    .as_millis() as u64,
+                direction,
+                id: p.id,
+                data: p.data.clone(),
+            };
+            if let Ok(json) = serde_json::to_string(&entry) {
+                if let Err(e) = writeln!(file, "{}", json) {
+                    eprintln!("Could not write to session log: {}", e);
+                }
+            }
+        }
+        let _ = file.flush(); // Keep the log intact if the app crashes
+    }
+}
+
+impl Interface for SessionRecorder {
+    fn setup(&mut self, cfg: &InterfaceConfig) -> InterfaceResult<()> {
+        self.iface.setup(cfg)
+    }
+
+    fn send_data(&mut self, data: &[InterfacePayload], timeout: u32) -> InterfaceResult<usize> {
+        let res = self.iface.send_data(data, timeout)?;
+        self.record(Direction::Tx, data);
+        Ok(res)
+    }
+
+    fn recv_data(&mut self, max: usize, timeout: u32) -> InterfaceResult<Vec<InterfacePayload>> {
+        let res = self.iface.recv_data(max, timeout)?;
+        self.record(Direction::Rx, &res);
+        Ok(res)
+    }
+
+    fn add_filter(&mut self, f: FilterType) -> InterfaceResult<u32> {
+        self.iface.add_filter(f)
+    }
+
+    fn rem_filter(&mut self, f_id: u32) -> InterfaceResult<()> {
+        self.iface.rem_filter(f_id)
+    }
+
+    fn close(&mut self) -> InterfaceResult<()> {
+        self.iface.close()
+    }
+
+    fn clear_buffer(&mut self, buffer_type: BufferType) -> InterfaceResult<()> {
+        self.iface.clear_buffer(buffer_type)
+    }
+
+    fn get_server(&self) -> Box<dyn ComServer> {
+        self.iface.get_server()
+    }
+
+    fn clone_box(&self) -> Box<dyn Interface> {
+        Box::new(Self {
+            iface: self.iface.clone_box(),
+            file: self.file.clone(),
+            start: self.start,
+        })
+    }
+}
@@ -5,7 +5,7 @@ use std::{
 };
 
 use crate::commapi::comm_api::{
-    CanFrame, ComServerError, DeviceCapabilities, FilterType, ISO15765Data,
+    CanFrame, ComErrorKind, ComServerError, DeviceCapabilities, FilterType, ISO15765Data,
 };
 use crate::{commapi, main};
 use commapi::comm_api::ComServer;
@@ -48,24 +48,31 @@ impl From<ConstructionError> for ComServerError {
 
 impl From<std::io::Error> for ComServerError {
     fn from(x: std::io::Error) -> Self {
-        Self {
-            err_code: x.raw_os_error().unwrap_or_default() as u32,
-            err_desc: x.to_string(),
-        }
+        const EBUSY: i32 = 16;
+        const ENODEV: i32 = 19;
+        let kind = match (x.kind(), x.raw_os_error()) {
+            (std::io::ErrorKind::NotFound, _) | (_, Some(ENODEV)) => ComErrorKind::DeviceNotFound,
+            (std::io::ErrorKind::PermissionDenied, _) => ComErrorKind::PermissionDenied,
+            (std::io::ErrorKind::AddrInUse, _) | (_, Some(EBUSY)) => ComErrorKind::DeviceBusy,
+            _ => {
+                return Self {
+                    err_code: x.raw_os_error().unwrap_or_default() as u32,
+                    err_desc: x.to_string(),
+                }
+            }
+        };
+        Self::from_kind(kind, x.to_string())
     }
 }
 
 impl From<socketcan_isotp::Error> for ComServerError {
     fn from(x: socketcan_isotp::Error) -> Self {
         match x {
-            socketcan_isotp::Error::LookupError { source } => ComServerError {
-                err_code: 2,
-                err_desc: source.to_string(),
-            },
-            socketcan_isotp::Error::IOError { source } => ComServerError {
-                err_code: 3,
-                err_desc: source.to_string(),
-            },
+            // Interface name does not exist
+            socketcan_isotp::Error::LookupError { source } => {
+                ComServerError::from_kind(ComErrorKind::DeviceNotFound, source.to_string())
+            }
+            socketcan_isotp::Error::IOError { source } => ComServerError::from(source),
         }
     }
 }
@@ -84,6 +91,15 @@ impl SocketCanAPI {
 }
 
 impl SocketCanAPI {
+    /// Error frames are not passed up as data. Bus-off is reported as an error,
+    /// as nothing can be sent or received until the bus is recovered
+    fn check_error_frame(frame: &socketcan::CANFrame) -> Result<(), ComServerError> {
+        match frame.error() {
+            Ok(CANError::BusOff) => Err(ComServerError::bus_off()),
+            _ => Ok(()),
+        }
+    }
+
     fn write_filters(&mut self) -> Result<(), ComServerError> {
         let filters: Vec<CANFilter> = self
             .can_filters
@@ -166,6 +182,7 @@ impl ComServer for SocketCanAPI {
         if timeout_ms == 0 {
             let v_timeout = 10;
             match &self.run_can_iface(|x| x.read_frame().map_err(|x| x.into())) {
+                Ok(cf) if cf.is_error() => Self::check_error_frame(cf)?,
                 Ok(cf) => res.push(CanFrame::from(*cf)),
                 Err(e) => {
                     return Ok(res); // Return what we have
@@ -178,6 +195,7 @@ impl ComServer for SocketCanAPI {
             let start = Instant::now();
             while start.elapsed().as_millis() <= timeout_ms as u128 {
                 match &self.run_can_iface(|x| x.read_frame().map_err(|x| x.into())) {
+                    Ok(cf) if cf.is_error() => Self::check_error_frame(cf)?,
                     Ok(cf) => res.push(CanFrame::from(*cf)),
                     Err(_) => {} // Ignore error when using timeout
                 }
@@ -271,6 +289,7 @@ impl ComServer for SocketCanAPI {
                 err_code: 1,
                 err_desc: x.to_string(),
             })?; // Disable blocking
+        tp_socket.error_filter_accept_all()?; // For bus-off detection
         *self.sockcan_iface.write().unwrap() = Some(tp_socket);
         Ok(())
     }
@@ -392,6 +411,10 @@ impl ComServer for SocketCanAPI {
         Ok(()) // SocketCAN will not do this - It can auto negotiate with the ECU
     }
 
+    fn set_iso15765_wait_limit(&mut self, _wft_max: u32) -> Result<(), ComServerError> {
+        Ok(()) // SocketCAN handles WAIT frames itself, bounded by its N_Bs timeout
+    }
+
     fn clear_can_rx_buffer(&self) -> Result<(), ComServerError> {
         Ok(()) // Socket CAN does not do this
     }
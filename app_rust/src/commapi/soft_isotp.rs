@@ -0,0 +1,467 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use super::{
+    comm_api::{Capability, ComServer, ComServerError, FilterType},
+    iface::{
+        add_length_prefix, hex_dump, strip_length_prefix, BufferType, CanbusInterface, Interface,
+        InterfaceConfig, InterfacePayload, InterfaceResult, PayloadFlag, IFACE_CFG,
+    },
+    isotp::{
+        self, FlowStatus, FrameLayout, IsoTpError, IsoTpFrame, IsoTpReassembler, CLASSIC_CAN_DLC,
+    },
+    protocols::ResponseOptions,
+};
+
+/// Longest time to wait for the ECU's Flow Control frame (ISO15765-2 N_Bs)
+const FLOW_CONTROL_TIMEOUT_MS: u64 = 1000;
+/// Most CAN frames read from the adapter at once
+const RECV_BATCH: usize = 32;
+/// Byte frames are padded with when padding is requested (ISO15765-2 recommends 0xCC)
+const PAD_BYTE: u8 = 0xCC;
+/// Most completed messages kept waiting to be read. Once this many are waiting, the ECU is
+/// sent a Flow Control WAIT at the start of its next transfer (Or block), and told to continue
+/// once a message has been read
+pub(crate) const RX_CAPACITY: usize = 16;
+
+/// Receive side of an ISO-TP filter
+#[derive(Debug, Clone)]
+struct Channel {
+    /// ID of the CAN filter passing the ECU's frames, which is also the ISO-TP filter's ID
+    filter_id: u32,
+    rx_id: u32,
+    mask: u32,
+    /// ID our Flow Control frames are sent to
+    fc_id: u32,
+    reassembler: IsoTpReassembler,
+    /// Consecutive frames received since our last Flow Control frame
+    cf_since_fc: u8,
+    /// The ECU was sent a WAIT, and is waiting for us to tell it to continue
+    fc_waiting: bool,
+}
+
+impl Channel {
+    fn matches(&self, id: u32) -> bool {
+        id & self.mask == self.rx_id & self.mask
+    }
+}
+
+/// ISO-TP done in software over raw CAN, for adapters which only send and receive individual
+/// CAN frames. Messages are segmented and reassembled with [crate::commapi::isotp], and flow
+/// control (Block size, ST_min and WAIT) is handled in both directions, so to the protocols
+/// this behaves like the adapter's own ISO-TP interface.
+///
+/// The Flow Control frames we send advertise [InterfaceConfig::fc_block_size] and
+/// [InterfaceConfig::fc_st_min]. Address bytes (Extended and mixed addressing) come from the
+/// payload flags, as set up by the ISO-TP addressing mode. Only classic CAN frames are supported.
+///
+/// Timing is only as precise as the OS scheduler, so ECUs with tight timeouts (N_Cr) may
+/// drop transfers when the host is under load
+#[derive(Debug)]
+pub struct SoftIsoTpInterface {
+    /// Raw CAN interface the frames are sent and received on
+    can: Box<dyn Interface>,
+    channels: Vec<Channel>,
+    block_size: u8,
+    st_min: u8,
+    wft_max: u32,
+    pad_flow_control: bool,
+    /// Address byte our frames to the ECU start with, set by the last sent message
+    tx_addr: Option<u8>,
+    /// Address byte the ECU's frames start with, set by the last sent message
+    rx_addr: Option<u8>,
+    /// Width of the length prefix used by the last sent message
+    length_prefix: Option<u8>,
+    /// Completed messages, waiting to be read
+    rx: VecDeque<InterfacePayload>,
+    /// Longest message accepted from the ECU ([IFACE_CFG::MAX_RESPONSE_LEN])
+    max_len: usize,
+    /// Error of a rejected transfer, returned by the next read
+    rx_error: Option<ComServerError>,
+}
+
+impl SoftIsoTpInterface {
+    pub fn new(dev: Box<dyn ComServer>) -> InterfaceResult<Box<dyn Interface>> {
+        Ok(Self::over_can(CanbusInterface::new(dev)?))
+    }
+
+    /// Runs software ISO-TP over an existing raw CAN interface
+    pub fn over_can(can: Box<dyn Interface>) -> Box<dyn Interface> {
+        Box::new(Self::new_raw(can))
+    }
+
+    fn new_raw(can: Box<dyn Interface>) -> Self {
+        Self {
+            can,
+            channels: Vec::new(),
+            block_size: 0,
+            st_min: 0,
+            wft_max: 10,
+            pad_flow_control: false,
+            tx_addr: None,
+            rx_addr: None,
+            length_prefix: None,
+            rx: VecDeque::new(),
+            max_len: ResponseOptions::DEFAULT_MAX_RESPONSE_LEN as usize,
+            rx_error: None,
+        }
+    }
+
+    /// Returns true if ISO-TP has to be done in software. Either the interface config asks
+    /// for it ([IFACE_CFG::SOFTWARE_ISOTP]), or the adapter has no ISO-TP of its own
+    pub fn is_enabled(dev: &dyn ComServer, cfg: &InterfaceConfig) -> bool {
+        cfg.get_param_or_default(IFACE_CFG::SOFTWARE_ISOTP, 0) > 0
+            || dev.get_capabilities().supports_iso15765() != Capability::Yes
+    }
+
+    /// Sends a single CAN frame of an ISO-TP transfer
+    fn send_frame(
+        &mut self,
+        id: u32,
+        addr: Option<u8>,
+        frame: &[u8],
+        pad: bool,
+        timeout: u32,
+    ) -> InterfaceResult<()> {
+        let mut data = Vec::with_capacity(CLASSIC_CAN_DLC);
+        data.extend(addr);
+        data.extend_from_slice(frame);
+        if pad {
+            data.resize(CLASSIC_CAN_DLC, PAD_BYTE);
+        }
+        self.can
+            .send_data(&[InterfacePayload::new(id, &data)], timeout)?;
+        Ok(())
+    }
+
+    /// Returns the ISO-TP bytes of a received frame, after checking and removing its
+    /// address byte. None if the frame is addressed to someone else
+    fn strip_address<'a>(&self, frame: &'a InterfacePayload) -> Option<&'a [u8]> {
+        match self.rx_addr {
+            None => Some(&frame.data),
+            Some(addr) if frame.data.first() == Some(&addr) => Some(&frame.data[1..]),
+            Some(_) => None,
+        }
+    }
+
+    /// Passes a received CAN frame to the reassembler of its channel, sending Flow Control
+    /// when the ECU starts a transfer, or finishes a block
+    fn on_can_frame(&mut self, frame: InterfacePayload) -> InterfaceResult<()> {
+        let idx = match self.channels.iter().position(|c| c.matches(frame.id)) {
+            Some(i) => i,
+            None => return Ok(()),
+        };
+        let data = match self.strip_address(&frame) {
+            Some(d) => d.to_vec(),
+            None => {
+                eprintln!(
+                    "ISO-TP - Dropping frame with wrong address extension {}",
+                    hex_dump(&frame.data)
+                );
+                return Ok(());
+            }
+        };
+        let block_size = self.block_size;
+        // No room for another message, so the ECU has to wait before sending the rest of it
+        let continue_status = if self.rx.len() >= RX_CAPACITY {
+            FlowStatus::Wait
+        } else {
+            FlowStatus::ContinueToSend
+        };
+        let ch = &mut self.channels[idx];
+        let mut send_fc = None; // ID and flow status of the Flow Control frame to send
+        match ch.reassembler.on_frame(&data) {
+            Ok(Some(msg)) => {
+                let mut payload = InterfacePayload {
+                    id: frame.id,
+                    data: msg,
+                    flags: vec![],
+                    timestamp: frame.timestamp,
+                };
+                if let Some(addr) = self.rx_addr {
+                    payload.flags.push(PayloadFlag::ISOTP_ADDR_EXTENSION(addr));
+                }
+                if let Some(width) = self.length_prefix {
+                    match strip_length_prefix(&payload.data, width) {
+                        Some(data) => {
+                            payload.data = data;
+                            payload.flags.push(PayloadFlag::LENGTH_PREFIXED(width));
+                        }
+                        None => eprintln!(
+                            "ISO-TP - Response {} has no valid length prefix",
+                            hex_dump(&payload.data)
+                        ),
+                    }
+                }
+                self.rx.push_back(payload);
+            }
+            Ok(None) if ch.reassembler.is_in_progress() => match data[0] >> 4 {
+                0x1 => {
+                    ch.cf_since_fc = 0;
+                    send_fc = Some((ch.fc_id, continue_status));
+                }
+                0x2 => {
+                    ch.cf_since_fc = ch.cf_since_fc.wrapping_add(1);
+                    if block_size != 0 && ch.cf_since_fc == block_size {
+                        ch.cf_since_fc = 0;
+                        send_fc = Some((ch.fc_id, continue_status));
+                    }
+                }
+                _ => {}
+            },
+            Ok(None) => {}
+            Err(e @ IsoTpError::MessageTooLong { .. }) => {
+                // Tell the ECU to abort the transfer (ISO15765-2 flow status overflow)
+                eprintln!("ISO-TP - Rejecting transfer from 0x{:04X}: {}", frame.id, e);
+                send_fc = Some((ch.fc_id, FlowStatus::Overflow));
+                self.rx_error = Some(e.into());
+            }
+            Err(e) => eprintln!("ISO-TP - Dropping frame {}: {}", hex_dump(&frame.data), e),
+        }
+        match send_fc {
+            Some((fc_id, status)) => {
+                self.channels[idx].fc_waiting = status == FlowStatus::Wait;
+                self.send_flow_control(fc_id, status)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn send_flow_control(&mut self, fc_id: u32, status: FlowStatus) -> InterfaceResult<()> {
+        let fc = isotp::flow_control_frame(status, self.block_size, self.st_min);
+        self.send_frame(fc_id, self.tx_addr, &fc, self.pad_flow_control, 0)
+    }
+
+    /// Tells ECUs sent a WAIT to continue, once there is room for their messages
+    fn resume_waiting_channels(&mut self) -> InterfaceResult<()> {
+        if self.rx.len() >= RX_CAPACITY {
+            return Ok(());
+        }
+        for idx in 0..self.channels.len() {
+            if self.channels[idx].fc_waiting {
+                self.channels[idx].fc_waiting = false;
+                let fc_id = self.channels[idx].fc_id;
+                self.send_flow_control(fc_id, FlowStatus::ContinueToSend)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for the ECU's Flow Control frame, returning its block size and ST_min once
+    /// the ECU is ready. Other frames received in the meantime are processed as normal
+    fn await_flow_control(&mut self, rx_id: u32) -> InterfaceResult<(u8, u8)> {
+        let mut waits = 0;
+        let mut deadline = Instant::now() + Duration::from_millis(FLOW_CONTROL_TIMEOUT_MS);
+        while Instant::now() < deadline {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            // One frame at a time, so frames after the Flow Control stay in the CAN interface
+            for frame in self.can.recv_data(1, timeout.as_millis() as u32)? {
+                let fc = match self.strip_address(&frame).map(IsoTpFrame::parse) {
+                    Some(Ok(IsoTpFrame::FlowControl {
+                        status,
+                        block_size,
+                        st_min,
+                    })) if frame.id == rx_id => (status, block_size, st_min),
+                    _ => {
+                        self.on_can_frame(frame)?;
+                        continue;
+                    }
+                };
+                match fc {
+                    (FlowStatus::ContinueToSend, block_size, st_min) => {
+                        return Ok((block_size, st_min))
+                    }
+                    (FlowStatus::Wait, _, _) => {
+                        waits += 1;
+                        if waits > self.wft_max {
+                            return Err(IsoTpError::WaitLimitExceeded(self.wft_max).into());
+                        }
+                        deadline = Instant::now() + Duration::from_millis(FLOW_CONTROL_TIMEOUT_MS);
+                    }
+                    (FlowStatus::Overflow, _, _) => return Err(IsoTpError::ReceiverOverflow.into()),
+                }
+            }
+        }
+        Err(ComServerError::timeout(
+            "Timeout waiting for ISO-TP flow control".into(),
+        ))
+    }
+
+    /// Segments and sends one message, following the ECU's flow control
+    fn send_message(&mut self, p: &InterfacePayload, timeout: u32) -> InterfaceResult<()> {
+        let msg = match p.get_length_prefix() {
+            Some(width) => add_length_prefix(&p.data, width)?,
+            None => p.data.clone(),
+        };
+        let tx_addr = p.get_addr_extension();
+        let frames = FrameLayout::new(CLASSIC_CAN_DLC, tx_addr.is_some()).segment(&msg)?;
+        let pad = p.is_flag_set(PayloadFlag::ISOTP_PAD_FRAME);
+        self.send_frame(p.id, tx_addr, &frames[0], pad, timeout)?;
+        if frames.len() == 1 {
+            return Ok(());
+        }
+        let rx_id = match self.channels.iter().find(|c| c.fc_id == p.id) {
+            Some(c) => c.rx_id,
+            None => {
+                return Err(ComServerError {
+                    err_code: 1,
+                    err_desc: format!("No ISO-TP filter receives flow control for 0x{:X}", p.id),
+                })
+            }
+        };
+        let mut remaining = frames[1..].iter().peekable();
+        while remaining.peek().is_some() {
+            let (block_size, st_min) = self.await_flow_control(rx_id)?;
+            let gap = isotp::st_min_duration(st_min);
+            let count = match block_size {
+                0 => usize::MAX,
+                bs => bs as usize,
+            };
+            for (i, frame) in remaining.by_ref().take(count).enumerate() {
+                if i > 0 {
+                    std::thread::sleep(gap);
+                }
+                self.send_frame(p.id, tx_addr, frame, pad, timeout)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Interface for SoftIsoTpInterface {
+    fn setup(&mut self, cfg: &InterfaceConfig) -> InterfaceResult<()> {
+        self.can.setup(cfg)?;
+        self.block_size = cfg.fc_block_size();
+        self.st_min = cfg.fc_st_min();
+        self.wft_max = cfg.get_param_or_default(IFACE_CFG::ISOTP_WFT_MAX, 10);
+        self.pad_flow_control = cfg.get_param_or_default(IFACE_CFG::PAD_FLOW_CONTROL, 0) > 0;
+        self.max_len = cfg.get_param_or_default(
+            IFACE_CFG::MAX_RESPONSE_LEN,
+            ResponseOptions::DEFAULT_MAX_RESPONSE_LEN,
+        ) as usize;
+        let max_len = self.max_len;
+        self.channels
+            .iter_mut()
+            .for_each(|c| c.reassembler = IsoTpReassembler::with_max_len(max_len));
+        Ok(())
+    }
+
+    fn send_data(&mut self, data: &[InterfacePayload], timeout: u32) -> InterfaceResult<usize> {
+        if let Some(last) = data.last() {
+            self.tx_addr = last.get_addr_extension();
+            self.rx_addr = last.get_rx_addr_extension();
+            self.length_prefix = last.get_length_prefix();
+        }
+        for p in data {
+            self.send_message(p, timeout)?;
+        }
+        Ok(data.len())
+    }
+
+    fn recv_data(&mut self, max: usize, timeout: u32) -> InterfaceResult<Vec<InterfacePayload>> {
+        let deadline = Instant::now() + Duration::from_millis(timeout as u64);
+        while self.rx.is_empty() {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            for frame in self.can.recv_data(RECV_BATCH, wait.as_millis() as u32)? {
+                self.on_can_frame(frame)?;
+            }
+            if let Some(e) = self.rx_error.take() {
+                return Err(e);
+            }
+            if Instant::now() >= deadline {
+                break;
+            }
+        }
+        let count = max.min(self.rx.len());
+        let res = self.rx.drain(..count).collect();
+        self.resume_waiting_channels()?;
+        Ok(res)
+    }
+
+    fn add_filter(&mut self, f: FilterType) -> InterfaceResult<u32> {
+        match f {
+            FilterType::IsoTP { id, mask, fc } => {
+                let filter_id = self.can.add_filter(FilterType::Pass { id, mask })?;
+                self.channels.push(Channel {
+                    filter_id,
+                    rx_id: id,
+                    mask,
+                    fc_id: fc,
+                    reassembler: IsoTpReassembler::with_max_len(self.max_len),
+                    cf_since_fc: 0,
+                    fc_waiting: false,
+                });
+                Ok(filter_id)
+            }
+            _ => Err(ComServerError {
+                err_code: 1,
+                err_desc: "Software ISO-TP only supports ISO-TP filters".into(),
+            }),
+        }
+    }
+
+    fn rem_filter(&mut self, f_id: u32) -> InterfaceResult<()> {
+        self.channels.retain(|c| c.filter_id != f_id);
+        self.can.rem_filter(f_id)
+    }
+
+    fn close(&mut self) -> InterfaceResult<()> {
+        // Closing the CAN interface removes its filters
+        self.channels.clear();
+        self.rx.clear();
+        self.rx_error = None;
+        self.can.close()
+    }
+
+    fn clear_buffer(&mut self, buffer_type: BufferType) -> InterfaceResult<()> {
+        if buffer_type != BufferType::TX {
+            self.rx.clear();
+            self.rx_error = None;
+            self.channels.iter_mut().for_each(|c| {
+                c.reassembler.reset();
+                c.fc_waiting = false;
+            });
+        }
+        self.can.clear_buffer(buffer_type)
+    }
+
+    fn flush_rx(&mut self) -> InterfaceResult<usize> {
+        let flushed = self.rx.len();
+        self.rx.clear();
+        self.rx_error = None;
+        self.channels.iter_mut().for_each(|c| {
+            c.reassembler.reset();
+            c.fc_waiting = false;
+        });
+        Ok(flushed + self.can.flush_rx()?)
+    }
+
+    fn get_server(&self) -> Box<dyn ComServer> {
+        self.can.get_server()
+    }
+
+    fn clone_box(&self) -> Box<dyn Interface> {
+        let mut res = Self::new_raw(self.can.clone_box());
+        res.channels = self
+            .channels
+            .iter()
+            .map(|c| Channel {
+                reassembler: IsoTpReassembler::with_max_len(self.max_len),
+                cf_since_fc: 0,
+                fc_waiting: false,
+                ..c.clone()
+            })
+            .collect();
+        res.block_size = self.block_size;
+        res.st_min = self.st_min;
+        res.wft_max = self.wft_max;
+        res.pad_flow_control = self.pad_flow_control;
+        res.tx_addr = self.tx_addr;
+        res.rx_addr = self.rx_addr;
+        res.length_prefix = self.length_prefix;
+        res.max_len = self.max_len;
+        Box::new(res)
+    }
+}
@@ -163,6 +163,7 @@ impl<'a> CanTracer {
                     id: 0x07DF,
                     data: vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
                     flags: vec![],
+                    timestamp: None,
                 }],
                 0,
             ) {
@@ -14,7 +14,7 @@ use crate::{
             BufferType, DynamicInterface, Interface, InterfaceConfig, InterfacePayload,
             InterfaceType, PayloadFlag, IFACE_CFG,
         },
-        protocols::{kwp2000::read_ecu_identification::read_dcx_mmc_id, DiagCfg},
+        protocols::{kwp2000::read_ecu_identification::read_dcx_mmc_id, DiagCfg, ProtocolVariant},
     },
     themes::{
         button_coloured, button_outlined, progress_bar, text, title_text, ButtonType, TextType,
@@ -346,6 +346,7 @@ impl DiagScanner {
                     send_id: ecu.send_id,
                     recv_id: ecu.recv_id,
                     global_id: None,
+                    variant: ProtocolVariant::Unknown,
                 };
 
                 let mut ecu_res = ECUDiagSettings {
@@ -399,6 +400,7 @@ impl DiagScanner {
                     send_id: ecu.send_id,
                     recv_id: ecu.recv_id,
                     global_id: None,
+                    variant: ProtocolVariant::Unknown,
                 };
 
                 // Interrogate the ECU with extended diagnostic session
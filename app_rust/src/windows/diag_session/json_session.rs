@@ -1,6 +1,6 @@
 use crate::commapi::{
     iface::{InterfaceConfig, InterfaceType, PayloadFlag, IFACE_CFG},
-    protocols::{kwp2000::read_ecu_identification, DiagCfg},
+    protocols::{kwp2000::read_ecu_identification, DiagCfg, ProtocolVariant},
 };
 use common::schema::{
     diag::{dtc::ECUDTC, service::Service},
@@ -134,6 +134,7 @@ impl JsonDiagSession {
                     send_id: connection_settings.send_id,
                     recv_id: connection_settings.recv_id,
                     global_id: connection_settings.global_send_id,
+                    variant: ProtocolVariant::Unknown,
                 };
 
                 let tx_flags = vec![PayloadFlag::ISOTP_PAD_FRAME];
@@ -457,7 +458,7 @@ impl SessionTrait for JsonDiagSession {
                             let ecu_dtc = dtc_list
                                 .clone()
                                 .into_iter()
-                                .find(|x| x.error_name.ends_with(&raw_dtc.error))
+                                .find(|x| raw_dtc.matches_name(&x.error_name))
                                 .unwrap_or(ECUDTC {
                                     error_name: raw_dtc.error.clone(),
                                     summary: "UNKNOWN ERROR".into(),
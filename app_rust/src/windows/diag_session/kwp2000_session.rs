@@ -6,8 +6,8 @@ use log_view::{LogType, LogView};
 use crate::{
     commapi::{
         comm_api::{ComServer, ISO15765Config},
-        iface::{InterfaceConfig, InterfaceType, PayloadFlag, IFACE_CFG},
-        protocols::{kwp2000::KWP2000ECU, DTCState, DiagCfg, ProtocolServer},
+        iface::{format_hex, InterfaceConfig, InterfaceType, PayloadFlag, IFACE_CFG},
+        protocols::{kwp2000::KWP2000ECU, DTCState, DiagCfg, ProtocolServer, ProtocolVariant},
     },
     themes::{button_outlined, text, text_input, title_text, ButtonType, TextType, TitleSize},
     windows::window,
@@ -175,6 +175,7 @@ impl SessionTrait for KWP2000DiagSession {
                     send_id: self.ecu.send_id,
                     recv_id: self.ecu.recv_id,
                     global_id: None,
+                    variant: ProtocolVariant::Unknown,
                 };
 
                 match KWP2000ECU::start_diag_session(
@@ -306,12 +307,12 @@ impl SessionTrait for KWP2000DiagSession {
                         if let Some(server) = &self.diag_server {
                             match server.run_command(r[0], &r[1..]) {
                                 Ok(res) => self.logview.add_log(
-                                    format!("Req:  {:02X?}", r),
-                                    format!("Resp: {:02X?}", res),
+                                    format!("Req:  {}", format_hex(&r)),
+                                    format!("Resp: {}", format_hex(&res)),
                                     LogType::Info,
                                 ),
                                 Err(e) => self.logview.add_log(
-                                    format!("Req:  {:02X?}", r),
+                                    format!("Req:  {}", format_hex(&r)),
                                     format!("Exec error: {}", e.get_text()),
                                     LogType::Error,
                                 ),
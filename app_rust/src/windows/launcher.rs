@@ -111,7 +111,7 @@ impl Launcher {
                         Ok((details, driver)) => {
                             let mut server = PassthruApi::new(details, driver);
                             if let Err(e) = server.open_device() {
-                                self.status_text = e.to_string()
+                                self.status_text = Self::open_error_text(&e)
                             } else {
                                 // Ready to launch OVD!
                                 return Some(WindowMessage::StartApp(server.clone_box()));
@@ -126,7 +126,7 @@ impl Launcher {
                     {
                         let mut server = SocketCanAPI::new(self.selected_device_socketcan.clone());
                         if let Err(e) = server.open_device() {
-                            self.status_text = e.to_string()
+                            self.status_text = Self::open_error_text(&e)
                         } else {
                             // Ready to launch OVD!
                             return Some(WindowMessage::StartApp(server.clone_box()));
@@ -262,6 +262,14 @@ impl Launcher {
             .into()
     }
 
+    /// Describes why the adapter could not be opened, telling the user how to fix it if possible
+    fn open_error_text(e: &ComServerError) -> String {
+        match e.get_help() {
+            Some(help) => format!("{} ({})", help, e),
+            None => e.to_string(),
+        }
+    }
+
     fn get_device_passthru(&self) -> Result<(PassthruDevice, PassthruDrv)> {
         match self
             .device_list_passthru
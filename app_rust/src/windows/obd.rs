@@ -5,7 +5,7 @@ use crate::{
         iface::{InterfaceConfig, InterfaceType, PayloadFlag, IFACE_CFG},
         protocols::{
             obd2::{service09::Service09Data, ObdServer},
-            DiagCfg, ProtocolServer,
+            DiagCfg, ProtocolServer, ProtocolVariant,
         },
     },
     themes::button_coloured,
@@ -59,6 +59,7 @@ impl OBDHome {
                         send_id: 0x07DF,
                         recv_id: *test_id,
                         global_id: None,
+                        variant: ProtocolVariant::Unknown,
                     };
                     if let Ok(server) = ObdServer::start_diag_session(
                         &self.server,